@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use criterion::{BatchSize, Criterion, SamplingMode, Throughput};
+use payments_engine::input::{self, CsvOptions, InvalidRows};
+use payments_engine::output::{self, OutputFormat};
+use payments_engine::parallel;
+use payments_engine::{Engine, RoundingMode, Transaction};
+use serde_json::Value;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::hint::black_box;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+/// Name of the benchmark group, which criterion keeps its results under
+const GROUP: &str = "throughput";
+
+/// Paths measured, in the order they're run
+const PATHS: [&str; 5] = ["parse", "parse_fast", "engine", "end_to_end", "pipelined"];
+
+/// Default relative change in a path's time that's considered noise rather than a regression,
+/// criterion's `--noise-threshold`
+const NOISE_THRESHOLD: f64 = 0.05;
+
+/// Measures the rows per second of parsing csvs (with and without `--fast-parse`), of the engine
+/// applying already parsed transactions, and of processing csvs end to end, on one thread and
+/// pipelined, on generated transactions. `BENCH_ROWS` and `BENCH_CLIENTS` set how many rows are
+/// generated and how many clients they're spread over.
+///
+/// Results can be saved as a named criterion baseline and later runs compared against it, which
+/// fails the run when a path got slower by more than the noise threshold:
+///
+/// cargo bench --bench throughput -- --save-baseline main
+/// cargo bench --bench throughput -- --baseline main
+fn main() -> Result<ExitCode> {
+    let started = SystemTime::now();
+    let mut criterion = Criterion::default()
+        .output_directory(&output_directory())
+        .noise_threshold(NOISE_THRESHOLD)
+        .configure_from_args();
+
+    let rows = env_var("BENCH_ROWS", 200_000)?;
+    let clients = env_var("BENCH_CLIENTS", 1_000)?.max(1);
+    let csv = generate_csv(rows, clients);
+    let options = CsvOptions::default();
+    let fast_options = CsvOptions {
+        fast_parse: true,
+        ..CsvOptions::default()
+    };
+    let mut transactions = Vec::new();
+    input::read_csv_rows(csv.as_bytes(), |row| {
+        transactions.push(row.transaction?);
+        Ok(())
+    })?;
+
+    let mut group = criterion.benchmark_group(GROUP);
+    // Every iteration goes through all the rows, so there's no need for many of them
+    group
+        .throughput(Throughput::Elements(rows as u64))
+        .sampling_mode(SamplingMode::Flat)
+        .sample_size(10);
+    group.bench_function(PATHS[0], |b| b.iter(|| parse_only(&csv, &options).unwrap()));
+    group.bench_function(PATHS[1], |b| {
+        b.iter(|| parse_only(&csv, &fast_options).unwrap())
+    });
+    group.bench_function(PATHS[2], |b| {
+        b.iter_batched(
+            || transactions.clone(),
+            |transactions| engine_only(transactions).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function(PATHS[3], |b| b.iter(|| end_to_end(&csv, &options).unwrap()));
+    group.bench_function(PATHS[4], |b| b.iter(|| pipelined(&csv, &options).unwrap()));
+    group.finish();
+    criterion.final_summary();
+
+    // Criterion only reports regressions, so check for them to fail the run
+    let Some(baseline) = arg_value("--baseline") else {
+        return Ok(ExitCode::SUCCESS);
+    };
+    let threshold = match arg_value("--noise-threshold") {
+        Some(threshold) => threshold.parse().context("Invalid --noise-threshold")?,
+        None => NOISE_THRESHOLD,
+    };
+    let mut regressions = 0;
+    for path in PATHS {
+        if let Some(change) = change_since(path, started)? {
+            if change > threshold {
+                println!(
+                    "{GROUP}/{path} regressed against baseline {baseline}: at least {:+.1}% time",
+                    change * 100.0
+                );
+                regressions += 1;
+            }
+        }
+    }
+    Ok(match regressions {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    })
+}
+
+/// Reads the rows without applying them
+fn parse_only(csv: &str, options: &CsvOptions) -> Result<()> {
+    input::read_csv_inputs(
+        vec![csv.as_bytes()],
+        options,
+        RoundingMode::default(),
+        |row| {
+            black_box(row.transaction?);
+            Ok(())
+        },
+    )
+}
+
+/// Applies the transactions, parsed beforehand
+fn engine_only(transactions: Vec<Transaction>) -> Result<Engine> {
+    let mut engine = Engine::new();
+    for transaction in transactions {
+        engine.process_transaction(transaction)?;
+    }
+    Ok(engine)
+}
+
+/// Processes the csv and writes the accounts, like the command line does
+fn end_to_end(csv: &str, options: &CsvOptions) -> Result<()> {
+    let mut engine = Engine::new();
+    input::process_csv_readers(
+        &mut engine,
+        vec![csv.as_bytes()],
+        options,
+        InvalidRows::Fail,
+        None,
+    )?;
+    output::write_accounts(&engine, OutputFormat::Csv, io::sink())
+}
+
+/// Processes the csv and writes the accounts like `--pipeline` does, parsing on another thread
+fn pipelined(csv: &str, options: &CsvOptions) -> Result<()> {
+    let mut engine = Engine::new();
+    parallel::process_csv_readers_pipelined(
+        &mut engine,
+        vec![csv.as_bytes()],
+        options,
+        InvalidRows::Fail,
+    )?;
+    output::write_accounts(&engine, OutputFormat::Csv, io::sink())
+}
+
+/// Generates rows the engine applies every one of: deposits, withdrawals never overdrawing an
+/// account, and every 50th row a dispute of the client's last deposit, resolved right after. The
+/// same arguments always generate the same rows.
+fn generate_csv(rows: u32, clients: u16) -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    let mut balances = vec![0u64; clients as usize];
+    let mut last_deposits = vec![None; clients as usize];
+    let mut random = 0x2545_f491_4f6c_dd1d_u64;
+    let mut tx_id = 0u32;
+    let mut row = 0;
+
+    while row < rows {
+        // Xorshift, good enough to spread rows over clients and amounts
+        random ^= random << 13;
+        random ^= random >> 7;
+        random ^= random << 17;
+        let client = (random % clients as u64) as usize;
+        let amount = (random >> 16) % 1000_0000 + 1;
+
+        match (row % 50, last_deposits[client]) {
+            (0, Some(deposit)) if row + 1 < rows => {
+                let _ = writeln!(csv, "dispute,{},{deposit},", client + 1);
+                let _ = writeln!(csv, "resolve,{},{deposit},", client + 1);
+                row += 2;
+                continue;
+            }
+            _ => {}
+        }
+        tx_id += 1;
+        if random.is_multiple_of(4) && balances[client] >= amount {
+            balances[client] -= amount;
+            let _ = writeln!(
+                csv,
+                "withdrawal,{},{tx_id},{}.{:04}",
+                client + 1,
+                amount / 1_0000,
+                amount % 1_0000
+            );
+        } else {
+            balances[client] += amount;
+            last_deposits[client] = Some(tx_id);
+            let _ = writeln!(
+                csv,
+                "deposit,{},{tx_id},{}.{:04}",
+                client + 1,
+                amount / 1_0000,
+                amount % 1_0000
+            );
+        }
+        row += 1;
+    }
+    csv
+}
+
+fn output_directory() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("criterion")
+}
+
+fn env_var<T: std::str::FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().with_context(|| format!("Invalid {name}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Value of a criterion argument, given as `--name value` or `--name=value`
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(name).and_then(|arg| arg.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Lower bound of the confidence interval of the relative change in a path's mean time, from the
+/// comparison criterion wrote during this run, if it measured the path
+fn change_since(path: &str, started: SystemTime) -> Result<Option<f64>> {
+    let file = output_directory()
+        .join(GROUP)
+        .join(path)
+        .join("change")
+        .join("estimates.json");
+    let Ok(modified) = fs::metadata(&file).and_then(|metadata| metadata.modified()) else {
+        return Ok(None);
+    };
+    if modified < started {
+        return Ok(None);
+    }
+    let estimates: Value = serde_json::from_slice(&fs::read(&file)?)?;
+    let change = estimates["mean"]["confidence_interval"]["lower_bound"]
+        .as_f64()
+        .with_context(|| format!("No mean change in {}", file.display()))?;
+    Ok(Some(change))
+}
@@ -0,0 +1,14 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto");
+    #[cfg(feature = "grpc")]
+    {
+        // protox compiles the protobuf definitions, so that protoc doesn't have to be installed
+        let descriptors = protox::compile(["proto/payments.proto"], ["proto"])
+            .expect("Failed to compile proto/payments.proto");
+        // Clients are generated by the `payments-engine-client` crate
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("Failed to generate the gRPC service");
+    }
+}
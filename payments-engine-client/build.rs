@@ -0,0 +1,20 @@
+use prost::Message;
+use std::path::PathBuf;
+use std::{env, fs};
+
+fn main() {
+    println!("cargo:rerun-if-changed=../proto");
+    // protox compiles the protobuf definitions, so that protoc doesn't have to be installed
+    let descriptors = protox::compile(["payments.proto"], ["../proto"])
+        .expect("Failed to compile proto/payments.proto");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    fs::write(
+        out_dir.join("payments_descriptor.bin"),
+        descriptors.encode_to_vec(),
+    )
+    .expect("Failed to write the descriptor set");
+    tonic_prost_build::configure()
+        .build_server(false)
+        .compile_fds(descriptors)
+        .expect("Failed to generate the gRPC client");
+}
@@ -0,0 +1,64 @@
+//! A client of the `PaymentsEngine` gRPC service of the payments engine's `grpc` mode, generated
+//! from [`proto/payments.proto`](../proto/payments.proto), for Rust services that submit
+//! transactions to a running engine without copying its protobuf definitions.
+//!
+//! ```no_run
+//! use payments_engine_client::{PaymentsEngineClient, Transaction, TransactionType};
+//!
+//! # async fn submit() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut client = PaymentsEngineClient::connect("http://127.0.0.1:50051").await?;
+//! let response = client
+//!     .submit_transaction(Transaction {
+//!         r#type: TransactionType::Deposit.into(),
+//!         client: 1,
+//!         tx: 1,
+//!         amount: Some("10.5".to_string()),
+//!         timestamp: None,
+//!         currency: None,
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+/// The messages and client generated from `proto/payments.proto`
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("payments");
+}
+
+pub use proto::payments_engine_client::PaymentsEngineClient;
+pub use proto::{
+    submit_response, Account, ExportReportRequest, GetAccountRequest, GetAccountResponse,
+    Rejection, SubmitResponse, Transaction, TransactionType,
+};
+
+/// The encoded `FileDescriptorSet` of `proto/payments.proto`, for gRPC reflection or tools
+/// decoding the service's messages dynamically. The same descriptors are shipped as
+/// `proto/payments_descriptor.bin` for clients in other languages, e.g.
+/// `grpcurl -protoset proto/payments_descriptor.bin`.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/payments_descriptor.bin"));
+
+#[cfg(test)]
+mod tests {
+    use crate::FILE_DESCRIPTOR_SET;
+    use prost::Message;
+    use prost_types::FileDescriptorSet;
+
+    #[test]
+    fn test_shipped_descriptor_set() {
+        let compiled = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+        let service = &compiled.file[0].service[0];
+        assert_eq!(service.name(), "PaymentsEngine");
+        assert_eq!(service.method.len(), 4);
+
+        // The shipped descriptors are those of the current proto, regenerated with
+        // `cp target/debug/build/payments-engine-client-*/out/payments_descriptor.bin proto/`
+        let shipped = FileDescriptorSet::decode(
+            include_bytes!("../../proto/payments_descriptor.bin").as_slice(),
+        )
+        .unwrap();
+        assert_eq!(shipped, compiled);
+    }
+}
@@ -0,0 +1,338 @@
+//! Node.js bindings of the payments engine, for TypeScript and JavaScript services that embed it
+//! instead of running the binary and parsing its output. Transactions and accounts have the
+//! fields of the transactions csv and the accounts report, amounts being decimal strings so that
+//! none of their 4 decimal places are lost to floats.
+//!
+//! ```js
+//! const { Engine } = require('payments-engine')
+//!
+//! const engine = new Engine()
+//! engine.submit({ type: 'deposit', client: 1, tx: 1, amount: '10.5' })
+//! const { rejection } = engine.submit({ type: 'withdrawal', client: 1, tx: 2, amount: '20' })
+//! // rejection.code === 'insufficient_funds'
+//! const [account] = engine.clientAccounts(1)
+//! // account.available === '10.5000'
+//! ```
+
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use payments_engine::transaction::RawTransaction;
+use payments_engine::{
+    fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str, AccountView,
+    Currency, Engine, RoundingMode, Transaction,
+};
+use std::fmt::Display;
+
+/// A transaction, with the same fields as a transactions csv row
+#[napi(object)]
+pub struct TransactionInput {
+    /// As in the csv, e.g. `deposit`
+    #[napi(js_name = "type")]
+    pub transaction_type: String,
+    /// Client ids are 16-bit
+    pub client: u32,
+    pub tx: u32,
+    /// Decimal amount of deposits, withdrawals and holds, e.g. `"1.5"`
+    pub amount: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub currency: Option<String>,
+}
+
+/// The balances of a client's account in a currency, as decimal strings
+#[napi(object)]
+pub struct Account {
+    pub client: u32,
+    pub currency: Option<String>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+}
+
+/// Outcome of a submitted transaction: the state of the account it applied to, or why it was
+/// rejected
+#[napi(object)]
+pub struct SubmitResult {
+    pub tx: u32,
+    pub account: Option<Account>,
+    pub rejection: Option<Rejection>,
+}
+
+#[napi(object)]
+pub struct Rejection {
+    /// The code written to `--rejected-output`, e.g. `insufficient_funds`
+    pub code: String,
+    pub message: String,
+}
+
+/// An engine, which applies transactions one at a time in the order they're submitted
+#[napi(js_name = "Engine")]
+pub struct NodeEngine {
+    engine: Engine,
+}
+
+#[napi]
+impl NodeEngine {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        Self {
+            engine: Engine::new(),
+        }
+    }
+
+    /// Applies a transaction. Rejected transactions get a result with a rejection, while invalid
+    /// ones, like a deposit without an amount, throw.
+    #[napi]
+    pub fn submit(&mut self, transaction: TransactionInput) -> Result<SubmitResult> {
+        let tx = transaction.tx;
+        let (transaction, timestamp) = read_transaction(transaction, self.rounding())?;
+        Ok(self.apply(tx, transaction, timestamp))
+    }
+
+    /// Applies transactions in order, carrying on past the rejected ones, and returns the outcome
+    /// of every one of them. The whole batch is read before any of its transactions is applied,
+    /// so that a batch with an invalid transaction throws, leaving the engine as it was.
+    #[napi]
+    pub fn submit_batch(
+        &mut self,
+        transactions: Vec<TransactionInput>,
+    ) -> Result<Vec<SubmitResult>> {
+        let rounding = self.rounding();
+        let transactions = transactions
+            .into_iter()
+            .map(|transaction| {
+                let tx = transaction.tx;
+                read_transaction(transaction, rounding)
+                    .map(|(transaction, timestamp)| (tx, transaction, timestamp))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(transactions
+            .into_iter()
+            .map(|(tx, transaction, timestamp)| self.apply(tx, transaction, timestamp))
+            .collect())
+    }
+
+    /// A client's account in a currency, the default one if none is given, if it has balances in
+    /// it
+    #[napi]
+    pub fn account(&self, client: u32, currency: Option<String>) -> Result<Option<Account>> {
+        let currency = currency
+            .as_deref()
+            .map(str::parse::<Currency>)
+            .transpose()
+            .map_err(invalid_argument)?;
+        Ok(self
+            .engine
+            .account_in(client_id(client)?, currency)
+            .map(account))
+    }
+
+    /// A client's account in every currency it has balances in, the default currency first
+    #[napi]
+    pub fn client_accounts(&self, client: u32) -> Result<Vec<Account>> {
+        Ok(self
+            .engine
+            .client_accounts(client_id(client)?)
+            .map(account)
+            .collect())
+    }
+
+    /// Every account, by client id and currency
+    #[napi]
+    pub fn accounts(&self) -> Vec<Account> {
+        let mut accounts: Vec<_> = self.engine.accounts().collect();
+        accounts.sort_by_key(|account| (account.client_id, account.currency));
+        accounts.into_iter().map(account).collect()
+    }
+
+    fn rounding(&self) -> RoundingMode {
+        self.engine.config().rounding
+    }
+
+    fn apply(&mut self, tx: u32, transaction: Transaction, timestamp: Option<u64>) -> SubmitResult {
+        let target = transaction.clone();
+        match self.engine.process_transaction_at(transaction, timestamp) {
+            Ok(()) => SubmitResult {
+                tx,
+                account: self.engine.account_for(&target).map(account),
+                rejection: None,
+            },
+            Err(e) => SubmitResult {
+                tx,
+                account: None,
+                rejection: Some(Rejection {
+                    code: e.code().to_string(),
+                    message: e.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+impl Default for NodeEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The engine's transaction of a JavaScript one, along with its timestamp
+fn read_transaction(
+    transaction: TransactionInput,
+    rounding: RoundingMode,
+) -> Result<(Transaction, Option<u64>)> {
+    let tx = transaction.tx;
+    let invalid =
+        |error: &dyn Display| invalid_argument(format!("Invalid transaction {tx}: {error}"));
+    let timestamp = transaction
+        .timestamp
+        .map(u64::try_from)
+        .transpose()
+        .map_err(|_| invalid(&"Timestamps can't be negative"))?;
+    let client = u16::try_from(transaction.client)
+        .map_err(|_| invalid(&format_args!("Invalid client id: {}", transaction.client)))?;
+    let raw = RawTransaction {
+        transaction_type: transaction
+            .transaction_type
+            .parse()
+            .map_err(|e| invalid(&e))?,
+        client,
+        tx,
+        amount: transaction.amount,
+        timestamp,
+        currency: transaction
+            .currency
+            .as_deref()
+            .map(str::parse::<Currency>)
+            .transpose()
+            .map_err(|e| invalid(&e))?,
+    };
+    let parsed = raw.into_transaction(rounding).map_err(|e| invalid(&e))?;
+    Ok((parsed, timestamp))
+}
+
+fn client_id(client: u32) -> Result<u16> {
+    u16::try_from(client).map_err(|_| invalid_argument(format!("Invalid client id: {client}")))
+}
+
+fn invalid_argument(error: impl Display) -> Error {
+    Error::new(Status::InvalidArg, error.to_string())
+}
+
+fn account(view: AccountView) -> Account {
+    Account {
+        client: view.client_id.into(),
+        currency: view.currency.map(String::from),
+        available: signed_fixed_point_4_decimal_to_float_str(view.available_amount),
+        held: fixed_point_4_decimal_to_float_str(view.held_amount),
+        total: signed_fixed_point_4_decimal_to_float_str(view.total_amount),
+        locked: view.locked,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NodeEngine, TransactionInput};
+
+    fn transaction(
+        transaction_type: &str,
+        client: u32,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> TransactionInput {
+        TransactionInput {
+            transaction_type: transaction_type.to_string(),
+            client,
+            tx,
+            amount: amount.map(str::to_string),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_submit() {
+        let mut engine = NodeEngine::new();
+        let result = engine
+            .submit(transaction("deposit", 1, 1, Some("10.5")))
+            .unwrap();
+        let account = result.account.unwrap();
+        assert_eq!((account.client, account.available.as_str()), (1, "10.5000"));
+
+        let result = engine
+            .submit(transaction("withdrawal", 1, 2, Some("20")))
+            .unwrap();
+        assert!(result.account.is_none());
+        assert_eq!(result.rejection.unwrap().code, "insufficient_funds");
+
+        // Invalid transactions throw rather than being rejected
+        for invalid in [
+            transaction("deposit", 1, 3, None),
+            transaction("bonus", 1, 3, Some("1")),
+            transaction("deposit", 70_000, 3, Some("1")),
+            TransactionInput {
+                timestamp: Some(-1),
+                ..transaction("deposit", 1, 3, Some("1"))
+            },
+        ] {
+            assert!(engine.submit(invalid).is_err());
+        }
+        assert!(engine.engine.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn test_submit_batch() {
+        let mut engine = NodeEngine::new();
+        let results = engine
+            .submit_batch(vec![
+                transaction("deposit", 2, 1, Some("1")),
+                transaction("deposit", 2, 1, Some("1")),
+                TransactionInput {
+                    currency: Some("eur".to_string()),
+                    ..transaction("deposit", 1, 2, Some("2.25"))
+                },
+            ])
+            .unwrap();
+        let codes: Vec<_> = results
+            .iter()
+            .map(|result| {
+                result
+                    .rejection
+                    .as_ref()
+                    .map(|rejection| rejection.code.as_str())
+            })
+            .collect();
+        assert_eq!(codes, [None, Some("duplicate_tx_id"), None]);
+
+        // Batches with an invalid transaction are rejected before any of their transactions apply
+        assert!(engine
+            .submit_batch(vec![
+                transaction("deposit", 3, 3, Some("1")),
+                transaction("deposit", 3, 4, Some("one")),
+            ])
+            .is_err());
+        assert!(engine.client_accounts(3).unwrap().is_empty());
+
+        let accounts: Vec<_> = engine
+            .accounts()
+            .into_iter()
+            .map(|account| (account.client, account.currency, account.total))
+            .collect();
+        assert_eq!(
+            accounts,
+            [
+                (1, Some("EUR".to_string()), "2.2500".to_string()),
+                (2, None, "1.0000".to_string()),
+            ]
+        );
+        assert!(engine.account(1, None).unwrap().is_none());
+        assert_eq!(
+            engine
+                .account(1, Some("EUR".to_string()))
+                .unwrap()
+                .unwrap()
+                .held,
+            "0.0000"
+        );
+    }
+}
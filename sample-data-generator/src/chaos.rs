@@ -0,0 +1,158 @@
+use crate::ledger::Ledger;
+use crate::output::Output;
+use crate::{Args, Row};
+use rand::rngs::ChaCha8Rng;
+use rand::{RngExt, SeedableRng};
+use std::collections::VecDeque;
+use std::io;
+use std::ops::Not;
+
+/// Upper bound on the number of rows a duplicated segment repeats
+const MAX_DUPLICATED_ROWS: usize = 100;
+
+/// Damages the generated rows the way files get damaged in practice: bytes flipped in transit,
+/// chunks sent twice and writers crashing mid-row. Rows are written (and applied to the ledger)
+/// through it, so the expected account states account for the damage.
+pub struct Chaos {
+    /// Kept apart from the generator's, so the rows are the same as without any damage
+    rng: ChaCha8Rng,
+    flip_rate: f64,
+    duplicate_rate: f64,
+    truncate: bool,
+    /// The latest rows, as generated, which duplicated segments repeat
+    recent: VecDeque<(Row, Option<u64>)>,
+    /// With `truncate`, the latest row, held back until it's known whether it's the last one
+    held_back: Option<(Row, Option<u64>)>,
+}
+
+impl Chaos {
+    /// The damage asked for by `args`, if any
+    pub fn new(seed: u64, args: &Args) -> Option<Self> {
+        if args.flip_rate == 0.0 && args.duplicate_rate == 0.0 && args.truncate.not() {
+            return None;
+        }
+
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(1);
+        Some(Self {
+            rng,
+            flip_rate: args.flip_rate,
+            duplicate_rate: args.duplicate_rate,
+            truncate: args.truncate,
+            recent: VecDeque::with_capacity(MAX_DUPLICATED_ROWS),
+            held_back: None,
+        })
+    }
+
+    /// Writes a row, possibly damaged or followed by a duplicated segment, and applies to `ledger`
+    /// whatever the engine will accept of it
+    pub fn write(
+        &mut self,
+        row: Row,
+        timestamp: Option<u64>,
+        output: &mut Output,
+        ledger: &mut Ledger,
+    ) -> io::Result<()> {
+        let (row, timestamp) = match self.truncate {
+            true => match self.held_back.replace((row, timestamp)) {
+                Some(held_back) => held_back,
+                None => return Ok(()),
+            },
+            false => (row, timestamp),
+        };
+
+        if self.rng.random_bool(self.flip_rate) {
+            let timestamps = timestamp.is_some();
+            output.write_with(&row, timestamp, |record| {
+                flip_byte(record, timestamps, &mut self.rng)
+            })?;
+        } else {
+            output.write(&row, timestamp)?;
+            ledger.apply(&row);
+        }
+
+        if self.recent.len() == MAX_DUPLICATED_ROWS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((row, timestamp));
+
+        // A chunk sent again holds the rows as they were before any damage
+        if self.rng.random_bool(self.duplicate_rate) {
+            let rows = self.rng.random_range(1..=self.recent.len());
+            for (row, timestamp) in self.recent.range(self.recent.len() - rows..) {
+                output.write(row, *timestamp)?;
+                ledger.apply(row);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the row held back with `truncate`, cut short
+    pub fn finish(&mut self, output: &mut Output) -> io::Result<()> {
+        match self.held_back.take() {
+            Some((row, timestamp)) => {
+                output.write_with(&row, timestamp, |record| truncate(record, &mut self.rng))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Flips the high bit of one of the bytes of a csv `record`, which makes it invalid UTF-8. The row
+/// terminator and the timestamp, which the engine doesn't read, are never flipped, so the engine
+/// always rejects the row.
+fn flip_byte(record: &mut [u8], timestamps: bool, rng: &mut ChaCha8Rng) {
+    let end = match timestamps {
+        // Flipping the comma before the timestamp is fine, it merges the two fields
+        true => nth_comma(record, 3) + 1,
+        false => record.len() - 1,
+    };
+    record[rng.random_range(0..end)] ^= 0x80;
+}
+
+/// Cuts a csv `record` short somewhere before the end of its tx id, dropping the row terminator,
+/// so it has too few fields for the engine to accept it
+fn truncate(record: &mut Vec<u8>, rng: &mut ChaCha8Rng) {
+    let len = rng.random_range(1..=nth_comma(record, 2));
+    record.truncate(len);
+}
+
+/// Index of the `n`-th comma of `record`, counting from 0
+fn nth_comma(record: &[u8], n: usize) -> usize {
+    record
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == b',')
+        .nth(n)
+        .map(|(index, _)| index)
+        .expect("Generated rows have a comma between every two fields")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chaos::{flip_byte, truncate};
+    use rand::rngs::ChaCha8Rng;
+    use rand::SeedableRng;
+    use std::ops::Not;
+
+    #[test]
+    fn test_damaged_rows_are_rejected() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut record = b"deposit,42,7,1.5000\n".to_vec();
+            flip_byte(&mut record, false, &mut rng);
+            assert!(std::str::from_utf8(&record).is_err());
+            assert_eq!(record.last(), Some(&b'\n'));
+
+            let mut record = b"dispute,42,7,,1704067200000\n".to_vec();
+            flip_byte(&mut record, true, &mut rng);
+            assert!(std::str::from_utf8(&record).is_err());
+            assert!(record.ends_with(b"1704067200000\n"));
+
+            let mut record = b"withdrawal,42,7,1.5000\n".to_vec();
+            truncate(&mut record, &mut rng);
+            assert!(record.is_empty().not());
+            assert!(record.iter().filter(|byte| **byte == b',').count() < 3);
+        }
+    }
+}
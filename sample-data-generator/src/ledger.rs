@@ -0,0 +1,206 @@
+use crate::{format_amount, format_signed_amount, Row};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::ops::Not;
+
+/// A deliberately naive re-implementation of the engine's rules, kept independent from the engine's
+/// code, used to compute the account states the engine is expected to output for a generated file
+#[derive(Default)]
+pub struct Ledger {
+    accounts: BTreeMap<u16, Account>,
+    tx_ids: HashSet<u32>,
+    /// Applied deposits and withdrawals, which can be disputed
+    disputables: HashMap<u32, Disputable>,
+}
+
+#[derive(Default)]
+struct Account {
+    available: i64,
+    held: i64,
+    locked: bool,
+}
+
+struct Disputable {
+    client_id: u16,
+    withdrawal: bool,
+    amount: i64,
+    disputed: bool,
+    charged_back: bool,
+}
+
+impl Ledger {
+    /// Applies a generated row, ignoring it whenever the engine would reject it
+    pub fn apply(&mut self, row: &Row) {
+        let (transaction_type, client_id, tx_id, amount) = row;
+        let (client_id, tx_id) = (*client_id, *tx_id);
+
+        match *transaction_type {
+            "deposit" | "withdrawal" => {
+                let Some(amount) = amount.as_deref().and_then(parse_engine_amount) else {
+                    return;
+                };
+                if self.tx_ids.insert(tx_id).not() {
+                    return;
+                }
+
+                if *transaction_type == "deposit" {
+                    let account = self.accounts.entry(client_id).or_default();
+                    if account.locked.not() {
+                        account.available += amount;
+                        self.disputables.insert(
+                            tx_id,
+                            Disputable {
+                                client_id,
+                                withdrawal: false,
+                                amount,
+                                disputed: false,
+                                charged_back: false,
+                            },
+                        );
+                    }
+                } else if let Some(account) = self.accounts.get_mut(&client_id) {
+                    if account.locked.not() && account.available >= amount {
+                        account.available -= amount;
+                        self.disputables.insert(
+                            tx_id,
+                            Disputable {
+                                client_id,
+                                withdrawal: true,
+                                amount,
+                                disputed: false,
+                                charged_back: false,
+                            },
+                        );
+                    }
+                }
+            }
+            "dispute" | "resolve" | "chargeback" => {
+                if amount.is_some() {
+                    return;
+                }
+                let Some(disputable) = self.disputables.get_mut(&tx_id) else {
+                    return;
+                };
+                if disputable.client_id != client_id || disputable.charged_back {
+                    return;
+                }
+                let account = self
+                    .accounts
+                    .get_mut(&client_id)
+                    .expect("Clients with deposits or withdrawals have an account");
+                // A disputed deposit's amount is no longer available, while a disputed
+                // withdrawal's is held on top of the available funds
+                let available = match disputable.withdrawal {
+                    true => 0,
+                    false => disputable.amount,
+                };
+
+                match (*transaction_type, disputable.disputed) {
+                    ("dispute", false) => {
+                        disputable.disputed = true;
+                        account.available -= available;
+                        account.held += disputable.amount;
+                    }
+                    ("resolve", true) => {
+                        disputable.disputed = false;
+                        account.available += available;
+                        account.held -= disputable.amount;
+                    }
+                    ("chargeback", true) => {
+                        disputable.disputed = false;
+                        disputable.charged_back = true;
+                        account.held -= disputable.amount;
+                        // Charging back a withdrawal refunds it
+                        account.available += disputable.amount - available;
+                        account.locked = true;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the accounts in the engine's output format, ordered by client
+    pub fn write_accounts_csv(&self, writer: impl Write) -> csv::Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        for (client_id, account) in self.accounts.iter() {
+            wtr.serialize((
+                client_id,
+                format_signed_amount(account.available),
+                format_amount(account.held as u64),
+                format_signed_amount(account.available + account.held),
+                account.locked,
+            ))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Parses an amount the way the engine does, which ignores any decimals past the fourth
+fn parse_engine_amount(amount: &str) -> Option<i64> {
+    let amount = amount.trim();
+    let (integer, fractional) = amount.split_once('.').unwrap_or((amount, ""));
+    let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if integer.is_empty() || digits(integer).not() || digits(fractional).not() {
+        return None;
+    }
+    let fractional: String = fractional.chars().chain("0000".chars()).take(4).collect();
+    let amount = integer
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(10_000)?
+        .checked_add(fractional.parse::<u64>().ok()?)?;
+    i64::try_from(amount).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::Ledger;
+
+    #[test]
+    fn test_ledger_follows_engine_rules() {
+        let rows = [
+            ("deposit", 1, 1, Some("10.0".to_string())),
+            ("deposit", 1, 2, Some("5.123456".to_string())),
+            ("deposit", 1, 2, Some("100".to_string())),
+            ("deposit", 1, 3, Some("garbage".to_string())),
+            ("deposit", 1, 12, Some("-1".to_string())),
+            ("deposit", 1, 13, Some("1.2.3".to_string())),
+            ("deposit", 1, 14, Some("+1".to_string())),
+            ("withdrawal", 1, 4, Some("20".to_string())),
+            ("withdrawal", 1, 5, Some("1".to_string())),
+            ("withdrawal", 2, 6, Some("1".to_string())),
+            ("dispute", 1, 1, None),
+            ("dispute", 1, 404, None),
+            ("resolve", 1, 1, None),
+            ("dispute", 1, 1, None),
+            ("deposit", 3, 7, Some("7".to_string())),
+            ("dispute", 3, 7, None),
+            ("chargeback", 3, 7, None),
+            ("deposit", 3, 8, Some("1".to_string())),
+            ("dispute", 3, 8, None),
+            ("deposit", 4, 9, Some("10".to_string())),
+            ("withdrawal", 4, 10, Some("4".to_string())),
+            ("dispute", 4, 10, None),
+            ("withdrawal", 4, 11, Some("7".to_string())),
+            ("chargeback", 4, 10, None),
+        ];
+        let mut ledger = Ledger::default();
+        for row in rows.iter() {
+            ledger.apply(row);
+        }
+
+        let mut output = Vec::new();
+        ledger.write_accounts_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+            1,4.1234,10.0000,14.1234,false\n\
+            3,0.0000,0.0000,0.0000,true\n\
+            4,10.0000,0.0000,10.0000,true\n"
+        );
+    }
+}
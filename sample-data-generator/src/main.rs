@@ -1,52 +1,806 @@
-const N_CLIENTS: u16 = 5_000;
-const N_DEPOSITS_PER_CLIENT: u32 = 8_000;
-const N_WITHDRAWALS_PER_CLIENT: u32 = 2_000;
-const N_DISPUTES_PER_CLIENT: u32 = 300;
+use crate::chaos::Chaos;
+use crate::ledger::Ledger;
+use crate::output::{Compression, Output};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use rand::distr::weighted::WeightedIndex;
+use rand::rngs::ChaCha8Rng;
+use rand::{RngExt, SeedableRng};
+use std::cmp::Reverse;
+use std::ffi::OsString;
+use std::fs::File;
+use std::mem;
+use std::ops::Not;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+mod chaos;
+mod ledger;
+mod output;
+
+/// Generates a sample transactions csv file for the payments engine.
+///
+/// Every client's deposits and withdrawals are interleaved, and rows of different clients are mixed
+/// in a random order. The first `disputes` deposits of every client are disputed, resolved,
+/// disputed again and finally charged back, each step a random number of the client's transactions
+/// after the previous one. Chargebacks wait for the client's other activity to end, so they don't
+/// lock the account mid-stream, unless the client is picked by `--lock-rate`. Withdrawals never
+/// overdraw an account, unless picked by `--overdraft-rate`. The same seed and options always
+/// produce the same file.
+#[derive(Parser)]
+#[command(version, about, args_override_self = true)]
+struct Args {
+    /// Preset options reproducing a known traffic pattern. Options given on the command line take
+    /// precedence over the preset's.
+    #[arg(long, value_enum)]
+    scenario: Option<Scenario>,
+
+    /// Number of clients
+    #[arg(long, default_value_t = 5_000)]
+    clients: u16,
+
+    /// Number of deposits per client (on average with a skewed distribution)
+    #[arg(long, default_value_t = 8_000)]
+    deposits: u32,
+
+    /// Number of withdrawals per client (on average with a skewed distribution)
+    #[arg(long, default_value_t = 2_000)]
+    withdrawals: u32,
+
+    /// Number of deposits per client that get disputed (on average with a skewed distribution)
+    #[arg(long, default_value_t = 300)]
+    disputes: u32,
+
+    /// How transactions are spread across clients
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// Smallest deposit/withdrawal amount
+    #[arg(long, default_value = "100", value_parser = parse_amount)]
+    min_amount: u64,
+
+    /// Largest deposit/withdrawal amount
+    #[arg(long, default_value = "100", value_parser = parse_amount)]
+    max_amount: u64,
+
+    /// Fraction of rows, between 0 and 1, followed by an extra malformed or invalid row
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    error_rate: f64,
+
+    /// Fraction of withdrawals, between 0 and 1, asking for more than the available funds
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    overdraft_rate: f64,
+
+    /// Fraction of clients, between 0 and 1, whose account gets locked by a chargeback while they
+    /// still have deposits and withdrawals left, which the engine must then reject
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    lock_rate: f64,
+
+    /// Fraction of rows, between 0 and 1, with a bit flipped in one of their bytes, as if damaged
+    /// in transit. The row is left invalid UTF-8, so the engine must reject it.
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    flip_rate: f64,
+
+    /// Fraction of rows, between 0 and 1, followed by a repeat of the rows written just before
+    /// them (up to 100), as if a chunk of the file had been sent twice
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    duplicate_rate: f64,
+
+    /// Cut the last row short, without a row terminator, as if the writer had crashed
+    #[arg(long)]
+    truncate: bool,
+
+    /// Seed for the random generator. A random seed is picked (and printed) when omitted.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Add a `timestamp` column, in milliseconds since the Unix epoch, increasing from one row to
+    /// the next
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Shift every timestamp by a random amount of up to this many milliseconds either way, so
+    /// some rows end up out of chronological order
+    #[arg(long, default_value_t = 0, requires = "timestamps")]
+    timestamp_jitter_ms: u64,
+
+    /// Number of threads generating rows. Threads take turns writing batches of rows, so the output
+    /// also depends on the number of threads.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    threads: u16,
+
+    /// Keep generating rows until the output reaches this size (e.g. `500MB` or `10GB`). Clients
+    /// start over with their per-client counts whenever they run out, and the last disputes may
+    /// be left open.
+    #[arg(long, value_parser = parse_size)]
+    target_size: Option<u64>,
+
+    /// Number of files to split the rows into, each holding the clients of a disjoint client id
+    /// range. The shard number is appended to the output file name (e.g. `transactions-0.csv`),
+    /// and the files are listed in a `manifest.csv` in the same directory.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    shards: u16,
+
+    /// Compress the generated files. Defaults to the compression matching the output's extension
+    /// (`.gz` or `.zst`), if any.
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+
+    /// Path of the generated csv file, or `-` for stdout. The account states the engine is
+    /// expected to output are written to `expected_accounts.csv` in the same directory.
+    #[arg(short, long, default_value = "transactions.csv")]
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Scenario {
+    /// Many disputes ending in chargebacks, locking accounts while they are still active
+    ChargebackStorm,
+    /// A large share of deposits disputed, resolved and disputed again
+    DisputeHeavy,
+    /// Every possible client, each with a handful of small transactions
+    ManySmallClients,
+    /// A few clients making most of the transactions, with large amounts
+    WhaleClients,
+}
+
+impl Scenario {
+    /// The preset's options, in command line form
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Scenario::ChargebackStorm => &[
+                "--clients=2000",
+                "--deposits=200",
+                "--withdrawals=20",
+                "--disputes=100",
+                "--lock-rate=0.5",
+            ],
+            Scenario::DisputeHeavy => &[
+                "--clients=2000",
+                "--deposits=500",
+                "--withdrawals=100",
+                "--disputes=250",
+            ],
+            Scenario::ManySmallClients => &[
+                "--clients=65535",
+                "--deposits=20",
+                "--withdrawals=5",
+                "--disputes=1",
+                "--min-amount=0.01",
+                "--max-amount=50",
+            ],
+            Scenario::WhaleClients => &[
+                "--clients=100",
+                "--distribution=zipf",
+                "--deposits=50000",
+                "--withdrawals=20000",
+                "--disputes=50",
+                "--min-amount=1000",
+                "--max-amount=1000000",
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Distribution {
+    /// Every client gets the same number of transactions
+    Uniform,
+    /// The client of rank `k` gets a share of the transactions proportional to `1/k`, so a few
+    /// clients account for most of them
+    Zipf,
+}
+
+impl Distribution {
+    /// Share of the transactions each of `clients` clients gets, relative to the average
+    fn client_factors(self, clients: u16) -> Vec<f64> {
+        match self {
+            Distribution::Uniform => vec![1.0; clients as usize],
+            Distribution::Zipf => {
+                let weights: Vec<f64> = (1..=clients).map(|rank| 1.0 / rank as f64).collect();
+                let total: f64 = weights.iter().sum();
+                weights
+                    .into_iter()
+                    .map(|weight| weight * clients as f64 / total)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Upper bound on the number of a client's own transactions between two steps of a dispute
+const MAX_DISPUTE_LAG: u32 = 50;
+
+/// Timestamp of the first row, 2024-01-01T00:00:00Z in milliseconds since the Unix epoch
+const START_TIMESTAMP_MS: u64 = 1_704_067_200_000;
+
+/// Upper bound on the number of milliseconds between two rows, before jitter
+const MAX_ROW_INTERVAL_MS: u64 = 100;
+
+/// Number of rows a worker thread sends to be written at a time
+const BATCH_ROWS: usize = 4_096;
+
+/// A generated csv row: type, client, tx and amount
+type Row = (&'static str, u16, u32, Option<String>);
 
 fn main() {
-    let mut wtr = csv::Writer::from_path("transactions.csv").unwrap();
+    let mut args = Args::parse();
+    if let Some(scenario) = args.scenario {
+        // Options given later override earlier ones, so the preset's go first
+        let mut command_line = std::env::args_os();
+        args = Args::parse_from(
+            command_line
+                .next()
+                .into_iter()
+                .chain(scenario.args().iter().map(OsString::from))
+                .chain(command_line),
+        );
+    }
+    validate_args(&args);
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    eprintln!("Generating transactions with seed {seed}");
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    let compression = args
+        .compression
+        .or_else(|| Compression::from_path(&args.output));
+    let mut output =
+        Output::create(&args.output, args.shards, compression, args.timestamps).unwrap();
+
+    let client_ids =
+        rand::seq::index::sample(&mut rng, u16::MAX as usize + 1, args.clients as usize);
+    let clients: Vec<Client> = client_ids
+        .into_iter()
+        .zip(args.distribution.client_factors(args.clients))
+        .map(|(client_id, factor)| {
+            let locks_early = rng.random_bool(args.lock_rate);
+            Client::new(client_id as u16, &args, factor, locks_early)
+        })
+        .filter(|client| client.is_done().not())
+        .collect();
+
+    let workers = partition(clients, args.threads);
+    validate_tx_id_space(&workers, &args);
+
+    let mut ledger = Ledger::default();
+    let mut chaos = Chaos::new(seed, &args);
+    let mut clock = args.timestamps.then_some(Clock {
+        now: START_TIMESTAMP_MS,
+        jitter: args.timestamp_jitter_ms,
+    });
+    thread::scope(|scope| {
+        let step = workers.len() as u32;
+        let mut receivers: Vec<Receiver<Vec<Row>>> = workers
+            .into_iter()
+            .enumerate()
+            .map(|(index, clients)| {
+                let (sender, receiver) = mpsc::sync_channel(4);
+                let worker_rng = ChaCha8Rng::from_rng(&mut rng);
+                let tx_ids = TxIds {
+                    first: index as u32,
+                    step,
+                    issued: 0,
+                };
+                let args = &args;
+                scope.spawn(move || generate(clients, worker_rng, tx_ids, args, sender));
+                receiver
+            })
+            .collect();
 
-    wtr.write_record(["type", "client", "tx", "amount"])
+        // Workers take turns, so rows of all of them stay mixed. Dropping the receivers once the
+        // target size is reached stops the workers.
+        let mut turn = 0;
+        while receivers.is_empty().not() {
+            let index = turn % receivers.len();
+            let Ok(rows) = receivers[index].recv() else {
+                receivers.remove(index);
+                turn = index;
+                continue;
+            };
+            for row in rows {
+                let timestamp = clock.as_mut().map(|clock| clock.tick(&mut rng));
+                match chaos.as_mut() {
+                    Some(chaos) => chaos
+                        .write(row, timestamp, &mut output, &mut ledger)
+                        .unwrap(),
+                    None => {
+                        output.write(&row, timestamp).unwrap();
+                        ledger.apply(&row);
+                    }
+                }
+            }
+            turn = index + 1;
+
+            // Bytes still in the csv writers' buffers aren't counted, so the output ends up
+            // slightly larger than the target
+            if args
+                .target_size
+                .is_some_and(|target_size| output.bytes() >= target_size)
+            {
+                break;
+            }
+        }
+    });
+
+    if let Some(chaos) = chaos.as_mut() {
+        chaos.finish(&mut output).unwrap();
+    }
+    output
+        .finish(&args.output.with_file_name("manifest.csv"))
+        .unwrap();
+
+    let expected_path = args.output.with_file_name("expected_accounts.csv");
+    ledger
+        .write_accounts_csv(File::create(expected_path).unwrap())
         .unwrap();
+}
 
-    let mut tx_id_count = 0;
+/// Timestamps of the rows, assigned in the order rows are written
+struct Clock {
+    now: u64,
+    jitter: u64,
+}
+
+impl Clock {
+    /// Advances the clock by a random interval and returns the next row's timestamp
+    fn tick(&mut self, rng: &mut ChaCha8Rng) -> u64 {
+        self.now += rng.random_range(1..=MAX_ROW_INTERVAL_MS);
+        if self.jitter == 0 {
+            return self.now;
+        }
+        (self.now + rng.random_range(0..=self.jitter * 2)).saturating_sub(self.jitter)
+    }
+}
+
+/// Splits the clients between `workers` worker threads, balancing the number of rows they generate
+fn partition(mut clients: Vec<Client>, workers: u16) -> Vec<Vec<Client>> {
+    clients.sort_by_key(|client| Reverse(client.weight()));
+
+    let mut partitions: Vec<(u64, Vec<Client>)> = (0..workers).map(|_| (0, Vec::new())).collect();
+    for client in clients {
+        let (weight, clients) = partitions
+            .iter_mut()
+            .min_by_key(|(weight, _)| *weight)
+            .expect("There's at least one worker");
+        *weight += client.weight();
+        clients.push(client);
+    }
+
+    partitions
+        .into_iter()
+        .map(|(_, clients)| clients)
+        .filter(|clients| clients.is_empty().not())
+        .collect()
+}
 
-    let mut disputes_to_create: Vec<(u16, u32)> = Vec::new();
+/// Generates the rows of `clients`, sending them in batches until all clients are done (or, with
+/// `--target-size`, until the receiver hangs up)
+fn generate(
+    mut clients: Vec<Client>,
+    mut rng: ChaCha8Rng,
+    mut tx_ids: TxIds,
+    args: &Args,
+    sender: SyncSender<Vec<Row>>,
+) {
+    let mut rows = Vec::with_capacity(BATCH_ROWS);
 
-    for client_id in 0..N_CLIENTS {
-        for d in 0..N_DEPOSITS_PER_CLIENT {
-            wtr.serialize(("deposit", client_id, tx_id_count, 100.0))
-                .unwrap();
-            if d < N_DISPUTES_PER_CLIENT {
-                disputes_to_create.push((client_id, tx_id_count));
+    // Clients are picked proportionally to the rows they have left, and the weights are only
+    // recomputed when a client is done to keep picking cheap
+    while clients.is_empty().not() {
+        let picker = WeightedIndex::new(clients.iter().map(Client::weight)).unwrap();
+        loop {
+            let i = rng.sample(&picker);
+            rows.push(clients[i].next_row(&mut rng, args, &mut tx_ids));
+            if rng.random_bool(args.error_rate) {
+                rows.push(invalid_row(&mut rng, clients[i].id, &mut tx_ids));
+            }
+
+            if rows.len() >= BATCH_ROWS {
+                let batch = mem::replace(&mut rows, Vec::with_capacity(BATCH_ROWS));
+                if sender.send(batch).is_err() {
+                    return;
+                }
+            }
+
+            if clients[i].is_done() {
+                if args.target_size.is_some() {
+                    clients[i].refill(args);
+                } else {
+                    clients.swap_remove(i);
+                    break;
+                }
             }
-            tx_id_count += 1;
         }
     }
 
-    for client_id in 0..N_CLIENTS {
-        for _ in 0..N_WITHDRAWALS_PER_CLIENT {
-            wtr.serialize(("withdrawal", client_id, tx_id_count, 100.0))
-                .unwrap();
-            tx_id_count += 1;
+    if rows.is_empty().not() {
+        let _ = sender.send(rows);
+    }
+}
+
+/// Tx ids handed out by a worker: `first`, `first + step`, `first + 2 * step`... so that workers
+/// never hand out the same one
+struct TxIds {
+    first: u32,
+    step: u32,
+    issued: u32,
+}
+
+impl TxIds {
+    fn next(&mut self) -> u32 {
+        let tx_id = self.first + self.step * self.issued;
+        self.issued += 1;
+        tx_id
+    }
+
+    /// A random tx id among the ones handed out so far
+    fn random_issued(&self, rng: &mut ChaCha8Rng) -> u32 {
+        self.first + self.step * rng.random_range(0..self.issued)
+    }
+}
+
+/// A row the engine must reject. Rows that get a new tx id never reuse it, so later valid rows are
+/// unaffected.
+fn invalid_row(rng: &mut ChaCha8Rng, client_id: u16, tx_ids: &mut TxIds) -> Row {
+    let tx_id = tx_ids.next();
+
+    match rng.random_range(0..5) {
+        0 => ("deposit", client_id, tx_id, Some("garbage".to_string())),
+        1 => ("withdrawal", client_id, tx_id, None),
+        2 => ("deposit", client_id, tx_id, Some("1.23456".to_string())),
+        // tx_id is only reserved, so no deposit or withdrawal exists with it
+        3 => ("dispute", client_id, tx_id, None),
+        _ => (
+            "deposit",
+            client_id,
+            tx_ids.random_issued(rng),
+            Some("1.0000".to_string()),
+        ),
+    }
+}
+
+struct Client {
+    id: u16,
+    /// Share of the transactions the client gets, relative to the average
+    factor: f64,
+    deposits: u32,
+    withdrawals: u32,
+    /// Number of upcoming deposits that will be disputed
+    disputes: u32,
+    /// Funds the engine will consider available, used to avoid generating overdrafts
+    available: i64,
+    /// Whether chargebacks may happen before the client's deposits and withdrawals are done
+    locks_early: bool,
+    locked: bool,
+    /// Number of rows generated for the client so far, the clock dispute lags are measured in
+    rows: u32,
+    pending_disputes: Vec<PendingDispute>,
+}
+
+struct PendingDispute {
+    tx_id: u32,
+    amount: u64,
+    step: DisputeStep,
+    due: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DisputeStep {
+    Dispute,
+    Resolve,
+    Redispute,
+    Chargeback,
+}
+
+impl Client {
+    /// A client getting `factor` times the average number of transactions
+    fn new(id: u16, args: &Args, factor: f64, locks_early: bool) -> Self {
+        let mut client = Self {
+            id,
+            factor,
+            deposits: 0,
+            withdrawals: 0,
+            disputes: 0,
+            available: 0,
+            locks_early,
+            locked: false,
+            rows: 0,
+            pending_disputes: Vec::new(),
+        };
+        client.refill(args);
+        client
+    }
+
+    /// Gives the client its share of deposits, withdrawals and disputes to generate
+    fn refill(&mut self, args: &Args) {
+        self.deposits = (args.deposits as f64 * self.factor) as u32;
+        self.withdrawals = (args.withdrawals as f64 * self.factor) as u32;
+        self.disputes = ((args.disputes as f64 * self.factor) as u32).min(self.deposits);
+    }
+
+    /// Rough number of rows left to generate, zero once the client is done
+    fn weight(&self) -> u64 {
+        self.deposits as u64 + self.withdrawals as u64 + self.pending_disputes.len() as u64
+    }
+
+    fn is_done(&self) -> bool {
+        self.deposits == 0 && self.withdrawals == 0 && self.pending_disputes.is_empty()
+    }
+
+    fn next_row(&mut self, rng: &mut ChaCha8Rng, args: &Args, tx_ids: &mut TxIds) -> Row {
+        let activity_left = self.deposits + self.withdrawals > 0;
+        let due_dispute = self.pending_disputes.iter().position(|dispute| {
+            dispute.due <= self.rows
+                && (dispute.step != DisputeStep::Chargeback
+                    || activity_left.not()
+                    || self.locks_early)
+        });
+
+        let row = match due_dispute {
+            Some(index) => self.dispute_row(rng, index),
+            None if activity_left => self.transfer_row(rng, args, tx_ids),
+            // Only disputes that aren't due yet are left, so fast-forward to the first one
+            None => {
+                let index = (0..self.pending_disputes.len())
+                    .min_by_key(|index| self.pending_disputes[*index].due)
+                    .expect("A client with no activity left has pending disputes");
+                self.dispute_row(rng, index)
+            }
+        };
+
+        self.rows += 1;
+        row
+    }
+
+    fn transfer_row(&mut self, rng: &mut ChaCha8Rng, args: &Args, tx_ids: &mut TxIds) -> Row {
+        let amount = rng.random_range(args.min_amount..=args.max_amount);
+        let tx_id = tx_ids.next();
+
+        let withdraw = rng.random_range(0..self.deposits + self.withdrawals) < self.withdrawals;
+        let overdraft = withdraw && rng.random_bool(args.overdraft_rate);
+        if overdraft {
+            self.withdrawals -= 1;
+            let amount = self.available.max(0) as u64 + amount;
+            return ("withdrawal", self.id, tx_id, Some(format_amount(amount)));
         }
+        if withdraw && (self.deposits == 0 || self.locked || amount as i64 <= self.available) {
+            self.withdrawals -= 1;
+            if self.locked.not() && amount as i64 <= self.available {
+                self.available -= amount as i64;
+            }
+            return ("withdrawal", self.id, tx_id, Some(format_amount(amount)));
+        }
+
+        self.deposits -= 1;
+        if self.locked {
+            return ("deposit", self.id, tx_id, Some(format_amount(amount)));
+        }
+        self.available += amount as i64;
+        if self.disputes > 0 {
+            self.disputes -= 1;
+            self.pending_disputes.push(PendingDispute {
+                tx_id,
+                amount,
+                step: DisputeStep::Dispute,
+                due: self.rows + rng.random_range(1..=MAX_DISPUTE_LAG),
+            });
+        }
+        ("deposit", self.id, tx_id, Some(format_amount(amount)))
     }
 
-    for (client_id, tx_id) in disputes_to_create.as_slice() {
-        wtr.serialize(("dispute", client_id, tx_id, "")).unwrap();
+    fn dispute_row(&mut self, rng: &mut ChaCha8Rng, index: usize) -> Row {
+        let dispute = &mut self.pending_disputes[index];
+        let (transaction_type, next_step) = match dispute.step {
+            DisputeStep::Dispute => {
+                self.available -= dispute.amount as i64;
+                ("dispute", Some(DisputeStep::Resolve))
+            }
+            DisputeStep::Resolve => {
+                self.available += dispute.amount as i64;
+                ("resolve", Some(DisputeStep::Redispute))
+            }
+            DisputeStep::Redispute => {
+                self.available -= dispute.amount as i64;
+                ("dispute", Some(DisputeStep::Chargeback))
+            }
+            DisputeStep::Chargeback => {
+                self.locked = true;
+                ("chargeback", None)
+            }
+        };
+        let row = (transaction_type, self.id, dispute.tx_id, None);
+
+        match next_step {
+            Some(step) => {
+                dispute.step = step;
+                dispute.due = self.rows + rng.random_range(1..=MAX_DISPUTE_LAG);
+            }
+            None => {
+                self.pending_disputes.swap_remove(index);
+            }
+        }
+        row
     }
+}
 
-    for (client_id, tx_id) in disputes_to_create.as_slice() {
-        wtr.serialize(("resolve", client_id, tx_id, "")).unwrap();
+fn validate_args(args: &Args) {
+    let mut cmd = Args::command();
+
+    if args.disputes > args.deposits {
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--disputes can't be larger than --deposits",
+        )
+        .exit();
+    }
+    if args.min_amount > args.max_amount {
+        cmd.error(
+            ErrorKind::ValueValidation,
+            "--min-amount can't be larger than --max-amount",
+        )
+        .exit();
     }
 
-    for (client_id, tx_id) in disputes_to_create.as_slice() {
-        wtr.serialize(("dispute", client_id, tx_id, "")).unwrap();
+    if args.shards > 1 && args.output.as_os_str() == "-" {
+        cmd.error(
+            ErrorKind::ArgumentConflict,
+            "--shards can't be used when writing to stdout",
+        )
+        .exit();
     }
 
-    for (client_id, tx_id) in disputes_to_create.as_slice() {
-        wtr.serialize(("chargeback", client_id, tx_id, "")).unwrap();
+    if let Some(target_size) = args.target_size {
+        // Rows taking a tx id are at least 16 bytes long (e.g. `withdrawal,0,0,\n`)
+        if target_size > (u32::MAX as u64 + 1) * 16 {
+            cmd.error(
+                ErrorKind::ValueValidation,
+                "--target-size is too large for the u32 tx id space",
+            )
+            .exit();
+        }
+        return;
     }
 
-    wtr.flush().unwrap();
+    let n_transfers = args.clients as u64 * (args.deposits as u64 + args.withdrawals as u64);
+    let n_rows = n_transfers + args.clients as u64 * args.disputes as u64 * 4;
+    // Invalid rows take a tx id of their own
+    let n_transactions = n_transfers + (n_rows as f64 * args.error_rate).ceil() as u64;
+    if n_transactions > u32::MAX as u64 + 1 {
+        cmd.error(
+            ErrorKind::ValueValidation,
+            format!("{n_transactions} transactions would not fit in the u32 tx id space"),
+        )
+        .exit();
+    }
+}
+
+/// Checks that no worker runs out of tx ids, which are spread over the u32 space in steps of the
+/// number of workers
+fn validate_tx_id_space(workers: &[Vec<Client>], args: &Args) {
+    if args.target_size.is_some() {
+        return;
+    }
+
+    let max_tx_ids = workers
+        .iter()
+        .map(|clients| {
+            let transfers: u64 = clients.iter().map(Client::weight).sum();
+            let disputes: u64 = clients.iter().map(|client| client.disputes as u64).sum();
+            // Invalid rows take a tx id of their own
+            let rows = transfers + disputes * 4;
+            transfers + (rows as f64 * args.error_rate).ceil() as u64
+        })
+        .max()
+        .unwrap_or_default();
+
+    if max_tx_ids * workers.len() as u64 > u32::MAX as u64 + 1 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "the transactions would not fit in the u32 tx id space with this many --threads",
+            )
+            .exit();
+    }
+}
+
+/// Parses a decimal amount into units of `0.0001`, like the engine does
+fn parse_amount(value: &str) -> Result<u64, String> {
+    let (integer, fractional) = value.split_once('.').unwrap_or((value, ""));
+    if fractional.len() > 4 {
+        return Err("amounts can have at most 4 decimal places".to_string());
+    }
+    let integer = integer
+        .parse::<u64>()
+        .map_err(|e| format!("invalid amount: {e}"))?;
+    let fractional = format!("{fractional:0<4}")
+        .parse::<u64>()
+        .map_err(|e| format!("invalid amount: {e}"))?;
+    integer
+        .checked_mul(10_000)
+        .and_then(|integer| integer.checked_add(fractional))
+        .ok_or_else(|| "amount is too large".to_string())
+}
+
+/// Parses a size in bytes, with an optional `KB`, `MB`, `GB` or `TB` (powers of 1000) suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim().to_ascii_uppercase();
+    let (number, unit) = value
+        .find(|c: char| c.is_ascii_digit().not())
+        .map_or((value.as_str(), ""), |index| value.split_at(index));
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        unit => return Err(format!("unknown size unit: {unit}")),
+    };
+    number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size: {e}"))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| "size is too large".to_string())
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid rate: {e}"))?;
+    if (0.0..=1.0).contains(&rate).not() {
+        return Err("rates must be between 0 and 1".to_string());
+    }
+    Ok(rate)
+}
+
+fn format_amount(amount: u64) -> String {
+    format!("{}.{:04}", amount / 10_000, amount % 10_000)
+}
+
+fn format_signed_amount(amount: i64) -> String {
+    let sign = if amount < 0 { "-" } else { "" };
+    format!("{sign}{}", format_amount(amount.unsigned_abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{format_amount, format_signed_amount, parse_amount, parse_rate, parse_size};
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("100"), Ok(1_000_000));
+        assert_eq!(parse_amount("0.5"), Ok(5_000));
+        assert_eq!(parse_amount("1.2345"), Ok(12_345));
+        assert!(parse_amount("1.23456").is_err());
+        assert!(parse_amount("-1").is_err());
+        assert!(parse_amount("abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert_eq!(parse_rate("0.05"), Ok(0.05));
+        assert_eq!(parse_rate("1"), Ok(1.0));
+        assert!(parse_rate("1.5").is_err());
+        assert!(parse_rate("-0.1").is_err());
+        assert!(parse_rate("NaN").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10GB"), Ok(10_000_000_000));
+        assert_eq!(parse_size("500 mb"), Ok(500_000_000));
+        assert!(parse_size("10XB").is_err());
+        assert!(parse_size("GB").is_err());
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1_000_000), "100.0000");
+        assert_eq!(format_amount(12_345), "1.2345");
+        assert_eq!(format_signed_amount(-12_345), "-1.2345");
+        assert_eq!(format_signed_amount(0), "0.0000");
+    }
 }
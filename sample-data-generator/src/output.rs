@@ -0,0 +1,239 @@
+use crate::Row;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// The generated csv files, each holding the rows of the clients in a disjoint range of the client
+/// id space
+pub struct Output {
+    shards: Vec<Shard>,
+    /// The csv bytes of the row being written. Generated rows never need quoting, so they're
+    /// formatted directly rather than through a csv writer.
+    record: Vec<u8>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression matching the extension of `path`, if any
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Wraps `writer` so written bytes get compressed. The compressed stream is finished when the
+    /// returned writer is dropped.
+    fn encoder(self, writer: Box<dyn Write>) -> io::Result<Box<dyn Write>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Box::new(zstd::Encoder::new(writer, 0)?.auto_finish()),
+        })
+    }
+}
+
+struct Shard {
+    path: PathBuf,
+    clients: RangeInclusive<u16>,
+    rows: u64,
+    writer: CountingWriter<BufWriter<Box<dyn Write>>>,
+}
+
+impl Output {
+    /// Creates `shards` files, named after `path` with the shard number appended when there's more
+    /// than one. A `path` of `-` with a single shard writes to stdout. With `timestamps`, the files
+    /// get a `timestamp` column.
+    pub fn create(
+        path: &Path,
+        shards: u16,
+        compression: Option<Compression>,
+        timestamps: bool,
+    ) -> io::Result<Self> {
+        let header: &[u8] = match timestamps {
+            true => b"type,client,tx,amount,timestamp\n",
+            false => b"type,client,tx,amount\n",
+        };
+
+        let shard_count = shards as u32;
+        let shards = (0..shard_count)
+            .map(|index| {
+                let path = match shards {
+                    1 => path.to_path_buf(),
+                    _ => shard_path(path, index),
+                };
+                let mut writer: Box<dyn Write> = if path.as_os_str() == "-" {
+                    Box::new(io::stdout().lock())
+                } else {
+                    Box::new(File::create(&path)?)
+                };
+                if let Some(compression) = compression {
+                    writer = compression.encoder(writer)?;
+                }
+
+                let mut writer = CountingWriter {
+                    inner: BufWriter::new(writer),
+                    bytes: 0,
+                };
+                writer.write_all(header)?;
+
+                let first_client = shard_start(index, shard_count);
+                let last_client = shard_start(index + 1, shard_count) - 1;
+                Ok(Shard {
+                    path,
+                    clients: first_client as u16..=last_client as u16,
+                    rows: 0,
+                    writer,
+                })
+            })
+            .collect::<io::Result<Vec<Shard>>>()?;
+
+        Ok(Self {
+            shards,
+            record: Vec::new(),
+        })
+    }
+
+    /// Writes a row, with a timestamp if the files have a `timestamp` column
+    pub fn write(&mut self, row: &Row, timestamp: Option<u64>) -> io::Result<()> {
+        self.write_with(row, timestamp, |_| {})
+    }
+
+    /// Writes a row like `write`, letting `edit` alter its csv bytes, row terminator included,
+    /// beforehand
+    pub fn write_with(
+        &mut self,
+        row: &Row,
+        timestamp: Option<u64>,
+        edit: impl FnOnce(&mut Vec<u8>),
+    ) -> io::Result<()> {
+        let (transaction_type, client_id, tx_id, amount) = row;
+        let amount = amount.as_deref().unwrap_or_default();
+        self.record.clear();
+        match timestamp {
+            Some(timestamp) => writeln!(
+                self.record,
+                "{transaction_type},{client_id},{tx_id},{amount},{timestamp}"
+            )?,
+            None => writeln!(
+                self.record,
+                "{transaction_type},{client_id},{tx_id},{amount}"
+            )?,
+        }
+        edit(&mut self.record);
+
+        let shard_count = self.shards.len() as u32;
+        let shard = &mut self.shards[((*client_id as u32 * shard_count) >> 16) as usize];
+        shard.rows += 1;
+        shard.writer.write_all(&self.record)
+    }
+
+    /// Bytes written to all the files so far, before compression
+    pub fn bytes(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.writer.bytes).sum()
+    }
+
+    /// Flushes the files and, when there's more than one, lists them in a manifest at
+    /// `manifest_path`, which is expected to be in the same directory
+    pub fn finish(self, manifest_path: &Path) -> csv::Result<()> {
+        let mut files = Vec::new();
+        for shard in self.shards {
+            // Unwrapping the buffer flushes it, and dropping the file finishes compression
+            drop(
+                shard
+                    .writer
+                    .inner
+                    .into_inner()
+                    .map_err(|e| e.into_error())?,
+            );
+            files.push((shard.path, shard.clients, shard.rows));
+        }
+        if files.len() == 1 {
+            return Ok(());
+        }
+
+        let mut manifest = csv::Writer::from_path(manifest_path)?;
+        manifest.write_record(["file", "first_client", "last_client", "rows"])?;
+        for (path, clients, rows) in files {
+            manifest.serialize((
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                clients.start(),
+                clients.end(),
+                rows,
+            ))?;
+        }
+        manifest.flush()?;
+        Ok(())
+    }
+}
+
+/// First client id of the shard at `index`, out of `shards` shards splitting the client id space
+/// evenly
+fn shard_start(index: u32, shards: u32) -> u32 {
+    (index * (u16::MAX as u32 + 1)).div_ceil(shards)
+}
+
+/// `path` with `-{index}` inserted before the file name's extensions, e.g. `transactions-0.csv.gz`
+fn shard_path(path: &Path, index: u32) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let file_name = match file_name.split_once('.') {
+        Some((base, extensions)) => format!("{base}-{index}.{extensions}"),
+        None => format!("{file_name}-{index}"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Forwards writes to `inner`, counting the bytes written
+struct CountingWriter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::{shard_path, shard_start};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_shards_split_the_client_id_space() {
+        assert_eq!(shard_start(0, 3), 0);
+        assert_eq!(shard_start(1, 3), 21_846);
+        assert_eq!(shard_start(2, 3), 43_691);
+        assert_eq!(shard_start(3, 3), 65_536);
+
+        // Clients are assigned to shards with `(client * shards) >> 16`
+        for client in [21_845u32, 21_846, 43_690, 43_691] {
+            let shard = (client * 3) >> 16;
+            assert!(shard_start(shard, 3) <= client && client < shard_start(shard + 1, 3));
+        }
+
+        assert_eq!(
+            shard_path(Path::new("data/transactions.csv"), 2),
+            PathBuf::from("data/transactions-2.csv")
+        );
+        assert_eq!(
+            shard_path(Path::new("transactions.csv.zst"), 0),
+            PathBuf::from("transactions-0.csv.zst")
+        );
+    }
+}
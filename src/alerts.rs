@@ -0,0 +1,337 @@
+use crate::engine::{AccountView, HeldBalance};
+use crate::output::account_to_json;
+use crate::transaction::Transaction;
+use crate::util::fixed_point_4_decimal_to_float_str;
+use crate::webhook::WebhookHandle;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::ops::Not;
+use tracing::{error, warn};
+
+/// Conditions that raise an alert. Balance conditions only fire when an account starts meeting
+/// them, not again for every following transaction while they still hold.
+#[derive(Debug, Clone, Default)]
+pub struct AlertRules {
+    pub negative_balance: bool,
+    pub held_above: Option<u64>,
+    pub chargebacks_above: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    NegativeBalance {
+        tx_id: u32,
+        account: AccountView,
+    },
+    HeldAbove {
+        tx_id: u32,
+        threshold: u64,
+        account: AccountView,
+    },
+    ChargebacksAbove {
+        tx_id: u32,
+        threshold: u32,
+        chargebacks: u32,
+        account: AccountView,
+    },
+}
+
+impl Alert {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Alert::NegativeBalance { tx_id, account } => {
+                let mut json = account_to_json(*account);
+                json["alert"] = "negative_balance".into();
+                json["tx"] = (*tx_id).into();
+                json
+            }
+            Alert::HeldAbove {
+                tx_id,
+                threshold,
+                account,
+            } => {
+                let mut json = account_to_json(*account);
+                json["alert"] = "held_above".into();
+                json["tx"] = (*tx_id).into();
+                json["threshold"] = fixed_point_4_decimal_to_float_str(*threshold).into();
+                json
+            }
+            Alert::ChargebacksAbove {
+                tx_id,
+                threshold,
+                chargebacks,
+                account,
+            } => {
+                let mut json = account_to_json(*account);
+                json["alert"] = "chargebacks_above".into();
+                json["tx"] = (*tx_id).into();
+                json["threshold"] = (*threshold).into();
+                json["chargebacks"] = (*chargebacks).into();
+                json
+            }
+        }
+    }
+}
+
+pub struct AlertEvaluator {
+    rules: AlertRules,
+    clients: HashMap<u16, ClientAlertState>,
+}
+
+#[derive(Default)]
+struct ClientAlertState {
+    negative_balance: bool,
+    held_above: bool,
+    chargebacks: u32,
+}
+
+impl AlertEvaluator {
+    pub fn new(rules: AlertRules) -> Self {
+        Self {
+            rules,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Evaluates the rules against an applied transaction and the resulting account state
+    pub fn evaluate(&mut self, transaction: &Transaction, account: &AccountView) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let tx_id = transaction.tx_id();
+        let state = self.clients.entry(account.client_id).or_default();
+
+        let negative_balance = account.available_amount < 0 || account.total_amount < 0;
+        if self.rules.negative_balance && negative_balance && state.negative_balance.not() {
+            alerts.push(Alert::NegativeBalance {
+                tx_id,
+                account: *account,
+            });
+        }
+        state.negative_balance = negative_balance;
+
+        if let Some(threshold) = self.rules.held_above {
+            let held_above = account.held_amount > threshold as HeldBalance;
+            if held_above && state.held_above.not() {
+                alerts.push(Alert::HeldAbove {
+                    tx_id,
+                    threshold,
+                    account: *account,
+                });
+            }
+            state.held_above = held_above;
+        }
+
+        if let Transaction::Chargeback { .. } = transaction {
+            state.chargebacks += 1;
+            if let Some(threshold) = self.rules.chargebacks_above {
+                if state.chargebacks == threshold + 1 {
+                    alerts.push(Alert::ChargebacksAbove {
+                        tx_id,
+                        threshold,
+                        chargebacks: state.chargebacks,
+                        account: *account,
+                    });
+                }
+            }
+        }
+
+        alerts
+    }
+}
+
+/// Where raised alerts are sent. Alerts are written to stderr when no other sink is configured.
+#[derive(Default)]
+pub struct AlertSinks {
+    pub file: Option<File>,
+    pub webhook: Option<WebhookHandle>,
+}
+
+impl AlertSinks {
+    pub fn emit(&mut self, alert: &Alert) {
+        let payload = alert.to_json();
+
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = writeln!(file, "{payload}") {
+                error!("Failed to write alert to alerts file: {e} - alert: {payload}");
+            }
+        }
+        if let Some(webhook) = self.webhook.as_ref() {
+            webhook.send(payload.clone());
+        }
+        if self.file.is_none() && self.webhook.is_none() {
+            warn!("Alert: {payload}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alerts::{Alert, AlertEvaluator, AlertRules};
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+    use std::sync::{Arc, Mutex};
+
+    fn run(rules: AlertRules, transactions: Vec<Transaction>) -> Vec<Alert> {
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let mut evaluator = AlertEvaluator::new(rules);
+        let mut engine = Engine::new();
+        let raised = Arc::clone(&alerts);
+        engine.on_transaction_applied(move |transaction, account| {
+            raised
+                .lock()
+                .unwrap()
+                .extend(evaluator.evaluate(transaction, account))
+        });
+
+        for transaction in transactions {
+            let _ = engine.process_transaction(transaction);
+        }
+
+        let alerts = alerts.lock().unwrap().clone();
+        alerts
+    }
+
+    #[test]
+    fn test_negative_balance_alert_fires_once_per_crossing() {
+        let alerts = run(
+            AlertRules {
+                negative_balance: true,
+                ..AlertRules::default()
+            },
+            vec![
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Withdrawal {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 3,
+                    amount: 10,
+                    currency: None,
+                },
+                Transaction::Resolve {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+            ],
+        );
+
+        let tx_ids: Vec<u32> = alerts
+            .iter()
+            .map(|alert| match alert {
+                Alert::NegativeBalance { tx_id, .. } => *tx_id,
+                _ => panic!("Unexpected alert: {alert:?}"),
+            })
+            .collect();
+        assert_eq!(tx_ids, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_held_above_alert() {
+        let alerts = run(
+            AlertRules {
+                held_above: Some(150),
+                ..AlertRules::default()
+            },
+            vec![
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 2,
+                    currency: None,
+                },
+            ],
+        );
+
+        assert_eq!(alerts.len(), 1);
+        assert!(matches!(
+            alerts[0],
+            Alert::HeldAbove {
+                tx_id: 2,
+                threshold: 150,
+                ..
+            }
+        ));
+        assert_eq!(alerts[0].to_json()["threshold"], "0.0150");
+    }
+
+    #[test]
+    fn test_chargebacks_above_alert() {
+        let mut transactions = Vec::new();
+        for tx_id in 1..=3 {
+            transactions.push(Transaction::Deposit {
+                client_id: 1,
+                tx_id,
+                amount: 100,
+                currency: None,
+            });
+        }
+        for tx_id in 1..=3 {
+            transactions.push(Transaction::Dispute {
+                client_id: 1,
+                tx_id,
+                currency: None,
+            });
+            transactions.push(Transaction::Chargeback {
+                client_id: 1,
+                tx_id,
+                currency: None,
+            });
+        }
+
+        let alerts = run(
+            AlertRules {
+                chargebacks_above: Some(1),
+                ..AlertRules::default()
+            },
+            transactions,
+        );
+
+        assert_eq!(alerts.len(), 1);
+        assert!(matches!(
+            alerts[0],
+            Alert::ChargebacksAbove {
+                tx_id: 2,
+                threshold: 1,
+                chargebacks: 2,
+                ..
+            }
+        ));
+    }
+}
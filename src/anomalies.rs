@@ -0,0 +1,247 @@
+use crate::transaction::Transaction;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::ops::Not;
+
+/// When a client's dispute activity is flagged as anomalous
+#[derive(Debug, Clone)]
+pub struct AnomalyThresholds {
+    /// Share of the client's deposits that were disputed above which the client is flagged
+    pub dispute_ratio: f64,
+    /// Share of the client's deposits that were charged back above which the client is flagged
+    pub chargeback_ratio: f64,
+    /// Clients with fewer deposits are never flagged, as their ratios say little
+    pub min_deposits: u64,
+}
+
+/// Tallies every client's applied deposits, disputes and chargebacks, to report the clients whose
+/// ratios stand out once processing is done
+pub struct AnomalyDetector {
+    thresholds: AnomalyThresholds,
+    clients: BTreeMap<u16, DisputeCounts>,
+    /// Deposits disputed at least once, so that disputing one again after a resolve doesn't count
+    /// twice
+    disputed: HashSet<u32>,
+    /// Applied withdrawals, whose disputes and chargebacks aren't counted
+    withdrawals: HashSet<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct DisputeCounts {
+    deposits: u64,
+    disputed: u64,
+    charged_back: u64,
+}
+
+impl DisputeCounts {
+    fn dispute_ratio(&self) -> f64 {
+        self.disputed as f64 / self.deposits as f64
+    }
+
+    fn chargeback_ratio(&self) -> f64 {
+        self.charged_back as f64 / self.deposits as f64
+    }
+}
+
+impl AnomalyDetector {
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        Self {
+            thresholds,
+            clients: BTreeMap::new(),
+            disputed: HashSet::new(),
+            withdrawals: HashSet::new(),
+        }
+    }
+
+    /// To be called for every transaction the engine applied
+    pub fn applied(&mut self, transaction: &Transaction) {
+        let counts = self.clients.entry(transaction.client_id()).or_default();
+        match *transaction {
+            Transaction::Deposit { .. } => counts.deposits += 1,
+            Transaction::Withdrawal { tx_id, .. } => {
+                self.withdrawals.insert(tx_id);
+            }
+            Transaction::Dispute { tx_id, .. } => {
+                if self.withdrawals.contains(&tx_id).not() && self.disputed.insert(tx_id) {
+                    counts.disputed += 1;
+                }
+            }
+            Transaction::Chargeback { tx_id, .. } => {
+                if self.withdrawals.contains(&tx_id).not() {
+                    counts.charged_back += 1;
+                }
+            }
+            Transaction::Resolve { .. }
+            | Transaction::Unlock { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::Hold { .. }
+            | Transaction::Release { .. } => {}
+        }
+    }
+
+    /// Writes the flagged clients as csv, highest dispute ratio first, and returns how many there
+    /// were
+    pub fn write_report(&self, writer: impl Write) -> Result<usize> {
+        let mut flagged: Vec<(u16, DisputeCounts, &str)> = self
+            .clients
+            .iter()
+            .filter_map(|(client_id, counts)| {
+                self.flag_reason(counts)
+                    .map(|reason| (*client_id, *counts, reason))
+            })
+            .collect();
+        flagged.sort_by(|(_, a, _), (_, b, _)| b.dispute_ratio().total_cmp(&a.dispute_ratio()));
+
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record([
+            "client",
+            "deposits",
+            "disputed",
+            "charged_back",
+            "dispute_ratio",
+            "chargeback_ratio",
+            "reason",
+        ])?;
+        for (client_id, counts, reason) in &flagged {
+            wtr.serialize((
+                client_id,
+                counts.deposits,
+                counts.disputed,
+                counts.charged_back,
+                format!("{:.4}", counts.dispute_ratio()),
+                format!("{:.4}", counts.chargeback_ratio()),
+                reason,
+            ))?;
+        }
+        wtr.flush()?;
+
+        Ok(flagged.len())
+    }
+
+    /// Number of clients with at least one applied transaction
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn flag_reason(&self, counts: &DisputeCounts) -> Option<&'static str> {
+        if counts.deposits < self.thresholds.min_deposits.max(1) {
+            return None;
+        }
+        let disputes = counts.dispute_ratio() > self.thresholds.dispute_ratio;
+        let chargebacks = counts.chargeback_ratio() > self.thresholds.chargeback_ratio;
+        match (disputes, chargebacks) {
+            (true, true) => Some("dispute and chargeback ratios"),
+            (true, false) => Some("dispute ratio"),
+            (false, true) => Some("chargeback ratio"),
+            (false, false) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomalies::{AnomalyDetector, AnomalyThresholds};
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_anomaly_report_flags_outliers() {
+        let detector = Arc::new(Mutex::new(AnomalyDetector::new(AnomalyThresholds {
+            dispute_ratio: 0.25,
+            chargeback_ratio: 0.1,
+            min_deposits: 2,
+        })));
+        let mut engine = Engine::new();
+        let listener = Arc::clone(&detector);
+        engine.on_transaction_applied(move |transaction, _| {
+            listener.lock().unwrap().applied(transaction);
+        });
+
+        let mut tx_id = 0;
+        let mut deposit = |engine: &mut Engine, client_id| {
+            tx_id += 1;
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id,
+                    tx_id,
+                    amount: 10_000,
+                    currency: None,
+                })
+                .unwrap();
+            tx_id
+        };
+        // Client 1 disputes 1 of 4 deposits, twice, and resolves it: at the threshold
+        let mut client_1 = Vec::new();
+        for _ in 0..4 {
+            client_1.push(deposit(&mut engine, 1));
+        }
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: client_1[0],
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: client_1[0],
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: client_1[0],
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: client_1[0],
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        // Client 2 disputes and charges back 1 of 2 deposits
+        let client_2 = deposit(&mut engine, 2);
+        deposit(&mut engine, 2);
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 2,
+                tx_id: client_2,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 2,
+                tx_id: client_2,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        // Client 3 disputes its only deposit, too few to be flagged
+        let client_3 = deposit(&mut engine, 3);
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 3,
+                tx_id: client_3,
+                currency: None,
+            })
+            .unwrap();
+        // A rejected dispute doesn't count
+        let _ = engine.process_transaction(Transaction::Dispute {
+            client_id: 1,
+            tx_id: client_2,
+            currency: None,
+        });
+
+        let mut output = Vec::new();
+        let detector = detector.lock().unwrap();
+        assert_eq!(detector.write_report(&mut output).unwrap(), 1);
+        assert_eq!(detector.clients(), 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,deposits,disputed,charged_back,dispute_ratio,chargeback_ratio,reason\n\
+            2,2,1,1,0.5000,0.5000,dispute and chargeback ratios\n"
+        );
+    }
+}
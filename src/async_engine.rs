@@ -0,0 +1,206 @@
+use crate::engine::{AccountView, Engine, SnapshotFormat};
+use crate::error::EngineError;
+use crate::transaction::Transaction;
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+type Job = Box<dyn FnOnce(&mut Engine) + Send>;
+
+/// What the engine's task is asked to do, in the order it's received
+enum Command {
+    Process {
+        transaction: Transaction,
+        timestamp: Option<u64>,
+        reply: Option<oneshot::Sender<Result<Option<AccountView>, EngineError>>>,
+    },
+    Run(Job),
+}
+
+/// Moves an engine into a Tokio task which applies the transactions and answers the queries sent
+/// through the handles it returns, one at a time, so that any number of connections or consumers
+/// can share it without locking it. At most `capacity` commands wait for the task, and sending
+/// more waits for room. The task ends once every handle is dropped, and gives back the engine.
+///
+/// Like `tokio::spawn`, it has to be called from within a Tokio runtime.
+pub fn spawn(mut engine: Engine, capacity: usize) -> (EngineHandle, JoinHandle<Engine>) {
+    let (sender, mut receiver) = mpsc::channel(capacity);
+    let task = tokio::spawn(async move {
+        while let Some(command) = receiver.recv().await {
+            match command {
+                Command::Process {
+                    transaction,
+                    timestamp,
+                    reply,
+                } => {
+                    let outcome = match reply {
+                        Some(_) => {
+                            let target = transaction.clone();
+                            engine
+                                .process_transaction_at(transaction, timestamp)
+                                .map(|()| engine.account_for(&target))
+                        }
+                        None => engine
+                            .process_transaction_at(transaction, timestamp)
+                            .map(|()| None),
+                    };
+                    match (reply, outcome) {
+                        // The caller may have stopped waiting, which doesn't undo the transaction
+                        (Some(reply), outcome) => {
+                            let _ = reply.send(outcome);
+                        }
+                        (None, Err(e)) => warn!(code = e.code(), "Transaction rejected: {e}"),
+                        (None, Ok(_)) => {}
+                    }
+                }
+                Command::Run(job) => job(&mut engine),
+            }
+        }
+        engine
+    });
+    (EngineHandle { sender }, task)
+}
+
+/// Sends transactions and queries to the engine of `spawn`. Every method fails once the engine's
+/// task has stopped, which only happens if it panicked or was aborted.
+#[derive(Clone)]
+pub struct EngineHandle {
+    sender: mpsc::Sender<Command>,
+}
+
+impl EngineHandle {
+    /// Processes a transaction along with its timestamp like `Engine::process_transaction_at`,
+    /// and returns the state of the account it applied to
+    pub async fn process(
+        &self,
+        transaction: Transaction,
+        timestamp: Option<u64>,
+    ) -> Result<Result<Option<AccountView>, EngineError>> {
+        let (reply, outcome) = oneshot::channel();
+        self.send(Command::Process {
+            transaction,
+            timestamp,
+            reply: Some(reply),
+        })
+        .await?;
+        outcome.await.map_err(|_| stopped())
+    }
+
+    /// Queues a transaction without waiting for its outcome, only for room in the queue.
+    /// Rejections are only logged.
+    pub async fn submit(&self, transaction: Transaction, timestamp: Option<u64>) -> Result<()> {
+        self.send(Command::Process {
+            transaction,
+            timestamp,
+            reply: None,
+        })
+        .await
+    }
+
+    /// Runs a function on the engine, between the transactions sent before and after it, and
+    /// returns its result
+    pub async fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Engine) -> T + Send + 'static,
+    ) -> Result<T> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Run(Box::new(move |engine| {
+            let _ = reply.send(query(engine));
+        })))
+        .await?;
+        result.await.map_err(|_| stopped())
+    }
+
+    /// The state of a client's account in every currency it has balances in, like
+    /// `Engine::client_accounts`
+    pub async fn client_accounts(&self, client_id: u16) -> Result<Vec<AccountView>> {
+        self.query(move |engine| engine.client_accounts(client_id).collect())
+            .await
+    }
+
+    /// The state of every account, in no particular order
+    pub async fn accounts(&self) -> Result<Vec<AccountView>> {
+        self.query(|engine| engine.accounts().collect()).await
+    }
+
+    /// A snapshot of the engine, as `Engine::snapshot_as` writes it
+    pub async fn snapshot(&self, format: SnapshotFormat) -> Result<Vec<u8>> {
+        self.query(move |engine| {
+            let mut snapshot = Vec::new();
+            engine.snapshot_as(format, &mut snapshot)?;
+            Ok(snapshot)
+        })
+        .await?
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.sender.send(command).await.map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> anyhow::Error {
+    anyhow!("The engine's task has stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_engine::spawn;
+    use crate::engine::{Engine, SnapshotFormat};
+    use crate::error::EngineError;
+    use crate::transaction::Transaction;
+
+    fn deposit(client_id: u16, tx_id: u32, amount: u64) -> Transaction {
+        Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount,
+            currency: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_engine() {
+        let (handle, task) = spawn(Engine::new(), 4);
+
+        let account = handle.process(deposit(1, 1, 10_0000), None).await.unwrap();
+        assert_eq!(account.unwrap().unwrap().available_amount, 10_0000);
+        assert!(matches!(
+            handle.process(deposit(1, 1, 1), None).await.unwrap(),
+            Err(EngineError::DuplicateTxId { tx_id: 1 })
+        ));
+
+        // Handles can be shared between tasks, and commands apply in the order they're sent
+        let submitter = handle.clone();
+        tokio::spawn(async move {
+            for tx_id in 2..=10 {
+                submitter
+                    .submit(deposit(2, tx_id, 1_0000), None)
+                    .await
+                    .unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        let accounts = handle.client_accounts(2).await.unwrap();
+        assert_eq!(accounts[0].total_amount, 9_0000);
+        let mut clients: Vec<_> = handle
+            .accounts()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|account| account.client_id)
+            .collect();
+        clients.sort();
+        assert_eq!(clients, [1, 2]);
+
+        let snapshot = handle.snapshot(SnapshotFormat::Bincode).await.unwrap();
+        let restored = Engine::restore(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.account(2).unwrap().total_amount, 9_0000);
+
+        // The task gives back the engine once every handle is dropped
+        drop(handle);
+        let engine = task.await.unwrap();
+        assert_eq!(engine.account(1).unwrap().total_amount, 10_0000);
+    }
+}
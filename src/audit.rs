@@ -0,0 +1,140 @@
+use crate::engine::{AccountView, Engine};
+use crate::input::{self, CsvRow};
+use crate::transaction::Transaction;
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use anyhow::Result;
+use std::io::{Read, Write};
+
+/// Replays a transactions csv and writes a step by step account of what happened to one client's
+/// account: every row for the client, whether it was applied, the resulting balances, and why
+/// rejected rows were rejected.
+///
+/// Every row goes through the engine, not only the client's, since other clients' rows can affect
+/// the client's (e.g. by using a tx id first).
+pub fn audit(reader: impl Read, client_id: u16, mut writer: impl Write) -> Result<()> {
+    let mut engine = Engine::new();
+    writeln!(writer, "Audit of client {client_id}")?;
+
+    input::read_csv_rows(reader, |row: CsvRow| {
+        let line = row.line;
+        let transaction = match row.transaction {
+            Ok(transaction) if transaction.client_id() == client_id => transaction,
+            Ok(transaction) => {
+                let _ = engine.process_transaction(transaction);
+                return Ok(());
+            }
+            Err(e) if row.client_id == Some(client_id) => {
+                writeln!(writer, "line {line}: invalid row, ignored: {e}")?;
+                return Ok(());
+            }
+            Err(_) => return Ok(()),
+        };
+
+        let description = describe(&transaction);
+        match engine.process_transaction(transaction.clone()) {
+            Ok(()) => {
+                let account = engine
+                    .account_for(&transaction)
+                    .expect("Applied transactions have an account");
+                writeln!(
+                    writer,
+                    "line {line}: {description} applied, {}",
+                    describe_account(&account)
+                )?;
+            }
+            Err(e) => writeln!(writer, "line {line}: {description} rejected: {e}")?,
+        }
+        Ok(())
+    })?;
+
+    // One line per currency the account has balances in
+    let mut accounts = engine
+        .accounts()
+        .filter(|account| account.client_id == client_id)
+        .peekable();
+    if accounts.peek().is_none() {
+        writeln!(writer, "Final state: no account")?;
+    }
+    for account in accounts {
+        writeln!(writer, "Final state: {}", describe_account(&account))?;
+    }
+    Ok(())
+}
+
+fn describe(transaction: &Transaction) -> String {
+    match *transaction {
+        Transaction::Deposit { tx_id, amount, .. } => format!(
+            "deposit of {} (tx {tx_id})",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Withdrawal { tx_id, amount, .. } => format!(
+            "withdrawal of {} (tx {tx_id})",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Dispute { tx_id, .. } => format!("dispute of tx {tx_id}"),
+        Transaction::Resolve { tx_id, .. } => format!("resolve of tx {tx_id}"),
+        Transaction::Chargeback { tx_id, .. } => format!("chargeback of tx {tx_id}"),
+        Transaction::Unlock { tx_id, .. } => format!("unlock (tx {tx_id})"),
+        Transaction::Reversal { tx_id, .. } => format!("reversal of tx {tx_id}"),
+        Transaction::Hold { tx_id, amount, .. } => format!(
+            "hold of {} (tx {tx_id})",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Release { tx_id, .. } => format!("release of hold {tx_id}"),
+    }
+}
+
+fn describe_account(account: &AccountView) -> String {
+    format!(
+        "{}available {}, held {}, total {}{}",
+        account
+            .currency
+            .map_or(String::new(), |currency| format!("{currency} ")),
+        signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+        fixed_point_4_decimal_to_float_str(account.held_amount),
+        signed_fixed_point_4_decimal_to_float_str(account.total_amount),
+        if account.locked { ", locked" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::audit;
+
+    #[test]
+    fn test_audit_narrates_one_client() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,5\n\
+            deposit,1,2,7\n\
+            withdrawal,1,3,25\n\
+            withdrawal,1,4,abc\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,1,5,1\n";
+
+        let mut output = Vec::new();
+        audit(csv.as_bytes(), 1, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], "Audit of client 1");
+        assert_eq!(
+            lines[1],
+            "line 2: deposit of 10.0000 (tx 1) applied, available 10.0000, held 0.0000, total 10.0000"
+        );
+        assert!(lines[2].starts_with("line 4: deposit of 7.0000 (tx 2) rejected: "));
+        assert!(lines[2].contains("duplicate tx_id"));
+        assert!(lines[3].starts_with("line 5: withdrawal of 25.0000 (tx 3) rejected: "));
+        assert!(lines[4].starts_with("line 6: invalid row, ignored: "));
+        assert_eq!(
+            lines[6],
+            "line 8: chargeback of tx 1 applied, available 0.0000, held 0.0000, total 0.0000, locked"
+        );
+        assert!(lines[7].contains("the target account is locked"));
+        assert_eq!(
+            lines[8],
+            "Final state: available 0.0000, held 0.0000, total 0.0000, locked"
+        );
+    }
+}
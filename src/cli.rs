@@ -0,0 +1,1150 @@
+use anyhow::{anyhow, ensure, Result};
+use clap::{Args, Parser, Subcommand};
+use payments_engine::alerts::{AlertEvaluator, AlertRules, AlertSinks};
+use payments_engine::anomalies::{AnomalyDetector, AnomalyThresholds};
+use payments_engine::engine::{
+    ChargebackLockPolicy, Engine, EngineConfig, LockedAccountPolicy, NegativeBalancePolicy,
+    SnapshotFormat,
+};
+use payments_engine::event_log::EventLog;
+use payments_engine::input::{
+    self, Compression, CsvOptions, DecimalSeparator, InputFormat, InputOrder, InvalidRows, Until,
+};
+use payments_engine::layout::CsvLayout;
+use payments_engine::loadtest::LoadTestConfig;
+use payments_engine::logging::LogFormat;
+use payments_engine::output::OutputFormat;
+use payments_engine::rules::RuleEngine;
+#[cfg(feature = "scripting")]
+use payments_engine::scripting::ValidationScript;
+use payments_engine::settlement::{Batching, SettlementConfig};
+use payments_engine::shadow::ShadowCheck;
+use payments_engine::soak::SoakConfig;
+use payments_engine::statement::StatementFormat;
+use payments_engine::util::{float_str_to_fixed_point_4_decimal, RoundingMode};
+use payments_engine::wal::{self, Wal};
+use payments_engine::webhook::{WebhookConfig, WebhookNotifier};
+use std::fs::{File, OpenOptions};
+use std::ops::Not;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Processes deposits, withdrawals, disputes, resolves and chargebacks into client account states
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(flatten)]
+    pub process: ProcessArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Format of the messages written to stderr, such as rejected transactions and invalid rows
+    #[arg(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Only write errors to stderr (invalid rows, shadow check mismatches and failures), leaving
+    /// out rejected transactions, flagged ones and progress. Reports asked for, like `--stats`,
+    /// are still written.
+    #[arg(long, global = true)]
+    pub quiet: bool,
+}
+
+/// Options for processing transactions into account states, given either without a subcommand or
+/// to the `process` subcommand
+#[derive(Args)]
+pub struct ProcessArgs {
+    /// Paths to the transactions csv files to process as a single stream, or `-` to read it from
+    /// stdin (the default)
+    pub transactions_csv_paths: Vec<PathBuf>,
+
+    /// Format of the transactions. The binary format has neither currencies nor timestamps, and
+    /// can't be combined with `--input-order timestamp`, `--until-tx`, `--until-timestamp`,
+    /// `--rejected-output`, `--threads` or `--tui`.
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv)]
+    pub format: InputFormat,
+
+    /// How the transactions csvs are compressed. Gzip and zstd are detected from `.gz` and `.zst`
+    /// extensions by default, but have to be given for stdin.
+    #[arg(long, value_enum, default_value_t = Compression::Auto)]
+    pub compression: Compression,
+
+    #[command(flatten)]
+    pub csv_format: CsvFormatArgs,
+
+    /// Order to read the rows of several transactions csvs in
+    #[arg(long, value_enum, default_value_t = InputOrder::Sequential)]
+    pub input_order: InputOrder,
+
+    /// Only process the rows of these clients, e.g. `--clients 1,42` or `--client 42`, skipping the
+    /// others before parsing them
+    #[arg(long, alias = "client", value_name = "ID", value_delimiter = ',')]
+    pub clients: Vec<u16>,
+
+    /// Stop processing at the first row with this tx id, without processing it, and write the
+    /// accounts as they were before it
+    #[arg(long, value_name = "TX_ID", conflicts_with = "until_timestamp")]
+    pub until_tx: Option<u32>,
+
+    /// Stop processing at the first row with a `timestamp` later than this one (milliseconds since
+    /// the Unix epoch), and write the accounts as they were at that time
+    #[arg(long, value_name = "MILLIS")]
+    pub until_timestamp: Option<u64>,
+
+    /// Read the rows of csvs straight from the bytes of their fields, rather than deserializing
+    /// them, for faster parsing. Rows are read the same, apart from the wording of why invalid
+    /// ones are.
+    #[arg(long)]
+    pub fast_parse: bool,
+
+    /// What to do with rows that can't be parsed (malformed, truncated or not valid UTF-8)
+    #[arg(long, value_enum, default_value_t = InvalidRows::Skip)]
+    pub invalid_rows: InvalidRows,
+
+    /// Abort, without writing any account states, when more than this share of rows (0 to 1) is
+    /// rejected by the engine or invalid. Checked as rows are read once 1000 of them were, unless
+    /// processing in parallel, and over every row at the end.
+    #[arg(long, value_name = "RATE", value_parser = parse_error_rate)]
+    pub max_error_rate: Option<f64>,
+
+    /// Abort, without writing any account states, at the first invalid row or transaction the
+    /// engine rejects, reporting its line. Implies `--invalid-rows fail`.
+    #[arg(long, conflicts_with_all = ["threads", "pipeline"])]
+    pub strict: bool,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+
+    /// File to write the accounts to, instead of stdout
+    #[arg(long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Format to write the accounts in
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub output_format: OutputFormat,
+
+    /// Character separating the fields of csv output formats, such as `tab` for TSV or `|`
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_csv_char)]
+    pub output_delimiter: u8,
+
+    /// Print a summary to stderr once every row has been processed: rows applied and rejected by
+    /// transaction type, the number of locked accounts and the sums of every account's balances
+    #[arg(long)]
+    pub stats: bool,
+
+    /// File to write every rejected or invalid row to, as csv, with its original fields and a
+    /// `reason_code` column
+    #[arg(long, value_name = "PATH")]
+    pub rejected_output: Option<PathBuf>,
+
+    /// Snapshot of the engine state to resume processing from, as written by `--save-state`
+    #[arg(long, value_name = "PATH")]
+    pub load_state: Option<PathBuf>,
+
+    /// File to write a snapshot of the engine state to once every row has been processed, to
+    /// resume from later with `--load-state`
+    #[arg(long, value_name = "PATH")]
+    pub save_state: Option<PathBuf>,
+
+    /// Format of the snapshot written by `--save-state`. `--load-state` reads any, and maps those
+    /// in the `mapped` format rather than reading them whole.
+    #[arg(long, value_enum, default_value_t, requires = "save_state")]
+    pub state_format: SnapshotFormat,
+
+    /// File to move the deposits and withdrawals that are neither in dispute nor held to once
+    /// more than `--max-transactions-in-memory` are, least recently used first, so that they can
+    /// still be disputed without being kept in memory. It's overwritten on every run.
+    #[arg(long, value_name = "PATH")]
+    pub transaction_store: Option<PathBuf>,
+
+    /// Number of deposits and withdrawals that are neither in dispute nor held kept in memory
+    /// with `--transaction-store`
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 1_000_000,
+        requires = "transaction_store"
+    )]
+    pub max_transactions_in_memory: usize,
+
+    /// Directory of a sled database to move accounts to once more than `--max-accounts-in-memory`
+    /// are in memory, least recently used first, so that memory use stays bounded however many
+    /// clients there are. It's cleared on every run.
+    #[cfg(feature = "account-store")]
+    #[arg(long, value_name = "PATH")]
+    pub account_store: Option<PathBuf>,
+
+    /// Number of accounts kept in memory with `--account-store`
+    #[cfg(feature = "account-store")]
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 100_000,
+        requires = "account_store"
+    )]
+    pub max_accounts_in_memory: usize,
+
+    /// Number of clients the transactions are expected to be for, to make room for their accounts
+    /// upfront rather than growing the map of accounts over and over
+    #[arg(long, value_name = "COUNT")]
+    pub expected_clients: Option<usize>,
+
+    /// Number of deposits and withdrawals expected, to give every account room for its share of
+    /// them upfront along with `--expected-clients`
+    #[arg(long, value_name = "COUNT", requires = "expected_clients")]
+    pub expected_transactions: Option<usize>,
+
+    /// Track tx ids in a Bloom filter sized for this many, rather than in a bitmap growing with
+    /// them. Memory is then fixed, at about 1.2 bytes per tx id with the default false positive
+    /// rate, against up to 2 for scattered tx ids in the bitmap, but some new tx ids are wrongly
+    /// rejected as duplicates.
+    #[arg(long, value_name = "CAPACITY", conflicts_with_all = ["save_state", "threads"])]
+    pub probabilistic_dedup: Option<u64>,
+
+    /// Share of new tx ids the Bloom filter of `--probabilistic-dedup` may wrongly reject, up to
+    /// its capacity. Every tenfold drop costs about 0.6 more bytes per tx id.
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value_t = 0.01,
+        requires = "probabilistic_dedup",
+        value_parser = parse_false_positive_rate
+    )]
+    pub dedup_false_positive_rate: f64,
+
+    /// Number of threads to spread the accounts over, by client id. Every client's transactions are
+    /// still applied in order, so the resulting accounts are the same as with a single thread.
+    /// Can't be combined with hooks, `--rejected-output`, `--load-state`, `--transaction-store`,
+    /// `--account-store`, `--max-deposits` or `--tui`.
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        conflicts_with_all = [
+            "rejected_output",
+            "lock_webhooks",
+            "alert_negative_balance",
+            "alert_held_above",
+            "alert_chargebacks_above",
+            "alert_webhooks",
+            "alerts_file",
+            "anomalies_report",
+            "rules",
+            "shadow_check",
+            "audit_log",
+            "suspense_account",
+            "wal",
+            "load_state",
+            "transaction_store",
+            "max_deposits",
+        ]
+    )]
+    #[cfg_attr(feature = "tui", arg(conflicts_with = "tui"))]
+    #[cfg_attr(feature = "scripting", arg(conflicts_with = "validation_script"))]
+    #[cfg_attr(feature = "account-store", arg(conflicts_with = "account_store"))]
+    pub threads: u16,
+
+    /// Apply the transactions of the `--threads` engines on a single thread instead, the engines
+    /// taking turns in an order drawn from this seed, so that runs with the same input, number of
+    /// threads and seed apply and log every transaction in the same order. Meant for reproducing
+    /// an interleaving of the engines, with `RUST_LOG=payments_engine=debug` logging every turn.
+    #[arg(long, value_name = "SEED", requires = "threads")]
+    pub schedule_seed: Option<u64>,
+
+    /// Parse csvs on a thread of their own, while their transactions are applied on another, so
+    /// that the two overlap. Every transaction still goes through the same engine in order, so the
+    /// resulting accounts are the same. Can't be combined with `--threads`, `--rejected-output` or
+    /// `--tui`.
+    #[arg(long, conflicts_with_all = ["threads", "rejected_output"])]
+    #[cfg_attr(feature = "tui", arg(conflicts_with = "tui"))]
+    pub pipeline: bool,
+
+    /// Show a live dashboard on stderr while processing, instead of reporting every rejected
+    /// transaction and invalid row
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+
+    #[command(flatten)]
+    pub hooks: HookArgs,
+
+    #[command(flatten)]
+    pub reports: ReportArgs,
+}
+
+impl ProcessArgs {
+    pub fn csv_options(&self) -> CsvOptions {
+        self.csv_format.apply(CsvOptions {
+            order: self.input_order,
+            clients: (self.clients.is_empty().not())
+                .then(|| self.clients.iter().copied().collect()),
+            until: match (self.until_tx, self.until_timestamp) {
+                (Some(tx_id), _) => Some(Until::Tx(tx_id)),
+                (None, Some(timestamp)) => Some(Until::Timestamp(timestamp)),
+                (None, None) => None,
+            },
+            fast_parse: self.fast_parse,
+            max_error_rate: self.max_error_rate,
+            strict: self.strict,
+            ..CsvOptions::default()
+        })
+    }
+}
+
+/// Options for how transactions csvs are laid out
+#[derive(Args)]
+pub struct CsvFormatArgs {
+    /// Read a field (`type`, `client`, `tx`, `amount`, `timestamp` or `currency`) from the column
+    /// with this header, e.g. `--map-column client="Client ID"`, for csvs whose columns aren't
+    /// named after the fields. Headers are otherwise matched to fields ignoring case, and other
+    /// columns are ignored.
+    #[arg(long, value_name = "FIELD=HEADER", value_parser = parse_column_mapping)]
+    map_column: Vec<(String, String)>,
+
+    /// Read csvs without a header row, whose columns hold the fields in `--column-order`
+    #[arg(long, conflicts_with = "map_column")]
+    no_header: bool,
+
+    /// Fields of the columns of csvs read with `--no-header`, in order, leaving a name empty for
+    /// columns to ignore, e.g. `--column-order client,,type,tx,amount`
+    #[arg(
+        long,
+        value_name = "FIELDS",
+        value_delimiter = ',',
+        default_value = "type,client,tx,amount",
+        value_parser = parse_column_field,
+        requires = "no_header"
+    )]
+    column_order: Vec<String>,
+
+    /// Character separating fields, such as `;` or `tab`
+    #[arg(long, value_name = "CHAR", value_parser = parse_csv_char)]
+    delimiter: Option<u8>,
+
+    /// Character quoting fields, such as `'`
+    #[arg(long, value_name = "CHAR", value_parser = parse_csv_char)]
+    quote_char: Option<u8>,
+
+    /// Read rows with more or fewer fields than the header instead of skipping them as invalid,
+    /// missing fields being empty
+    #[arg(long)]
+    flexible: bool,
+
+    /// Character separating the integer and fractional parts of amounts, `comma` for amounts like
+    /// `1,5`, whose fields are then separated by another `--delimiter` or quoted
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Point)]
+    decimal_separator: DecimalSeparator,
+
+    /// YAML file describing how the csvs are laid out, as written by `infer-layout`: their
+    /// delimiter, decimal separator, and the columns holding each field, by header or in order
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = parse_csv_layout,
+        conflicts_with_all = ["map_column", "no_header", "column_order", "delimiter", "decimal_separator"]
+    )]
+    csv_layout: Option<CsvLayout>,
+}
+
+impl CsvFormatArgs {
+    /// Sets how the csvs are laid out in the options they're read with
+    pub fn apply(&self, options: CsvOptions) -> CsvOptions {
+        let options = CsvOptions {
+            quote: self.quote_char,
+            flexible: self.flexible,
+            ..options
+        };
+        if let Some(layout) = &self.csv_layout {
+            return layout.apply(options);
+        }
+        CsvOptions {
+            columns: self.map_column.iter().cloned().collect(),
+            headerless: self.no_header.then(|| self.column_order.clone()),
+            delimiter: self.delimiter,
+            decimal_separator: self.decimal_separator,
+            ..options
+        }
+    }
+}
+
+/// Options for how the engine processes transactions, see `EngineConfig`
+#[derive(Args)]
+pub struct EngineArgs {
+    /// Reject transactions without a `timestamp`, or with one earlier than that of the client's
+    /// last applied transaction
+    #[arg(long)]
+    strict_ordering: bool,
+
+    /// What to do with deposits into accounts a chargeback locked. The shadow check expects them to
+    /// be rejected.
+    #[arg(long, value_enum, default_value_t = LockedAccountPolicy::RejectAll)]
+    locked_accounts: LockedAccountPolicy,
+
+    /// Which chargebacks lock the account: `first`, `never` (the extended output's chargeback
+    /// count flagging accounts instead), or a count of chargebacks, e.g. `3` to lock on the third.
+    /// The shadow check expects `first`.
+    #[arg(long, value_name = "POLICY", default_value = "first", value_parser = parse_chargeback_lock)]
+    chargeback_lock: ChargebackLockPolicy,
+
+    /// What to do when a dispute holds more of a deposit than is available, its funds having been
+    /// spent. The shadow check expects them to be allowed.
+    #[arg(long, value_enum, default_value_t = NegativeBalancePolicy::Allow)]
+    negative_balance: NegativeBalancePolicy,
+
+    /// Let withdrawals draw on held funds when the available ones aren't enough, taking them
+    /// negative. The shadow check expects withdrawals to only draw on available funds.
+    #[arg(long)]
+    withdraw_from_held: bool,
+
+    /// Reject disputes filed longer than this after the transaction they dispute, e.g. `90d`,
+    /// `12h` or `30m`. Needs a `timestamp` column.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    dispute_window: Option<Duration>,
+
+    /// What to do with amounts that have more than 4 decimal places
+    #[arg(long, value_enum, default_value_t = RoundingMode::Truncate)]
+    rounding: RoundingMode,
+
+    /// Evict a client's oldest deposits that aren't disputed once it has more than this many, so
+    /// that memory stays bounded at the cost of them no longer being disputable
+    #[arg(long, value_name = "COUNT")]
+    max_deposits_per_account: Option<usize>,
+
+    /// Evict the oldest deposits that aren't disputed, across every client, once there are more
+    /// than this many
+    #[arg(long, value_name = "COUNT")]
+    max_deposits: Option<usize>,
+}
+
+impl EngineArgs {
+    pub fn engine_config(&self) -> EngineConfig {
+        EngineConfig {
+            locked_account_policy: self.locked_accounts,
+            chargeback_lock_policy: self.chargeback_lock,
+            negative_balance_policy: self.negative_balance,
+            withdraw_from_held: self.withdraw_from_held,
+            strict_ordering: self.strict_ordering,
+            dispute_window: self.dispute_window,
+            rounding: self.rounding,
+            max_deposits_per_account: self.max_deposits_per_account,
+            max_deposits: self.max_deposits,
+            keep_history: false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Process transactions into account states, as without a subcommand
+    Process(Box<ProcessArgs>),
+    /// Serve a JSON-RPC 2.0 interface (newline-delimited) over stdio, or over TCP with `--listen`
+    Rpc {
+        /// Address to accept JSON-RPC connections on, e.g. `127.0.0.1:9000`. The server runs
+        /// until it's killed, so it can't be combined with `--anomalies-report` or
+        /// `--shadow-check`, which report once processing is done.
+        #[arg(long, conflicts_with_all = ["anomalies_report", "shadow_check"])]
+        listen: Option<String>,
+
+        #[command(flatten)]
+        hooks: Box<HookArgs>,
+
+        #[command(flatten)]
+        reports: Box<ReportArgs>,
+    },
+    /// Serve a REST interface over HTTP: `POST /transactions`, `GET /accounts/{id}` and
+    /// `GET /report`, described by the OpenAPI document at `GET /openapi.json`
+    Serve {
+        /// Address to accept HTTP connections on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Connections served at the same time, further ones get a `503` response
+        #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u16).range(1..))]
+        max_connections: u16,
+
+        /// Seconds to wait for the rest of a request before closing the connection
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        read_timeout_secs: u64,
+
+        /// Snapshot of the engine state to start from, as written by `process --save-state`.
+        /// Snapshots in the `mapped` format are memory-mapped, for the server to be up in about
+        /// the time it takes to read the accounts whatever the number of deposits.
+        #[arg(long, value_name = "PATH")]
+        load_state: Option<PathBuf>,
+
+        #[command(flatten)]
+        hooks: Box<HookArgs>,
+    },
+    /// Serve the `PaymentsEngine` gRPC service of `proto/payments.proto`: `SubmitTransaction`,
+    /// `StreamTransactions`, `GetAccount` and `ExportReport`
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Address to accept gRPC connections on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: std::net::SocketAddr,
+
+        /// Snapshot of the engine state to start from, as written by `process --save-state`
+        #[arg(long, value_name = "PATH")]
+        load_state: Option<PathBuf>,
+
+        #[command(flatten)]
+        hooks: Box<HookArgs>,
+    },
+    /// Submit generated transactions to a JSON-RPC server started with `rpc --listen` at a
+    /// steady rate, then report throughput, rejections and latency percentiles
+    Loadtest(LoadTestArgs),
+    /// Stream generated transactions through the engine in memory for a long time, reporting
+    /// throughput and memory usage along the way to catch leaks and slowdowns
+    Soak(SoakArgs),
+    /// Check transactions csvs without producing account states, reporting every row that
+    /// processing them would reject and why. Exits with status 1 if there's any.
+    Validate {
+        /// Paths to the transactions csv files to check as a single stream, or `-` to read it
+        /// from stdin (the default)
+        transactions_csv_paths: Vec<PathBuf>,
+
+        /// How the transactions csvs are compressed
+        #[arg(long, value_enum, default_value_t = Compression::Auto)]
+        compression: Compression,
+
+        /// Order to read the rows of several transactions csvs in
+        #[arg(long, value_enum, default_value_t = InputOrder::Sequential)]
+        input_order: InputOrder,
+
+        #[command(flatten)]
+        csv_format: CsvFormatArgs,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+    /// Convert a transactions csv to the binary format read with `--format bin`
+    Encode {
+        /// Path to the transactions csv file to convert, or `-` to read it from stdin
+        transactions_csv_path: PathBuf,
+
+        /// File to write the transactions to
+        #[arg(long, value_name = "PATH")]
+        output: PathBuf,
+    },
+    /// Guess how a transactions csv of an unfamiliar format is laid out from its first rows (its
+    /// delimiter, decimal separator, header row and the columns holding each field) and write the
+    /// layout to stdout as YAML, to process it with `--csv-layout`. What each column was taken for,
+    /// and why, is written to stderr.
+    InferLayout {
+        /// Path to the transactions csv file, or `-` to read it from stdin
+        transactions_csv_path: PathBuf,
+
+        /// Number of rows to guess from, after the header
+        #[arg(long, value_name = "ROWS", default_value_t = 100)]
+        sample_rows: usize,
+
+        /// How the transactions csv is compressed
+        #[arg(long, value_enum, default_value_t = Compression::Auto)]
+        compression: Compression,
+    },
+    /// Check a snapshot written with `--save-state`: restore the engine from it, write it again to
+    /// compare their SHA-256 digests, and check the restored state's invariants. Exits with status
+    /// 1 if any check fails.
+    VerifySnapshot {
+        /// Path to the snapshot
+        snapshot_path: PathBuf,
+    },
+    /// Replay a transactions csv and narrate what happened to one client's account, row by row,
+    /// including why rows were rejected
+    Audit {
+        /// Client to audit
+        #[arg(long)]
+        client: u16,
+
+        /// Path to the transactions csv file to replay, or `-` to read it from stdin
+        transactions_csv_path: PathBuf,
+    },
+    /// Replay a transactions csv and write the statement of one client's account: every
+    /// transaction applied to it, in order, with the balances it resulted in
+    Statement {
+        /// Client to write the statement of
+        #[arg(long)]
+        client: u16,
+
+        /// Format to write the statement in
+        #[arg(long, value_enum, default_value_t = StatementFormat::Csv)]
+        format: StatementFormat,
+
+        /// Path to the transactions csv file to replay, or `-` to read it from stdin
+        transactions_csv_path: PathBuf,
+
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+    /// Replay a transactions csv and explain why the rows with a given tx id were rejected, down
+    /// to the rows that caused it
+    Explain {
+        /// Tx id of the deposit or withdrawal to explain, along with its disputes
+        #[arg(long)]
+        tx: u32,
+
+        /// Path to the transactions csv file to replay, or `-` to read it from stdin
+        transactions_csv_path: PathBuf,
+    },
+    /// Replay a transactions csv and write the accepted withdrawals to settlement batch files,
+    /// along with an index of every batch's number of withdrawals and total
+    Settle(SettleArgs),
+}
+
+#[derive(Args)]
+pub struct LoadTestArgs {
+    /// Address of the JSON-RPC server, e.g. `127.0.0.1:9000`
+    #[arg(long)]
+    addr: String,
+
+    /// Requests per second to send, across all connections
+    #[arg(long, default_value_t = 1_000, value_parser = clap::value_parser!(u32).range(1..))]
+    rps: u32,
+
+    /// How long to send requests for, in seconds
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+
+    /// Number of connections, each with one request in flight at a time
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    connections: u16,
+
+    /// Number of distinct clients to spread transactions over
+    #[arg(long, default_value_t = 1_000, value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+
+    /// Tx id of the first transaction. Pick one above the previous run's when testing the same
+    /// server again, or all transactions get rejected as duplicates.
+    #[arg(long, default_value_t = 0)]
+    first_tx_id: u32,
+}
+
+impl LoadTestArgs {
+    pub fn config(&self) -> LoadTestConfig {
+        LoadTestConfig {
+            addr: self.addr.clone(),
+            rps: self.rps,
+            duration: Duration::from_secs(self.duration_secs),
+            connections: self.connections,
+            clients: self.clients,
+            first_tx_id: self.first_tx_id,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct SoakArgs {
+    /// Number of rows to stream through the engine, e.g. `1e9`
+    #[arg(long, default_value = "1e9", value_parser = parse_row_count)]
+    rows: u64,
+
+    /// Number of distinct clients to spread transactions over
+    #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+
+    /// How often to report throughput and memory usage, in seconds
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    report_interval_secs: u64,
+}
+
+impl SoakArgs {
+    pub fn config(&self) -> SoakConfig {
+        SoakConfig {
+            rows: self.rows,
+            clients: self.clients,
+            report_interval: Duration::from_secs(self.report_interval_secs),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct SettleArgs {
+    /// How withdrawals are grouped into batches
+    #[arg(long, value_enum, default_value_t = Batching::Rows)]
+    by: Batching,
+
+    /// Client ids per batch with `--by clients`, rows per batch with `--by rows`
+    #[arg(long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+    size: u64,
+
+    /// Directory to write the settlement files to, created if needed
+    #[arg(long, value_name = "DIR")]
+    output_dir: PathBuf,
+
+    /// Path to the transactions csv file to replay, or `-` to read it from stdin
+    pub transactions_csv_path: PathBuf,
+}
+
+impl SettleArgs {
+    pub fn config(&self) -> SettlementConfig {
+        SettlementConfig {
+            batching: self.by,
+            size: self.size,
+            output_dir: self.output_dir.clone(),
+        }
+    }
+}
+
+/// Parses a row count, either as an integer or in scientific notation such as `1e9`. Rows are
+/// numbered with tx ids, so there can be at most one per possible tx id.
+fn parse_row_count(value: &str) -> Result<u64> {
+    let rows = match value.parse::<u64>() {
+        Ok(rows) => rows,
+        Err(_) => {
+            let rows = value.parse::<f64>()?;
+            if rows.fract() != 0.0 || rows < 0.0 {
+                return Err(anyhow!("Expected a whole number of rows"));
+            }
+            rows as u64
+        }
+    };
+    if rows > u32::MAX as u64 + 1 {
+        return Err(anyhow!(
+            "At most {} rows are supported",
+            u32::MAX as u64 + 1
+        ));
+    }
+    Ok(rows)
+}
+
+/// Parses a chargeback lock policy: `first`, `never` or a count of chargebacks
+fn parse_chargeback_lock(value: &str) -> Result<ChargebackLockPolicy> {
+    match value {
+        "first" => Ok(ChargebackLockPolicy::FirstChargeback),
+        "never" => Ok(ChargebackLockPolicy::Never),
+        count => {
+            let count: u32 = count
+                .parse()
+                .map_err(|_| anyhow!("Expected first, never or a count of chargebacks"))?;
+            ensure!(count > 0, "The count of chargebacks must be at least 1");
+            Ok(ChargebackLockPolicy::AfterChargebacks(count))
+        }
+    }
+}
+
+fn parse_false_positive_rate(value: &str) -> Result<f64> {
+    let rate: f64 = value.parse()?;
+    ensure!(
+        rate > 0.0 && rate < 1.0,
+        "The false positive rate must be between 0 and 1"
+    );
+    Ok(rate)
+}
+
+fn parse_column_mapping(value: &str) -> Result<(String, String)> {
+    let (field, header) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected FIELD=HEADER"))?;
+    ensure!(
+        input::FIELDS.contains(&field),
+        "Unknown field {field}, expected one of {}",
+        input::FIELDS.join(", ")
+    );
+    Ok((field.to_string(), header.to_string()))
+}
+
+/// A field of `--column-order`, or an empty name for a column to ignore
+fn parse_column_field(value: &str) -> Result<String> {
+    ensure!(
+        value.is_empty() || input::FIELDS.contains(&value),
+        "Unknown field {value}, expected one of {}",
+        input::FIELDS.join(", ")
+    );
+    Ok(value.to_string())
+}
+
+/// A single ASCII character, or `tab` or `\t` for a tab
+fn parse_csv_char(value: &str) -> Result<u8> {
+    match value.as_bytes() {
+        b"tab" | b"\\t" => Ok(b'\t'),
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(anyhow!("Expected a single ASCII character or tab")),
+    }
+}
+
+fn parse_csv_layout(value: &str) -> Result<CsvLayout> {
+    CsvLayout::load(value.as_ref()).map_err(|e| anyhow!("Invalid csv layout: {e}"))
+}
+
+fn parse_error_rate(value: &str) -> Result<f64> {
+    let rate: f64 = value.parse()?;
+    ensure!(
+        (0.0..=1.0).contains(&rate),
+        "The error rate must be between 0 and 1"
+    );
+    Ok(rate)
+}
+
+/// Parses a duration made of a whole number and a unit: `ms`, `s`, `m`, `h` or `d`
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| c.is_ascii_digit().not())
+        .ok_or_else(|| anyhow!("Expected a unit: ms, s, m, h or d"))?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse()?;
+    let millis_per_unit = match unit {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60 * 1_000,
+        "h" => 60 * 60 * 1_000,
+        "d" => 24 * 60 * 60 * 1_000,
+        _ => return Err(anyhow!("Unknown unit {unit}, expected ms, s, m, h or d")),
+    };
+    let millis = amount
+        .checked_mul(millis_per_unit)
+        .ok_or_else(|| anyhow!("Duration is too long"))?;
+    Ok(Duration::from_millis(millis))
+}
+
+/// Options for hooks registered with the engine (webhooks, alerts and validation rules) and for
+/// the engine's suspense account
+#[derive(Args)]
+pub struct HookArgs {
+    #[command(flatten)]
+    webhooks: WebhookArgs,
+
+    #[command(flatten)]
+    alerts: AlertArgs,
+
+    /// YAML file with declarative rules evaluated before every transaction
+    #[arg(long, value_name = "PATH")]
+    rules: Option<PathBuf>,
+
+    /// rhai script defining a `validate(tx, account)` function run before every transaction
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "PATH")]
+    validation_script: Option<PathBuf>,
+
+    /// File to append every applied change to an account to, with its balances before and after,
+    /// one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Client id of a system account credited with charged-back funds, listed along with the
+    /// client accounts. Transactions targeting it are rejected.
+    #[arg(long, value_name = "CLIENT_ID")]
+    suspense_account: Option<u16>,
+
+    /// Write-ahead log to replay on startup, then to append every transaction to before applying
+    /// it. It's emptied whenever `--save-state` writes a snapshot.
+    #[arg(long, value_name = "PATH")]
+    wal: Option<PathBuf>,
+}
+
+/// Options for hooks that report once processing is done, which the servers never are, so they
+/// only take them when processing a file or serving over stdio
+#[derive(Args)]
+pub struct ReportArgs {
+    #[command(flatten)]
+    anomalies: AnomalyArgs,
+
+    /// Run a simple reference ledger alongside the engine and report every transaction on which
+    /// they disagree, failing at the end if they did
+    #[arg(long)]
+    shadow_check: bool,
+}
+
+#[derive(Args)]
+struct WebhookArgs {
+    /// URL to POST a JSON notification to whenever a chargeback locks an account (repeatable)
+    #[arg(long = "lock-webhook", value_name = "URL")]
+    lock_webhooks: Vec<String>,
+
+    /// How many times a failed webhook delivery is retried
+    #[arg(long, default_value_t = 3)]
+    webhook_retries: u32,
+
+    /// Delay before the first webhook retry in milliseconds, doubled after every attempt
+    #[arg(long, default_value_t = 500)]
+    webhook_backoff_ms: u64,
+}
+
+#[derive(Args)]
+struct AlertArgs {
+    /// Raise an alert whenever an account's balance goes negative
+    #[arg(long)]
+    alert_negative_balance: bool,
+
+    /// Raise an alert whenever an account's held funds exceed this amount
+    #[arg(long, value_name = "AMOUNT", value_parser = float_str_to_fixed_point_4_decimal)]
+    alert_held_above: Option<u64>,
+
+    /// Raise an alert when a client's number of chargebacks exceeds this count
+    #[arg(long, value_name = "COUNT")]
+    alert_chargebacks_above: Option<u32>,
+
+    /// URL to POST raised alerts to (repeatable). Shares the webhook retry settings.
+    #[arg(long = "alert-webhook", value_name = "URL")]
+    alert_webhooks: Vec<String>,
+
+    /// File to append raised alerts to, one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    alerts_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct AnomalyArgs {
+    /// File to write the clients with anomalous dispute or chargeback ratios to, as csv, once
+    /// processing is done
+    #[arg(long, value_name = "PATH")]
+    anomalies_report: Option<PathBuf>,
+
+    /// Flag clients who disputed more than this share of their deposits
+    #[arg(long, value_name = "RATIO", default_value_t = 0.05)]
+    anomaly_dispute_ratio: f64,
+
+    /// Flag clients who had more than this share of their deposits charged back
+    #[arg(long, value_name = "RATIO", default_value_t = 0.01)]
+    anomaly_chargeback_ratio: f64,
+
+    /// Never flag clients with fewer deposits than this, whose ratios say little
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    anomaly_min_deposits: u64,
+}
+
+impl WebhookArgs {
+    fn config(&self, urls: &[String]) -> WebhookConfig {
+        WebhookConfig {
+            urls: urls.to_vec(),
+            max_retries: self.webhook_retries,
+            initial_backoff: Duration::from_millis(self.webhook_backoff_ms),
+        }
+    }
+}
+
+impl AlertArgs {
+    fn rules(&self) -> AlertRules {
+        AlertRules {
+            negative_balance: self.alert_negative_balance,
+            held_above: self.alert_held_above,
+            chargebacks_above: self.alert_chargebacks_above,
+        }
+    }
+
+    fn has_rules(&self) -> bool {
+        self.alert_negative_balance
+            || self.alert_held_above.is_some()
+            || self.alert_chargebacks_above.is_some()
+    }
+}
+
+impl AnomalyArgs {
+    fn thresholds(&self) -> AnomalyThresholds {
+        AnomalyThresholds {
+            dispute_ratio: self.anomaly_dispute_ratio,
+            chargeback_ratio: self.anomaly_chargeback_ratio,
+            min_deposits: self.anomaly_min_deposits,
+        }
+    }
+}
+
+/// Hooks registered with an engine that need wrapping up once processing is done
+pub struct Hooks {
+    notifiers: Vec<WebhookNotifier>,
+    rules: Option<Arc<Mutex<RuleEngine>>>,
+    shadow: Option<Arc<Mutex<ShadowCheck>>>,
+    /// The anomaly detector, along with the path its report is written to
+    anomalies: Option<(Arc<Mutex<AnomalyDetector>>, PathBuf)>,
+}
+
+impl HookArgs {
+    /// Registers the configured hooks with the engine, along with the reporting ones if any
+    pub fn register(&self, engine: &mut Engine, reports: Option<&ReportArgs>) -> Result<Hooks> {
+        let webhooks = &self.webhooks;
+        let alerts = &self.alerts;
+        let mut notifiers = Vec::new();
+
+        if let Some(client_id) = self.suspense_account {
+            engine.set_suspense_account(client_id);
+        }
+
+        // Replayed before any hook is registered, so that transactions aren't validated, alerted
+        // on or logged twice
+        if let Some(path) = self.wal.as_ref() {
+            let replayed = wal::replay(engine, path)?;
+            if replayed > 0 {
+                info!("Replayed {replayed} transactions from the write-ahead log");
+            }
+            engine.set_wal(Wal::open(path)?);
+        }
+
+        let rules = match self.rules.as_ref() {
+            Some(path) => {
+                let rules = Arc::new(Mutex::new(RuleEngine::from_file(path)?));
+                let validator_rules = Arc::clone(&rules);
+                engine.add_validator(move |transaction, account| {
+                    validator_rules
+                        .lock()
+                        .map_err(|_| anyhow!("Rule engine lock was poisoned"))?
+                        .validate(transaction, account)
+                });
+                Some(rules)
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "scripting")]
+        if let Some(path) = self.validation_script.as_ref() {
+            let script = ValidationScript::from_file(path)?;
+            engine.add_validator(move |transaction, account| script.validate(transaction, account));
+        }
+
+        // Registered after every other validator but the event log's, so it only sees the
+        // transactions they let through
+        let shadow = match reports.is_some_and(|reports| reports.shadow_check) {
+            true => {
+                ensure!(
+                    engine.config().locked_account_policy == LockedAccountPolicy::RejectAll,
+                    "The shadow check expects deposits into locked accounts to be rejected"
+                );
+                ensure!(
+                    engine.config().chargeback_lock_policy == ChargebackLockPolicy::FirstChargeback,
+                    "The shadow check expects every chargeback to lock the account"
+                );
+                ensure!(
+                    engine.config().negative_balance_policy == NegativeBalancePolicy::Allow,
+                    "The shadow check expects disputes to take available funds negative"
+                );
+                ensure!(
+                    engine.config().withdraw_from_held.not(),
+                    "The shadow check expects withdrawals to only draw on available funds"
+                );
+                let shadow = Arc::new(Mutex::new(ShadowCheck::default()));
+                let validator_shadow = Arc::clone(&shadow);
+                engine.add_validator(move |transaction, _| {
+                    validator_shadow
+                        .lock()
+                        .map_err(|_| anyhow!("Shadow check lock was poisoned"))?
+                        .before(transaction);
+                    Ok(())
+                });
+                let listener_shadow = Arc::clone(&shadow);
+                engine.on_transaction_applied(move |transaction, account| {
+                    if let Ok(mut shadow) = listener_shadow.lock() {
+                        shadow.applied(transaction, account);
+                    }
+                });
+                Some(shadow)
+            }
+            false => None,
+        };
+
+        // Registered last, so it only records the state before transactions every validator let
+        // through
+        if let Some(path) = self.audit_log.as_ref() {
+            let log = Arc::new(Mutex::new(EventLog::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )));
+            let validator_log = Arc::clone(&log);
+            engine.add_validator(move |_, account| {
+                validator_log
+                    .lock()
+                    .map_err(|_| anyhow!("Event log lock was poisoned"))?
+                    .before(account);
+                Ok(())
+            });
+            engine.on_transaction_applied(move |transaction, account| {
+                if let Ok(mut log) = log.lock() {
+                    log.applied(transaction, account);
+                }
+            });
+        }
+
+        if webhooks.lock_webhooks.is_empty().not() {
+            let notifier = WebhookNotifier::spawn(webhooks.config(&webhooks.lock_webhooks));
+            engine.on_account_locked(notifier.account_locked_listener());
+            notifiers.push(notifier);
+        }
+
+        if alerts.has_rules() {
+            let mut sinks = AlertSinks::default();
+            if let Some(path) = alerts.alerts_file.as_ref() {
+                sinks.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+            }
+            if alerts.alert_webhooks.is_empty().not() {
+                let notifier = WebhookNotifier::spawn(webhooks.config(&alerts.alert_webhooks));
+                sinks.webhook = Some(notifier.handle());
+                notifiers.push(notifier);
+            }
+
+            let mut evaluator = AlertEvaluator::new(alerts.rules());
+            engine.on_transaction_applied(move |transaction, account| {
+                for alert in evaluator.evaluate(transaction, account) {
+                    sinks.emit(&alert);
+                }
+            });
+        }
+
+        let anomalies = match reports.map(|reports| &reports.anomalies) {
+            Some(
+                args @ AnomalyArgs {
+                    anomalies_report: Some(path),
+                    ..
+                },
+            ) => {
+                let detector = Arc::new(Mutex::new(AnomalyDetector::new(args.thresholds())));
+                let listener_detector = Arc::clone(&detector);
+                engine.on_transaction_applied(move |transaction, _| {
+                    if let Ok(mut detector) = listener_detector.lock() {
+                        detector.applied(transaction);
+                    }
+                });
+                Some((detector, path.clone()))
+            }
+            _ => None,
+        };
+
+        Ok(Hooks {
+            notifiers,
+            rules,
+            shadow,
+            anomalies,
+        })
+    }
+}
+
+impl Hooks {
+    /// Reports the rule hit counts and the shadow check's outcome, writes the anomalies report, and
+    /// waits for pending webhooks to be delivered. The engine the hooks were registered with must
+    /// be dropped beforehand, otherwise this never returns.
+    pub fn finish(self) -> Result<()> {
+        if let Some(rules) = self.rules {
+            let mut rules = rules
+                .lock()
+                .map_err(|_| anyhow!("Rule engine lock was poisoned"))?;
+            rules.flush()?;
+
+            for (name, hits) in rules.hits() {
+                info!(rule = name, hits, "Rule hits: {name}: {hits}");
+            }
+        }
+
+        if let Some((detector, path)) = self.anomalies {
+            let detector = detector
+                .lock()
+                .map_err(|_| anyhow!("Anomaly detector lock was poisoned"))?;
+            let flagged = detector.write_report(File::create(&path)?)?;
+            info!(
+                flagged,
+                clients = detector.clients(),
+                "Anomalies: {flagged} of {} clients flagged, written to {}",
+                detector.clients(),
+                path.display()
+            );
+        }
+
+        for notifier in self.notifiers {
+            notifier.finish();
+        }
+
+        if let Some(shadow) = self.shadow {
+            shadow
+                .lock()
+                .map_err(|_| anyhow!("Shadow check lock was poisoned"))?
+                .finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+use crate::engine::Engine;
+use crate::input::{self, InvalidRows, ProcessedRows};
+use crate::transaction::{RawTransactionType, Transaction};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::ops::Not;
+use tracing::{error, warn};
+
+/// Size of an encoded transaction
+pub const RECORD_SIZE: usize = 15;
+
+/// Encodes a transaction in the binary format: a fixed width record of its type (`u8`), client
+/// (`u16`), tx id (`u32`) and amount (`u64`, zero for transactions without one), all little
+/// endian. The type is 0 for deposits, 1 for withdrawals, 2 for disputes, 3 for resolves, 4 for
+/// chargebacks, 5 for unlocks, 6 for reversals, 7 for holds and 8 for releases.
+///
+/// There's no room for currencies, so transactions with one can't be encoded.
+pub fn encode(transaction: &Transaction) -> Result<[u8; RECORD_SIZE]> {
+    ensure!(
+        transaction.currency().is_none(),
+        "Transactions with a currency can't be encoded - tx_id: {}",
+        transaction.tx_id()
+    );
+    let transaction_type: u8 = match transaction.transaction_type() {
+        RawTransactionType::Deposit => 0,
+        RawTransactionType::Withdrawal => 1,
+        RawTransactionType::Dispute => 2,
+        RawTransactionType::Resolve => 3,
+        RawTransactionType::Chargeback => 4,
+        RawTransactionType::Unlock => 5,
+        RawTransactionType::Reversal => 6,
+        RawTransactionType::Hold => 7,
+        RawTransactionType::Release => 8,
+    };
+
+    let mut record = [0; RECORD_SIZE];
+    record[0] = transaction_type;
+    record[1..3].copy_from_slice(&transaction.client_id().to_le_bytes());
+    record[3..7].copy_from_slice(&transaction.tx_id().to_le_bytes());
+    record[7..15].copy_from_slice(&transaction.amount().unwrap_or(0).to_le_bytes());
+    Ok(record)
+}
+
+/// Decodes a transaction encoded by `encode`. Like in csvs, disputes, resolves, chargebacks,
+/// unlocks, reversals and releases can't have an amount.
+pub fn decode(record: &[u8; RECORD_SIZE]) -> Result<Transaction> {
+    let client_id = u16::from_le_bytes([record[1], record[2]]);
+    let tx_id = u32::from_le_bytes(record[3..7].try_into().expect("4 bytes"));
+    let amount = u64::from_le_bytes(record[7..15].try_into().expect("8 bytes"));
+    let no_amount = |transaction: Transaction| {
+        ensure!(
+            amount == 0,
+            "{:?} found with amount",
+            transaction.transaction_type()
+        );
+        Ok(transaction)
+    };
+
+    match record[0] {
+        0 => Ok(Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount,
+            currency: None,
+        }),
+        1 => Ok(Transaction::Withdrawal {
+            client_id,
+            tx_id,
+            amount,
+            currency: None,
+        }),
+        2 => no_amount(Transaction::Dispute {
+            client_id,
+            tx_id,
+            currency: None,
+        }),
+        3 => no_amount(Transaction::Resolve {
+            client_id,
+            tx_id,
+            currency: None,
+        }),
+        4 => no_amount(Transaction::Chargeback {
+            client_id,
+            tx_id,
+            currency: None,
+        }),
+        5 => no_amount(Transaction::Unlock { client_id, tx_id }),
+        6 => no_amount(Transaction::Reversal {
+            client_id,
+            tx_id,
+            currency: None,
+        }),
+        7 => Ok(Transaction::Hold {
+            client_id,
+            tx_id,
+            amount,
+            currency: None,
+        }),
+        8 => no_amount(Transaction::Release {
+            client_id,
+            tx_id,
+            currency: None,
+        }),
+        transaction_type => Err(anyhow!("Unknown transaction type {transaction_type}")),
+    }
+}
+
+/// Writes transactions in the binary format, see `encode`
+pub struct TransactionWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TransactionWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write(&mut self, transaction: &Transaction) -> Result<()> {
+        self.writer.write_all(&encode(transaction)?)?;
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads transactions in the binary format one record at a time. Invalid records are returned as
+/// errors, after which reading carries on with the next record, while I/O errors and a truncated
+/// last record end the transactions.
+pub struct TransactionReader<R: Read> {
+    reader: R,
+    /// Number of records read so far
+    records: u64,
+    done: bool,
+}
+
+impl<R: Read> TransactionReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            records: 0,
+            done: false,
+        }
+    }
+
+    /// Number of records read so far, which is also the number of the last one read, counting
+    /// from 1
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+}
+
+impl<R: Read> Iterator for TransactionReader<R> {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut record = [0; RECORD_SIZE];
+        let mut read = 0;
+        while read < RECORD_SIZE {
+            match self.reader.read(&mut record[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        match read {
+            0 => {
+                self.done = true;
+                None
+            }
+            RECORD_SIZE => {
+                self.records += 1;
+                Some(decode(&record))
+            }
+            _ => {
+                self.done = true;
+                self.records += 1;
+                Some(Err(anyhow!(
+                    "Truncated record of {read} bytes, expected {RECORD_SIZE}"
+                )))
+            }
+        }
+    }
+}
+
+/// Converts a transactions csv to the binary format, returning the number of transactions
+/// written. Invalid rows, and those with a currency or timestamp, which the format has no room
+/// for, are reported on stderr and left out.
+pub fn encode_csv(reader: impl Read, writer: impl Write) -> Result<u64> {
+    let mut writer = TransactionWriter::new(writer);
+    let mut written = 0;
+
+    input::read_csv_rows(reader, |row| {
+        let encoded = row.transaction.and_then(|transaction| {
+            ensure!(row.timestamp.is_none(), "Timestamps can't be encoded");
+            encode(&transaction)
+        });
+        match encoded {
+            Ok(record) => {
+                writer.writer.write_all(&record)?;
+                written += 1;
+            }
+            Err(e) => warn!(line = row.line, "Skipped row at line {}: {e}", row.line),
+        }
+        Ok(())
+    })?;
+
+    writer.into_inner()?;
+    Ok(written)
+}
+
+/// Applies the transactions of one or more inputs in the binary format to the engine, one input
+/// after the other, like `input::process_csv_readers` does for csvs. Only the transactions of
+/// `clients` are applied if given, the others being skipped like invalid records are.
+pub fn process_readers<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    invalid_rows: InvalidRows,
+    clients: Option<&HashSet<u16>>,
+) -> Result<ProcessedRows> {
+    let mut processed = ProcessedRows::default();
+    let inputs = readers.len();
+
+    for (index, reader) in readers.into_iter().enumerate() {
+        let mut transactions = TransactionReader::new(reader);
+        while let Some(transaction) = transactions.next() {
+            let location = || match inputs {
+                1 => format!("record {} of provided input", transactions.records()),
+                _ => format!(
+                    "record {} of provided input #{}",
+                    transactions.records(),
+                    index + 1
+                ),
+            };
+            match transaction {
+                // Transactions of other clients are skipped
+                Ok(transaction)
+                    if clients.is_some_and(|clients| {
+                        clients.contains(&transaction.client_id()).not()
+                    }) => {}
+                Ok(transaction) => {
+                    let (tx_id, client_id) = (transaction.tx_id(), transaction.client_id());
+                    let transaction_type = transaction.transaction_type();
+                    match engine.process_transaction(transaction) {
+                        Ok(()) => processed.count(transaction_type, true),
+                        Err(e) => {
+                            processed.count(transaction_type, false);
+                            warn!(
+                                tx = tx_id,
+                                client = client_id,
+                                record = transactions.records(),
+                                code = e.code(),
+                                "Engine failed to process transaction: {e}"
+                            );
+                        }
+                    }
+                }
+                Err(e) if invalid_rows == InvalidRows::Fail => {
+                    bail!("Invalid record at {}: {e}", location())
+                }
+                Err(e) => {
+                    processed.invalid += 1;
+                    error!(
+                        record = transactions.records(),
+                        "Invalid record at {}: {e}",
+                        location()
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{decode, process_readers, TransactionReader, TransactionWriter};
+    use crate::engine::Engine;
+    use crate::input::InvalidRows;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_codec_round_trip() {
+        let transactions = vec![
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 10_0000,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: u16::MAX,
+                tx_id: u32::MAX,
+                amount: u64::MAX,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Unlock {
+                client_id: 1,
+                tx_id: 4,
+            },
+            Transaction::Reversal {
+                client_id: 2,
+                tx_id: 5,
+                currency: None,
+            },
+        ];
+        let mut writer = TransactionWriter::new(Vec::new());
+        for transaction in transactions.iter() {
+            writer.write(transaction).unwrap();
+        }
+        let encoded = writer.into_inner().unwrap();
+        assert_eq!(encoded.len(), 5 * 15);
+        assert_eq!(
+            &encoded[..15],
+            b"\x00\x01\x00\x01\x00\x00\x00\xa0\x86\x01\x00\x00\x00\x00\x00"
+        );
+
+        let decoded = TransactionReader::new(encoded.as_slice())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(decoded, transactions);
+
+        let with_currency = Transaction::Deposit {
+            client_id: 1,
+            tx_id: 1,
+            amount: 1,
+            currency: Some("USD".parse().unwrap()),
+        };
+        assert!(TransactionWriter::new(Vec::new())
+            .write(&with_currency)
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let mut dispute_with_amount = [0; 15];
+        dispute_with_amount[0] = 2;
+        dispute_with_amount[7] = 1;
+        assert!(decode(&dispute_with_amount).is_err());
+        assert!(decode(&[9; 15]).is_err());
+
+        // An unknown type, a valid deposit, then a truncated record
+        let mut input = vec![9; 15];
+        input.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        input.extend_from_slice(&[0, 1, 0]);
+        let mut engine = Engine::new();
+        let processed =
+            process_readers(&mut engine, vec![input.as_slice()], InvalidRows::Skip, None).unwrap();
+        assert_eq!((processed.applied, processed.invalid), (1, 2));
+        assert_eq!(engine.account(1).unwrap().available_amount, 1);
+
+        assert!(process_readers(
+            &mut Engine::new(),
+            vec![input.as_slice()],
+            InvalidRows::Fail,
+            None
+        )
+        .is_err());
+    }
+}
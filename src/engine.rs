@@ -1,34 +1,491 @@
-use crate::transaction::Transaction;
-use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
-use anyhow::{anyhow, bail, ensure, Result};
-use std::collections::{HashMap, HashSet};
+use crate::error::EngineError;
+use crate::handlers::{AccountHandle, CustomTransaction, HandlerRegistry};
+pub use crate::ledger::{
+    Balance, DisputableKind, DisputeState, HeldBalance, NegativeBalancePolicy,
+};
+use crate::ledger::{Balances, DisputableTransaction, DisputeError};
+#[cfg(feature = "storage")]
+use crate::store::MappedStore;
+use crate::store::{
+    merge_by_tx_id, read_mapped_record, write_mapped_record, AccountStore, StoredTransaction,
+    StoredTransactions, TransactionStore, MAPPED_RECORD_SIZE,
+};
+use crate::transaction::{Currency, RawTransactionType, Transaction};
+use crate::tx_index::{TxIdBitmap, TxIdIndex};
+use crate::util::RoundingMode;
+#[cfg(feature = "storage")]
+use crate::wal::Wal;
+use anyhow::{bail, Result};
+use lru::LruCache;
+#[cfg(feature = "storage")]
+use memmap2::Mmap;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(feature = "storage")]
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::ops::Not;
+#[cfg(feature = "storage")]
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug_span, error};
+
+/// Identifies engine snapshots, followed by the version of their format
+const SNAPSHOT_MAGIC: &[u8; 4] = b"PESN";
+
+/// Identifies engine snapshots in the `SnapshotFormat::Mapped` format, followed by the version
+/// of their format like the others
+const MAPPED_SNAPSHOT_MAGIC: &[u8; 4] = b"PEMM";
+
+/// Bumped whenever the snapshot format changes, so that older snapshots are refused instead of
+/// misread
+const SNAPSHOT_VERSION: u32 = 13;
+
+/// `kind` of MessagePack snapshot envelopes
+const ENVELOPE_KIND: &str = "payments-engine/snapshot";
+
+/// Version of the state in MessagePack snapshots, bumped whenever it changes. Its fields are
+/// named, so readers skip those they don't know and default those missing, and changes limited
+/// to that leave `ENVELOPE_COMPATIBLE_VERSION` as is.
+const ENVELOPE_VERSION: u32 = 1;
+
+/// Oldest reader version able to decode the MessagePack snapshots written, only bumped by changes
+/// older readers would misread, such as a field changing meaning or a new kind of transaction
+const ENVELOPE_COMPATIBLE_VERSION: u32 = 1;
+
+/// How the engine processes transactions, apart from its hooks
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    /// What happens to deposits into accounts a chargeback locked
+    pub locked_account_policy: LockedAccountPolicy,
+    /// Which chargebacks lock the account they're on
+    pub chargeback_lock_policy: ChargebackLockPolicy,
+    /// What happens when disputing a deposit whose funds were spent already
+    pub negative_balance_policy: NegativeBalancePolicy,
+    /// Let withdrawals draw on the held funds as well as the available ones, taking the available
+    /// funds negative, for users who consider held funds spendable until a chargeback lands
+    pub withdraw_from_held: bool,
+    /// Reject transactions without a timestamp, or with one earlier than that of the last
+    /// transaction applied to the same client, to catch corrupted or replayed feeds
+    pub strict_ordering: bool,
+    /// Reject disputes filed longer than this after the deposit or withdrawal they dispute, like
+    /// card networks do. Disputes need a timestamp to be checked against the window, while
+    /// transactions without one can be disputed at any time.
+    pub dispute_window: Option<Duration>,
+    /// How amounts with more than 4 decimal places are handled when reading transactions for the
+    /// engine, e.g. from a csv or a JSON-RPC request
+    pub rounding: RoundingMode,
+    /// Evict a client's oldest deposits that aren't disputed, once it has more than this many,
+    /// so that they can no longer be disputed but don't take up memory anymore
+    pub max_deposits_per_account: Option<usize>,
+    /// Evict the oldest deposits that aren't disputed, across every client, once there are more
+    /// than this many
+    pub max_deposits: Option<usize>,
+    /// Keep every applied transaction along with the balances it resulted in, for
+    /// `Engine::client_statement`, at the cost of memory growing with every transaction
+    pub keep_history: bool,
+}
+
+/// How engine snapshots are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SnapshotFormat {
+    /// Compact bincode, which only engines of the same snapshot version read
+    #[default]
+    Bincode,
+    /// MessagePack with named fields, in a versioned envelope, which engines of other versions
+    /// read as long as the changes in between are compatible
+    #[cfg_attr(feature = "cli", value(name = "msgpack"))]
+    MessagePack,
+    /// Bincode for the accounts, followed by their deposits and withdrawals that are neither in
+    /// dispute nor held as fixed size records sorted by tx id, which `Engine::restore_mapped`
+    /// memory-maps and reads in place instead of loading them. Only read by engines of the same
+    /// snapshot version, like bincode.
+    Mapped,
+}
+
+impl SnapshotFormat {
+    /// The format of a snapshot, from its first bytes
+    pub fn detect(snapshot: &[u8]) -> Self {
+        if snapshot.starts_with(SNAPSHOT_MAGIC) {
+            Self::Bincode
+        } else if snapshot.starts_with(MAPPED_SNAPSHOT_MAGIC) {
+            Self::Mapped
+        } else {
+            Self::MessagePack
+        }
+    }
+}
+
+/// What happens to deposits into locked accounts. Withdrawals from locked accounts are always
+/// rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LockedAccountPolicy {
+    /// Reject deposits like withdrawals
+    #[default]
+    RejectAll,
+    /// Credit deposits to the available funds as usual
+    AllowDeposits,
+    /// Accept deposits but hold their funds until the account is unlocked
+    HoldDeposits,
+}
+
+/// Which chargebacks lock the account they're on. Accounts count their chargebacks either way, see
+/// `Engine::chargeback_count`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChargebackLockPolicy {
+    /// Lock the account on its first chargeback
+    #[default]
+    FirstChargeback,
+    /// Lock the account once it had this many chargebacks
+    AfterChargebacks(u32),
+    /// Never lock accounts, leaving it to their chargeback count to flag them
+    Never,
+}
+
+impl ChargebackLockPolicy {
+    /// Whether an account with this many chargebacks gets locked
+    fn locks(&self, chargebacks: u32) -> bool {
+        match *self {
+            ChargebackLockPolicy::FirstChargeback => true,
+            ChargebackLockPolicy::AfterChargebacks(count) => chargebacks >= count,
+            ChargebackLockPolicy::Never => false,
+        }
+    }
+}
+
+/// Map of accounts or transactions, hashed with ahash under the `fast-hash` feature
+#[cfg(feature = "fast-hash")]
+type FastHashMap<K, V> = HashMap<K, V, ahash::RandomState>;
+#[cfg(not(feature = "fast-hash"))]
+type FastHashMap<K, V> = HashMap<K, V>;
+
+type AccountLockedListener = Box<dyn FnMut(&AccountLockedEvent) + Send>;
+type DepositEvictedListener = Box<dyn FnMut(&DepositEvictedEvent) + Send>;
+type TransactionAppliedListener = Box<dyn FnMut(&Transaction, &AccountView) + Send>;
+type TransactionValidator = Box<dyn FnMut(&Transaction, Option<&AccountView>) -> Result<()> + Send>;
 
 pub struct Engine {
-    accounts: HashMap<u16, Account>,
-    transactions: HashSet<u32>,
+    accounts: FastHashMap<u16, Account>,
+    /// Number of deposits and withdrawals new accounts have room for, see `Engine::reserve`
+    transactions_per_account: usize,
+    /// Tx ids of the deposits, withdrawals and holds seen, applied or not
+    tx_ids: Box<dyn TxIdIndex>,
+    /// System account credited with charged-back funds, if any
+    suspense_account: Option<u16>,
+    config: EngineConfig,
+    /// Log every transaction is appended to before being applied, if any
+    #[cfg(feature = "storage")]
+    wal: Option<Wal>,
+    /// Where settled deposits and withdrawals are moved out of memory to, if anywhere
+    spill: Option<Spill>,
+    /// Where accounts are moved out of memory to, if anywhere
+    paging: Option<AccountPaging>,
+    /// Client and tx id of the deposits that can be evicted under `EngineConfig::max_deposits`,
+    /// by sequence number, oldest first
+    deposit_order: BTreeMap<u64, (u16, u32)>,
+    /// Sequence number of the next deposit made with a cap on deposits
+    next_deposit_seq: u64,
+    account_locked_listeners: Vec<AccountLockedListener>,
+    deposit_evicted_listeners: Vec<DepositEvictedListener>,
+    transaction_applied_listeners: Vec<TransactionAppliedListener>,
+    validators: Vec<TransactionValidator>,
+    handlers: HandlerRegistry,
 }
 
 impl Engine {
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
         Self {
-            accounts: HashMap::new(),
-            transactions: HashSet::new(),
+            accounts: FastHashMap::default(),
+            transactions_per_account: 0,
+            tx_ids: Box::new(TxIdBitmap::new()),
+            suspense_account: None,
+            config,
+            #[cfg(feature = "storage")]
+            wal: None,
+            spill: None,
+            paging: None,
+            deposit_order: BTreeMap::new(),
+            next_deposit_seq: 0,
+            account_locked_listeners: Vec::new(),
+            deposit_evicted_listeners: Vec::new(),
+            transaction_applied_listeners: Vec::new(),
+            validators: Vec::new(),
+            handlers: HandlerRegistry::default(),
+        }
+    }
+
+    /// Creates an engine with room for the accounts of `clients` clients and their `transactions`
+    /// deposits and withdrawals, see `Engine::reserve`
+    pub fn with_capacity(clients: usize, transactions: usize) -> Self {
+        let mut engine = Self::new();
+        engine.reserve(clients, transactions);
+        engine
+    }
+
+    /// Makes room for the accounts of `clients` clients in all, and gives accounts opened from now
+    /// on room for their share of `transactions` deposits and withdrawals, so that their maps
+    /// don't have to grow over and over on large inputs
+    pub fn reserve(&mut self, clients: usize, transactions: usize) {
+        self.accounts
+            .reserve(clients.saturating_sub(self.accounts.len()));
+        self.transactions_per_account = transactions / clients.max(1);
+    }
+
+    /// Makes `client_id` a system suspense account, which chargebacks credit with the funds they
+    /// take out of the disputed account instead of letting them vanish from the books (and debit
+    /// with the funds chargebacks of withdrawals refund). It can't be the target of transactions
+    /// itself.
+    pub fn set_suspense_account(&mut self, client_id: u16) {
+        self.suspense_account = Some(client_id);
+    }
+
+    /// Appends every transaction that gets past the validators to a write-ahead log before applying
+    /// it. Transactions that can't be appended are rejected.
+    #[cfg(feature = "storage")]
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    #[cfg(feature = "storage")]
+    pub fn wal_mut(&mut self) -> Option<&mut Wal> {
+        self.wal.as_mut()
+    }
+
+    /// Keeps at most `max_in_memory` settled deposits and withdrawals (neither in dispute nor
+    /// held) in memory, moving the least recently used ones to `store`, from which they're moved
+    /// back when disputed. The transactions of the engine's previous store, if any, are moved to
+    /// the new one. Snapshots read the transactions back from the store.
+    pub fn set_transaction_store(
+        &mut self,
+        mut store: impl TransactionStore + 'static,
+        max_in_memory: usize,
+    ) -> io::Result<()> {
+        if let Some(spill) = self.spill.take() {
+            for transaction in spill.store.transactions() {
+                let (tx_id, transaction) = transaction?;
+                store.insert(tx_id, transaction)?;
+            }
+        }
+        let mut in_memory = LruCache::unbounded();
+        for (client_id, account) in self.accounts.iter() {
+            for (tx_id, transaction) in account.transactions.iter() {
+                if transaction.is_settled() {
+                    in_memory.put(*tx_id, *client_id);
+                }
+            }
+        }
+        self.spill = Some(Spill {
+            store: Box::new(store),
+            in_memory,
+            max_in_memory,
+        });
+        Ok(())
+    }
+
+    /// Keeps at most `max_in_memory` accounts in memory, moving the least recently used ones to
+    /// `store`, from which they're moved back when a transaction targets them, so that memory use
+    /// stays bounded however many clients there are. The accounts of the engine's previous store,
+    /// if any, are moved to the new one. Reports and snapshots read the accounts back from the
+    /// store.
+    pub fn set_account_store(
+        &mut self,
+        store: impl AccountStore + 'static,
+        max_in_memory: usize,
+    ) -> io::Result<()> {
+        if let Some(paging) = self.paging.take() {
+            for account in paging.store.accounts() {
+                let (client_id, account) = account?;
+                self.accounts.insert(client_id, decode_account(&account)?);
+            }
+        }
+        let mut in_memory = LruCache::unbounded();
+        for client_id in self.accounts.keys() {
+            in_memory.put(*client_id, ());
+        }
+        self.paging = Some(AccountPaging {
+            store: Box::new(store),
+            in_memory,
+            // The account a transaction targets is always in memory
+            max_in_memory: max_in_memory.max(1),
+        });
+        self.page_out_excess()
+    }
+
+    /// Keeps the tx ids seen in `index` rather than in the default `TxIdBitmap`, such as a
+    /// `TxIdBloomFilter` whose memory doesn't grow with the number of tx ids. The tx ids seen so
+    /// far are added to it.
+    pub fn set_tx_id_index(&mut self, mut index: impl TxIdIndex + 'static) {
+        if let Some(tx_ids) = self.tx_ids.tx_ids() {
+            for tx_id in tx_ids {
+                index.insert(tx_id);
+            }
+        }
+        self.tx_ids = Box::new(index);
+    }
+
+    /// Changes how the engine processes the transactions to come, e.g. after restoring a snapshot
+    pub fn set_config(&mut self, config: EngineConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Registers a listener called whenever a chargeback locks a previously unlocked account
+    pub fn on_account_locked(
+        &mut self,
+        listener: impl FnMut(&AccountLockedEvent) + Send + 'static,
+    ) {
+        self.account_locked_listeners.push(Box::new(listener));
+    }
+
+    /// Registers a listener called whenever a deposit is evicted because of
+    /// `EngineConfig::max_deposits_per_account` or `EngineConfig::max_deposits`
+    pub fn on_deposit_evicted(
+        &mut self,
+        listener: impl FnMut(&DepositEvictedEvent) + Send + 'static,
+    ) {
+        self.deposit_evicted_listeners.push(Box::new(listener));
+    }
+
+    /// Registers a listener called after every successfully applied transaction, along with the
+    /// resulting state of the affected account
+    pub fn on_transaction_applied(
+        &mut self,
+        listener: impl FnMut(&Transaction, &AccountView) + Send + 'static,
+    ) {
+        self.transaction_applied_listeners.push(Box::new(listener));
+    }
+
+    /// Registers the handler applying the custom transactions of a type the engine doesn't know,
+    /// such as `bonus`, given to `Engine::process_custom`. It's called with the transaction and a
+    /// handle on the account it targets, see `AccountHandle`, and returning an error rejects the
+    /// transaction.
+    pub fn register_handler(
+        &mut self,
+        transaction_type: &str,
+        handler: impl FnMut(&CustomTransaction, &mut AccountHandle) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        self.handlers.register(transaction_type, handler)
+    }
+
+    pub fn handlers(&self) -> &HandlerRegistry {
+        &self.handlers
+    }
+
+    /// Registers a validator run before a transaction is applied, given the current state of the
+    /// target account (if it exists). Returning an error rejects the transaction.
+    pub fn add_validator(
+        &mut self,
+        validator: impl FnMut(&Transaction, Option<&AccountView>) -> Result<()> + Send + 'static,
+    ) {
+        self.validators.push(Box::new(validator));
+    }
+
+    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<(), EngineError> {
+        self.process_transaction_at(transaction, None)
+    }
+
+    /// Processes transactions one after the other like `process_transaction`, carrying on past
+    /// the rejected ones, and returns the outcome of every one of them
+    pub fn process_batch(
+        &mut self,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> BatchResult {
+        BatchResult {
+            outcomes: transactions
+                .into_iter()
+                .map(|transaction| self.process_transaction(transaction))
+                .collect(),
         }
     }
 
-    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<()> {
+    /// Processes a transaction along with its timestamp (milliseconds since the Unix epoch), when
+    /// it has one
+    pub fn process_transaction_at(
+        &mut self,
+        transaction: Transaction,
+        timestamp: Option<u64>,
+    ) -> Result<(), EngineError> {
+        let _span = debug_span!(
+            "transaction",
+            tx = transaction.tx_id(),
+            client = transaction.client_id()
+        )
+        .entered();
+
+        if self.paging.is_some() {
+            // The suspense account is credited by chargebacks
+            let suspense_account = match transaction {
+                Transaction::Chargeback { .. } => self.suspense_account,
+                _ => None,
+            };
+            self.page_in([Some(transaction.client_id()), suspense_account])?;
+        }
+
+        if self.validators.is_empty().not() {
+            let account = self.account_for(&transaction);
+            for validator in self.validators.iter_mut() {
+                validator(&transaction, account.as_ref()).map_err(EngineError::Validation)?;
+            }
+        }
+
+        if self.spill.is_some() {
+            self.spill_excess()?;
+            if let Transaction::Dispute {
+                client_id, tx_id, ..
+            }
+            | Transaction::Resolve {
+                client_id, tx_id, ..
+            }
+            | Transaction::Chargeback {
+                client_id, tx_id, ..
+            }
+            | Transaction::Reversal {
+                client_id, tx_id, ..
+            } = transaction
+            {
+                self.unspill(client_id, tx_id)?;
+            }
+        }
+
+        #[cfg(feature = "storage")]
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&transaction, timestamp)
+                .map_err(EngineError::Wal)?;
+        }
+
+        if self.suspense_account == Some(transaction.client_id()) {
+            return Err(EngineError::SuspenseAccount);
+        }
+
+        self.check_ordering(transaction.client_id(), timestamp)?;
+
         // Check for tx_id uniqueness
         match transaction {
-            Transaction::Deposit { tx_id, .. } | Transaction::Withdrawal { tx_id, .. } => {
-                ensure!(
-                    self.transactions.insert(tx_id),
-                    anyhow!("A transaction failed because it had a duplicate tx_id: {tx_id}")
-                );
+            Transaction::Deposit { tx_id, .. }
+            | Transaction::Withdrawal { tx_id, .. }
+            | Transaction::Hold { tx_id, .. } => {
+                if self.tx_ids.insert(tx_id).not() {
+                    return Err(EngineError::DuplicateTxId { tx_id });
+                }
             }
+            // An unlock's tx id only identifies the operation, so a rejected one can be retried
             Transaction::Dispute { .. }
             | Transaction::Resolve { .. }
-            | Transaction::Chargeback { .. } => {}
+            | Transaction::Chargeback { .. }
+            | Transaction::Unlock { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::Release { .. } => {}
         }
 
         // Process transaction
@@ -37,306 +494,3483 @@ impl Engine {
                 client_id,
                 tx_id,
                 amount,
+                currency,
             } => {
-                let account = self.accounts.entry(client_id).or_insert_with(Account::new);
-                account.deposit(tx_id, amount)?;
+                let policy = self.config.locked_account_policy;
+                match self.accounts.get_mut(&client_id) {
+                    Some(account) => account.deposit(tx_id, amount, currency, timestamp, policy)?,
+                    // Accounts are only opened by deposits that could be applied
+                    None => {
+                        let mut account = Account::with_capacity(self.transactions_per_account);
+                        account.deposit(tx_id, amount, currency, timestamp, policy)?;
+                        self.accounts.insert(client_id, account);
+                    }
+                }
             }
             Transaction::Withdrawal {
-                client_id, amount, ..
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    account.withdraw(
+                        tx_id,
+                        amount,
+                        currency,
+                        timestamp,
+                        self.config.withdraw_from_held,
+                    )?
+                } else {
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Withdrawal,
+                    });
+                }
+            }
+            Transaction::Dispute {
+                client_id,
+                tx_id,
+                currency,
+            } => {
+                // Only transactions from within the window before the dispute can be disputed
+                let not_before = match self.config.dispute_window {
+                    Some(window) => Some(
+                        timestamp
+                            .ok_or(EngineError::MissingTimestamp)?
+                            .saturating_sub(window.as_millis().try_into().unwrap_or(u64::MAX)),
+                    ),
+                    None => None,
+                };
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    account.start_dispute(
+                        tx_id,
+                        currency,
+                        not_before,
+                        self.config.negative_balance_policy,
+                    )?
+                } else {
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Dispute,
+                    });
+                }
+            }
+            Transaction::Resolve {
+                client_id,
+                tx_id,
+                currency,
+            } => {
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    account.resolve_dispute(tx_id, currency)?
+                } else {
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Resolve,
+                    });
+                }
+            }
+            Transaction::Chargeback {
+                client_id,
+                tx_id,
+                currency,
             } => {
+                // The suspense account's balance is checked first, so that a chargeback it
+                // can't take is rejected before the client's account is touched
+                let suspense_balances = match self.suspense_account {
+                    Some(suspense_account) => {
+                        self.suspense_balances(suspense_account, client_id, tx_id, currency)?
+                    }
+                    None => None,
+                };
                 if let Some(account) = self.accounts.get_mut(&client_id) {
-                    account.withdraw(amount)?
+                    let was_locked = account.locked;
+                    let charged_back =
+                        account.chargeback(tx_id, currency, self.config.chargeback_lock_policy)?;
+
+                    if was_locked.not() && account.locked {
+                        let event = AccountLockedEvent {
+                            tx_id,
+                            amount: charged_back.amount,
+                            account: account.view(client_id, charged_back.currency),
+                        };
+                        for listener in self.account_locked_listeners.iter_mut() {
+                            listener(&event);
+                        }
+                    }
+
+                    if let (Some(suspense_account), Some(balances)) =
+                        (self.suspense_account, suspense_balances)
+                    {
+                        self.accounts
+                            .entry(suspense_account)
+                            .or_insert_with(Account::new)
+                            .balances
+                            .insert(charged_back.currency, balances);
+                    }
                 } else {
-                    bail!("An withdrawal failed because the target account couldn't be found")
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Chargeback,
+                    });
                 }
             }
-            Transaction::Dispute { client_id, tx_id } => {
+            Transaction::Unlock { client_id, .. } => self.unlock_account(client_id)?,
+            Transaction::Reversal {
+                client_id,
+                tx_id,
+                currency,
+            } => {
                 if let Some(account) = self.accounts.get_mut(&client_id) {
-                    account.start_dispute(tx_id)?
+                    account.reverse_withdrawal(tx_id, currency)?
                 } else {
-                    bail!("A dispute start failed because the target account couldn't be found")
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Reversal,
+                    });
                 }
             }
-            Transaction::Resolve { client_id, tx_id } => {
+            Transaction::Hold {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
                 if let Some(account) = self.accounts.get_mut(&client_id) {
-                    account.resolve_dispute(tx_id)?
+                    account.hold(tx_id, amount, currency)?
                 } else {
-                    bail!("A dispute resolve failed because the target account couldn't be found")
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Hold,
+                    });
                 }
             }
-            Transaction::Chargeback { client_id, tx_id } => {
+            Transaction::Release {
+                client_id,
+                tx_id,
+                currency,
+            } => {
                 if let Some(account) = self.accounts.get_mut(&client_id) {
-                    account.chargeback(tx_id)?
+                    account.release(tx_id, currency)?
                 } else {
-                    bail!("A chargeback failed because the target account couldn't be found")
+                    return Err(EngineError::AccountNotFound {
+                        transaction_type: RawTransactionType::Release,
+                    });
                 }
             }
         };
-        Ok(())
-    }
 
-    pub fn print_state_csv(&self) -> Result<()> {
-        let mut wtr = csv::Writer::from_writer(std::io::stdout());
-
-        wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        if let Some(timestamp) = timestamp {
+            if let Some(account) = self.accounts.get_mut(&transaction.client_id()) {
+                account.last_timestamp = account.last_timestamp.max(Some(timestamp));
+            }
+        }
 
-        for (client_id, account) in self.accounts.iter() {
-            let available_amount =
-                signed_fixed_point_4_decimal_to_float_str(account.available_amount);
-            let held_amount = fixed_point_4_decimal_to_float_str(account.held_amount);
-            let total_amount = signed_fixed_point_4_decimal_to_float_str(
-                account.available_amount + account.held_amount as i64,
-            );
+        if let Some(spill) = self.spill.as_mut() {
+            let (client_id, tx_id) = (transaction.client_id(), transaction.tx_id());
+            let settled = self
+                .accounts
+                .get(&client_id)
+                .and_then(|account| account.transactions.get(&tx_id))
+                .is_some_and(DisputableTransaction::is_settled);
+            match transaction {
+                Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Resolve { .. }
+                    if settled =>
+                {
+                    spill.in_memory.put(tx_id, client_id);
+                }
+                // Disputed and reversed transactions can't be restored from the store as they are
+                Transaction::Dispute { .. } | Transaction::Reversal { .. } => {
+                    spill.in_memory.pop(&tx_id);
+                }
+                Transaction::Unlock { .. } => spill.track_settled(client_id, &self.accounts),
+                _ => {}
+            }
+        }
 
-            wtr.serialize((
-                client_id,
-                available_amount,
-                held_amount,
-                total_amount,
-                account.locked,
-            ))?;
+        if let Transaction::Deposit {
+            client_id, tx_id, ..
+        } = transaction
+        {
+            self.track_deposit(client_id, tx_id);
         }
 
-        wtr.flush()?;
+        if self.transaction_applied_listeners.is_empty().not() {
+            if let Some(account) = self.account_for(&transaction) {
+                for listener in self.transaction_applied_listeners.iter_mut() {
+                    listener(&transaction, &account);
+                }
+            }
+        }
 
+        if self.config.keep_history {
+            if let Some(view) = self.account_for(&transaction) {
+                let entry = StatementEntry {
+                    tx_id: transaction.tx_id(),
+                    transaction_type: transaction.transaction_type(),
+                    amount: transaction.amount(),
+                    timestamp,
+                    currency: view.currency,
+                    available_amount: view.available_amount,
+                    held_amount: view.held_amount,
+                    total_amount: view.total_amount,
+                };
+                if let Some(account) = self.accounts.get_mut(&transaction.client_id()) {
+                    account.history.push(entry);
+                }
+            }
+        }
         Ok(())
     }
-}
 
-struct Account {
-    available_amount: i64,
-    held_amount: u64,
-    locked: bool,
-    deposits: HashMap<u32, Deposit>,
-}
+    /// Applies a custom transaction with the handler registered for its type, along with its
+    /// timestamp like `process_transaction_at`. Its tx id has to be unique, like a deposit's, and
+    /// strict ordering applies to it, but validators and listeners, which take the engine's own
+    /// transactions, aren't called, nor is it kept in the statement of
+    /// `EngineConfig::keep_history`. Engines with a write-ahead log reject custom transactions,
+    /// which it couldn't replay.
+    pub fn process_custom(
+        &mut self,
+        transaction: CustomTransaction,
+        timestamp: Option<u64>,
+    ) -> Result<(), EngineError> {
+        let (client_id, currency) = (transaction.client_id, transaction.currency);
+        let _span =
+            debug_span!("transaction", tx = transaction.tx_id, client = client_id).entered();
 
-impl Account {
-    fn new() -> Self {
-        Self {
-            available_amount: 0,
-            held_amount: 0,
-            locked: false,
-            deposits: HashMap::new(),
+        #[cfg(feature = "storage")]
+        if self.wal.is_some() {
+            return Err(EngineError::Wal(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Custom transactions can't be written to the write-ahead log",
+            )));
+        }
+        if self.suspense_account == Some(client_id) {
+            return Err(EngineError::SuspenseAccount);
+        }
+        self.page_in([Some(client_id)])?;
+        self.check_ordering(client_id, timestamp)?;
+        let Some(handler) = self.handlers.get_mut(&transaction.transaction_type) else {
+            return Err(EngineError::UnknownTransactionType {
+                transaction_type: transaction.transaction_type,
+            });
+        };
+        if self.tx_ids.insert(transaction.tx_id).not() {
+            return Err(EngineError::DuplicateTxId {
+                tx_id: transaction.tx_id,
+            });
         }
-    }
-
-    fn deposit(&mut self, tx_id: u32, amount: u64) -> Result<()> {
-        ensure!(
-            self.locked.not(),
-            anyhow!("A deposit failed because the target account is locked")
-        );
 
-        self.deposits.insert(
-            tx_id,
-            Deposit {
-                amount,
-                state: DepositState::Valid,
-            },
-        );
+        let view = match self.accounts.get(&client_id) {
+            Some(account) => account.view(client_id, currency),
+            None => Account::new().view(client_id, currency),
+        };
+        let mut account = AccountHandle::new(&transaction, view);
+        // Errors of the handle's own operations keep their reason codes
+        handler(&transaction, &mut account).map_err(|e| match e.downcast::<EngineError>() {
+            Ok(e) => e,
+            Err(e) => EngineError::Handler(e),
+        })?;
+        if account.changed() {
+            let view = account.view();
+            let transactions = self.transactions_per_account;
+            self.accounts
+                .entry(client_id)
+                .or_insert_with(|| Account::with_capacity(transactions))
+                .set_balances(
+                    currency,
+                    Balances::new(view.available_amount, view.held_amount)?,
+                );
+        }
 
-        self.available_amount += amount as i64;
+        if let Some(timestamp) = timestamp {
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                account.last_timestamp = account.last_timestamp.max(Some(timestamp));
+            }
+        }
         Ok(())
     }
 
-    fn withdraw(&mut self, amount: u64) -> Result<()> {
-        ensure!(
-            self.locked.not(),
-            anyhow!("An withdrawal failed because the target account is locked")
-        );
-
-        if self.available_amount >= amount as i64 {
-            self.available_amount -= amount as i64
-        } else {
-            bail!("An withdrawal failed because there wasn't enough balance");
+    /// Under strict ordering, checks that a transaction has a timestamp, no earlier than that of
+    /// the client's last one
+    fn check_ordering(&self, client_id: u16, timestamp: Option<u64>) -> Result<(), EngineError> {
+        if self.config.strict_ordering.not() {
+            return Ok(());
+        }
+        let timestamp = timestamp.ok_or(EngineError::MissingTimestamp)?;
+        let account = self.accounts.get(&client_id);
+        if let Some(last_timestamp) = account.and_then(|account| account.last_timestamp) {
+            if timestamp < last_timestamp {
+                return Err(EngineError::OutOfOrder {
+                    timestamp,
+                    last_timestamp,
+                });
+            }
         }
         Ok(())
     }
 
-    fn start_dispute(&mut self, tx_id: u32) -> Result<()> {
-        let deposit = self.deposits.get_mut(&tx_id);
+    /// Records a deposit in the order deposits are evicted in, when they're capped, then evicts
+    /// the oldest ones that aren't disputed over the caps. Deposits made while there were no
+    /// caps are never evicted.
+    fn track_deposit(&mut self, client_id: u16, tx_id: u32) {
+        let max_per_account = self.config.max_deposits_per_account;
+        let max = self.config.max_deposits;
+        if max_per_account.is_none() && max.is_none() {
+            return;
+        }
+        let seq = self.next_deposit_seq;
+        self.next_deposit_seq += 1;
+        if max.is_some() {
+            self.deposit_order.insert(seq, (client_id, tx_id));
+        }
 
-        if let Some(deposit) = deposit {
-            match deposit.state {
-                DepositState::Valid => {
-                    deposit.state = DepositState::InDispute;
-                    self.available_amount -= deposit.amount as i64;
-                    self.held_amount += deposit.amount;
-                }
-                DepositState::InDispute | DepositState::ChargedBack => {
-                    bail!(
-                        "A dispute start failed because the referenced deposit was already \
-                chargedback or is currently in an active dispute - tx_id: {tx_id} \
-                - deposit state: {:?}",
-                        deposit.state
-                    )
-                }
+        if let Some(max_per_account) = max_per_account {
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                account.deposits.push_back((seq, tx_id));
+            }
+            while let Some((seq, tx_id)) = self
+                .accounts
+                .get(&client_id)
+                .and_then(|account| account.oldest_evictable(max_per_account))
+            {
+                self.evict_deposit(client_id, seq, tx_id);
+            }
+        }
+
+        if let Some(max) = max {
+            while self.deposit_order.len() > max {
+                let Some((&seq, &(client_id, tx_id))) =
+                    self.deposit_order.iter().find(|(_, (client_id, tx_id))| {
+                        self.account_ref(*client_id)
+                            .is_some_and(|account| account.is_evictable(*tx_id))
+                    })
+                else {
+                    break;
+                };
+                self.evict_deposit(client_id, seq, tx_id);
             }
-        } else {
-            bail!(
-                "A dispute start failed because the referenced deposit couldn't be found \
-            - tx_id: {tx_id}"
-            )
         }
-        Ok(())
     }
 
-    fn resolve_dispute(&mut self, tx_id: u32) -> Result<()> {
-        let deposit = self.deposits.get_mut(&tx_id);
+    /// Forgets a deposit, so that it can no longer be disputed
+    fn evict_deposit(&mut self, client_id: u16, seq: u64, tx_id: u32) {
+        self.deposit_order.remove(&seq);
+        // Left in the account if it can't be moved back from the store, where it's harmless
+        if let Err(e) = self.page_in([Some(client_id)]) {
+            error!(
+                client = client_id,
+                tx = tx_id,
+                "Failed to evict deposit: {e}"
+            );
+            return;
+        }
+        let Some(account) = self.accounts.get_mut(&client_id) else {
+            return;
+        };
+        if let Some(position) = account.deposits.iter().position(|(s, _)| *s == seq) {
+            account.deposits.remove(position);
+        }
+        let evicted = match account.transactions.remove(&tx_id) {
+            Some(transaction) => Some(transaction.stored(client_id)),
+            // Left in the store if it can't be removed, where it's harmless
+            None => self.spill.as_mut().and_then(|spill| {
+                spill.in_memory.pop(&tx_id);
+                spill.store.remove(tx_id).ok().flatten()
+            }),
+        };
+        if let Some(spill) = self.spill.as_mut() {
+            spill.in_memory.pop(&tx_id);
+        }
 
-        if let Some(deposit) = deposit {
-            match deposit.state {
-                DepositState::InDispute => {
-                    deposit.state = DepositState::Valid;
-                    self.available_amount += deposit.amount as i64;
-                    self.held_amount -= deposit.amount;
-                }
-                DepositState::ChargedBack | DepositState::Valid => {
-                    bail!(
-                        "A dispute resolve failed because the referenced deposit wasn't in an \
-                active dispute - tx_id: {tx_id} - deposit state: {:?}",
-                        deposit.state
-                    )
-                }
+        if let Some(evicted) = evicted {
+            let event = DepositEvictedEvent {
+                client_id,
+                tx_id,
+                amount: evicted.amount,
+                currency: evicted.currency,
+            };
+            for listener in self.deposit_evicted_listeners.iter_mut() {
+                listener(&event);
             }
-        } else {
-            bail!(
-                "A dispute resolve failed because the referenced deposit couldn't be found \
-            - tx_id: {tx_id}"
-            )
         }
-        Ok(())
     }
 
-    fn chargeback(&mut self, tx_id: u32) -> Result<()> {
-        let deposit = self.deposits.get_mut(&tx_id);
-
-        if let Some(deposit) = deposit {
-            match deposit.state {
-                DepositState::InDispute => {
-                    deposit.state = DepositState::ChargedBack;
-                    self.held_amount -= deposit.amount;
-                    self.locked = true;
-                }
-                DepositState::ChargedBack | DepositState::Valid => {
-                    bail!(
-                        "A chargeback failed because the referenced deposit wasn't in an active \
-                dispute - tx_id: {tx_id} - deposit state: {:?}",
-                        deposit.state
-                    )
+    /// Moves the least recently used settled transactions to the transaction store, until there
+    /// are no more in memory than allowed
+    fn spill_excess(&mut self) -> Result<(), EngineError> {
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(());
+        };
+        while spill.in_memory.len() > spill.max_in_memory {
+            let Some((&tx_id, &client_id)) = spill.in_memory.peek_lru() else {
+                break;
+            };
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                if let Some(transaction) = account.transactions.get(&tx_id) {
+                    spill
+                        .store
+                        .insert(tx_id, transaction.stored(client_id))
+                        .map_err(EngineError::TransactionStore)?;
+                    account.transactions.remove(&tx_id);
                 }
             }
-        } else {
-            bail!(
-                "A chargeback failed because the referenced deposit couldn't be found \
-            - tx_id: {tx_id}"
-            )
+            spill.in_memory.pop_lru();
         }
         Ok(())
     }
-}
 
-struct Deposit {
-    amount: u64,
-    state: DepositState,
-}
-
-#[derive(PartialEq, Debug)]
-enum DepositState {
-    Valid,
-    InDispute,
-    ChargedBack,
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::engine::Account;
-    use std::ops::Not;
-
-    #[test]
-    fn test_account_flow() {
-        let mut account = Account::new();
-        assert_eq!(account.available_amount, 0);
-        assert_eq!(account.held_amount, 0);
-        assert!(account.locked.not());
-        assert!(account.deposits.is_empty());
+    /// Moves a client's transaction back from the transaction store, if it's there, so that it
+    /// can be disputed or reversed
+    fn unspill(&mut self, client_id: u16, tx_id: u32) -> Result<(), EngineError> {
+        let in_memory = self
+            .accounts
+            .get(&client_id)
+            .is_none_or(|account| account.transactions.contains_key(&tx_id));
+        if in_memory || self.spilled(client_id, tx_id)?.is_none() {
+            return Ok(());
+        }
+        let (Some(spill), Some(account)) = (self.spill.as_mut(), self.accounts.get_mut(&client_id))
+        else {
+            return Ok(());
+        };
+        if let Some(stored) = spill
+            .store
+            .remove(tx_id)
+            .map_err(EngineError::TransactionStore)?
+        {
+            account
+                .transactions
+                .insert(tx_id, DisputableTransaction::from(stored));
+            spill.in_memory.put(tx_id, client_id);
+        }
+        Ok(())
+    }
 
-        // Make 2 deposits totalling 60
-        account.deposit(1, 20).unwrap();
-        account.deposit(2, 40).unwrap();
-        assert_eq!(account.available_amount, 60);
-        assert_eq!(account.held_amount, 0);
+    /// A client's transaction in the transaction store, if it's there
+    fn spilled(
+        &self,
+        client_id: u16,
+        tx_id: u32,
+    ) -> Result<Option<StoredTransaction>, EngineError> {
+        match self.spill.as_ref() {
+            Some(spill) => Ok(spill
+                .store
+                .get(tx_id)
+                .map_err(EngineError::TransactionStore)?
+                .filter(|stored| stored.client_id == client_id)),
+            None => Ok(None),
+        }
+    }
 
-        // Check disputing tx 1
-        account.start_dispute(1).unwrap();
-        assert_eq!(account.available_amount, 40);
-        assert_eq!(account.held_amount, 20);
+    /// The balances the suspense account would have, in the charged back transaction's currency,
+    /// after the chargeback of a client's transaction, or `None` if the chargeback itself is to
+    /// be rejected
+    fn suspense_balances(
+        &self,
+        suspense_account: u16,
+        client_id: u16,
+        tx_id: u32,
+        currency: Option<Currency>,
+    ) -> Result<Option<Balances>, EngineError> {
+        let Some(transaction) = self
+            .accounts
+            .get(&client_id)
+            .and_then(|account| {
+                account
+                    .disputed(RawTransactionType::Chargeback, tx_id, currency)
+                    .ok()
+            })
+            .filter(|transaction| transaction.state == DisputeState::InDispute)
+        else {
+            return Ok(None);
+        };
+        let balances = self
+            .accounts
+            .get(&suspense_account)
+            .map(|suspense| suspense.balances(transaction.currency))
+            .unwrap_or_default();
+        Ok(Some(transaction.suspense_balances(balances)?))
+    }
 
-        // Check resolving tx 1
-        account.resolve_dispute(1).unwrap();
-        assert_eq!(account.available_amount, 60);
-        assert_eq!(account.held_amount, 0);
+    /// Clears the lock a chargeback put on a client account, once it's been reviewed. Accounts
+    /// with a deposit or withdrawal still in dispute stay locked until every dispute is settled.
+    pub fn unlock_account(&mut self, client_id: u16) -> Result<(), EngineError> {
+        self.page_in([Some(client_id)])?;
+        match self.accounts.get_mut(&client_id) {
+            Some(account) => account.unlock(),
+            None => Err(EngineError::AccountNotFound {
+                transaction_type: RawTransactionType::Unlock,
+            }),
+        }
+    }
 
-        // Check dispute can be started again + can't dispute same tx again
-        account.start_dispute(1).unwrap();
-        assert!(account.start_dispute(1).is_err());
-        assert_eq!(account.available_amount, 40);
-        assert_eq!(account.held_amount, 20);
+    /// A client's account, in the first currency it has balances in: the default currency if it
+    /// has any, which for feeds without currencies is the only one
+    pub fn account(&self, client_id: u16) -> Option<AccountView> {
+        self.account_ref(client_id)
+            .and_then(|account| account.views(client_id).next())
+    }
 
-        // Check having multiple in-progress disputes
-        account.start_dispute(2).unwrap();
-        assert_eq!(account.available_amount, 0);
-        assert_eq!(account.held_amount, 60);
+    /// A client's account in every currency it has balances in, the default currency first
+    pub fn client_accounts(&self, client_id: u16) -> impl Iterator<Item = AccountView> + '_ {
+        self.account_ref(client_id)
+            .into_iter()
+            .flat_map(move |account| account.views(client_id).collect::<Vec<_>>())
+    }
 
-        // Resolve all disputes
-        account.resolve_dispute(1).unwrap();
-        account.resolve_dispute(2).unwrap();
-        assert_eq!(account.available_amount, 60);
-        assert_eq!(account.held_amount, 0);
+    /// A client's account in a currency, if it has balances in it
+    pub fn account_in(&self, client_id: u16, currency: Option<Currency>) -> Option<AccountView> {
+        self.account_ref(client_id)
+            .filter(|account| account.balances.contains_key(&currency))
+            .map(|account| account.view(client_id, currency))
+    }
 
-        // Chargeback non disputed tx returns error
-        assert!(account.chargeback(1).is_err());
-        assert_eq!(account.available_amount, 60);
-        assert_eq!(account.held_amount, 0);
+    /// The account a transaction applies to, in its currency, or for disputes, resolves,
+    /// chargebacks, reversals and releases without one, in the currency of the transaction they
+    /// reference. Unlocks apply to the account in every currency, and get the same one as
+    /// `Engine::account`.
+    pub fn account_for(&self, transaction: &Transaction) -> Option<AccountView> {
+        let client_id = transaction.client_id();
+        let account = self.account_ref(client_id)?;
+        let currency = match *transaction {
+            Transaction::Unlock { .. } => return account.views(client_id).next(),
+            Transaction::Release {
+                tx_id,
+                currency: None,
+                ..
+            } => account.holds.get(&tx_id).and_then(|hold| hold.currency),
+            Transaction::Dispute {
+                tx_id,
+                currency: None,
+                ..
+            }
+            | Transaction::Resolve {
+                tx_id,
+                currency: None,
+                ..
+            }
+            | Transaction::Chargeback {
+                tx_id,
+                currency: None,
+                ..
+            }
+            | Transaction::Reversal {
+                tx_id,
+                currency: None,
+                ..
+            } => match account.transactions.get(&tx_id) {
+                Some(transaction) => transaction.currency,
+                None => self
+                    .spilled(client_id, tx_id)
+                    .ok()
+                    .flatten()
+                    .and_then(|transaction| transaction.currency),
+            },
+            _ => transaction.currency(),
+        };
+        Some(account.view(client_id, currency))
+    }
 
-        // Check chargeback
-        account.start_dispute(1).unwrap();
-        account.chargeback(1).unwrap();
-        assert_eq!(account.available_amount, 40);
-        assert_eq!(account.held_amount, 0);
-        assert!(account.locked);
+    /// Every account, once per currency it has balances in. Accounts that can't be read back from
+    /// the account store are logged and left out.
+    pub fn accounts(&self) -> impl Iterator<Item = AccountView> + '_ {
+        self.all_accounts()
+            .filter_map(|account| {
+                account
+                    .inspect_err(|e| error!("Failed to read account from the account store: {e}"))
+                    .ok()
+            })
+            .flat_map(|(client_id, account)| account.views(client_id).collect::<Vec<_>>())
     }
 
-    #[test]
+    /// A client's account, in memory or read back from the account store. Accounts that can't be
+    /// read are logged and taken as missing, for the methods that can't fail.
+    fn account_ref(&self, client_id: u16) -> Option<Cow<'_, Account>> {
+        if let Some(account) = self.accounts.get(&client_id) {
+            return Some(Cow::Borrowed(account));
+        }
+        let account = self
+            .paging
+            .as_ref()?
+            .store
+            .get(client_id)
+            .and_then(|account| account.as_deref().map(decode_account).transpose());
+        match account {
+            Ok(account) => account.map(Cow::Owned),
+            Err(e) => {
+                error!(
+                    client = client_id,
+                    "Failed to read account from the account store: {e}"
+                );
+                None
+            }
+        }
+    }
+
+    /// Every account, in memory or in the account store, in no particular order
+    fn all_accounts(&self) -> impl Iterator<Item = io::Result<(u16, Cow<'_, Account>)>> + '_ {
+        let stored = self
+            .paging
+            .iter()
+            .flat_map(|paging| paging.store.accounts())
+            .map(|account| {
+                let (client_id, account) = account?;
+                Ok((client_id, Cow::Owned(decode_account(&account)?)))
+            });
+        self.accounts
+            .iter()
+            .map(|(client_id, account)| Ok((*client_id, Cow::Borrowed(account))))
+            .chain(stored)
+    }
+
+    /// Moves the accounts of the clients back from the account store, if they're there, along
+    /// with the least recently used accounts to it until there are no more in memory than allowed
+    fn page_in(
+        &mut self,
+        client_ids: impl IntoIterator<Item = Option<u16>> + Clone,
+    ) -> Result<(), EngineError> {
+        let Some(paging) = self.paging.as_mut() else {
+            return Ok(());
+        };
+        // Accounts opened by the transaction are tracked from the start
+        for client_id in client_ids.clone().into_iter().flatten() {
+            paging.in_memory.put(client_id, ());
+        }
+        self.page_out_excess().map_err(EngineError::AccountStore)?;
+        let Some(paging) = self.paging.as_mut() else {
+            return Ok(());
+        };
+        for client_id in client_ids.into_iter().flatten() {
+            if self.accounts.contains_key(&client_id) {
+                continue;
+            }
+            let stored = paging.store.get(client_id);
+            if let Some(account) = stored.map_err(EngineError::AccountStore)? {
+                let account = decode_account(&account).map_err(EngineError::AccountStore)?;
+                paging
+                    .store
+                    .remove(client_id)
+                    .map_err(EngineError::AccountStore)?;
+                self.accounts.insert(client_id, account);
+                paging.in_memory.put(client_id, ());
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the least recently used accounts to the account store, until there are no more in
+    /// memory than allowed
+    fn page_out_excess(&mut self) -> io::Result<()> {
+        let Some(paging) = self.paging.as_mut() else {
+            return Ok(());
+        };
+        while paging.in_memory.len() > paging.max_in_memory {
+            let Some((&client_id, _)) = paging.in_memory.peek_lru() else {
+                break;
+            };
+            if let Some(account) = self.accounts.get(&client_id) {
+                paging.store.insert(client_id, encode_account(account)?)?;
+                self.accounts.remove(&client_id);
+            }
+            paging.in_memory.pop_lru();
+        }
+        Ok(())
+    }
+
+    /// Writes the accounts and the tx ids seen so far, in a versioned binary format, so that
+    /// processing can be resumed later with `Engine::restore`. Accounts and their transactions are
+    /// written in client and tx id order, so that snapshots of the same state are byte for byte
+    /// the same.
+    pub fn snapshot(&self, writer: impl Write) -> Result<()> {
+        self.snapshot_as(SnapshotFormat::Bincode, writer)
+    }
+
+    /// Writes a snapshot like `Engine::snapshot`, in the given format
+    pub fn snapshot_as(&self, format: SnapshotFormat, mut writer: impl Write) -> Result<()> {
+        let Some(tx_ids) = self.tx_ids.tx_ids() else {
+            bail!("Engines with a tx id index that can't list its tx ids can't be snapshotted");
+        };
+        let tx_ids: TxIdBitmap = tx_ids.collect();
+        let accounts: BTreeMap<u16, Cow<Account>> =
+            self.all_accounts().collect::<io::Result<_>>()?;
+        // Accounts written with other transactions than those they have in memory: without the
+        // settled ones in the mapped format, with those of the transaction store in the others
+        let mut rewritten: BTreeMap<u16, Account> = BTreeMap::new();
+        match format {
+            SnapshotFormat::Mapped => {
+                for (client_id, account) in accounts.iter() {
+                    let unsettled = account
+                        .transactions
+                        .iter()
+                        .filter(|(_, transaction)| transaction.is_settled().not())
+                        .map(|(tx_id, transaction)| (*tx_id, *transaction))
+                        .collect();
+                    rewritten.insert(*client_id, account.clone_with(unsettled));
+                }
+            }
+            SnapshotFormat::Bincode | SnapshotFormat::MessagePack => {
+                for transaction in self.settled_transactions(None) {
+                    let (tx_id, stored) = transaction?;
+                    let Some(account) = accounts.get(&stored.client_id) else {
+                        continue;
+                    };
+                    rewritten
+                        .entry(stored.client_id)
+                        .or_insert_with(|| account.clone_with(account.transactions.clone()))
+                        .transactions
+                        .insert(tx_id, DisputableTransaction::from(stored));
+                }
+            }
+        }
+        let state = SnapshotStateRef {
+            accounts: accounts
+                .iter()
+                .map(|(client_id, account)| {
+                    (client_id, rewritten.get(client_id).unwrap_or(account))
+                })
+                .collect(),
+            tx_ids: &tx_ids,
+            deposit_order: &self.deposit_order,
+            next_deposit_seq: self.next_deposit_seq,
+        };
+        match format {
+            SnapshotFormat::Bincode => {
+                writer.write_all(SNAPSHOT_MAGIC)?;
+                writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+                writer.write_all(&[Balance::BITS as u8])?;
+                bincode::serde::encode_into_std_write(
+                    &state,
+                    &mut writer,
+                    bincode::config::standard(),
+                )?;
+            }
+            SnapshotFormat::Mapped => {
+                let state = bincode::serde::encode_to_vec(&state, bincode::config::standard())?;
+                writer.write_all(MAPPED_SNAPSHOT_MAGIC)?;
+                writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+                writer.write_all(&[Balance::BITS as u8])?;
+                writer.write_all(&(state.len() as u64).to_le_bytes())?;
+                writer.write_all(&state)?;
+                for transaction in self.settled_transactions(Some(&accounts)) {
+                    let (tx_id, transaction) = transaction?;
+                    write_mapped_record(&mut writer, tx_id, &transaction)?;
+                }
+            }
+            SnapshotFormat::MessagePack => {
+                let envelope = Envelope {
+                    kind: ENVELOPE_KIND.to_string(),
+                    version: ENVELOPE_VERSION,
+                    compatible_version: ENVELOPE_COMPATIBLE_VERSION,
+                    balance_bits: Balance::BITS,
+                    state,
+                };
+                rmp_serde::encode::write_named(&mut writer, &envelope)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The settled deposits and withdrawals in the transaction store, along with those of the
+    /// accounts given, in tx id order
+    fn settled_transactions<'a>(
+        &'a self,
+        accounts: Option<&BTreeMap<u16, Cow<Account>>>,
+    ) -> StoredTransactions<'a> {
+        let stored = match self.spill.as_ref() {
+            Some(spill) => spill.store.transactions(),
+            None => Box::new(std::iter::empty()),
+        };
+        let Some(accounts) = accounts else {
+            return stored;
+        };
+        let mut settled: Vec<_> = accounts
+            .iter()
+            .flat_map(|(client_id, account)| {
+                account
+                    .transactions
+                    .iter()
+                    .filter(|(_, transaction)| transaction.is_settled())
+                    .map(|(tx_id, transaction)| (*tx_id, transaction.stored(*client_id)))
+            })
+            .collect();
+        settled.sort_unstable_by_key(|(tx_id, _)| *tx_id);
+        merge_by_tx_id(stored, settled)
+    }
+
+    /// Creates an engine from a snapshot written by `Engine::snapshot` or `Engine::snapshot_as`,
+    /// in any format, reading all of it. Listeners, validators, the write-ahead log and the
+    /// suspense account aren't part of snapshots and have to be set up again.
+    pub fn restore(mut reader: impl Read) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let state = match SnapshotFormat::detect(&magic) {
+            SnapshotFormat::Bincode => {
+                let mut version = [0; 4];
+                reader.read_exact(&mut version)?;
+                check_snapshot_version(u32::from_le_bytes(version))?;
+                let mut balance_bits = [0];
+                reader.read_exact(&mut balance_bits)?;
+                check_balance_bits(balance_bits[0].into())?;
+                bincode::serde::decode_from_std_read(&mut reader, bincode::config::standard())?
+            }
+            SnapshotFormat::MessagePack => {
+                let mut snapshot = magic.to_vec();
+                reader.read_to_end(&mut snapshot)?;
+                decode_envelope(&snapshot)?
+            }
+            SnapshotFormat::Mapped => {
+                let mut snapshot = magic.to_vec();
+                reader.read_to_end(&mut snapshot)?;
+                let (mut state, offset) = decode_mapped_state(&snapshot)?;
+                let records = snapshot[offset..].chunks_exact(MAPPED_RECORD_SIZE);
+                if records.remainder().is_empty().not() {
+                    bail!("Engine snapshot ends in a truncated transaction record");
+                }
+                for record in records {
+                    let (tx_id, stored) =
+                        read_mapped_record(record.try_into().expect("Record size"))?;
+                    let Some(account) = state.accounts.get_mut(&stored.client_id) else {
+                        bail!(
+                            "Engine snapshot has tx {tx_id} of client {}, which has no account",
+                            stored.client_id
+                        );
+                    };
+                    account
+                        .transactions
+                        .insert(tx_id, DisputableTransaction::from(stored));
+                }
+                state
+            }
+        };
+        Ok(Self::from_state(state))
+    }
+
+    /// Creates an engine from a snapshot in the `SnapshotFormat::Mapped` format without reading
+    /// its settled deposits and withdrawals: the file is memory-mapped and becomes the engine's
+    /// transaction store (see `Engine::set_transaction_store`), which looks them up in place by
+    /// tx id when they're disputed. Restoring only takes as long as reading the accounts, however
+    /// many deposits they had, and the file must not be modified while the engine is around.
+    #[cfg(feature = "storage")]
+    pub fn restore_mapped(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is only ever read, and snapshots are replaced by renaming a new file
+        // over them (see `save_state` in main.rs) rather than modified in place
+        let map = unsafe { Mmap::map(&file)? };
+        if SnapshotFormat::detect(&map) != SnapshotFormat::Mapped {
+            bail!("Not an engine snapshot in the mapped format");
+        }
+        let (state, offset) = decode_mapped_state(&map)?;
+        let mut engine = Self::from_state(state);
+        engine.spill = Some(Spill {
+            store: Box::new(MappedStore::new(map, offset)?),
+            in_memory: LruCache::unbounded(),
+            max_in_memory: usize::MAX,
+        });
+        Ok(engine)
+    }
+
+    fn from_state(state: SnapshotState) -> Self {
+        Self {
+            accounts: state.accounts,
+            tx_ids: Box::new(state.tx_ids),
+            deposit_order: state.deposit_order,
+            next_deposit_seq: state.next_deposit_seq,
+            ..Self::new()
+        }
+    }
+
+    /// Checks the consistency of the engine's state, as restored from a snapshot or after
+    /// processing, returning every violation found, in client order: an account's held funds in
+    /// each currency must add up to those of its disputes, manual holds and deposits held while
+    /// it's locked, its total funds must fit in a balance, deposits are only held in locked
+    /// accounts, disputes never hold more than their amount, every deposit, withdrawal and hold
+    /// must have its tx id seen and belong to a single account, and deposit sequence numbers must
+    /// have been handed out already.
+    pub fn check_invariants(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        let mut owners: HashMap<u32, u16> = HashMap::new();
+        let mut check_tx_id = |violations: &mut Vec<String>, client_id: u16, tx_id: u32| {
+            if self.tx_ids.contains(tx_id).not() {
+                violations.push(format!("Client {client_id}: tx {tx_id} isn't a seen tx id"));
+            }
+            if let Some(owner) = owners.insert(tx_id, client_id) {
+                violations.push(format!(
+                    "Client {client_id}: tx {tx_id} also belongs to client {owner}"
+                ));
+            }
+        };
+        let currency_name = |currency: Option<Currency>| match currency {
+            Some(currency) => currency.to_string(),
+            None => "the default currency".to_string(),
+        };
+
+        let mut accounts = Vec::new();
+        for account in self.all_accounts() {
+            match account {
+                Ok(account) => accounts.push(account),
+                Err(e) => violations.push(format!("An account can't be read back: {e}")),
+            }
+        }
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        for (client_id, account) in accounts {
+            let mut held: BTreeMap<Option<Currency>, HeldBalance> = BTreeMap::new();
+            let mut transactions: Vec<_> = account.transactions.iter().collect();
+            transactions.sort_by_key(|(tx_id, _)| **tx_id);
+            for (&tx_id, transaction) in transactions {
+                check_tx_id(&mut violations, client_id, tx_id);
+                if transaction.shortfall > transaction.amount {
+                    violations.push(format!(
+                        "Client {client_id}: tx {tx_id} has a shortfall of {} over its amount of {}",
+                        transaction.shortfall, transaction.amount
+                    ));
+                    continue;
+                }
+                if transaction.held && account.locked.not() {
+                    violations.push(format!(
+                        "Client {client_id}: tx {tx_id} is held but the account isn't locked"
+                    ));
+                }
+                let holding = match (transaction.held, transaction.state) {
+                    (true, DisputeState::Valid | DisputeState::InDispute) => transaction.amount,
+                    (false, DisputeState::InDispute) => transaction.covered_amount(),
+                    _ => 0,
+                };
+                let sum = held.entry(transaction.currency).or_default();
+                *sum = sum.saturating_add(holding as HeldBalance);
+            }
+            for (&tx_id, hold) in account.holds.iter() {
+                check_tx_id(&mut violations, client_id, tx_id);
+                let sum = held.entry(hold.currency).or_default();
+                *sum = sum.saturating_add(hold.amount as HeldBalance);
+            }
+
+            for (currency, balances) in account.balances.iter() {
+                let expected = held.remove(currency).unwrap_or(0);
+                if balances.held_amount != expected {
+                    violations.push(format!(
+                        "Client {client_id}: holds {} in {}, but its disputes and holds add up to \
+                        {expected}",
+                        balances.held_amount,
+                        currency_name(*currency)
+                    ));
+                }
+                if balances
+                    .available_amount
+                    .checked_add_unsigned(balances.held_amount)
+                    .is_none()
+                {
+                    violations.push(format!(
+                        "Client {client_id}: total funds in {} overflow a balance",
+                        currency_name(*currency)
+                    ));
+                }
+            }
+            for (currency, expected) in held {
+                if expected > 0 {
+                    violations.push(format!(
+                        "Client {client_id}: its disputes and holds add up to {expected} in {}, \
+                        which it has no balances in",
+                        currency_name(currency)
+                    ));
+                }
+            }
+
+            if let Some((seq, tx_id)) = account
+                .deposits
+                .iter()
+                .find(|(seq, _)| *seq >= self.next_deposit_seq)
+            {
+                violations.push(format!(
+                    "Client {client_id}: deposit {tx_id} has sequence number {seq}, which wasn't \
+                    handed out yet"
+                ));
+            }
+        }
+
+        if let Some((seq, (client_id, tx_id))) = self.deposit_order.last_key_value() {
+            if *seq >= self.next_deposit_seq {
+                violations.push(format!(
+                    "Client {client_id}: deposit {tx_id} has sequence number {seq}, which wasn't \
+                    handed out yet"
+                ));
+            }
+        }
+        violations
+    }
+
+    /// The transactions applied to a client's account, in the order they were, each with the
+    /// balances it resulted in. It's only kept with `EngineConfig::keep_history`, and is empty
+    /// otherwise.
+    pub fn client_statement(&self, client_id: u16) -> Cow<'_, [StatementEntry]> {
+        match self.account_ref(client_id) {
+            Some(Cow::Borrowed(account)) => Cow::Borrowed(account.history.as_slice()),
+            Some(Cow::Owned(account)) => Cow::Owned(account.history),
+            None => Cow::Borrowed(&[]),
+        }
+    }
+
+    /// Whether a deposit, withdrawal or hold with the tx id was processed already, applied or
+    /// not, so that the transaction would be rejected as a duplicate. Tx ids are part of
+    /// snapshots, so this holds across runs resumed from one, for callers to drop duplicates
+    /// before they even reach the engine.
+    pub fn has_seen_tx(&self, tx_id: u32) -> bool {
+        self.tx_ids.contains(tx_id)
+    }
+
+    /// The number of chargebacks applied to a client's account, in every currency, whether they
+    /// locked it or not
+    pub fn chargeback_count(&self, client_id: u16) -> u32 {
+        self.account_ref(client_id)
+            .map_or(0, |account| account.chargebacks)
+    }
+
+    /// The disputes a client's deposits and withdrawals went through, in tx id order: those
+    /// currently in dispute and those charged back
+    pub fn disputes(&self, client_id: u16) -> Vec<DisputeView> {
+        let account = self.account_ref(client_id);
+        let mut disputes: Vec<DisputeView> = account
+            .iter()
+            .flat_map(|account| account.transactions.iter())
+            .filter(|(_, transaction)| {
+                matches!(
+                    transaction.state,
+                    DisputeState::InDispute | DisputeState::ChargedBack
+                )
+            })
+            .map(|(tx_id, transaction)| DisputeView {
+                tx_id: *tx_id,
+                kind: transaction.kind,
+                amount: transaction.amount,
+                currency: transaction.currency,
+                state: transaction.state,
+                shortfall: transaction.shortfall,
+            })
+            .collect();
+        disputes.sort_by_key(|dispute| dispute.tx_id);
+        disputes
+    }
+
+    /// An engine without accounts but with the same settings, listeners, validators, the
+    /// write-ahead log and the suspense account aside, to process a shard of the clients with
+    #[cfg(feature = "csv")]
+    pub(crate) fn shard(&self) -> Engine {
+        Self {
+            config: self.config.clone(),
+            transactions_per_account: self.transactions_per_account,
+            ..Self::new()
+        }
+    }
+
+    /// Moves the accounts and tx ids of an engine that processed a different set of clients into
+    /// this one, as when putting the shards of parallel processing back together. Listeners and
+    /// validators aren't moved, nor are the tx ids of an index that can't list them.
+    pub fn merge(&mut self, other: Engine) {
+        if let Some(paging) = self.paging.as_mut() {
+            for client_id in other.accounts.keys() {
+                paging.in_memory.put(*client_id, ());
+            }
+        }
+        self.accounts.extend(other.accounts);
+        if let Some(tx_ids) = other.tx_ids.tx_ids() {
+            for tx_id in tx_ids {
+                self.tx_ids.insert(tx_id);
+            }
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outcomes of the transactions of a batch given to `Engine::process_batch`, in the batch's order
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub outcomes: Vec<Result<(), EngineError>>,
+}
+
+impl BatchResult {
+    /// Number of transactions applied
+    pub fn applied(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.is_ok())
+            .count()
+    }
+
+    /// The rejected transactions, by their index in the batch, along with why they were
+    pub fn rejections(&self) -> impl Iterator<Item = (usize, &EngineError)> {
+        self.outcomes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, outcome)| outcome.as_ref().err().map(|e| (index, e)))
+    }
+
+    /// Whether every transaction was applied
+    pub fn is_clean(&self) -> bool {
+        self.outcomes.iter().all(Result::is_ok)
+    }
+}
+
+/// Read-only copy of a disputed deposit or withdrawal, as exposed outside the engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisputeView {
+    pub tx_id: u32,
+    pub kind: DisputableKind,
+    pub amount: u64,
+    pub currency: Option<Currency>,
+    pub state: DisputeState,
+    /// The part of a disputed deposit's amount that was spent already and so couldn't be held,
+    /// under `NegativeBalancePolicy::Clamp`. It's what the client owes if the deposit is charged
+    /// back.
+    pub shortfall: u64,
+}
+
+/// Read-only copy of a client account's balances, as exposed outside the engine
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccountView {
+    pub client_id: u16,
+    /// The currency of the balances, `None` being the default one of transactions without any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available_amount: Balance,
+    pub held_amount: HeldBalance,
+    pub total_amount: Balance,
+    pub locked: bool,
+}
+
+/// A transaction applied to a client's account, along with the account's balances right after it,
+/// in the currency it applied to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StatementEntry {
+    pub tx_id: u32,
+    pub transaction_type: RawTransactionType,
+    /// The amount of deposits and withdrawals
+    pub amount: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub currency: Option<Currency>,
+    pub available_amount: Balance,
+    pub held_amount: HeldBalance,
+    pub total_amount: Balance,
+}
+
+/// A deposit evicted to keep the number of deposits under the engine's caps, which can no longer
+/// be disputed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DepositEvictedEvent {
+    pub client_id: u16,
+    pub tx_id: u32,
+    pub amount: u64,
+    pub currency: Option<Currency>,
+}
+
+/// Details of a chargeback that locked a client account
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccountLockedEvent {
+    pub tx_id: u32,
+    pub amount: u64,
+    pub account: AccountView,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Account {
+    /// Balances by currency, `None` being the default currency of transactions without one
+    balances: BTreeMap<Option<Currency>, Balances>,
+    locked: bool,
+    /// Latest timestamp of the transactions applied to the account, if any had one
+    last_timestamp: Option<u64>,
+    /// Applied deposits and withdrawals, which can be disputed
+    #[serde(serialize_with = "serialize_sorted")]
+    transactions: FastHashMap<u32, DisputableTransaction>,
+    /// Sequence number and tx id of the deposits that can be evicted under
+    /// `EngineConfig::max_deposits_per_account`, oldest first
+    deposits: VecDeque<(u64, u32)>,
+    /// Applied transactions, oldest first, under `EngineConfig::keep_history`
+    history: Vec<StatementEntry>,
+    /// Manual holds in place, by the tx id of the hold, whose funds are part of the held
+    /// balances along with those of disputes
+    holds: BTreeMap<u32, ManualHold>,
+    /// Number of chargebacks applied to the account
+    chargebacks: u32,
+}
+
+impl Account {
+    fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// A copy of the account with other transactions, for snapshots
+    fn clone_with(&self, transactions: FastHashMap<u32, DisputableTransaction>) -> Self {
+        Self {
+            balances: self.balances.clone(),
+            locked: self.locked,
+            last_timestamp: self.last_timestamp,
+            transactions,
+            deposits: self.deposits.clone(),
+            history: self.history.clone(),
+            holds: self.holds.clone(),
+            chargebacks: self.chargebacks,
+        }
+    }
+
+    /// An account with room for this many deposits and withdrawals
+    fn with_capacity(transactions: usize) -> Self {
+        Self {
+            balances: BTreeMap::new(),
+            locked: false,
+            last_timestamp: None,
+            transactions: FastHashMap::with_capacity_and_hasher(transactions, Default::default()),
+            deposits: VecDeque::new(),
+            history: Vec::new(),
+            holds: BTreeMap::new(),
+            chargebacks: 0,
+        }
+    }
+
+    /// The oldest deposit that can be evicted, if the account has more than `max` deposits
+    fn oldest_evictable(&self, max: usize) -> Option<(u64, u32)> {
+        if self.deposits.len() <= max {
+            return None;
+        }
+        self.deposits
+            .iter()
+            .find(|(_, tx_id)| self.is_evictable(*tx_id))
+            .copied()
+    }
+
+    /// Whether one of the account's deposits can be evicted: it isn't disputed, nor held. Those
+    /// moved to a transaction store are settled, and so can be.
+    fn is_evictable(&self, tx_id: u32) -> bool {
+        self.transactions
+            .get(&tx_id)
+            .is_none_or(DisputableTransaction::is_settled)
+    }
+
+    fn view(&self, client_id: u16, currency: Option<Currency>) -> AccountView {
+        let balances = self.balances(currency);
+        AccountView {
+            client_id,
+            currency,
+            available_amount: balances.available_amount,
+            held_amount: balances.held_amount,
+            total_amount: balances.total(),
+            locked: self.locked,
+        }
+    }
+
+    /// Views of the account, one per currency it has balances in, the default currency first
+    fn views(&self, client_id: u16) -> impl Iterator<Item = AccountView> + '_ {
+        self.balances
+            .keys()
+            .map(move |currency| self.view(client_id, *currency))
+    }
+
+    /// The account's balances in a currency, which are zero until a transaction in it is applied
+    fn balances(&self, currency: Option<Currency>) -> Balances {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    fn set_balances(&mut self, currency: Option<Currency>, balances: Balances) {
+        self.balances.insert(currency, balances);
+    }
+
+    fn deposit(
+        &mut self,
+        tx_id: u32,
+        amount: u64,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+        locked_account_policy: LockedAccountPolicy,
+    ) -> Result<(), EngineError> {
+        let held = match (self.locked, locked_account_policy) {
+            (false, _) | (true, LockedAccountPolicy::AllowDeposits) => false,
+            (true, LockedAccountPolicy::HoldDeposits) => true,
+            (true, LockedAccountPolicy::RejectAll) => {
+                return Err(EngineError::AccountLocked {
+                    transaction_type: RawTransactionType::Deposit,
+                })
+            }
+        };
+
+        let balances = self.balances(currency);
+        let balances = match held {
+            true => balances.credit_held(amount)?,
+            false => balances.credit(amount)?,
+        };
+        self.set_balances(currency, balances);
+        self.transactions.insert(
+            tx_id,
+            DisputableTransaction::new(DisputableKind::Deposit, amount, currency, timestamp, held),
+        );
+        Ok(())
+    }
+
+    /// Withdrawing takes from the available funds, which can't go negative unless `from_held`
+    /// lets the held funds make up for them, the total staying positive
+    fn withdraw(
+        &mut self,
+        tx_id: u32,
+        amount: u64,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+        from_held: bool,
+    ) -> Result<(), EngineError> {
+        if self.locked {
+            return Err(EngineError::AccountLocked {
+                transaction_type: RawTransactionType::Withdrawal,
+            });
+        }
+
+        let balances = self.balances(currency).withdraw(amount, from_held)?;
+        self.set_balances(currency, balances);
+        self.transactions.insert(
+            tx_id,
+            DisputableTransaction::new(
+                DisputableKind::Withdrawal,
+                amount,
+                currency,
+                timestamp,
+                false,
+            ),
+        );
+        Ok(())
+    }
+
+    /// The deposit or withdrawal a dispute, resolve, chargeback or reversal references, as long
+    /// as the latter has no currency or the same one
+    fn disputed(
+        &self,
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+        currency: Option<Currency>,
+    ) -> Result<DisputableTransaction, EngineError> {
+        let transaction = self
+            .transactions
+            .get(&tx_id)
+            .copied()
+            .ok_or(EngineError::UnknownTx {
+                transaction_type,
+                tx_id,
+            })?;
+        if currency.is_some_and(|currency| transaction.currency != Some(currency)) {
+            return Err(EngineError::CurrencyMismatch {
+                transaction_type,
+                tx_id,
+            });
+        }
+        Ok(transaction)
+    }
+
+    /// Applies a dispute, resolve, chargeback or reversal to the deposit or withdrawal it
+    /// references, see `DisputableTransaction`, returning the transaction as it results
+    fn transition(
+        &mut self,
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+        currency: Option<Currency>,
+        transition: impl FnOnce(
+            DisputableTransaction,
+            Balances,
+        ) -> Result<(DisputableTransaction, Balances), DisputeError>,
+    ) -> Result<DisputableTransaction, EngineError> {
+        let transaction = self.disputed(transaction_type, tx_id, currency)?;
+        let (transaction, balances) = transition(transaction, self.balances(transaction.currency))
+            .map_err(|e| EngineError::from_dispute(e, transaction_type, tx_id))?;
+        self.set_balances(transaction.currency, balances);
+        self.transactions.insert(tx_id, transaction);
+        Ok(transaction)
+    }
+
+    /// Disputes a deposit or withdrawal, see `DisputableTransaction::dispute`
+    fn start_dispute(
+        &mut self,
+        tx_id: u32,
+        currency: Option<Currency>,
+        not_before: Option<u64>,
+        negative_balance_policy: NegativeBalancePolicy,
+    ) -> Result<(), EngineError> {
+        self.transition(
+            RawTransactionType::Dispute,
+            tx_id,
+            currency,
+            |transaction, balances| {
+                transaction.dispute(balances, not_before, negative_balance_policy)
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Resolves the dispute of a deposit or withdrawal, see `DisputableTransaction::resolve`
+    fn resolve_dispute(
+        &mut self,
+        tx_id: u32,
+        currency: Option<Currency>,
+    ) -> Result<(), EngineError> {
+        self.transition(
+            RawTransactionType::Resolve,
+            tx_id,
+            currency,
+            DisputableTransaction::resolve,
+        )?;
+        Ok(())
+    }
+
+    /// Reverses a withdrawal, see `DisputableTransaction::reverse`. Locked accounts can't have
+    /// withdrawals reversed, like they can't make them.
+    fn reverse_withdrawal(
+        &mut self,
+        tx_id: u32,
+        currency: Option<Currency>,
+    ) -> Result<(), EngineError> {
+        if self.locked {
+            return Err(EngineError::AccountLocked {
+                transaction_type: RawTransactionType::Reversal,
+            });
+        }
+        self.transition(
+            RawTransactionType::Reversal,
+            tx_id,
+            currency,
+            DisputableTransaction::reverse,
+        )?;
+        Ok(())
+    }
+
+    /// Holding funds moves them from the available to the held balance until released, whether
+    /// the account is locked or not, as long as enough of them are available
+    fn hold(
+        &mut self,
+        tx_id: u32,
+        amount: u64,
+        currency: Option<Currency>,
+    ) -> Result<(), EngineError> {
+        let balances = self.balances(currency).hold(amount)?;
+        self.set_balances(currency, balances);
+        self.holds.insert(tx_id, ManualHold { amount, currency });
+        Ok(())
+    }
+
+    /// Releasing a hold moves its funds back to the available balance
+    fn release(&mut self, tx_id: u32, currency: Option<Currency>) -> Result<(), EngineError> {
+        let hold = self
+            .holds
+            .get(&tx_id)
+            .copied()
+            .ok_or(EngineError::UnknownHold { tx_id })?;
+        if currency.is_some_and(|currency| hold.currency != Some(currency)) {
+            return Err(EngineError::CurrencyMismatch {
+                transaction_type: RawTransactionType::Release,
+                tx_id,
+            });
+        }
+
+        let balances = self.balances(hold.currency).release(hold.amount)?;
+        self.set_balances(hold.currency, balances);
+        self.holds.remove(&tx_id);
+        Ok(())
+    }
+
+    /// Unlocking releases the funds of the deposits made while the account was locked
+    fn unlock(&mut self) -> Result<(), EngineError> {
+        if self.locked.not() {
+            return Err(EngineError::AccountNotLocked);
+        }
+        if let Some((tx_id, _)) = self
+            .transactions
+            .iter()
+            .find(|(_, transaction)| transaction.state == DisputeState::InDispute)
+        {
+            return Err(EngineError::ActiveDispute { tx_id: *tx_id });
+        }
+        let mut balances = self.balances.clone();
+        for transaction in self.transactions.values() {
+            if transaction.held && transaction.state == DisputeState::Valid {
+                let released = balances.entry(transaction.currency).or_default();
+                *released = released.release(transaction.amount)?;
+            }
+        }
+        self.balances = balances;
+        for transaction in self.transactions.values_mut() {
+            transaction.held = false;
+        }
+        self.locked = false;
+        Ok(())
+    }
+
+    /// Charges back a deposit or withdrawal, see `DisputableTransaction::chargeback`, locking
+    /// the account if the policy says so. Returns the charged back transaction.
+    fn chargeback(
+        &mut self,
+        tx_id: u32,
+        currency: Option<Currency>,
+        lock_policy: ChargebackLockPolicy,
+    ) -> Result<DisputableTransaction, EngineError> {
+        let transaction = self.transition(
+            RawTransactionType::Chargeback,
+            tx_id,
+            currency,
+            DisputableTransaction::chargeback,
+        )?;
+        self.chargebacks = self.chargebacks.saturating_add(1);
+        if lock_policy.locks(self.chargebacks) {
+            self.locked = true;
+        }
+        Ok(transaction)
+    }
+}
+
+/// The state snapshots hold, as written
+#[derive(Serialize)]
+struct SnapshotStateRef<'a> {
+    accounts: BTreeMap<&'a u16, &'a Account>,
+    tx_ids: &'a TxIdBitmap,
+    deposit_order: &'a BTreeMap<u64, (u16, u32)>,
+    next_deposit_seq: u64,
+}
+
+/// The state snapshots hold, as read. Fields added since the first MessagePack snapshots have to
+/// default when missing, for those to still be read.
+#[derive(Deserialize)]
+struct SnapshotState {
+    accounts: FastHashMap<u16, Account>,
+    tx_ids: TxIdBitmap,
+    deposit_order: BTreeMap<u64, (u16, u32)>,
+    next_deposit_seq: u64,
+}
+
+/// What MessagePack snapshots are wrapped in, for readers to tell whether they can decode the
+/// state before trying to
+#[derive(Serialize, Deserialize)]
+struct Envelope<State> {
+    kind: String,
+    version: u32,
+    compatible_version: u32,
+    balance_bits: u32,
+    state: State,
+}
+
+/// Reads the state out of a MessagePack snapshot, refusing those of readers newer than
+/// `ENVELOPE_COMPATIBLE_VERSION` allows
+fn decode_envelope(snapshot: &[u8]) -> Result<SnapshotState> {
+    let envelope = match rmp_serde::from_slice::<Envelope<IgnoredAny>>(snapshot) {
+        Ok(envelope) if envelope.kind == ENVELOPE_KIND => envelope,
+        _ => bail!("Not an engine snapshot"),
+    };
+    if envelope.compatible_version > ENVELOPE_VERSION {
+        bail!(
+            "Engine snapshot version {} can only be read by version {} or later, this is version \
+            {ENVELOPE_VERSION}",
+            envelope.version,
+            envelope.compatible_version
+        );
+    }
+    check_balance_bits(envelope.balance_bits)?;
+    let envelope: Envelope<SnapshotState> = rmp_serde::from_slice(snapshot)?;
+    Ok(envelope.state)
+}
+
+/// Reads the accounts out of a snapshot in the mapped format, returning them along with the
+/// offset of the transaction records that follow
+fn decode_mapped_state(snapshot: &[u8]) -> Result<(SnapshotState, usize)> {
+    let Some((header, rest)) = snapshot.split_at_checked(17) else {
+        bail!("Engine snapshot is truncated");
+    };
+    check_snapshot_version(u32::from_le_bytes(header[4..8].try_into()?))?;
+    check_balance_bits(header[8].into())?;
+    let length = usize::try_from(u64::from_le_bytes(header[9..17].try_into()?))?;
+    let Some(state) = rest.get(..length) else {
+        bail!("Engine snapshot is truncated");
+    };
+    let (state, _) = bincode::serde::decode_from_slice(state, bincode::config::standard())?;
+    Ok((state, header.len() + length))
+}
+
+fn check_snapshot_version(version: u32) -> Result<()> {
+    if version != SNAPSHOT_VERSION {
+        bail!("Unsupported engine snapshot version {version}, expected {SNAPSHOT_VERSION}");
+    }
+    Ok(())
+}
+
+fn check_balance_bits(balance_bits: u32) -> Result<()> {
+    if balance_bits != Balance::BITS {
+        bail!(
+            "Engine snapshot has {balance_bits}-bit balances, expected {}-bit ones (see the \
+            `wide-balances` feature)",
+            Balance::BITS
+        );
+    }
+    Ok(())
+}
+
+/// Serializes a map in key order rather than its hash order, for snapshots to be deterministic
+fn serialize_sorted<K, V, S>(map: &FastHashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: serde::Serializer,
+{
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    serializer.collect_map(entries)
+}
+
+/// The account store of an engine, see `Engine::set_account_store`
+struct AccountPaging {
+    store: Box<dyn AccountStore>,
+    /// Clients whose accounts are in memory, or about to be opened, least recently used first
+    in_memory: LruCache<u16, ()>,
+    max_in_memory: usize,
+}
+
+/// An account as kept in an account store
+fn encode_account(account: &Account) -> io::Result<Vec<u8>> {
+    bincode::serde::encode_to_vec(account, bincode::config::standard()).map_err(io::Error::other)
+}
+
+fn decode_account(account: &[u8]) -> io::Result<Account> {
+    bincode::serde::decode_from_slice(account, bincode::config::standard())
+        .map(|(account, _)| account)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The transaction store of an engine, see `Engine::set_transaction_store`
+struct Spill {
+    store: Box<dyn TransactionStore>,
+    /// The client of every settled transaction still in memory, by tx id, least recently used
+    /// first
+    in_memory: LruCache<u32, u16>,
+    max_in_memory: usize,
+}
+
+impl Spill {
+    /// Starts tracking a client's settled transactions that aren't tracked yet, such as the
+    /// deposits held while its account was locked
+    fn track_settled(&mut self, client_id: u16, accounts: &FastHashMap<u16, Account>) {
+        let Some(account) = accounts.get(&client_id) else {
+            return;
+        };
+        for (tx_id, transaction) in account.transactions.iter() {
+            if transaction.is_settled() && self.in_memory.contains(tx_id).not() {
+                self.in_memory.put(*tx_id, client_id);
+            }
+        }
+    }
+}
+
+impl DisputableTransaction {
+    /// What a transaction store keeps of a settled transaction, which it can be restored from
+    fn stored(&self, client_id: u16) -> StoredTransaction {
+        StoredTransaction {
+            client_id,
+            kind: self.kind,
+            amount: self.amount,
+            currency: self.currency,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+impl From<StoredTransaction> for DisputableTransaction {
+    fn from(stored: StoredTransaction) -> Self {
+        Self::new(
+            stored.kind,
+            stored.amount,
+            stored.currency,
+            stored.timestamp,
+            false,
+        )
+    }
+}
+
+/// Funds an operations team held on an account, outside of any dispute
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct ManualHold {
+    amount: u64,
+    currency: Option<Currency>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{
+        Account, AccountView, Balance, ChargebackLockPolicy, DepositEvictedEvent, DisputeState,
+        Engine, EngineConfig, Envelope, LockedAccountPolicy, NegativeBalancePolicy, SnapshotFormat,
+        SnapshotStateRef, ENVELOPE_KIND, ENVELOPE_VERSION,
+    };
+    use crate::error::EngineError;
+    #[cfg(feature = "storage")]
+    use crate::store::{read_mapped_record, MAPPED_RECORD_SIZE};
+    use crate::store::{MemoryAccountStore, MemoryStore};
+    use crate::transaction::Transaction;
+    use crate::tx_index::{TxIdBitmap, TxIdBloomFilter};
+    use serde::Serialize;
+    #[cfg(feature = "storage")]
+    use std::fs;
+    use std::ops::Not;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_account_flow() {
+        let mut account = Account::new();
+        assert_eq!(account.balances(None).available_amount, 0);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(account.locked.not());
+        assert!(account.transactions.is_empty());
+
+        // Make 2 deposits totalling 60
+        account
+            .deposit(1, 20, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account
+            .deposit(2, 40, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 60);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        // Check disputing tx 1
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 40);
+        assert_eq!(account.balances(None).held_amount, 20);
+
+        // Check resolving tx 1
+        account.resolve_dispute(1, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 60);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        // Check dispute can be started again + can't dispute same tx again
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert!(account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .is_err());
+        assert_eq!(account.balances(None).available_amount, 40);
+        assert_eq!(account.balances(None).held_amount, 20);
+
+        // Check having multiple in-progress disputes
+        account
+            .start_dispute(2, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 0);
+        assert_eq!(account.balances(None).held_amount, 60);
+
+        // Resolve all disputes
+        account.resolve_dispute(1, None).unwrap();
+        account.resolve_dispute(2, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 60);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        // Chargeback non disputed tx returns error
+        assert!(account
+            .chargeback(1, None, ChargebackLockPolicy::FirstChargeback)
+            .is_err());
+        assert_eq!(account.balances(None).available_amount, 60);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        // Check chargeback
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        account
+            .chargeback(1, None, ChargebackLockPolicy::FirstChargeback)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 40);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(account.locked);
+    }
+
+    #[test]
     fn test_account_chargeback_after_withdrawal_flow() {
         let mut account = Account::new();
-        account.deposit(1, 100).unwrap();
-        account.deposit(2, 50).unwrap();
-        assert_eq!(account.available_amount, 150);
-        assert_eq!(account.held_amount, 0);
-
-        account.withdraw(100).unwrap();
-        assert_eq!(account.available_amount, 50);
-        assert_eq!(account.held_amount, 0);
-
-        account.start_dispute(1).unwrap();
-        assert_eq!(account.available_amount, -50);
-        assert_eq!(account.held_amount, 100);
-
-        account.deposit(3, 25).unwrap();
-        assert_eq!(account.available_amount, -25);
-        assert_eq!(account.held_amount, 100);
-
-        account.start_dispute(3).unwrap();
-        assert_eq!(account.available_amount, -50);
-        assert_eq!(account.held_amount, 125);
-
-        account.resolve_dispute(3).unwrap();
-        assert_eq!(account.available_amount, -25);
-        assert_eq!(account.held_amount, 100);
-
-        account.chargeback(1).unwrap();
-        assert_eq!(account.available_amount, -25);
-        assert_eq!(account.held_amount, 0);
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account
+            .deposit(2, 50, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 150);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        account.withdraw(4, 100, None, None, false).unwrap();
+        assert_eq!(account.balances(None).available_amount, 50);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -50);
+        assert_eq!(account.balances(None).held_amount, 100);
+
+        account
+            .deposit(3, 25, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -25);
+        assert_eq!(account.balances(None).held_amount, 100);
+
+        account
+            .start_dispute(3, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -50);
+        assert_eq!(account.balances(None).held_amount, 125);
+
+        account.resolve_dispute(3, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, -25);
+        assert_eq!(account.balances(None).held_amount, 100);
+
+        account
+            .chargeback(1, None, ChargebackLockPolicy::FirstChargeback)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -25);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_account_withdrawal_dispute_flow() {
+        let mut account = Account::new();
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account.withdraw(2, 60, None, None, false).unwrap();
+        assert_eq!(account.balances(None).available_amount, 40);
+
+        // The withdrawn funds are held while disputed, without being available
+        account
+            .start_dispute(2, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert!(account
+            .start_dispute(2, None, None, NegativeBalancePolicy::Allow)
+            .is_err());
+        assert_eq!(account.balances(None).available_amount, 40);
+        assert_eq!(account.balances(None).held_amount, 60);
+        assert!(account.withdraw(3, 50, None, None, false).is_err());
+
+        // Resolving the dispute lets the withdrawal stand
+        account.resolve_dispute(2, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 40);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        // Charging it back refunds the withdrawn funds
+        account
+            .start_dispute(2, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(
+            account
+                .chargeback(2, None, ChargebackLockPolicy::FirstChargeback)
+                .unwrap()
+                .amount,
+            60
+        );
+        assert_eq!(account.balances(None).available_amount, 100);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(account.locked);
+        assert!(account
+            .start_dispute(2, None, None, NegativeBalancePolicy::Allow)
+            .is_err());
+
+        // Rejected withdrawals can't be disputed
+        let mut account = Account::new();
+        assert!(account.withdraw(1, 10, None, None, false).is_err());
+        assert!(account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .is_err());
+    }
+
+    #[test]
+    fn test_account_withdrawal_reversal_flow() {
+        let mut account = Account::new();
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account.withdraw(2, 60, None, None, false).unwrap();
+        account.withdraw(3, 10, None, None, false).unwrap();
+        assert_eq!(account.balances(None).available_amount, 30);
+
+        // Reversing a withdrawal credits its amount back, once
+        account.reverse_withdrawal(2, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 90);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(matches!(
+            account.reverse_withdrawal(2, None),
+            Err(EngineError::AlreadyReversed { tx_id: 2 })
+        ));
+        assert_eq!(account.balances(None).available_amount, 90);
+
+        // Reversed withdrawals can't be disputed, nor can deposits or unknown txs be reversed
+        assert!(matches!(
+            account.start_dispute(2, None, None, NegativeBalancePolicy::Allow),
+            Err(EngineError::InvalidDisputeState {
+                state: DisputeState::Reversed,
+                ..
+            })
+        ));
+        assert!(matches!(
+            account.reverse_withdrawal(1, None),
+            Err(EngineError::NotAWithdrawal { tx_id: 1 })
+        ));
+        assert!(matches!(
+            account.reverse_withdrawal(9, None),
+            Err(EngineError::UnknownTx { tx_id: 9, .. })
+        ));
+
+        // Disputed withdrawals can't be reversed until the dispute is resolved
+        account
+            .start_dispute(3, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert!(matches!(
+            account.reverse_withdrawal(3, None),
+            Err(EngineError::InvalidDisputeState {
+                state: DisputeState::InDispute,
+                ..
+            })
+        ));
+        account.resolve_dispute(3, None).unwrap();
+        account.reverse_withdrawal(3, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 100);
+        assert_eq!(account.balances(None).held_amount, 0);
+    }
+
+    #[test]
+    fn test_account_manual_hold_flow() {
+        let mut account = Account::new();
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+
+        // Held funds can't be withdrawn, nor held again
+        account.hold(2, 70, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 30);
+        assert_eq!(account.balances(None).held_amount, 70);
+        assert!(matches!(
+            account.hold(3, 40, None),
+            Err(EngineError::InsufficientFunds)
+        ));
+        assert!(account.withdraw(4, 40, None, None, false).is_err());
+
+        // Manual holds stay apart from dispute holds, and outlive the lock of a chargeback
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -70);
+        assert_eq!(account.balances(None).held_amount, 170);
+        account
+            .chargeback(1, None, ChargebackLockPolicy::FirstChargeback)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, -70);
+        assert_eq!(account.balances(None).held_amount, 70);
+        assert!(account.locked);
+
+        // Releasing moves the held funds back, once
+        account.release(2, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 0);
+        assert_eq!(account.balances(None).held_amount, 0);
+        assert!(matches!(
+            account.release(2, None),
+            Err(EngineError::UnknownHold { tx_id: 2 })
+        ));
+        assert!(matches!(
+            account.release(1, None),
+            Err(EngineError::UnknownHold { tx_id: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_account_view_serde_round_trip() {
+        let mut account = Account::new();
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        let view = account.view(7, None);
+
+        let json = serde_json::to_string(&view).unwrap();
+        assert_eq!(
+            json,
+            r#"{"client_id":7,"available_amount":0,"held_amount":100,"total_amount":100,"locked":false}"#
+        );
+        assert_eq!(serde_json::from_str::<AccountView>(&json).unwrap(), view);
+    }
+
+    #[test]
+    fn test_suspense_account_receives_chargebacks() {
+        let mut engine = Engine::new();
+        engine.set_suspense_account(u16::MAX);
+
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 2,
+                amount: 30,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert!(engine
+            .process_transaction(Transaction::Withdrawal {
+                client_id: u16::MAX,
+                tx_id: 3,
+                amount: 50,
+                currency: None,
+            })
+            .is_err());
+
+        let suspense = engine.account(u16::MAX).unwrap();
+        assert_eq!(suspense.available_amount, 100);
+        assert_eq!(suspense.held_amount, 0);
+        assert!(suspense.locked.not());
+        // Every deposited amount is still on the books
+        let total: Balance = engine.accounts().map(|account| account.total_amount).sum();
+        assert_eq!(total, 130);
+    }
+
+    #[test]
+    fn test_engine_errors_can_be_matched() {
+        let mut engine = Engine::new();
+        engine.add_validator(|transaction, _| match transaction.amount() {
+            Some(amount) if amount > 1_000 => anyhow::bail!("Above the limit"),
+            _ => Ok(()),
+        });
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            })
+            .unwrap();
+
+        assert!(matches!(
+            engine.process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            }),
+            Err(EngineError::DuplicateTxId { tx_id: 1 })
+        ));
+        assert!(matches!(
+            engine.process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 500,
+                currency: None,
+            }),
+            Err(EngineError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            engine.process_transaction(Transaction::Resolve {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            }),
+            Err(EngineError::InvalidDisputeState {
+                tx_id: 1,
+                state: DisputeState::Valid,
+                ..
+            })
+        ));
+        let error = engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 3,
+                amount: 5_000,
+                currency: None,
+            })
+            .unwrap_err();
+        assert!(matches!(error, EngineError::Validation(_)));
+        assert_eq!(error.to_string(), "Above the limit");
+    }
+
+    #[test]
+    fn test_snapshot_restore_round_trip() {
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 2,
+                tx_id: 2,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Engine::restore(snapshot.as_slice()).unwrap();
+        let mut accounts: Vec<AccountView> = restored.accounts().collect();
+        accounts.sort_by_key(|account| account.client_id);
+        let mut expected: Vec<AccountView> = engine.accounts().collect();
+        expected.sort_by_key(|account| account.client_id);
+        assert_eq!(accounts, expected);
+        // Tx ids and disputes carry over
+        assert!(restored.has_seen_tx(2));
+        assert!(restored.has_seen_tx(3).not());
+        assert!(matches!(
+            restored.process_transaction(Transaction::Deposit {
+                client_id: 3,
+                tx_id: 2,
+                amount: 10,
+                currency: None,
+            }),
+            Err(EngineError::DuplicateTxId { tx_id: 2 })
+        ));
+        restored
+            .process_transaction(Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        assert!(restored.account(1).unwrap().locked);
+
+        snapshot[4] += 1;
+        assert!(Engine::restore(snapshot.as_slice()).is_err());
+        assert!(Engine::restore(b"type,client,tx,amount\n".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_message_pack_snapshot() {
+        let usd = "USD".parse().unwrap();
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 2,
+                amount: 50,
+                currency: Some(usd),
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        engine
+            .snapshot_as(SnapshotFormat::MessagePack, &mut snapshot)
+            .unwrap();
+        assert_eq!(
+            SnapshotFormat::detect(&snapshot),
+            SnapshotFormat::MessagePack
+        );
+
+        let restored = Engine::restore(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.accounts().collect::<Vec<_>>(),
+            engine.accounts().collect::<Vec<_>>()
+        );
+        assert!(restored.has_seen_tx(2));
+        assert_eq!(restored.disputes(1).len(), 1);
+
+        // Snapshots of later versions with fields this one doesn't know are read, unless they
+        // say this version can't
+        #[derive(Serialize)]
+        struct LaterState<'a> {
+            #[serde(flatten)]
+            state: SnapshotStateRef<'a>,
+            risk_scores: Vec<u32>,
+        }
+        let tx_ids: TxIdBitmap = engine.tx_ids.tx_ids().unwrap().collect();
+        let later = |compatible_version| {
+            let envelope = Envelope {
+                kind: ENVELOPE_KIND.to_string(),
+                version: ENVELOPE_VERSION + 1,
+                compatible_version,
+                balance_bits: Balance::BITS,
+                state: LaterState {
+                    state: SnapshotStateRef {
+                        accounts: engine.accounts.iter().collect(),
+                        tx_ids: &tx_ids,
+                        deposit_order: &engine.deposit_order,
+                        next_deposit_seq: engine.next_deposit_seq,
+                    },
+                    risk_scores: vec![7],
+                },
+            };
+            rmp_serde::to_vec_named(&envelope).unwrap()
+        };
+        let restored = Engine::restore(later(ENVELOPE_VERSION).as_slice()).unwrap();
+        assert_eq!(restored.account_in(1, Some(usd)).unwrap().held_amount, 50);
+        let error = Engine::restore(later(ENVELOPE_VERSION + 1).as_slice())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Engine snapshot version 2 can only be read by version 2 or later, this is version 1"
+        );
+
+        assert!(Engine::restore(b"\x81\xa4kind\xa3foo".as_slice()).is_err());
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn test_mapped_snapshot() {
+        let usd = "USD".parse().unwrap();
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 3,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 2,
+                tx_id: 1,
+                amount: 50,
+                currency: Some(usd),
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 30,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 4,
+                amount: 20,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 4,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        engine
+            .snapshot_as(SnapshotFormat::Mapped, &mut snapshot)
+            .unwrap();
+        assert_eq!(SnapshotFormat::detect(&snapshot), SnapshotFormat::Mapped);
+        // The 3 settled transactions follow the accounts as records, the disputed one doesn't
+        let records: Vec<_> = snapshot[snapshot.len() - 3 * MAPPED_RECORD_SIZE..]
+            .chunks_exact(MAPPED_RECORD_SIZE)
+            .map(|record| read_mapped_record(record.try_into().unwrap()).unwrap())
+            .map(|(tx_id, transaction)| (tx_id, transaction.client_id))
+            .collect();
+        assert_eq!(records, [(1, 2), (2, 1), (3, 1)]);
+
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-mapped-test-{}.bin",
+            std::process::id()
+        ));
+        fs::write(&path, &snapshot).unwrap();
+        let sorted_accounts = |engine: &Engine| {
+            let mut accounts: Vec<_> = engine.accounts().collect();
+            accounts.sort_by_key(|account| account.client_id);
+            accounts
+        };
+        for mut restored in [
+            Engine::restore(snapshot.as_slice()).unwrap(),
+            Engine::restore_mapped(&path).unwrap(),
+        ] {
+            assert_eq!(sorted_accounts(&restored), sorted_accounts(&engine));
+            assert!(restored.check_invariants().is_empty());
+            // Snapshots of the restored engine are the same, in any format
+            for format in [SnapshotFormat::Mapped, SnapshotFormat::Bincode] {
+                let (mut expected, mut actual) = (Vec::new(), Vec::new());
+                engine.snapshot_as(format, &mut expected).unwrap();
+                restored.snapshot_as(format, &mut actual).unwrap();
+                assert_eq!(actual, expected);
+            }
+
+            // Mapped deposits can be disputed and charged back, and their tx ids aren't reused
+            for transaction in [
+                Transaction::Dispute {
+                    client_id: 2,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Transaction::Chargeback {
+                    client_id: 2,
+                    tx_id: 1,
+                    currency: None,
+                },
+            ] {
+                restored.process_transaction(transaction).unwrap();
+            }
+            let view = restored.account_in(2, Some(usd)).unwrap();
+            assert_eq!((view.total_amount, view.locked), (0, true));
+            let error = restored
+                .process_transaction(Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 3,
+                    amount: 1,
+                    currency: None,
+                })
+                .unwrap_err();
+            assert!(matches!(error, EngineError::DuplicateTxId { tx_id: 3 }));
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(Engine::restore(&snapshot[..snapshot.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut engine = Engine::with_config(EngineConfig {
+            locked_account_policy: LockedAccountPolicy::HoldDeposits,
+            ..EngineConfig::default()
+        });
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 2,
+                amount: 30,
+                currency: None,
+            },
+            Transaction::Hold {
+                client_id: 1,
+                tx_id: 3,
+                amount: 20,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 4,
+                amount: 5,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert_eq!(engine.account(1).unwrap().held_amount, 25);
+        assert!(engine.check_invariants().is_empty());
+
+        let account = engine.accounts.get_mut(&1).unwrap();
+        account.locked = false;
+        account.balances.get_mut(&None).unwrap().held_amount = 20;
+        let moved = account.transactions.remove(&1).unwrap();
+        engine.accounts.insert(2, Account::new());
+        engine
+            .accounts
+            .get_mut(&2)
+            .unwrap()
+            .transactions
+            .insert(4, moved);
+        assert_eq!(
+            engine.check_invariants(),
+            [
+                "Client 1: tx 4 is held but the account isn't locked",
+                "Client 1: holds 20 in the default currency, but its disputes and holds add up \
+                to 25",
+                "Client 2: tx 4 also belongs to client 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_batch() {
+        let deposit = |client_id, tx_id| Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount: 10,
+            currency: None,
+        };
+        let mut engine = Engine::new();
+        let result = engine.process_batch([
+            deposit(1, 1),
+            deposit(2, 1),
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 20,
+                currency: None,
+            },
+            deposit(1, 3),
+        ]);
+
+        // Transactions past the rejected ones are still applied
+        assert_eq!(result.outcomes.len(), 4);
+        assert_eq!(result.applied(), 2);
+        assert!(result.is_clean().not());
+        let rejections: Vec<_> = result.rejections().collect();
+        assert_eq!(rejections.len(), 2);
+        assert!(matches!(
+            rejections[0],
+            (1, EngineError::DuplicateTxId { tx_id: 1 })
+        ));
+        assert!(matches!(rejections[1], (2, EngineError::InsufficientFunds)));
+        assert_eq!(engine.account(1).unwrap().available_amount, 20);
+        assert!(engine.process_batch([deposit(3, 4)]).is_clean());
+    }
+
+    #[test]
+    fn test_engine_with_capacity() {
+        let mut engine = Engine::with_capacity(100, 1_000);
+        assert!(engine.accounts.capacity() >= 100);
+        for tx_id in 1..=3 {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id: 1,
+                    tx_id,
+                    amount: 10,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        assert!(engine.accounts[&1].transactions.capacity() >= 10);
+        assert_eq!(engine.account(1).unwrap().available_amount, 30);
+
+        // Shards give their new accounts as much room
+        #[cfg(feature = "csv")]
+        assert_eq!(engine.shard().transactions_per_account, 10);
+    }
+
+    #[test]
+    fn test_bloom_filter_tx_id_index() {
+        let deposit = |tx_id| Transaction::Deposit {
+            client_id: 1,
+            tx_id,
+            amount: 10,
+            currency: None,
+        };
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1)).unwrap();
+        // Tx ids seen so far move over to the new index
+        engine.set_tx_id_index(TxIdBloomFilter::new(1_000, 0.001));
+        assert!(engine.has_seen_tx(1));
+        engine.process_transaction(deposit(2)).unwrap();
+        assert!(matches!(
+            engine.process_transaction(deposit(1)),
+            Err(EngineError::DuplicateTxId { tx_id: 1 })
+        ));
+        assert!(matches!(
+            engine.process_transaction(deposit(2)),
+            Err(EngineError::DuplicateTxId { tx_id: 2 })
+        ));
+        assert_eq!(engine.account(1).unwrap().available_amount, 20);
+
+        // Its tx ids can't be listed, so can't be snapshotted
+        assert!(engine.snapshot(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_strict_ordering_per_client() {
+        let mut engine = Engine::new();
+        let deposit = |client_id, tx_id| Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount: 10,
+            currency: None,
+        };
+
+        // Without strict ordering timestamps are only recorded
+        engine
+            .process_transaction_at(deposit(1, 1), Some(2_000))
+            .unwrap();
+        engine
+            .process_transaction_at(deposit(1, 2), Some(1_000))
+            .unwrap();
+        engine.process_transaction(deposit(1, 3)).unwrap();
+
+        engine.set_config(EngineConfig {
+            strict_ordering: true,
+            ..EngineConfig::default()
+        });
+        assert!(matches!(
+            engine.process_transaction_at(deposit(1, 4), Some(1_500)),
+            Err(EngineError::OutOfOrder {
+                timestamp: 1_500,
+                last_timestamp: 2_000
+            })
+        ));
+        assert!(matches!(
+            engine.process_transaction(deposit(1, 5)),
+            Err(EngineError::MissingTimestamp)
+        ));
+        engine
+            .process_transaction_at(deposit(1, 6), Some(2_000))
+            .unwrap();
+        // Other clients have their own order
+        engine
+            .process_transaction_at(deposit(2, 7), Some(1_000))
+            .unwrap();
+        // A rejected transaction doesn't move the client's last timestamp
+        assert!(engine
+            .process_transaction_at(
+                Transaction::Withdrawal {
+                    client_id: 2,
+                    tx_id: 8,
+                    amount: 1_000,
+                    currency: None,
+                },
+                Some(5_000),
+            )
+            .is_err());
+        engine
+            .process_transaction_at(deposit(2, 9), Some(1_000))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available_amount, 40);
+        assert_eq!(engine.account(2).unwrap().available_amount, 20);
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        const DAY: u64 = 24 * 60 * 60 * 1000;
+        let mut engine = Engine::with_config(EngineConfig {
+            dispute_window: Some(Duration::from_millis(90 * DAY)),
+            ..EngineConfig::default()
+        });
+        for (transaction, timestamp) in [
+            (
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100,
+                    currency: None,
+                },
+                Some(0),
+            ),
+            (
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: 100,
+                    currency: None,
+                },
+                Some(10 * DAY),
+            ),
+            (
+                Transaction::Withdrawal {
+                    client_id: 1,
+                    tx_id: 3,
+                    amount: 50,
+                    currency: None,
+                },
+                None,
+            ),
+        ] {
+            engine
+                .process_transaction_at(transaction, timestamp)
+                .unwrap();
+        }
+
+        let dispute = |tx_id| Transaction::Dispute {
+            client_id: 1,
+            tx_id,
+            currency: None,
+        };
+        assert!(matches!(
+            engine.process_transaction_at(dispute(1), Some(90 * DAY + 1)),
+            Err(EngineError::DisputeWindowExpired { tx_id: 1 })
+        ));
+        assert!(matches!(
+            engine.process_transaction(dispute(2)),
+            Err(EngineError::MissingTimestamp)
+        ));
+        engine
+            .process_transaction_at(dispute(2), Some(100 * DAY))
+            .unwrap();
+        // Transactions without a timestamp can't be too old
+        engine
+            .process_transaction_at(dispute(3), Some(1_000 * DAY))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().held_amount, 150);
+    }
+
+    #[test]
+    fn test_unlock_account_after_review() {
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 2,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+
+        assert!(matches!(
+            engine.unlock_account(2),
+            Err(EngineError::AccountNotFound { .. })
+        ));
+        assert!(matches!(
+            engine.unlock_account(1),
+            Err(EngineError::ActiveDispute { tx_id: 2 })
+        ));
+        engine
+            .process_transaction(Transaction::Resolve {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            })
+            .unwrap();
+        engine
+            .process_transaction(Transaction::Unlock {
+                client_id: 1,
+                tx_id: 3,
+            })
+            .unwrap();
+        assert!(engine.account(1).unwrap().locked.not());
+        assert!(matches!(
+            engine.unlock_account(1),
+            Err(EngineError::AccountNotLocked)
+        ));
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 4,
+                amount: 10,
+                currency: None,
+            })
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available_amount, 60);
+    }
+
+    #[test]
+    fn test_chargeback_lock_policies() {
+        let charge_back = |engine: &mut Engine, tx_id: u32| {
+            for transaction in [
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id,
+                    amount: 10,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id,
+                    currency: None,
+                },
+                Transaction::Chargeback {
+                    client_id: 1,
+                    tx_id,
+                    currency: None,
+                },
+            ] {
+                engine.process_transaction(transaction).unwrap();
+            }
+        };
+
+        let mut engine = Engine::with_config(EngineConfig {
+            chargeback_lock_policy: ChargebackLockPolicy::AfterChargebacks(2),
+            ..EngineConfig::default()
+        });
+        let locked = Arc::new(Mutex::new(Vec::new()));
+        let listener_locked = Arc::clone(&locked);
+        engine.on_account_locked(move |event| listener_locked.lock().unwrap().push(event.tx_id));
+        charge_back(&mut engine, 1);
+        assert!(engine.account(1).unwrap().locked.not());
+        assert_eq!(engine.chargeback_count(1), 1);
+        charge_back(&mut engine, 2);
+        assert!(engine.account(1).unwrap().locked);
+        assert_eq!(engine.chargeback_count(1), 2);
+        assert_eq!(*locked.lock().unwrap(), vec![2]);
+
+        let mut engine = Engine::with_config(EngineConfig {
+            chargeback_lock_policy: ChargebackLockPolicy::Never,
+            ..EngineConfig::default()
+        });
+        for tx_id in 1..=3 {
+            charge_back(&mut engine, tx_id);
+        }
+        assert!(engine.account(1).unwrap().locked.not());
+        assert_eq!(engine.account(1).unwrap().available_amount, 0);
+        assert_eq!(engine.chargeback_count(1), 3);
+        assert_eq!(engine.chargeback_count(2), 0);
+    }
+
+    #[test]
+    fn test_negative_balance_policies() {
+        let engine_with_spent_deposit = |policy| {
+            let mut engine = Engine::with_config(EngineConfig {
+                negative_balance_policy: policy,
+                ..EngineConfig::default()
+            });
+            for transaction in [
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Withdrawal {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: 70,
+                    currency: None,
+                },
+            ] {
+                engine.process_transaction(transaction).unwrap();
+            }
+            engine
+        };
+        let dispute = |engine: &mut Engine| {
+            engine.process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+        };
+        let balances = |engine: &Engine| {
+            let account = engine.account(1).unwrap();
+            (account.available_amount, account.held_amount)
+        };
+
+        let mut engine = engine_with_spent_deposit(NegativeBalancePolicy::Allow);
+        dispute(&mut engine).unwrap();
+        assert_eq!(balances(&engine), (-70, 100));
+
+        // Only the available funds are held, the rest being the dispute's shortfall
+        let mut engine = engine_with_spent_deposit(NegativeBalancePolicy::Clamp);
+        dispute(&mut engine).unwrap();
+        assert_eq!(balances(&engine), (0, 30));
+        assert_eq!(engine.disputes(1)[0].shortfall, 70);
+        engine
+            .process_transaction(Transaction::Resolve {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        assert_eq!(balances(&engine), (30, 0));
+        assert!(engine.disputes(1).is_empty());
+        dispute(&mut engine).unwrap();
+        engine
+            .process_transaction(Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        assert_eq!(balances(&engine), (0, 0));
+        assert_eq!(engine.disputes(1)[0].shortfall, 70);
+
+        let mut engine = engine_with_spent_deposit(NegativeBalancePolicy::Reject);
+        assert!(matches!(
+            dispute(&mut engine),
+            Err(EngineError::DisputedFundsSpent { tx_id: 1 })
+        ));
+        assert_eq!(balances(&engine), (30, 0));
+        // Withdrawals can still be disputed, not taking any available funds
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            })
+            .unwrap();
+        assert_eq!(balances(&engine), (30, 70));
+    }
+
+    #[test]
+    fn test_withdraw_from_held() {
+        let mut account = Account::new();
+        account
+            .deposit(1, 100, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account
+            .deposit(2, 50, None, None, LockedAccountPolicy::RejectAll)
+            .unwrap();
+        account
+            .start_dispute(1, None, None, NegativeBalancePolicy::Allow)
+            .unwrap();
+        assert_eq!(account.balances(None).available_amount, 50);
+        assert_eq!(account.balances(None).held_amount, 100);
+
+        // Only available funds can be withdrawn by default
+        assert!(matches!(
+            account.withdraw(3, 80, None, None, false),
+            Err(EngineError::InsufficientFunds)
+        ));
+
+        // Held funds make up for the available ones, but the total can't go negative
+        account.withdraw(3, 80, None, None, true).unwrap();
+        assert_eq!(account.balances(None).available_amount, -30);
+        assert_eq!(account.balances(None).held_amount, 100);
+        assert!(matches!(
+            account.withdraw(4, 71, None, None, true),
+            Err(EngineError::InsufficientFunds)
+        ));
+        account.withdraw(4, 70, None, None, true).unwrap();
+        assert_eq!(account.balances(None).available_amount, -100);
+
+        // The dispute still settles the held funds as usual
+        account.resolve_dispute(1, None).unwrap();
+        assert_eq!(account.balances(None).available_amount, 0);
+        assert_eq!(account.balances(None).held_amount, 0);
+
+        let mut engine = Engine::with_config(EngineConfig {
+            withdraw_from_held: true,
+            ..EngineConfig::default()
+        });
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 100,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert_eq!(engine.account(1).unwrap().available_amount, -100);
+        assert_eq!(engine.account(1).unwrap().total_amount, 0);
+    }
+
+    #[test]
+    fn test_locked_account_policies() {
+        let lock = |engine: &mut Engine| {
+            for transaction in [
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100,
+                    currency: None,
+                },
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Transaction::Chargeback {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+            ] {
+                engine.process_transaction(transaction).unwrap();
+            }
+        };
+        let deposit = |tx_id| Transaction::Deposit {
+            client_id: 1,
+            tx_id,
+            amount: 40,
+            currency: None,
+        };
+        let withdrawal = |tx_id| Transaction::Withdrawal {
+            client_id: 1,
+            tx_id,
+            amount: 10,
+            currency: None,
+        };
+
+        let mut engine = Engine::new();
+        lock(&mut engine);
+        assert!(matches!(
+            engine.process_transaction(deposit(2)),
+            Err(EngineError::AccountLocked { .. })
+        ));
+
+        let mut engine = Engine::with_config(EngineConfig {
+            locked_account_policy: LockedAccountPolicy::AllowDeposits,
+            ..EngineConfig::default()
+        });
+        lock(&mut engine);
+        engine.process_transaction(deposit(2)).unwrap();
+        assert!(engine.process_transaction(withdrawal(4)).is_err());
+        assert_eq!(engine.account(1).unwrap().available_amount, 40);
+
+        let mut engine = Engine::with_config(EngineConfig {
+            locked_account_policy: LockedAccountPolicy::HoldDeposits,
+            ..EngineConfig::default()
+        });
+        lock(&mut engine);
+        engine.process_transaction(deposit(2)).unwrap();
+        engine.process_transaction(deposit(3)).unwrap();
+        assert!(engine.process_transaction(withdrawal(4)).is_err());
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available_amount, account.held_amount), (0, 80));
+        // Disputing a held deposit keeps its funds held, and charging it back takes them out
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 3,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 3,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available_amount, account.held_amount), (0, 40));
+        // Unlocking releases the held deposits
+        engine.unlock_account(1).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available_amount, account.held_amount), (40, 0));
+        engine.process_transaction(withdrawal(5)).unwrap();
+    }
+
+    #[test]
+    fn test_multi_currency_accounts() {
+        let usd = Some("USD".parse().unwrap());
+        let eur = Some("eur".parse().unwrap());
+        let mut engine = Engine::new();
+        for (tx_id, amount, currency) in [(1, 100, usd), (2, 50, eur), (3, 10, None)] {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id: 1,
+                    tx_id,
+                    amount,
+                    currency,
+                })
+                .unwrap();
+        }
+
+        // Withdrawals only draw on the balance in their currency
+        assert!(matches!(
+            engine.process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 4,
+                amount: 60,
+                currency: eur,
+            }),
+            Err(EngineError::InsufficientFunds)
+        ));
+        engine
+            .process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 5,
+                amount: 60,
+                currency: usd,
+            })
+            .unwrap();
+
+        // Disputes either have the referenced deposit's currency or none
+        assert!(matches!(
+            engine.process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: eur,
+            }),
+            Err(EngineError::CurrencyMismatch { tx_id: 1, .. })
+        ));
+        let dispute = Transaction::Dispute {
+            client_id: 1,
+            tx_id: 1,
+            currency: None,
+        };
+        engine.process_transaction(dispute.clone()).unwrap();
+        assert_eq!(
+            engine.account_for(&dispute).unwrap(),
+            engine.account_in(1, usd).unwrap()
+        );
+        engine
+            .process_transaction(Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: usd,
+            })
+            .unwrap();
+
+        // The chargeback locks the account in every currency
+        assert_eq!(
+            engine.accounts().collect::<Vec<_>>(),
+            [
+                AccountView {
+                    client_id: 1,
+                    currency: None,
+                    available_amount: 10,
+                    held_amount: 0,
+                    total_amount: 10,
+                    locked: true,
+                },
+                AccountView {
+                    client_id: 1,
+                    currency: eur,
+                    available_amount: 50,
+                    held_amount: 0,
+                    total_amount: 50,
+                    locked: true,
+                },
+                AccountView {
+                    client_id: 1,
+                    currency: usd,
+                    available_amount: -60,
+                    held_amount: 0,
+                    total_amount: -60,
+                    locked: true,
+                },
+            ]
+        );
+        assert_eq!(engine.account(1), engine.account_in(1, None));
+        assert!(engine.account_in(1, Some("GBP".parse().unwrap())).is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-balances"))]
+    fn test_balance_overflow() {
+        let max = i64::MAX as u64;
+        let mut engine = Engine::new();
+        engine.set_suspense_account(u16::MAX);
+
+        // An account isn't opened by a deposit that can't be applied
+        assert!(matches!(
+            engine.process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: u64::MAX,
+                currency: None,
+            }),
+            Err(EngineError::BalanceOverflow)
+        ));
+        assert!(engine.account(1).is_none());
+
+        for (client_id, tx_id) in [(1, 2), (2, 3)] {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id,
+                    tx_id,
+                    amount: max,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        assert!(matches!(
+            engine.process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 4,
+                amount: 1,
+                currency: None,
+            }),
+            Err(EngineError::BalanceOverflow)
+        ));
+        // Withdrawing more than an `i64` can hold is no different from any other withdrawal
+        // above the available funds
+        assert!(matches!(
+            engine.process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 5,
+                amount: u64::MAX,
+                currency: None,
+            }),
+            Err(EngineError::InsufficientFunds)
+        ));
+        assert_eq!(engine.account(1).unwrap().total_amount, i64::MAX);
+
+        // Disputing a withdrawal would credit the held balance beyond what the total can hold
+        engine
+            .process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 6,
+                amount: max,
+                currency: None,
+            })
+            .unwrap();
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 7,
+                amount: max,
+                currency: None,
+            })
+            .unwrap();
+        assert!(matches!(
+            engine.process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 6,
+                currency: None,
+            }),
+            Err(EngineError::BalanceOverflow)
+        ));
+        assert!(engine.disputes(1).is_empty());
+
+        // The suspense account can't be credited with both chargebacks, so the second one is
+        // rejected before it touches the client's account
+        for (client_id, tx_id) in [(1, 7), (2, 3)] {
+            engine
+                .process_transaction(Transaction::Dispute {
+                    client_id,
+                    tx_id,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 7,
+                currency: None,
+            })
+            .unwrap();
+        assert!(matches!(
+            engine.process_transaction(Transaction::Chargeback {
+                client_id: 2,
+                tx_id: 3,
+                currency: None,
+            }),
+            Err(EngineError::BalanceOverflow)
+        ));
+        assert_eq!(
+            engine.account(2).unwrap(),
+            AccountView {
+                client_id: 2,
+                currency: None,
+                available_amount: 0,
+                held_amount: max,
+                total_amount: i64::MAX,
+                locked: false,
+            }
+        );
+        assert_eq!(engine.account(u16::MAX).unwrap().available_amount, i64::MAX);
+    }
+
+    #[test]
+    #[cfg(feature = "wide-balances")]
+    fn test_wide_balances() {
+        let mut engine = Engine::new();
+        for tx_id in 1..=3 {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id: 1,
+                    tx_id,
+                    amount: u64::MAX,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        let expected = AccountView {
+            client_id: 1,
+            currency: None,
+            available_amount: 2 * Balance::from(u64::MAX),
+            held_amount: u64::MAX.into(),
+            total_amount: 3 * Balance::from(u64::MAX),
+            locked: false,
+        };
+        assert_eq!(engine.account(1).unwrap(), expected);
+
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let restored = Engine::restore(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.account(1).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_transaction_store() {
+        let mut engine = Engine::new();
+        engine
+            .set_transaction_store(MemoryStore::default(), 1)
+            .unwrap();
+        let usd = "USD".parse().unwrap();
+        for (client_id, tx_id, currency) in [(1, 1, Some(usd)), (1, 2, None), (2, 3, None)] {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id,
+                    tx_id,
+                    amount: 100,
+                    currency,
+                })
+                .unwrap();
+        }
+        let in_memory = |engine: &Engine, client_id: u16, tx_id: u32| {
+            engine.accounts[&client_id]
+                .transactions
+                .contains_key(&tx_id)
+        };
+        assert!(in_memory(&engine, 1, 1).not());
+        assert!(in_memory(&engine, 1, 2));
+        assert!(in_memory(&engine, 2, 3));
+
+        // The currency of a dispute without one is that of the deposit in the store
+        let dispute = Transaction::Dispute {
+            client_id: 1,
+            tx_id: 1,
+            currency: None,
+        };
+        assert_eq!(engine.account_for(&dispute).unwrap().currency, Some(usd));
+        engine.process_transaction(dispute).unwrap();
+        assert!(in_memory(&engine, 1, 1));
+        assert!(in_memory(&engine, 1, 2).not());
+        assert_eq!(engine.account_in(1, Some(usd)).unwrap().held_amount, 100);
+
+        // Transactions in the store are only found for their own client
+        let error = engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 2,
+                tx_id: 2,
+                currency: None,
+            })
+            .unwrap_err();
+        assert!(matches!(error, EngineError::UnknownTx { tx_id: 2, .. }));
+
+        // Transactions in dispute stay in memory, and chargebacks too
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: 2,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 2,
+                tx_id: 4,
+                amount: 100,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert!(in_memory(&engine, 1, 1));
+        assert!(in_memory(&engine, 2, 3).not());
+        assert_eq!(engine.disputes(1).len(), 1);
+        assert_eq!(engine.account(1).unwrap().available_amount, 100);
+        assert!(engine.account(1).unwrap().locked);
+
+        // Snapshots hold the transactions in the store too
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let restored = Engine::restore(snapshot.as_slice()).unwrap();
+        assert!(in_memory(&restored, 2, 3));
+        assert!(restored.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn test_account_store() {
+        let mut engine = Engine::with_config(EngineConfig {
+            keep_history: true,
+            ..EngineConfig::default()
+        });
+        engine.set_suspense_account(9);
+        engine
+            .set_account_store(MemoryAccountStore::default(), 2)
+            .unwrap();
+        for client_id in 1..=5 {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id,
+                    tx_id: client_id.into(),
+                    amount: 100,
+                    currency: None,
+                })
+                .unwrap();
+            assert!(engine.accounts.len() <= 2);
+        }
+        assert!(engine.accounts.contains_key(&1).not());
+
+        // Accounts in the store are read back, and moved back to memory when a transaction
+        // targets them, the suspense account along with a chargeback's
+        assert_eq!(engine.account(1).unwrap().available_amount, 100);
+        assert_eq!(engine.client_statement(1).len(), 1);
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 2,
+                tx_id: 6,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert!(engine.accounts.len() <= 3);
+        let account = engine.account(1).unwrap();
         assert!(account.locked);
+        assert_eq!(account.total_amount, 0);
+        assert_eq!(engine.account(9).unwrap().available_amount, 100);
+        assert_eq!(engine.chargeback_count(1), 1);
+        assert_eq!(engine.disputes(1).len(), 1);
+
+        let mut accounts: Vec<_> = engine.accounts().map(|account| account.client_id).collect();
+        accounts.sort();
+        assert_eq!(accounts, [1, 2, 3, 4, 5, 9]);
+        assert!(engine.check_invariants().is_empty());
+
+        // Snapshots hold the accounts in the store too, as does a new store
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let restored = Engine::restore(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.accounts.len(), 6);
+        assert_eq!(restored.account(2).unwrap().available_amount, 150);
+        engine
+            .set_account_store(MemoryAccountStore::default(), 1)
+            .unwrap();
+        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.accounts().count(), 6);
+    }
+
+    #[test]
+    fn test_deposit_eviction() {
+        let mut engine = Engine::with_config(EngineConfig {
+            max_deposits_per_account: Some(2),
+            max_deposits: Some(3),
+            ..EngineConfig::default()
+        });
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener_evicted = Arc::clone(&evicted);
+        engine.on_deposit_evicted(move |event: &DepositEvictedEvent| {
+            listener_evicted
+                .lock()
+                .unwrap()
+                .push((event.client_id, event.tx_id))
+        });
+        let deposit = |client_id, tx_id| Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount: 100,
+            currency: None,
+        };
+        let dispute = |client_id, tx_id| Transaction::Dispute {
+            client_id,
+            tx_id,
+            currency: None,
+        };
+
+        engine.process_transaction(deposit(1, 1)).unwrap();
+        engine.process_transaction(deposit(1, 2)).unwrap();
+        engine.process_transaction(dispute(1, 1)).unwrap();
+        // The oldest deposit is in dispute, so the next one is evicted instead
+        engine.process_transaction(deposit(1, 3)).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![(1, 2)]);
+        assert!(matches!(
+            engine.process_transaction(dispute(1, 2)),
+            Err(EngineError::UnknownTx { tx_id: 2, .. })
+        ));
+
+        // Evicted deposits keep their funds
+        assert_eq!(engine.account(1).unwrap().available_amount, 200);
+        assert_eq!(engine.account(1).unwrap().held_amount, 100);
+
+        // Past the global cap, the oldest deposit of any client is evicted
+        engine.process_transaction(deposit(2, 4)).unwrap();
+        engine.process_transaction(deposit(2, 5)).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![(1, 2), (1, 3)]);
+
+        // The order of deposits survives snapshots
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = Engine::restore(snapshot.as_slice()).unwrap();
+        restored.set_config(engine.config().clone());
+        restored.process_transaction(deposit(3, 6)).unwrap();
+        assert!(matches!(
+            restored.process_transaction(dispute(2, 4)),
+            Err(EngineError::UnknownTx { tx_id: 4, .. })
+        ));
+        restored.process_transaction(dispute(2, 5)).unwrap();
+        restored.process_transaction(dispute(3, 6)).unwrap();
     }
 }
@@ -0,0 +1,491 @@
+use crate::ledger::{BalanceError, DisputeError, DisputeState};
+use crate::transaction::RawTransactionType;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+
+/// Why the engine rejected a transaction
+#[derive(Debug)]
+pub enum EngineError {
+    /// A validator registered with `Engine::add_validator` rejected the transaction
+    Validation(anyhow::Error),
+    /// The handler registered for a custom transaction's type with `Engine::register_handler`
+    /// rejected it
+    Handler(anyhow::Error),
+    /// No handler is registered for a custom transaction's type
+    UnknownTransactionType { transaction_type: String },
+    /// A custom transaction's handler tried to change the balances of a locked account
+    CustomAccountLocked { transaction_type: String },
+    /// The transaction couldn't be appended to the engine's write-ahead log
+    Wal(std::io::Error),
+    /// A deposit or withdrawal couldn't be moved to or from the engine's transaction store
+    TransactionStore(std::io::Error),
+    /// An account couldn't be moved to or from the engine's account store
+    AccountStore(std::io::Error),
+    /// The transaction targets the engine's suspense account
+    SuspenseAccount,
+    /// Strict ordering is on and the transaction has no timestamp
+    MissingTimestamp,
+    /// Strict ordering is on and the transaction's timestamp is earlier than that of the last
+    /// transaction applied to the client
+    OutOfOrder { timestamp: u64, last_timestamp: u64 },
+    /// A deposit or withdrawal reused the tx id of an earlier one
+    DuplicateTxId { tx_id: u32 },
+    /// A deposit or withdrawal targets a locked account
+    AccountLocked {
+        transaction_type: RawTransactionType,
+    },
+    /// The transaction targets a client without an account
+    AccountNotFound {
+        transaction_type: RawTransactionType,
+    },
+    /// A withdrawal or hold asked for more than the available funds
+    InsufficientFunds,
+    /// The transaction would take one of the account's balances, or its total, out of range
+    BalanceOverflow,
+    /// A dispute, resolve or chargeback references a tx id the client has no applied deposit or
+    /// withdrawal with
+    UnknownTx {
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+    },
+    /// A dispute, resolve or chargeback has a different currency than the transaction it
+    /// references
+    CurrencyMismatch {
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+    },
+    /// An unlock targets an account that isn't locked
+    AccountNotLocked,
+    /// An unlock targets an account with a deposit or withdrawal still in dispute
+    ActiveDispute { tx_id: u32 },
+    /// A dispute was filed after the engine's dispute window for the transaction it references
+    DisputeWindowExpired { tx_id: u32 },
+    /// A dispute references a deposit whose funds were spent already, which
+    /// `NegativeBalancePolicy::Reject` rejects
+    DisputedFundsSpent { tx_id: u32 },
+    /// A reversal references a deposit
+    NotAWithdrawal { tx_id: u32 },
+    /// A reversal references a withdrawal that was reversed already
+    AlreadyReversed { tx_id: u32 },
+    /// A release references a tx id the client has no hold in place with
+    UnknownHold { tx_id: u32 },
+    /// A dispute, resolve, chargeback or reversal doesn't fit the state of the transaction it
+    /// references
+    InvalidDisputeState {
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+        state: DisputeState,
+    },
+}
+
+impl EngineError {
+    /// A stable, machine-readable code for the error's kind
+    pub fn code(&self) -> &'static str {
+        self.reason().as_str()
+    }
+
+    /// The reason code of the error's kind
+    pub fn reason(&self) -> ReasonCode {
+        match self {
+            EngineError::Validation(_) => ReasonCode::RejectedByValidator,
+            EngineError::Handler(_) => ReasonCode::RejectedByHandler,
+            EngineError::UnknownTransactionType { .. } => ReasonCode::UnknownTransactionType,
+            EngineError::CustomAccountLocked { .. } => ReasonCode::AccountLocked,
+            EngineError::Wal(_) => ReasonCode::WalWriteFailed,
+            EngineError::TransactionStore(_) => ReasonCode::TransactionStoreFailed,
+            EngineError::AccountStore(_) => ReasonCode::AccountStoreFailed,
+            EngineError::SuspenseAccount => ReasonCode::SuspenseAccount,
+            EngineError::MissingTimestamp => ReasonCode::MissingTimestamp,
+            EngineError::OutOfOrder { .. } => ReasonCode::OutOfOrder,
+            EngineError::DuplicateTxId { .. } => ReasonCode::DuplicateTxId,
+            EngineError::AccountLocked { .. } => ReasonCode::AccountLocked,
+            EngineError::AccountNotFound { .. } => ReasonCode::AccountNotFound,
+            EngineError::InsufficientFunds => ReasonCode::InsufficientFunds,
+            EngineError::BalanceOverflow => ReasonCode::BalanceOverflow,
+            EngineError::UnknownTx { .. } => ReasonCode::UnknownTx,
+            EngineError::CurrencyMismatch { .. } => ReasonCode::CurrencyMismatch,
+            EngineError::InvalidDisputeState { .. } => ReasonCode::InvalidDisputeState,
+            EngineError::DisputeWindowExpired { .. } => ReasonCode::DisputeWindowExpired,
+            EngineError::DisputedFundsSpent { .. } => ReasonCode::DisputedFundsSpent,
+            EngineError::AccountNotLocked => ReasonCode::AccountNotLocked,
+            EngineError::ActiveDispute { .. } => ReasonCode::ActiveDispute,
+            EngineError::NotAWithdrawal { .. } => ReasonCode::NotAWithdrawal,
+            EngineError::AlreadyReversed { .. } => ReasonCode::AlreadyReversed,
+            EngineError::UnknownHold { .. } => ReasonCode::UnknownHold,
+        }
+    }
+
+    /// The error of a dispute, resolve, chargeback or reversal refused by the transaction it
+    /// references
+    pub(crate) fn from_dispute(
+        error: DisputeError,
+        transaction_type: RawTransactionType,
+        tx_id: u32,
+    ) -> Self {
+        match error {
+            DisputeError::Balance(error) => error.into(),
+            DisputeError::WindowExpired => EngineError::DisputeWindowExpired { tx_id },
+            DisputeError::FundsSpent => EngineError::DisputedFundsSpent { tx_id },
+            DisputeError::NotAWithdrawal => EngineError::NotAWithdrawal { tx_id },
+            DisputeError::AlreadyReversed => EngineError::AlreadyReversed { tx_id },
+            DisputeError::InvalidState(state) => EngineError::InvalidDisputeState {
+                transaction_type,
+                tx_id,
+                state,
+            },
+        }
+    }
+}
+
+impl From<BalanceError> for EngineError {
+    fn from(error: BalanceError) -> Self {
+        match error {
+            BalanceError::InsufficientFunds => EngineError::InsufficientFunds,
+            BalanceError::Overflow => EngineError::BalanceOverflow,
+        }
+    }
+}
+
+/// Machine-readable reason a transaction or row was rejected, serialized as its `code`, such as
+/// `duplicate_tx_id`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCode {
+    RejectedByValidator,
+    WalWriteFailed,
+    TransactionStoreFailed,
+    AccountStoreFailed,
+    SuspenseAccount,
+    MissingTimestamp,
+    OutOfOrder,
+    DuplicateTxId,
+    AccountLocked,
+    AccountNotFound,
+    InsufficientFunds,
+    BalanceOverflow,
+    UnknownTx,
+    CurrencyMismatch,
+    InvalidDisputeState,
+    DisputeWindowExpired,
+    DisputedFundsSpent,
+    AccountNotLocked,
+    ActiveDispute,
+    NotAWithdrawal,
+    AlreadyReversed,
+    UnknownHold,
+    RejectedByHandler,
+    UnknownTransactionType,
+    /// A row that couldn't be parsed into a transaction, which the engine never saw
+    InvalidRow,
+}
+
+impl ReasonCode {
+    /// Every reason code, in declaration order
+    pub const ALL: [ReasonCode; 25] = [
+        ReasonCode::RejectedByValidator,
+        ReasonCode::WalWriteFailed,
+        ReasonCode::TransactionStoreFailed,
+        ReasonCode::AccountStoreFailed,
+        ReasonCode::SuspenseAccount,
+        ReasonCode::MissingTimestamp,
+        ReasonCode::OutOfOrder,
+        ReasonCode::DuplicateTxId,
+        ReasonCode::AccountLocked,
+        ReasonCode::AccountNotFound,
+        ReasonCode::InsufficientFunds,
+        ReasonCode::BalanceOverflow,
+        ReasonCode::UnknownTx,
+        ReasonCode::CurrencyMismatch,
+        ReasonCode::InvalidDisputeState,
+        ReasonCode::DisputeWindowExpired,
+        ReasonCode::DisputedFundsSpent,
+        ReasonCode::AccountNotLocked,
+        ReasonCode::ActiveDispute,
+        ReasonCode::NotAWithdrawal,
+        ReasonCode::AlreadyReversed,
+        ReasonCode::UnknownHold,
+        ReasonCode::RejectedByHandler,
+        ReasonCode::UnknownTransactionType,
+        ReasonCode::InvalidRow,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            ReasonCode::RejectedByValidator => "rejected_by_validator",
+            ReasonCode::WalWriteFailed => "wal_write_failed",
+            ReasonCode::TransactionStoreFailed => "transaction_store_failed",
+            ReasonCode::AccountStoreFailed => "account_store_failed",
+            ReasonCode::SuspenseAccount => "suspense_account",
+            ReasonCode::MissingTimestamp => "missing_timestamp",
+            ReasonCode::OutOfOrder => "out_of_order",
+            ReasonCode::DuplicateTxId => "duplicate_tx_id",
+            ReasonCode::AccountLocked => "account_locked",
+            ReasonCode::AccountNotFound => "account_not_found",
+            ReasonCode::InsufficientFunds => "insufficient_funds",
+            ReasonCode::BalanceOverflow => "balance_overflow",
+            ReasonCode::UnknownTx => "unknown_tx",
+            ReasonCode::CurrencyMismatch => "currency_mismatch",
+            ReasonCode::InvalidDisputeState => "invalid_dispute_state",
+            ReasonCode::DisputeWindowExpired => "dispute_window_expired",
+            ReasonCode::DisputedFundsSpent => "disputed_funds_spent",
+            ReasonCode::AccountNotLocked => "account_not_locked",
+            ReasonCode::ActiveDispute => "active_dispute",
+            ReasonCode::NotAWithdrawal => "not_a_withdrawal",
+            ReasonCode::AlreadyReversed => "already_reversed",
+            ReasonCode::UnknownHold => "unknown_hold",
+            ReasonCode::RejectedByHandler => "rejected_by_handler",
+            ReasonCode::UnknownTransactionType => "unknown_transaction_type",
+            ReasonCode::InvalidRow => "invalid_row",
+        }
+    }
+}
+
+/// Errors serialize as their reason `code` and `message`, since the I/O and validator errors some
+/// carry can't be serialized themselves
+impl Serialize for EngineError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("EngineError", 2)?;
+        error.serialize_field("code", &self.reason())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+/// How error messages refer to a transaction of the given type
+fn action(transaction_type: RawTransactionType) -> &'static str {
+    match transaction_type {
+        RawTransactionType::Deposit => "A deposit",
+        RawTransactionType::Withdrawal => "An withdrawal",
+        RawTransactionType::Dispute => "A dispute start",
+        RawTransactionType::Resolve => "A dispute resolve",
+        RawTransactionType::Chargeback => "A chargeback",
+        RawTransactionType::Unlock => "An unlock",
+        RawTransactionType::Reversal => "A reversal",
+        RawTransactionType::Hold => "A hold",
+        RawTransactionType::Release => "A release",
+    }
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Validation(e) | EngineError::Handler(e) => write!(f, "{e}"),
+            EngineError::UnknownTransactionType { transaction_type } => write!(
+                f,
+                "A transaction failed because no handler is registered for its type: \
+                {transaction_type}"
+            ),
+            EngineError::CustomAccountLocked { transaction_type } => write!(
+                f,
+                "A {transaction_type} failed because the target account is locked"
+            ),
+            EngineError::Wal(e) => write!(
+                f,
+                "A transaction failed because it couldn't be written to the write-ahead log: {e}"
+            ),
+            EngineError::TransactionStore(e) => write!(
+                f,
+                "A transaction failed because the transaction store couldn't be accessed: {e}"
+            ),
+            EngineError::AccountStore(e) => write!(
+                f,
+                "A transaction failed because the account store couldn't be accessed: {e}"
+            ),
+            EngineError::SuspenseAccount => write!(
+                f,
+                "A transaction failed because it targets the suspense account"
+            ),
+            EngineError::MissingTimestamp => write!(
+                f,
+                "A transaction failed because it has no timestamp, which strict ordering requires"
+            ),
+            EngineError::OutOfOrder {
+                timestamp,
+                last_timestamp,
+            } => write!(
+                f,
+                "A transaction failed because its timestamp is earlier than the client's last one \
+                - timestamp: {timestamp} - last timestamp: {last_timestamp}"
+            ),
+            EngineError::DuplicateTxId { tx_id } => write!(
+                f,
+                "A transaction failed because it had a duplicate tx_id: {tx_id}"
+            ),
+            EngineError::AccountLocked { transaction_type } => write!(
+                f,
+                "{} failed because the target account is locked",
+                action(*transaction_type)
+            ),
+            EngineError::AccountNotFound { transaction_type } => write!(
+                f,
+                "{} failed because the target account couldn't be found",
+                action(*transaction_type)
+            ),
+            EngineError::InsufficientFunds => write!(
+                f,
+                "An withdrawal or hold failed because there wasn't enough balance"
+            ),
+            EngineError::BalanceOverflow => write!(
+                f,
+                "A transaction failed because it would overflow the target account's balance"
+            ),
+            EngineError::UnknownTx {
+                transaction_type,
+                tx_id,
+            } => write!(
+                f,
+                "{} failed because the referenced transaction couldn't be found - tx_id: {tx_id}",
+                action(*transaction_type)
+            ),
+            EngineError::CurrencyMismatch {
+                transaction_type,
+                tx_id,
+            } => write!(
+                f,
+                "{} failed because its currency doesn't match that of the referenced transaction \
+                - tx_id: {tx_id}",
+                action(*transaction_type)
+            ),
+            EngineError::AccountNotLocked => write!(
+                f,
+                "An unlock failed because the target account isn't locked"
+            ),
+            EngineError::ActiveDispute { tx_id } => write!(
+                f,
+                "An unlock failed because the target account has a transaction in an active \
+                dispute - tx_id: {tx_id}"
+            ),
+            EngineError::DisputeWindowExpired { tx_id } => write!(
+                f,
+                "A dispute start failed because the referenced transaction is older than the \
+                dispute window - tx_id: {tx_id}"
+            ),
+            EngineError::DisputedFundsSpent { tx_id } => write!(
+                f,
+                "A dispute start failed because the funds of the referenced deposit aren't \
+                available anymore - tx_id: {tx_id}"
+            ),
+            EngineError::NotAWithdrawal { tx_id } => write!(
+                f,
+                "A reversal failed because the referenced transaction isn't a withdrawal - tx_id: \
+                {tx_id}"
+            ),
+            EngineError::AlreadyReversed { tx_id } => write!(
+                f,
+                "A reversal failed because the referenced withdrawal was already reversed - \
+                tx_id: {tx_id}"
+            ),
+            EngineError::UnknownHold { tx_id } => write!(
+                f,
+                "A release failed because the target account has no hold with the tx_id: {tx_id}"
+            ),
+            EngineError::InvalidDisputeState {
+                transaction_type,
+                tx_id,
+                state,
+            } => {
+                let reason = match (transaction_type, state) {
+                    (_, DisputeState::Reversed) => "was reversed",
+                    (RawTransactionType::Dispute, _) => {
+                        "was already chargedback or is currently in an active dispute"
+                    }
+                    (RawTransactionType::Reversal, _) => {
+                        "is in an active dispute or was charged back"
+                    }
+                    _ => "wasn't in an active dispute",
+                };
+                write!(
+                    f,
+                    "{} failed because the referenced transaction {reason} - tx_id: {tx_id} - \
+                    transaction state: {state:?}",
+                    action(*transaction_type)
+                )
+            }
+        }
+    }
+}
+
+/// Why an amount couldn't be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmountError {
+    /// The amount is empty or only whitespace
+    EmptyAmount,
+    /// The amount has a minus sign
+    NegativeAmount,
+    /// The amount has more than one decimal point
+    TooManyDecimalPoints,
+    /// The amount has a character other than digits and a decimal point, such as a `+` sign or
+    /// whitespace between its digits
+    InvalidCharacter(char),
+    /// The amount has no digits before its decimal point
+    MissingIntegerPart,
+    /// The amount has more than 4 decimal places, and the rounding mode rejects such amounts
+    TooManyDecimalPlaces,
+    /// The amount doesn't fit the engine's fixed point representation
+    AmountTooLarge,
+}
+
+impl Display for AmountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmountError::EmptyAmount => write!(f, "The amount is empty"),
+            AmountError::NegativeAmount => write!(f, "The amount is negative"),
+            AmountError::TooManyDecimalPoints => {
+                write!(f, "The amount has more than one decimal point")
+            }
+            AmountError::InvalidCharacter(c) => {
+                write!(f, "The amount has an invalid character: {c:?}")
+            }
+            AmountError::MissingIntegerPart => {
+                write!(f, "The amount has no digits before its decimal point")
+            }
+            AmountError::TooManyDecimalPlaces => {
+                write!(f, "The amount has more than 4 decimal places")
+            }
+            AmountError::AmountTooLarge => write!(f, "The amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Validation(e) | EngineError::Handler(e) => Some(e.as_ref()),
+            EngineError::Wal(e)
+            | EngineError::TransactionStore(e)
+            | EngineError::AccountStore(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{EngineError, ReasonCode};
+    use serde_json::json;
+
+    #[test]
+    fn test_serialize_reason_codes() {
+        let error = EngineError::DuplicateTxId { tx_id: 7 };
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code": "duplicate_tx_id",
+                "message": "A transaction failed because it had a duplicate tx_id: 7"
+            })
+        );
+
+        for reason in [
+            error.reason(),
+            EngineError::InsufficientFunds.reason(),
+            ReasonCode::InvalidRow,
+        ] {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(json, format!("\"{}\"", reason.as_str()));
+            assert_eq!(serde_json::from_str::<ReasonCode>(&json).unwrap(), reason);
+        }
+    }
+}
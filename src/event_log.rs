@@ -0,0 +1,187 @@
+use crate::engine::AccountView;
+use crate::transaction::Transaction;
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::ops::Not;
+use tracing::error;
+
+/// Writes an append-only record of every change the engine applies to an account, one JSON object
+/// per line, with the account's balances before and after the change. The last event of every
+/// client account holds its final balances, so the record can be used to reconcile the accounts
+/// report.
+///
+/// ```json
+/// {"after":{"available":"10.0000","held":"0.0000","locked":false,"total":"10.0000"},"amount":"10.0000","before":null,"client":1,"event":"deposit","seq":1,"tx":1}
+/// ```
+///
+/// Chargebacks that lock an account are followed by an `account_locked` event. Funds moved into
+/// the suspense account aren't recorded.
+pub struct EventLog<W: Write> {
+    writer: W,
+    /// The account of the latest transaction the engine is about to process, as it was before,
+    /// or `None` for clients without an account yet
+    before: Option<AccountView>,
+    events: u64,
+}
+
+impl<W: Write> EventLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            before: None,
+            events: 0,
+        }
+    }
+
+    /// To be called right before the engine processes a transaction, with the account it's for
+    pub fn before(&mut self, account: Option<&AccountView>) {
+        self.before = account.copied();
+    }
+
+    /// To be called once the engine applied `transaction`, with the resulting account state
+    pub fn applied(&mut self, transaction: &Transaction, account: &AccountView) {
+        let before = self.before.take();
+        let (event, amount) = match transaction {
+            Transaction::Deposit { amount, .. } => ("deposit", Some(*amount)),
+            Transaction::Withdrawal { amount, .. } => ("withdrawal", Some(*amount)),
+            Transaction::Dispute { .. } => ("dispute", None),
+            Transaction::Resolve { .. } => ("resolve", None),
+            Transaction::Chargeback { .. } => ("chargeback", None),
+            Transaction::Unlock { .. } => ("unlock", None),
+            Transaction::Reversal { .. } => ("reversal", None),
+            Transaction::Hold { amount, .. } => ("hold", Some(*amount)),
+            Transaction::Release { .. } => ("release", None),
+        };
+
+        let mut json = self.event(event, transaction, account);
+        if let Some(amount) = amount {
+            json["amount"] = fixed_point_4_decimal_to_float_str(amount).into();
+        }
+        json["before"] = before.map_or(Value::Null, balances_to_json);
+        json["after"] = balances_to_json(*account);
+        self.write(json);
+
+        if account.locked && before.is_some_and(|before| before.locked).not() {
+            let json = self.event("account_locked", transaction, account);
+            self.write(json);
+        }
+    }
+
+    fn event(&mut self, event: &str, transaction: &Transaction, account: &AccountView) -> Value {
+        self.events += 1;
+        let mut json = json!({
+            "seq": self.events,
+            "event": event,
+            "client": account.client_id,
+            "tx": transaction.tx_id(),
+        });
+        if let Some(currency) = account.currency {
+            json["currency"] = currency.as_str().into();
+        }
+        json
+    }
+
+    fn write(&mut self, json: Value) {
+        if let Err(e) = writeln!(self.writer, "{json}") {
+            error!("Failed to write to event log: {e} - event: {json}");
+        }
+    }
+}
+
+fn balances_to_json(account: AccountView) -> Value {
+    json!({
+        "available": signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+        "held": fixed_point_4_decimal_to_float_str(account.held_amount),
+        "total": signed_fixed_point_4_decimal_to_float_str(account.total_amount),
+        "locked": account.locked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::event_log::EventLog;
+    use crate::transaction::Transaction;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_event_log_records_applied_transactions() {
+        let log = Arc::new(Mutex::new(EventLog::new(Vec::new())));
+        let mut engine = Engine::new();
+        let before = Arc::clone(&log);
+        engine.add_validator(move |_, account| {
+            before.lock().unwrap().before(account);
+            Ok(())
+        });
+        let applied = Arc::clone(&log);
+        engine.on_transaction_applied(move |transaction, account| {
+            applied.lock().unwrap().applied(transaction, account);
+        });
+
+        let transactions = [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 500,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ];
+        for transaction in transactions {
+            let _ = engine.process_transaction(transaction);
+        }
+
+        let log = log.lock().unwrap();
+        let events: Vec<Value> = String::from_utf8(log.writer.clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<(u64, &str, u64)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event["seq"].as_u64().unwrap(),
+                    event["event"].as_str().unwrap(),
+                    event["tx"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // The rejected withdrawal isn't recorded
+        assert_eq!(
+            summary,
+            vec![
+                (1, "deposit", 1),
+                (2, "dispute", 1),
+                (3, "chargeback", 1),
+                (4, "account_locked", 1)
+            ]
+        );
+
+        assert_eq!(events[0]["amount"], "0.0100");
+        assert_eq!(events[0]["before"], Value::Null);
+        assert_eq!(events[1]["before"]["available"], "0.0100");
+        assert_eq!(events[1]["after"]["available"], "0.0000");
+        assert_eq!(events[1]["after"]["held"], "0.0100");
+        assert_eq!(events[2]["before"]["locked"], false);
+        assert_eq!(events[2]["after"]["total"], "0.0000");
+        assert_eq!(events[2]["after"]["locked"], true);
+    }
+}
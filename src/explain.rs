@@ -0,0 +1,331 @@
+use crate::engine::{AccountView, Engine};
+use crate::input::{self, CsvRow};
+use crate::transaction::Transaction;
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Not;
+
+/// Replays a transactions csv and writes the outcome of every row with tx id `tx_id` (the deposit
+/// or withdrawal itself, and the disputes, resolves, chargebacks and reversals referencing it, or a
+/// hold and its release), tracing each rejection back to the rows that caused it.
+pub fn explain(reader: impl Read, tx_id: u32, mut writer: impl Write) -> Result<()> {
+    let mut engine = Engine::new();
+    let mut history = History::default();
+    let mut rows = 0;
+
+    input::read_csv_rows(reader, |row: CsvRow| {
+        let Ok(transaction) = row.transaction else {
+            return Ok(());
+        };
+        let involved = tx_id_of(&transaction) == tx_id;
+        let before = engine.account_for(&transaction);
+        let explained = involved.then(|| {
+            (
+                describe(&transaction),
+                history.explain_rejection(&transaction, before),
+            )
+        });
+
+        let result = engine.process_transaction(transaction.clone());
+        let after = engine.account_for(&transaction);
+        if let Some((description, explanation)) = explained {
+            rows += 1;
+            match &result {
+                Ok(()) => writeln!(writer, "line {}: {description} applied", row.line)?,
+                Err(e) => writeln!(
+                    writer,
+                    "line {}: {description} rejected ({e}) because {explanation}",
+                    row.line
+                )?,
+            }
+        }
+        history.record(row.line, tx_id, &transaction, result.is_ok(), before, after);
+        Ok(())
+    })?;
+
+    if rows == 0 {
+        writeln!(writer, "No valid row has tx id {tx_id}")?;
+    }
+    Ok(())
+}
+
+/// What's needed to explain rejections of the rows with the tx id being explained
+#[derive(Default)]
+struct History {
+    /// The first deposit, withdrawal or hold with the tx id: its line, the transaction, and
+    /// whether it was applied
+    first_use: Option<(u64, Transaction, bool)>,
+    /// The latest applied dispute, resolve, chargeback, reversal or release of the tx id, and its
+    /// line
+    last_dispute_step: Option<(u64, Transaction)>,
+    /// The line and tx id of the chargeback that locked each locked account
+    locks: HashMap<u16, (u64, u32)>,
+}
+
+impl History {
+    fn record(
+        &mut self,
+        line: u64,
+        tx_id: u32,
+        transaction: &Transaction,
+        applied: bool,
+        before: Option<AccountView>,
+        after: Option<AccountView>,
+    ) {
+        if let (
+            Transaction::Chargeback {
+                client_id, tx_id, ..
+            },
+            true,
+        ) = (transaction, applied)
+        {
+            if before.is_some_and(|account| account.locked).not()
+                && after.is_some_and(|account| account.locked)
+            {
+                self.locks.insert(*client_id, (line, *tx_id));
+            }
+        }
+        if let (Transaction::Unlock { client_id, .. }, true) = (transaction, applied) {
+            self.locks.remove(client_id);
+        }
+
+        if tx_id_of(transaction) != tx_id {
+            return;
+        }
+        match transaction {
+            Transaction::Deposit { .. }
+            | Transaction::Withdrawal { .. }
+            | Transaction::Hold { .. } => {
+                if self.first_use.is_none() {
+                    self.first_use = Some((line, transaction.clone(), applied));
+                }
+            }
+            Transaction::Unlock { .. } => {}
+            Transaction::Dispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::Release { .. } => {
+                if applied {
+                    self.last_dispute_step = Some((line, transaction.clone()));
+                }
+            }
+        }
+    }
+
+    /// Why the engine would reject `transaction`, given the state of its account beforehand.
+    /// Only meaningful when it does get rejected.
+    fn explain_rejection(&self, transaction: &Transaction, account: Option<AccountView>) -> String {
+        let client_id = transaction.client_id();
+        let locked_by = || match self.locks.get(&client_id) {
+            Some((line, tx_id)) => format!(
+                "client {client_id}'s account was locked on line {line} by the chargeback of tx \
+                {tx_id}"
+            ),
+            None => format!("client {client_id}'s account is locked"),
+        };
+
+        match *transaction {
+            Transaction::Deposit { tx_id, .. }
+            | Transaction::Withdrawal { tx_id, .. }
+            | Transaction::Hold { tx_id, .. } => {
+                if let Some((line, first, _)) = &self.first_use {
+                    return format!(
+                        "tx {tx_id} was already used by the {} on line {line}",
+                        describe(first)
+                    );
+                }
+                let Some(account) = account else {
+                    return format!("client {client_id} has no account yet, having no deposit");
+                };
+                // Holds can be placed on locked accounts
+                if account.locked && matches!(transaction, Transaction::Hold { .. }).not() {
+                    return locked_by();
+                }
+                format!(
+                    "client {client_id} only had {} available",
+                    signed_fixed_point_4_decimal_to_float_str(account.available_amount)
+                )
+            }
+            Transaction::Dispute { tx_id, .. }
+            | Transaction::Resolve { tx_id, .. }
+            | Transaction::Chargeback { tx_id, .. }
+            | Transaction::Reversal { tx_id, .. }
+            | Transaction::Release { tx_id, .. } => {
+                let (line, disputed, disputed_client_id) = match &self.first_use {
+                    None => {
+                        return format!(
+                            "no deposit, withdrawal or hold with tx {tx_id} was made before"
+                        )
+                    }
+                    Some((line, first, false)) => {
+                        return format!(
+                            "the {} with tx {tx_id} on line {line} was rejected",
+                            kind(first)
+                        )
+                    }
+                    Some((line, first, true)) => (line, first, first.client_id()),
+                };
+                if disputed_client_id != client_id {
+                    return format!(
+                        "the {} with tx {tx_id} on line {line} was made by client \
+                        {disputed_client_id}",
+                        kind(disputed)
+                    );
+                }
+                if let Transaction::Release { .. } = transaction {
+                    if kind(disputed) != "hold" {
+                        return format!(
+                            "the {} with tx {tx_id} on line {line} isn't a hold",
+                            kind(disputed)
+                        );
+                    }
+                } else if kind(disputed) == "hold" {
+                    return format!(
+                        "the hold with tx {tx_id} on line {line} isn't a deposit or withdrawal"
+                    );
+                }
+                if let Transaction::Reversal { .. } = transaction {
+                    if kind(disputed) != "withdrawal" {
+                        return format!(
+                            "the {} with tx {tx_id} on line {line} isn't a withdrawal",
+                            kind(disputed)
+                        );
+                    }
+                    if account.is_some_and(|account| account.locked) {
+                        return locked_by();
+                    }
+                }
+
+                match &self.last_dispute_step {
+                    Some((line, Transaction::Dispute { .. })) => {
+                        format!("tx {tx_id} has been in dispute since line {line}")
+                    }
+                    Some((line, Transaction::Chargeback { .. })) => {
+                        format!("tx {tx_id} was charged back on line {line}")
+                    }
+                    Some((line, Transaction::Reversal { .. })) => {
+                        format!("tx {tx_id} was reversed on line {line}")
+                    }
+                    Some((line, Transaction::Release { .. })) => {
+                        format!("hold {tx_id} was released on line {line}")
+                    }
+                    Some((line, _)) => {
+                        format!("tx {tx_id}'s dispute was resolved on line {line}")
+                    }
+                    None => format!("tx {tx_id} was never disputed"),
+                }
+            }
+            Transaction::Unlock { .. } => match account {
+                None => format!("client {client_id} has no account yet, having no deposit"),
+                Some(account) if account.locked.not() => {
+                    format!("client {client_id}'s account isn't locked")
+                }
+                Some(account) => format!(
+                    "client {client_id} still has {} held by disputes in progress",
+                    fixed_point_4_decimal_to_float_str(account.held_amount)
+                ),
+            },
+        }
+    }
+}
+
+fn tx_id_of(transaction: &Transaction) -> u32 {
+    match *transaction {
+        Transaction::Deposit { tx_id, .. }
+        | Transaction::Withdrawal { tx_id, .. }
+        | Transaction::Dispute { tx_id, .. }
+        | Transaction::Resolve { tx_id, .. }
+        | Transaction::Chargeback { tx_id, .. }
+        | Transaction::Unlock { tx_id, .. }
+        | Transaction::Reversal { tx_id, .. }
+        | Transaction::Hold { tx_id, .. }
+        | Transaction::Release { tx_id, .. } => tx_id,
+    }
+}
+
+fn kind(transaction: &Transaction) -> &'static str {
+    match transaction {
+        Transaction::Withdrawal { .. } => "withdrawal",
+        Transaction::Hold { .. } => "hold",
+        _ => "deposit",
+    }
+}
+
+fn describe(transaction: &Transaction) -> String {
+    let client_id = transaction.client_id();
+    match *transaction {
+        Transaction::Deposit { amount, .. } => format!(
+            "deposit of {} by client {client_id}",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Withdrawal { amount, .. } => format!(
+            "withdrawal of {} by client {client_id}",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Dispute { .. } => format!("dispute by client {client_id}"),
+        Transaction::Resolve { .. } => format!("resolve by client {client_id}"),
+        Transaction::Chargeback { .. } => format!("chargeback by client {client_id}"),
+        Transaction::Unlock { .. } => format!("unlock by client {client_id}"),
+        Transaction::Reversal { .. } => format!("reversal by client {client_id}"),
+        Transaction::Hold { amount, .. } => format!(
+            "hold of {} by client {client_id}",
+            fixed_point_4_decimal_to_float_str(amount)
+        ),
+        Transaction::Release { .. } => format!("release by client {client_id}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::explain::explain;
+
+    fn explain_lines(csv: &str, tx_id: u32) -> Vec<String> {
+        let mut output = Vec::new();
+        explain(csv.as_bytes(), tx_id, &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_explain_traces_rejections_back() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,1,5\n\
+            dispute,2,1,\n\
+            dispute,1,1,\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            resolve,1,1,\n\
+            deposit,1,2,5\n\
+            withdrawal,3,3,1\n";
+
+        let lines = explain_lines(csv, 1);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "line 2: deposit of 10.0000 by client 1 applied");
+        assert!(lines[1].starts_with("line 3: deposit of 5.0000 by client 2 rejected ("));
+        assert!(lines[1].ends_with(
+            "because tx 1 was already used by the deposit of 10.0000 by client 1 on line 2"
+        ));
+        assert!(lines[2].ends_with("because the deposit with tx 1 on line 2 was made by client 1"));
+        assert_eq!(lines[3], "line 5: dispute by client 1 applied");
+        assert!(lines[4].ends_with("because tx 1 has been in dispute since line 5"));
+        assert_eq!(lines[5], "line 7: chargeback by client 1 applied");
+        assert!(lines[6].ends_with("because tx 1 was charged back on line 7"));
+
+        let lines = explain_lines(csv, 2);
+        assert!(lines[0].ends_with(
+            "because client 1's account was locked on line 7 by the chargeback of tx 1"
+        ));
+
+        let lines = explain_lines(csv, 3);
+        assert!(lines[0].ends_with("because client 3 has no account yet, having no deposit"));
+
+        assert_eq!(explain_lines(csv, 4), ["No valid row has tx id 4"]);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::async_engine::EngineHandle;
+use crate::engine::{AccountView, Balance};
+use anyhow::Result;
+use arrow_array::builder::{BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use arrow_ipc::writer::IpcWriteOptions;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+/// The name of the only flight, as the path of its descriptor and its ticket
+pub const ACCOUNTS: &str = "accounts";
+
+/// Accounts per record batch, so that clients can start on the first ones while the others are
+/// still encoded
+const BATCH_SIZE: usize = 8192;
+
+/// The schema of the accounts flight. Balances are decimals with the engine's 4 decimal places,
+/// so that analytics tools read them without rounding.
+pub static ACCOUNTS_SCHEMA: LazyLock<SchemaRef> = LazyLock::new(|| {
+    let balance = DataType::Decimal128(38, 4);
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("currency", DataType::Utf8, true),
+        Field::new("available", balance.clone(), false),
+        Field::new("held", balance.clone(), false),
+        Field::new("total", balance, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+});
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The Arrow Flight service of the `grpc` mode, which serves the state of every account as record
+/// batches, for analytics tools pulling snapshots of it. Its only flight is `accounts`, by client
+/// id and currency, read from the engine when the ticket is redeemed.
+pub fn service(handle: EngineHandle) -> FlightServiceServer<AccountsFlight> {
+    FlightServiceServer::new(AccountsFlight { handle })
+}
+
+pub struct AccountsFlight {
+    handle: EngineHandle,
+}
+
+/// The accounts as record batches of the accounts schema, by client id and currency
+pub fn account_batches(mut accounts: Vec<AccountView>) -> Result<Vec<RecordBatch>, ArrowError> {
+    accounts.sort_by_key(|account| (account.client_id, account.currency));
+    accounts.chunks(BATCH_SIZE).map(account_batch).collect()
+}
+
+fn account_batch(accounts: &[AccountView]) -> Result<RecordBatch, ArrowError> {
+    let decimals =
+        || Decimal128Builder::with_capacity(accounts.len()).with_precision_and_scale(38, 4);
+    let mut clients = UInt16Builder::with_capacity(accounts.len());
+    let mut currencies = StringBuilder::new();
+    let (mut available, mut held, mut total) = (decimals()?, decimals()?, decimals()?);
+    let mut locked = BooleanBuilder::with_capacity(accounts.len());
+    for account in accounts {
+        clients.append_value(account.client_id);
+        currencies.append_option(account.currency.as_ref().map(|currency| currency.as_str()));
+        available.append_value(decimal(account.available_amount));
+        // Held funds always fit a `Balance`, see `Balances::new`
+        held.append_value(decimal(account.held_amount as Balance));
+        total.append_value(decimal(account.total_amount));
+        locked.append_value(account.locked);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(clients.finish()),
+        Arc::new(currencies.finish()),
+        Arc::new(available.finish()),
+        Arc::new(held.finish()),
+        Arc::new(total.finish()),
+        Arc::new(locked.finish()),
+    ];
+    RecordBatch::try_new(ACCOUNTS_SCHEMA.clone(), columns)
+}
+
+/// A balance as the value of a `Decimal128` with 4 decimal places
+#[cfg_attr(feature = "wide-balances", allow(clippy::useless_conversion))]
+fn decimal(balance: Balance) -> i128 {
+    balance.into()
+}
+
+impl AccountsFlight {
+    fn flight_info(&self, accounts: usize) -> Result<FlightInfo, Status> {
+        FlightInfo::new()
+            .try_with_schema(&ACCOUNTS_SCHEMA)
+            .map_err(|e| Status::internal(e.to_string()))
+            .map(|info| {
+                info.with_descriptor(FlightDescriptor::new_path(vec![ACCOUNTS.to_string()]))
+                    .with_endpoint(FlightEndpoint::new().with_ticket(Ticket::new(ACCOUNTS)))
+                    .with_total_records(accounts as i64)
+                    .with_ordered(true)
+            })
+    }
+
+    async fn account_count(&self) -> Result<usize, Status> {
+        self.handle
+            .query(|engine| engine.accounts().count())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// Checks that a descriptor is the accounts flight's
+fn check_descriptor(descriptor: &FlightDescriptor) -> Result<(), Status> {
+    match descriptor.path.as_slice() {
+        [path] if path == ACCOUNTS => Ok(()),
+        _ => Err(Status::not_found(format!(
+            "Unknown flight, the only one is [\"{ACCOUNTS}\"]"
+        ))),
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for AccountsFlight {
+    type HandshakeStream = ResponseStream<HandshakeResponse>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented(
+            "The accounts flight needs no handshake",
+        ))
+    }
+
+    type ListFlightsStream = ResponseStream<FlightInfo>;
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let info = self.flight_info(self.account_count().await?)?;
+        Ok(Response::new(Box::pin(tokio_stream::iter([Ok(info)]))))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        check_descriptor(request.get_ref())?;
+        Ok(Response::new(
+            self.flight_info(self.account_count().await?)?,
+        ))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<PollInfo>, Status> {
+        check_descriptor(request.get_ref())?;
+        let info = self.flight_info(self.account_count().await?)?;
+        Ok(Response::new(PollInfo::new().with_info(info)))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        check_descriptor(request.get_ref())?;
+        SchemaAsIpc::new(&ACCOUNTS_SCHEMA, &IpcWriteOptions::default())
+            .try_into()
+            .map(Response::new)
+            .map_err(|e: ArrowError| Status::internal(e.to_string()))
+    }
+
+    type DoGetStream = ResponseStream<FlightData>;
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        if request.get_ref().ticket != ACCOUNTS.as_bytes() {
+            return Err(Status::not_found(format!(
+                "Unknown ticket, the only one is \"{ACCOUNTS}\""
+            )));
+        }
+        let accounts = self
+            .handle
+            .accounts()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let batches = account_batches(accounts).map_err(|e| Status::internal(e.to_string()))?;
+        let flight_data = FlightDataEncoderBuilder::new()
+            .with_schema(ACCOUNTS_SCHEMA.clone())
+            .build(tokio_stream::iter(batches.into_iter().map(Ok)))
+            .map(|flight_data| flight_data.map_err(Status::from));
+        Ok(Response::new(Box::pin(flight_data)))
+    }
+
+    type DoPutStream = ResponseStream<PutResult>;
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented(
+            "Transactions are submitted through the PaymentsEngine service",
+        ))
+    }
+
+    type DoExchangeStream = ResponseStream<FlightData>;
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented(
+            "Transactions are submitted through the PaymentsEngine service",
+        ))
+    }
+
+    type DoActionStream = ResponseStream<arrow_flight::Result>;
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("The accounts flight has no actions"))
+    }
+
+    type ListActionsStream = ResponseStream<ActionType>;
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_engine;
+    use crate::engine::Engine;
+    use crate::flight::{self, ACCOUNTS, ACCOUNTS_SCHEMA};
+    use crate::transaction::Transaction;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt16Type};
+    use arrow_flight::{FlightClient, FlightDescriptor, Ticket};
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn test_accounts_flight() {
+        let mut engine = Engine::new();
+        for (client_id, tx_id, currency) in [(2, 1, None), (1, 2, Some("EUR")), (1, 3, None)] {
+            engine
+                .process_transaction(Transaction::Deposit {
+                    client_id,
+                    tx_id,
+                    amount: 12_3456,
+                    currency: currency.map(|currency| currency.parse().unwrap()),
+                })
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 2,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        let (handle, _task) = async_engine::spawn(engine, 16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(flight::service(handle))
+                .serve_with_incoming(TcpIncoming::from(listener)),
+        );
+        let channel = Channel::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = FlightClient::new(channel);
+
+        let descriptor = FlightDescriptor::new_path(vec![ACCOUNTS.to_string()]);
+        let info = client.get_flight_info(descriptor.clone()).await.unwrap();
+        assert_eq!(info.total_records, 3);
+        assert_eq!(info.try_decode_schema().unwrap(), *ACCOUNTS_SCHEMA.as_ref());
+        let schema = client.get_schema(descriptor).await.unwrap();
+        assert_eq!(schema, *ACCOUNTS_SCHEMA.as_ref());
+
+        let batches: Vec<_> = client
+            .do_get(Ticket::new(ACCOUNTS))
+            .await
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect()
+            .await;
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let clients: Vec<_> = batch
+            .column(0)
+            .as_primitive::<UInt16Type>()
+            .values()
+            .to_vec();
+        assert_eq!(clients, [1, 1, 2]);
+        let currencies: Vec<_> = batch.column(1).as_string::<i32>().iter().collect();
+        assert_eq!(currencies, [None, Some("EUR"), None]);
+        let held = batch.column(3).as_primitive::<Decimal128Type>();
+        assert_eq!(held.value_as_string(2), "12.3456");
+        assert_eq!(held.value_as_string(0), "0.0000");
+
+        let status = client
+            .do_get(Ticket::new("transactions"))
+            .await
+            .unwrap_err();
+        let arrow_flight::error::FlightError::Tonic(status) = status else {
+            panic!("Unexpected error: {status}");
+        };
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::async_engine::EngineHandle;
+use crate::engine::AccountView;
+use crate::error::ReasonCode;
+use crate::transaction::{Currency, RawFields, RawTransactionType, Transaction};
+use crate::util::{
+    fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str, RoundingMode,
+};
+use anyhow::{anyhow, Result};
+use proto::payments_engine_server::{PaymentsEngine, PaymentsEngineServer};
+use proto::{
+    submit_response, Account, ExportReportRequest, GetAccountRequest, GetAccountResponse,
+    Rejection, SubmitResponse, TransactionType,
+};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::info;
+
+/// The messages and service generated from `proto/payments.proto`
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("payments");
+}
+
+/// Responses to a `StreamTransactions` call buffered while the client is slow to read them
+const STREAM_BUFFER: usize = 64;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Serves the `PaymentsEngine` gRPC service of `proto/payments.proto` on the engine of a handle
+/// until the server fails, along with the Arrow Flight service of the accounts under the `flight`
+/// feature
+pub async fn serve(handle: EngineHandle, addr: SocketAddr) -> Result<()> {
+    #[cfg(feature = "flight")]
+    let flight = crate::flight::service(handle.clone());
+    let service = service(handle).await?;
+    info!("Serving gRPC on {addr}");
+    let router = Server::builder().add_service(service);
+    #[cfg(feature = "flight")]
+    let router = router.add_service(flight);
+    router.serve(addr).await?;
+    Ok(())
+}
+
+/// The `PaymentsEngine` gRPC service, for servers also serving other services. Amounts are read
+/// with the rounding mode the engine is configured with at the time.
+pub async fn service(handle: EngineHandle) -> Result<PaymentsEngineServer<GrpcService>> {
+    let rounding = handle.query(|engine| engine.config().rounding).await?;
+    Ok(PaymentsEngineServer::new(GrpcService { handle, rounding }))
+}
+
+pub struct GrpcService {
+    handle: EngineHandle,
+    rounding: RoundingMode,
+}
+
+#[tonic::async_trait]
+impl PaymentsEngine for GrpcService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<SubmitResponse>, Status> {
+        let transaction = request.into_inner();
+        let tx = transaction.tx;
+        let transaction = into_transaction(transaction, self.rounding)
+            .map_err(|e| Status::invalid_argument(format!("Invalid transaction: {e}")))?;
+        let outcome = submit(&self.handle, tx, transaction).await?;
+        Ok(Response::new(outcome))
+    }
+
+    type StreamTransactionsStream = ResponseStream<SubmitResponse>;
+
+    async fn stream_transactions(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<Self::StreamTransactionsStream>, Status> {
+        let mut transactions = request.into_inner();
+        let (handle, rounding) = (self.handle.clone(), self.rounding);
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            while let Some(transaction) = transactions.next().await {
+                let response = match transaction {
+                    Ok(transaction) => {
+                        let tx = transaction.tx;
+                        match into_transaction(transaction, rounding) {
+                            Ok(transaction) => submit(&handle, tx, transaction).await,
+                            Err(e) => Ok(rejection(tx, ReasonCode::InvalidRow.as_str(), e)),
+                        }
+                    }
+                    Err(status) => Err(status),
+                };
+                let stop = response.is_err();
+                // Stop applying transactions once the client stops reading their outcomes
+                if sender.send(response).await.is_err() || stop {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<GetAccountRequest>,
+    ) -> Result<Response<GetAccountResponse>, Status> {
+        let client = request.into_inner().client;
+        let client_id = u16::try_from(client)
+            .map_err(|_| Status::invalid_argument(format!("Invalid client id: {client}")))?;
+        let accounts = self
+            .handle
+            .client_accounts(client_id)
+            .await
+            .map_err(internal)?;
+        if accounts.is_empty() {
+            return Err(Status::not_found(format!(
+                "Account not found - client: {client_id}"
+            )));
+        }
+        Ok(Response::new(GetAccountResponse {
+            accounts: accounts.into_iter().map(account).collect(),
+        }))
+    }
+
+    type ExportReportStream = ResponseStream<Account>;
+
+    async fn export_report(
+        &self,
+        _request: Request<ExportReportRequest>,
+    ) -> Result<Response<Self::ExportReportStream>, Status> {
+        let mut accounts = self.handle.accounts().await.map_err(internal)?;
+        accounts.sort_by_key(|account| (account.client_id, account.currency));
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            accounts.into_iter().map(|view| Ok(account(view))),
+        ))))
+    }
+}
+
+async fn submit(
+    handle: &EngineHandle,
+    tx: u32,
+    (transaction, timestamp): (Transaction, Option<u64>),
+) -> Result<SubmitResponse, Status> {
+    Ok(
+        match handle
+            .process(transaction, timestamp)
+            .await
+            .map_err(internal)?
+        {
+            Ok(view) => SubmitResponse {
+                tx,
+                outcome: view.map(|view| submit_response::Outcome::Account(account(view))),
+            },
+            Err(e) => rejection(tx, e.code(), e),
+        },
+    )
+}
+
+/// The engine's transaction of a gRPC one, along with its timestamp
+fn into_transaction(
+    transaction: proto::Transaction,
+    rounding: RoundingMode,
+) -> Result<(Transaction, Option<u64>)> {
+    let transaction_type = match transaction.r#type() {
+        TransactionType::Unspecified => return Err(anyhow!("Transaction type is missing")),
+        TransactionType::Deposit => RawTransactionType::Deposit,
+        TransactionType::Withdrawal => RawTransactionType::Withdrawal,
+        TransactionType::Dispute => RawTransactionType::Dispute,
+        TransactionType::Resolve => RawTransactionType::Resolve,
+        TransactionType::Chargeback => RawTransactionType::Chargeback,
+        TransactionType::Unlock => RawTransactionType::Unlock,
+        TransactionType::Reversal => RawTransactionType::Reversal,
+        TransactionType::Hold => RawTransactionType::Hold,
+        TransactionType::Release => RawTransactionType::Release,
+    };
+    let client = u16::try_from(transaction.client)
+        .map_err(|_| anyhow!("Invalid client id: {}", transaction.client))?;
+    let currency = transaction
+        .currency
+        .as_deref()
+        .map(str::parse::<Currency>)
+        .transpose()?;
+    let parsed = RawFields {
+        transaction_type,
+        client,
+        tx: transaction.tx,
+        amount: transaction.amount.as_deref(),
+        currency,
+    }
+    .into_transaction(rounding)?;
+    Ok((parsed, transaction.timestamp))
+}
+
+fn rejection(tx: u32, code: &str, error: impl ToString) -> SubmitResponse {
+    SubmitResponse {
+        tx,
+        outcome: Some(submit_response::Outcome::Rejection(Rejection {
+            code: code.to_string(),
+            message: error.to_string(),
+        })),
+    }
+}
+
+fn account(view: AccountView) -> Account {
+    Account {
+        client: view.client_id.into(),
+        currency: view.currency.map(String::from),
+        available: signed_fixed_point_4_decimal_to_float_str(view.available_amount),
+        held: fixed_point_4_decimal_to_float_str(view.held_amount),
+        total: signed_fixed_point_4_decimal_to_float_str(view.total_amount),
+        locked: view.locked,
+    }
+}
+
+fn internal(error: anyhow::Error) -> Status {
+    Status::internal(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_engine;
+    use crate::engine::Engine;
+    use crate::grpc::service;
+    use payments_engine_client::{
+        submit_response::Outcome, ExportReportRequest, GetAccountRequest, PaymentsEngineClient,
+        Transaction, TransactionType,
+    };
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::Code;
+
+    fn transaction(
+        transaction_type: TransactionType,
+        client: u32,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            r#type: transaction_type.into(),
+            client,
+            tx,
+            amount: amount.map(str::to_string),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let (handle, _task) = async_engine::spawn(Engine::new(), 16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = service(handle).await.unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpIncoming::from(listener)),
+        );
+        let mut client = PaymentsEngineClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+
+        let response = client
+            .submit_transaction(transaction(TransactionType::Deposit, 1, 1, Some("10.5")))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(Outcome::Account(account)) = response.outcome else {
+            panic!("Deposit was rejected: {response:?}");
+        };
+        assert_eq!((account.client, account.available.as_str()), (1, "10.5000"));
+        let status = client
+            .submit_transaction(transaction(TransactionType::Deposit, 1, 2, None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // Every streamed transaction gets a response, in order, invalid ones included
+        let transactions = tokio_stream::iter([
+            transaction(TransactionType::Withdrawal, 1, 3, Some("20")),
+            transaction(TransactionType::Unspecified, 1, 4, None),
+            transaction(TransactionType::Deposit, 2, 5, Some("1")),
+        ]);
+        let responses: Vec<_> = client
+            .stream_transactions(transactions)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|response| response.unwrap())
+            .collect()
+            .await;
+        let outcomes: Vec<_> = responses
+            .into_iter()
+            .map(|response| match response.outcome {
+                Some(Outcome::Rejection(rejection)) => (response.tx, rejection.code),
+                _ => (response.tx, "applied".to_string()),
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (3, "insufficient_funds".to_string()),
+                (4, "invalid_row".to_string()),
+                (5, "applied".to_string()),
+            ]
+        );
+
+        let accounts = client
+            .get_account(GetAccountRequest { client: 2 })
+            .await
+            .unwrap()
+            .into_inner()
+            .accounts;
+        assert_eq!(accounts[0].total, "1.0000");
+        let status = client
+            .get_account(GetAccountRequest { client: 3 })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let clients: Vec<_> = client
+            .export_report(ExportReportRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|account| account.unwrap().client)
+            .collect()
+            .await;
+        assert_eq!(clients, [1, 2]);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::engine::{AccountView, Balance, HeldBalance};
+use crate::error::EngineError;
+use crate::transaction::{Currency, RawTransactionType};
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Not;
+
+/// A transaction of a type the engine doesn't know, such as a `bonus` or a `fee_refund`, applied
+/// by the handler registered for its type with `Engine::register_handler`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomTransaction {
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    pub client_id: u16,
+    pub tx_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+}
+
+pub type TransactionHandler =
+    Box<dyn FnMut(&CustomTransaction, &mut AccountHandle) -> Result<()> + Send>;
+
+/// Handlers of custom transactions, by transaction type
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, TransactionHandler>,
+}
+
+impl HandlerRegistry {
+    /// Registers the handler of a transaction type, which can be neither one of the engine's own
+    /// types nor one registered already
+    pub fn register(
+        &mut self,
+        transaction_type: &str,
+        handler: impl FnMut(&CustomTransaction, &mut AccountHandle) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        let builtin = RawTransactionType::ALL.iter().any(|builtin| {
+            serde_json::to_value(builtin).is_ok_and(|name| name.as_str() == Some(transaction_type))
+        });
+        ensure!(
+            builtin.not(),
+            "{transaction_type} transactions are handled by the engine"
+        );
+        ensure!(
+            self.handlers.contains_key(transaction_type).not(),
+            "A handler is registered for {transaction_type} transactions already"
+        );
+        self.handlers
+            .insert(transaction_type.to_string(), Box::new(handler));
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, transaction_type: &str) -> Option<&mut TransactionHandler> {
+        self.handlers.get_mut(transaction_type)
+    }
+
+    /// The transaction types handlers are registered for, in no particular order
+    pub fn transaction_types(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+/// What handlers can do to the account a custom transaction targets: read its balances in the
+/// transaction's currency, and credit or debit its available funds. Held funds, disputes and the
+/// lock are the engine's own, and the balances of locked accounts can't be changed. Changes only
+/// apply if the handler returns `Ok`, and open the account if the client has none yet.
+pub struct AccountHandle {
+    transaction_type: String,
+    client_id: u16,
+    currency: Option<Currency>,
+    available_amount: Balance,
+    held_amount: HeldBalance,
+    locked: bool,
+    changed: bool,
+}
+
+impl AccountHandle {
+    pub(crate) fn new(transaction: &CustomTransaction, view: AccountView) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type.clone(),
+            client_id: view.client_id,
+            currency: view.currency,
+            available_amount: view.available_amount,
+            held_amount: view.held_amount,
+            locked: view.locked,
+            changed: false,
+        }
+    }
+
+    /// The account's state, with the changes made so far
+    pub fn view(&self) -> AccountView {
+        AccountView {
+            client_id: self.client_id,
+            currency: self.currency,
+            available_amount: self.available_amount,
+            held_amount: self.held_amount,
+            total_amount: self
+                .available_amount
+                .wrapping_add_unsigned(self.held_amount),
+            locked: self.locked,
+        }
+    }
+
+    /// Adds to the available funds
+    pub fn credit(&mut self, amount: u64) -> Result<(), EngineError> {
+        self.check_unlocked()?;
+        let available = self
+            .available_amount
+            .checked_add_unsigned(amount as HeldBalance)
+            // The total has to stay within range too, like every balance the engine sets
+            .filter(|available| available.checked_add_unsigned(self.held_amount).is_some())
+            .ok_or(EngineError::BalanceOverflow)?;
+        self.set_available(available);
+        Ok(())
+    }
+
+    /// Takes from the available funds, which can't go negative
+    pub fn debit(&mut self, amount: u64) -> Result<(), EngineError> {
+        self.check_unlocked()?;
+        match self
+            .available_amount
+            .checked_sub_unsigned(amount as HeldBalance)
+        {
+            Some(available) if available >= 0 => {
+                self.set_available(available);
+                Ok(())
+            }
+            _ => Err(EngineError::InsufficientFunds),
+        }
+    }
+
+    /// Whether the handler changed the balances
+    pub(crate) fn changed(&self) -> bool {
+        self.changed
+    }
+
+    fn check_unlocked(&self) -> Result<(), EngineError> {
+        match self.locked {
+            true => Err(EngineError::CustomAccountLocked {
+                transaction_type: self.transaction_type.clone(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    fn set_available(&mut self, available: Balance) {
+        self.available_amount = available;
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::error::{EngineError, ReasonCode};
+    use crate::handlers::CustomTransaction;
+    use crate::transaction::Transaction;
+
+    fn custom(
+        transaction_type: &str,
+        client_id: u16,
+        tx_id: u32,
+        amount: u64,
+    ) -> CustomTransaction {
+        CustomTransaction {
+            transaction_type: transaction_type.to_string(),
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_custom_transactions() {
+        let mut engine = Engine::new();
+        engine
+            .register_handler("bonus", |transaction, account| {
+                account.credit(transaction.amount.unwrap_or_default())?;
+                Ok(())
+            })
+            .unwrap();
+        // Refunds a fee of at most 5, taking back what was refunded above it
+        engine
+            .register_handler("fee_refund", |transaction, account| {
+                let amount = transaction.amount.unwrap_or_default();
+                account.credit(amount)?;
+                if amount > 5_0000 {
+                    account.debit(amount)?;
+                    anyhow::bail!("Fee refunds are capped at 5");
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert!(engine.register_handler("bonus", |_, _| Ok(())).is_err());
+        assert!(engine.register_handler("deposit", |_, _| Ok(())).is_err());
+        let mut types: Vec<_> = engine.handlers().transaction_types().collect();
+        types.sort();
+        assert_eq!(types, ["bonus", "fee_refund"]);
+
+        // A bonus opens the account of a client without one
+        engine
+            .process_custom(custom("bonus", 1, 1, 10_0000), None)
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available_amount, 10_0000);
+        engine
+            .process_custom(custom("fee_refund", 1, 2, 2_5000), None)
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().total_amount, 12_5000);
+
+        // Rejected transactions leave the account as it was
+        let error = engine
+            .process_custom(custom("fee_refund", 1, 3, 6_0000), None)
+            .unwrap_err();
+        assert_eq!(error.reason(), ReasonCode::RejectedByHandler);
+        assert_eq!(error.to_string(), "Fee refunds are capped at 5");
+        assert_eq!(engine.account(1).unwrap().available_amount, 12_5000);
+        assert!(matches!(
+            engine.process_custom(custom("bonus", 1, 1, 1), None),
+            Err(EngineError::DuplicateTxId { tx_id: 1 })
+        ));
+        assert_eq!(
+            engine
+                .process_custom(custom("cashback", 1, 4, 1), None)
+                .unwrap_err()
+                .reason(),
+            ReasonCode::UnknownTransactionType
+        );
+        assert!(engine.account(2).is_none());
+
+        // Custom transactions aren't deposits, which can be disputed
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap_err();
+        }
+
+        // Handlers can't change the balances of locked accounts
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 2,
+                tx_id: 5,
+                amount: 1_0000,
+                currency: None,
+            })
+            .unwrap();
+        for transaction in [
+            Transaction::Dispute {
+                client_id: 2,
+                tx_id: 5,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 2,
+                tx_id: 5,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let error = engine
+            .process_custom(custom("bonus", 2, 6, 1_0000), None)
+            .unwrap_err();
+        assert_eq!(error.reason(), ReasonCode::AccountLocked);
+        assert_eq!(
+            error.to_string(),
+            "A bonus failed because the target account is locked"
+        );
+        assert_eq!(engine.account(2).unwrap().total_amount, 0);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::engine::Engine;
+use crate::openapi;
+use crate::output::{account_to_json, write_accounts_csv};
+use crate::transaction::RawTransaction;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Largest request (head and body) accepted, which is plenty for a single transaction
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 32;
+
+/// Limits on the connections an HTTP server handles
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Connections served at the same time, each on its own thread. Connections accepted beyond
+    /// it get a `503` response right away.
+    pub max_connections: usize,
+    /// How long to wait for more of a request before giving up on the connection with a `408`
+    pub read_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Decrements the number of open connections when a connection's thread is done with it
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves a REST interface over HTTP/1.1:
+///
+/// * `POST /transactions` applies the transaction in the JSON body (with the same fields as a
+///   transactions csv row, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`) and
+///   responds with the resulting account state
+/// * `GET /accounts/{id}` responds with the state of a client's account in every currency it has
+///   balances in, as a JSON array
+/// * `GET /report` responds with the state of every account as a csv report, streamed with
+///   chunked transfer encoding as it's written
+/// * `GET /openapi.json` responds with the OpenAPI document describing them, see
+///   `openapi::document`
+///
+/// Every connection operates on the same engine, requests are applied one at a time, and every
+/// connection is closed after its response. At most `config.max_connections` connections are
+/// served at the same time, and idle ones are closed after `config.read_timeout`.
+pub fn serve(engine: Engine, addr: impl ToSocketAddrs, config: &ServerConfig) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("HTTP server listening on {}", listener.local_addr()?);
+    serve_listener(engine, listener, config)
+}
+
+/// Serves HTTP connections accepted by an already bound listener, see `serve`
+pub fn serve_listener(engine: Engine, listener: TcpListener, config: &ServerConfig) -> Result<()> {
+    let engine = Arc::new(Mutex::new(engine));
+    let open_connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept HTTP connection: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_read_timeout(Some(config.read_timeout)) {
+            error!("Failed to set HTTP connection read timeout: {e}");
+            continue;
+        }
+        if open_connections.fetch_add(1, Ordering::SeqCst) >= config.max_connections {
+            open_connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Refusing HTTP connection, {} connections are already open",
+                config.max_connections
+            );
+            let busy = Response::error(503, "Too many connections, try again later");
+            // Don't let a client that doesn't read its response block accepting connections
+            let _ = stream.set_write_timeout(Some(config.read_timeout));
+            if let Err(e) = busy.write(BufWriter::new(stream)) {
+                warn!("HTTP connection closed with an error: {e}");
+            }
+            continue;
+        }
+        let guard = ConnectionGuard(Arc::clone(&open_connections));
+        let engine = Arc::clone(&engine);
+
+        thread::spawn(move || {
+            let _guard = guard;
+            if let Err(e) = serve_connection(&engine, stream) {
+                warn!("HTTP connection closed with an error: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn serve_connection(engine: &Mutex<Engine>, mut stream: TcpStream) -> Result<()> {
+    let request = read_request(&mut stream);
+    let writer = BufWriter::new(stream);
+    match request {
+        Ok(request) => {
+            // Held until the response is written, for streamed bodies to read from the engine
+            let mut engine = engine
+                .lock()
+                .map_err(|_| anyhow!("Engine lock was poisoned"))?;
+            handle_request(&mut engine, &request.method, &request.path, &request.body).write(writer)
+        }
+        Err(e) if is_timeout(&e) => {
+            Response::error(408, "Timed out reading the request").write(writer)
+        }
+        Err(e) => Response::error(400, format!("Bad request: {e}")).write(writer),
+    }
+}
+
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    })
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &mut impl Read) -> Result<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+
+    loop {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            bail!("Connection closed before the end of the request");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if buffer.len() > MAX_REQUEST_SIZE {
+            bail!("Request too large");
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(head_length) = request.parse(&buffer)? else {
+            continue;
+        };
+
+        let content_length = match request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+        {
+            Some(header) => std::str::from_utf8(header.value)?.trim().parse::<usize>()?,
+            None => 0,
+        };
+        if head_length + content_length > MAX_REQUEST_SIZE {
+            bail!("Request too large");
+        }
+        let method = request.method.unwrap_or_default().to_string();
+        let path = request.path.unwrap_or_default().to_string();
+
+        while buffer.len() < head_length + content_length {
+            let read = stream.read(&mut chunk)?;
+            if read == 0 {
+                bail!("Connection closed before the end of the request body");
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+        buffer.truncate(head_length + content_length);
+
+        return Ok(Request {
+            method,
+            path,
+            body: buffer.split_off(head_length),
+        });
+    }
+}
+
+/// Handles a single request, returning the response to send back
+pub fn handle_request<'a>(
+    engine: &'a mut Engine,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Response<'a> {
+    // Query strings aren't used by any endpoint
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("POST", ["transactions"]) => {
+            let transaction = match serde_json::from_slice::<RawTransaction>(body)
+                .map_err(Into::into)
+                .and_then(|raw| raw.into_transaction(engine.config().rounding))
+            {
+                Ok(transaction) => transaction,
+                Err(e) => return Response::error(400, format!("Invalid transaction: {e}")),
+            };
+
+            match engine.process_transaction(transaction.clone()) {
+                Ok(()) => Response::json(
+                    200,
+                    engine
+                        .account_for(&transaction)
+                        .map(account_to_json)
+                        .unwrap_or(Value::Null),
+                ),
+                Err(e) => Response::json(422, json!({ "error": e.to_string(), "code": e.code() })),
+            }
+        }
+        ("GET", ["accounts", client_id]) => match client_id.parse::<u16>() {
+            Ok(client_id) => {
+                let accounts: Vec<Value> = engine
+                    .client_accounts(client_id)
+                    .map(account_to_json)
+                    .collect();
+                if accounts.is_empty() {
+                    Response::error(404, format!("Account not found - client: {client_id}"))
+                } else {
+                    Response::json(200, Value::Array(accounts))
+                }
+            }
+            Err(_) => Response::error(400, format!("Invalid client id: {client_id}")),
+        },
+        ("GET", ["report"]) => {
+            let engine: &Engine = engine;
+            Response {
+                status: 200,
+                content_type: "text/csv",
+                body: Body::Stream(Box::new(move |writer| {
+                    write_accounts_csv(engine.accounts(), b',', writer)
+                })),
+            }
+        }
+        ("GET", ["openapi.json"]) => Response::json(200, openapi::document()),
+        (_, ["transactions"] | ["accounts", _] | ["report"] | ["openapi.json"]) => {
+            Response::error(405, format!("Method not allowed: {method}"))
+        }
+        _ => Response::error(404, format!("Not found: {path}")),
+    }
+}
+
+pub struct Response<'a> {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body<'a>,
+}
+
+pub enum Body<'a> {
+    /// A body sent as is, with a `Content-Length`
+    Bytes(Vec<u8>),
+    /// A body written as it's sent, with chunked transfer encoding, so that large ones don't have
+    /// to be held in memory. Errors writing it abort the connection, since the status has already
+    /// been sent.
+    Stream(WriteBody<'a>),
+}
+
+pub type WriteBody<'a> = Box<dyn FnOnce(&mut dyn Write) -> Result<()> + 'a>;
+
+impl Response<'_> {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: Body::Bytes(body.to_string().into_bytes()),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, json!({ "error": message.into() }))
+    }
+
+    fn write(self, mut writer: impl Write) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            422 => "Unprocessable Entity",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\n",
+            self.status, self.content_type
+        )?;
+        match self.body {
+            Body::Bytes(body) => {
+                write!(
+                    writer,
+                    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )?;
+                writer.write_all(&body)?;
+            }
+            Body::Stream(write_body) => {
+                write!(
+                    writer,
+                    "Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+                )?;
+                let mut chunked = ChunkedWriter(&mut writer);
+                write_body(&mut chunked)?;
+                chunked.finish()?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes each write as a chunk of a chunked transfer encoded body
+struct ChunkedWriter<W: Write>(W);
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Writes the last, empty, chunk that ends the body
+    fn finish(mut self) -> io::Result<()> {
+        self.0.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.0, "{:x}\r\n", buf.len())?;
+        self.0.write_all(buf)?;
+        self.0.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::http::{handle_request, serve_listener, Body, ChunkedWriter, ServerConfig};
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn call(engine: &mut Engine, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let response = handle_request(engine, method, path, body.to_string().as_bytes());
+        let Body::Bytes(body) = response.body else {
+            panic!("Expected a JSON response");
+        };
+        (response.status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_submit_transaction_and_get_account() {
+        let mut engine = Engine::new();
+
+        let (status, account) = call(
+            &mut engine,
+            "POST",
+            "/transactions",
+            json!({"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            account,
+            json!({"client": 1, "available": "10.5000", "held": "0.0000", "total": "10.5000", "locked": false})
+        );
+
+        let (status, error) = call(
+            &mut engine,
+            "POST",
+            "/transactions",
+            json!({"type": "withdrawal", "client": 1, "tx": 2, "amount": "20"}),
+        );
+        assert_eq!(status, 422);
+        assert_eq!(error["code"], "insufficient_funds");
+
+        let (status, _) = call(
+            &mut engine,
+            "POST",
+            "/transactions",
+            json!({"type": "deposit", "client": 1, "tx": 3}),
+        );
+        assert_eq!(status, 400);
+
+        let (status, accounts) = call(&mut engine, "GET", "/accounts/1", Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(accounts[0]["available"], "10.5000");
+
+        call(
+            &mut engine,
+            "POST",
+            "/transactions",
+            json!({"type": "deposit", "client": 1, "tx": 4, "amount": "3", "currency": "EUR"}),
+        );
+        let (_, accounts) = call(&mut engine, "GET", "/accounts/1", Value::Null);
+        assert_eq!(
+            accounts,
+            json!([
+                {"client": 1, "available": "10.5000", "held": "0.0000", "total": "10.5000", "locked": false},
+                {"client": 1, "available": "3.0000", "held": "0.0000", "total": "3.0000", "locked": false, "currency": "EUR"},
+            ])
+        );
+
+        assert_eq!(call(&mut engine, "GET", "/accounts/2", Value::Null).0, 404);
+        assert_eq!(
+            call(&mut engine, "GET", "/accounts/abc", Value::Null).0,
+            400
+        );
+        assert_eq!(
+            call(&mut engine, "DELETE", "/accounts/1", Value::Null).0,
+            405
+        );
+        assert_eq!(call(&mut engine, "GET", "/unknown", Value::Null).0, 404);
+    }
+
+    #[test]
+    fn test_report_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_listener(Engine::new(), listener, &ServerConfig::default()));
+
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let body = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2"}"#;
+        let response = request(&format!(
+            "POST /transactions HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = request("GET /report HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.contains("Content-Type: text/csv\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\n40\r\nclient,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n\r\n0\r\n\r\n"
+        ));
+
+        let response = request("NOT HTTP\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_connection_limit_and_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            max_connections: 1,
+            read_timeout: Duration::from_millis(200),
+        };
+        thread::spawn(move || serve_listener(Engine::new(), listener, &config));
+
+        let read_response = |mut stream: TcpStream| {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        // An idle connection takes the only slot until it times out
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET /report HTTP/1.1\r\n").unwrap();
+
+        let busy = TcpStream::connect(addr).unwrap();
+        assert!(read_response(busy).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(read_response(idle).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut body = Vec::new();
+        let mut chunked = ChunkedWriter(&mut body);
+        chunked.write_all(b"client,available").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(&[b'x'; 300]).unwrap();
+        chunked.finish().unwrap();
+
+        let mut expected = b"10\r\nclient,available\r\n12c\r\n".to_vec();
+        expected.extend_from_slice(&[b'x'; 300]);
+        expected.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert_eq!(body, expected);
+    }
+}
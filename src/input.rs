@@ -0,0 +1,1119 @@
+use crate::engine::Engine;
+use crate::error::AmountError;
+use crate::rejected::{self, RejectedRows};
+use crate::transaction::{RawFields, RawTransaction, RawTransactionType, Transaction};
+use crate::util::RoundingMode;
+use anyhow::{anyhow, bail, ensure, Result};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io::Read;
+use std::ops::Not;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{error, warn};
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InvalidRows {
+    /// Report the row on stderr, along with its line number, and carry on with the next one
+    Skip,
+    /// Stop at the first invalid row, without writing any account states
+    Fail,
+}
+
+/// The format transactions are read in
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InputFormat {
+    Csv,
+    /// Fixed width binary records, see `codec::encode`
+    Bin,
+}
+
+/// How a transactions csv is compressed
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Compression {
+    /// Detected from the file's extension: `.gz` for gzip, `.zst` for zstd, and none otherwise or
+    /// for stdin
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression of the file at `path`, with `Auto` detected from its extension
+    pub fn of(self, path: &Path) -> Compression {
+        match self {
+            Compression::Auto => match path.extension().and_then(|extension| extension.to_str()) {
+                Some("gz") => Compression::Gzip,
+                Some("zst") => Compression::Zstd,
+                _ => Compression::None,
+            },
+            compression => compression,
+        }
+    }
+}
+
+/// Wraps a reader of compressed transactions with the matching decompressor. `Auto` is read as
+/// uncompressed, not knowing where the reader comes from.
+pub fn decompress(reader: impl Read + 'static, compression: Compression) -> Result<Box<dyn Read>> {
+    Ok(match compression {
+        Compression::Auto | Compression::None => Box::new(reader),
+        // Multi-member files, such as concatenated gzip files, are read whole
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+    })
+}
+
+/// The order rows are read in when several transactions csvs are read together
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InputOrder {
+    /// Every row of a csv before those of the next one, in the order the csvs were given
+    #[default]
+    Sequential,
+    /// Rows merged by their `timestamp` column (milliseconds since the Unix epoch), each csv being
+    /// sorted already. Rows without a valid timestamp keep their place in their csv, and rows with
+    /// the same timestamp go in the order the csvs were given.
+    Timestamp,
+}
+
+/// How transactions csvs are read, apart from the rounding of their amounts, which is the
+/// engine's
+#[derive(Clone, Default)]
+pub struct CsvOptions {
+    pub order: InputOrder,
+    /// Clients to read the rows of, if not all of them. The rows of other clients, and those whose
+    /// `client` field can't be read, are skipped without being parsed any further or counted.
+    pub clients: Option<HashSet<u16>>,
+    /// Where to stop reading, if before the end of the csvs
+    pub until: Option<Until>,
+    /// Read transactions straight from the bytes of their fields, rather than deserializing every
+    /// row with serde, which allocates a string for every amount. Rows read the same either way,
+    /// apart from the wording of why invalid ones are, and `CsvRow::record` keeping the spaces
+    /// around fields.
+    pub fast_parse: bool,
+    /// Share of rows, from 0 to 1, that can be rejected by the engine or be invalid before
+    /// processing is aborted. Single threaded processing checks it as rows are read, once
+    /// `ERROR_RATE_MIN_ROWS` of them were, so that a few failures at the start of a csv don't
+    /// abort it.
+    pub max_error_rate: Option<f64>,
+    /// Stop at the first transaction the engine rejects, failing with where it is, like
+    /// `InvalidRows::Fail` does for invalid rows
+    pub strict: bool,
+    /// Header of the column each field is read from, by field name (see `FIELDS`), for csvs whose
+    /// columns aren't named after the fields, such as `client` from `Client ID`. Other headers
+    /// are matched to fields ignoring case and surrounding spaces, and columns of no field are
+    /// ignored.
+    pub columns: HashMap<String, String>,
+    /// For csvs without a header row, the field each column holds, in order, such as
+    /// `["type", "client", "tx", "amount"]`, with an empty name for columns to ignore. Every row,
+    /// starting with the first, is then a transaction.
+    pub headerless: Option<Vec<String>>,
+    /// Byte separating fields, if not a comma, such as `;` for many European exports
+    pub delimiter: Option<u8>,
+    /// Byte quoting fields, if not a double quote
+    pub quote: Option<u8>,
+    /// Read rows with more or fewer fields than the header instead of reporting them as invalid,
+    /// missing fields being empty
+    pub flexible: bool,
+    /// Character separating the integer and fractional parts of amounts
+    pub decimal_separator: DecimalSeparator,
+}
+
+/// Character separating the integer and fractional parts of amounts
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum DecimalSeparator {
+    /// `1.5`
+    #[default]
+    Point,
+    /// `1,5`, as in many European exports, whose fields are then separated by something else than
+    /// commas or quoted. Amounts with a point are invalid.
+    Comma,
+}
+
+impl DecimalSeparator {
+    /// The amount with a decimal point, as the engine parses amounts
+    fn to_point(self, amount: &str) -> Result<Cow<'_, str>, AmountError> {
+        match self {
+            DecimalSeparator::Point => Ok(Cow::Borrowed(amount)),
+            DecimalSeparator::Comma if amount.contains('.') => {
+                Err(AmountError::InvalidCharacter('.'))
+            }
+            DecimalSeparator::Comma => Ok(Cow::Owned(amount.replace(',', "."))),
+        }
+    }
+}
+
+impl CsvOptions {
+    /// The byte separating fields, a comma unless set otherwise
+    pub fn delimiter_byte(&self) -> u8 {
+        self.delimiter.unwrap_or(b',')
+    }
+
+    /// The byte quoting fields, a double quote unless set otherwise
+    pub fn quote_byte(&self) -> u8 {
+        self.quote.unwrap_or(b'"')
+    }
+}
+
+/// Names of the fields transactions csvs can have a column for
+pub const FIELDS: [&str; 6] = ["type", "client", "tx", "amount", "timestamp", "currency"];
+
+/// Number of rows read before `CsvOptions::max_error_rate` is checked while reading
+pub const ERROR_RATE_MIN_ROWS: u64 = 1000;
+
+/// A point in transactions csvs to stop reading at, to get the accounts as they were then
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Until {
+    /// The first row with this tx id, whichever client it's for, which isn't read
+    Tx(u32),
+    /// The first row with a timestamp later than this one (milliseconds since the Unix epoch).
+    /// Rows without a timestamp are read as long as the last one before them that had one isn't
+    /// later.
+    Timestamp(u64),
+}
+
+/// Number of rows read from a transactions csv, by outcome
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessedRows {
+    pub applied: u64,
+    /// Valid transactions the engine rejected
+    pub rejected: u64,
+    /// Rows that couldn't be parsed into a transaction
+    pub invalid: u64,
+    /// Valid transactions by type
+    pub by_type: BTreeMap<RawTransactionType, TransactionCounts>,
+}
+
+/// Number of valid transactions of a type, by outcome
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TransactionCounts {
+    pub applied: u64,
+    pub rejected: u64,
+}
+
+impl ProcessedRows {
+    /// Counts a valid transaction of the given type, applied or rejected by the engine
+    pub fn count(&mut self, transaction_type: RawTransactionType, applied: bool) {
+        let counts = self.by_type.entry(transaction_type).or_default();
+        if applied {
+            self.applied += 1;
+            counts.applied += 1;
+        } else {
+            self.rejected += 1;
+            counts.rejected += 1;
+        }
+    }
+
+    /// Number of rows that were rejected by the engine or invalid
+    pub fn failed(&self) -> u64 {
+        self.rejected + self.invalid
+    }
+
+    /// Fails if more than `max_error_rate` of the rows counted failed
+    pub fn check_error_rate(&self, max_error_rate: f64) -> Result<()> {
+        let rows = self.applied + self.failed();
+        ensure!(
+            self.failed() as f64 <= max_error_rate * rows as f64,
+            "{} of {rows} rows failed, more than the maximum error rate of {max_error_rate}",
+            self.failed()
+        );
+        Ok(())
+    }
+
+    /// Checks the error rate while rows are being read, once there are enough of them
+    pub fn check_error_rate_so_far(&self, options: &CsvOptions) -> Result<()> {
+        match options.max_error_rate {
+            Some(max_error_rate) if self.applied + self.failed() >= ERROR_RATE_MIN_ROWS => {
+                self.check_error_rate(max_error_rate)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the rows counted by another, e.g. from another thread
+    pub fn add(&mut self, other: ProcessedRows) {
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        self.invalid += other.invalid;
+        for (transaction_type, counts) in other.by_type {
+            let total = self.by_type.entry(transaction_type).or_default();
+            total.applied += counts.applied;
+            total.rejected += counts.rejected;
+        }
+    }
+}
+
+/// A row of a transactions csv
+pub struct CsvRow<'a> {
+    /// Line the row starts on, counting the header, if any, as line 1
+    pub line: u64,
+    /// Byte offset the row starts at in its csv
+    pub byte: u64,
+    /// Index of the csv the row was read from, among those read together
+    pub input: usize,
+    /// The client the row is for, when its `client` field could be read even if the rest of the
+    /// row is invalid
+    pub client_id: Option<u16>,
+    /// The row's `timestamp` field (milliseconds since the Unix epoch), when the csv has one and
+    /// it could be read
+    pub timestamp: Option<u64>,
+    pub transaction: Result<Transaction>,
+    /// The row's fields as read, which for some invalid rows may only be part of them
+    pub record: &'a csv::ByteRecord,
+    /// The csv's header row, or the fields of its columns when it has none
+    pub headers: &'a csv::ByteRecord,
+    /// The options the csv is read with, such as its delimiter
+    pub options: &'a CsvOptions,
+}
+
+impl CsvRow<'_> {
+    /// The fields read from the row, written back with the csv's delimiter and quote, with invalid
+    /// UTF-8 replaced
+    pub fn raw(&self) -> String {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.delimiter_byte())
+            .quote(self.options.quote_byte())
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(Vec::new());
+        let raw = match wtr.write_byte_record(self.record) {
+            Ok(()) => wtr.into_inner().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        String::from_utf8_lossy(raw.strip_suffix(b"\n").unwrap_or(&raw)).into_owned()
+    }
+}
+
+/// Reads a transactions csv one row at a time, handing every row, valid or not, to `handle`.
+/// Reading stops at the first I/O error or error returned by `handle`. Amounts with more than 4
+/// decimal places are truncated.
+pub fn read_csv_rows(
+    reader: impl Read,
+    handle: impl FnMut(CsvRow<'_>) -> Result<()>,
+) -> Result<()> {
+    read_csv_inputs(
+        vec![reader],
+        &CsvOptions::default(),
+        RoundingMode::default(),
+        handle,
+    )
+}
+
+/// Reads several transactions csvs as a single stream of rows, in the order, for the clients and
+/// up to the point given in `options`, handing every row to `handle` like `read_csv_rows`. Amounts
+/// are parsed with the given rounding mode.
+pub fn read_csv_inputs<R: Read>(
+    readers: Vec<R>,
+    options: &CsvOptions,
+    rounding: RoundingMode,
+    mut handle: impl FnMut(CsvRow<'_>) -> Result<()>,
+) -> Result<()> {
+    let mut inputs = readers
+        .into_iter()
+        .map(|reader| CsvRows::new(reader, rounding, options))
+        .collect::<Result<Vec<_>>>()?;
+
+    match options.order {
+        InputOrder::Sequential => {
+            for (index, rows) in inputs.iter_mut().enumerate() {
+                while rows.advance()? {
+                    if rows.stopped {
+                        return Ok(());
+                    }
+                    handle(rows.row(index))?;
+                }
+            }
+        }
+        InputOrder::Timestamp => {
+            // Ties go to the input given first, so the merge is the same on every run
+            let mut next = BinaryHeap::new();
+            for (index, rows) in inputs.iter_mut().enumerate() {
+                if rows.advance()? {
+                    next.push(Reverse((rows.merge_timestamp, index)));
+                }
+            }
+            while let Some(Reverse((_, index))) = next.pop() {
+                let rows = &mut inputs[index];
+                if rows.stopped {
+                    break;
+                }
+                handle(rows.row(index))?;
+                if rows.advance()? {
+                    next.push(Reverse((rows.merge_timestamp, index)));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The rows of one transactions csv, read one at a time into the same record
+struct CsvRows<'a, R: Read> {
+    reader: csv::Reader<R>,
+    headers: csv::ByteRecord,
+    /// The field each column holds, named like the headers `RawTransaction` is deserialized with
+    fields: csv::ByteRecord,
+    type_column: Option<usize>,
+    client_column: Option<usize>,
+    tx_column: Option<usize>,
+    amount_column: Option<usize>,
+    timestamp_column: Option<usize>,
+    currency_column: Option<usize>,
+    record: csv::ByteRecord,
+    line: u64,
+    byte: u64,
+    /// Why the current row couldn't be read, if it couldn't
+    error: Option<csv::Error>,
+    /// Timestamp the current row is merged by: its own, or that of the last row before it that
+    /// had one, so rows without a timestamp keep their place in the csv
+    merge_timestamp: u64,
+    rounding: RoundingMode,
+    options: &'a CsvOptions,
+    /// Whether the current row is the one to stop reading at, see `CsvOptions::until`
+    stopped: bool,
+}
+
+impl<'a, R: Read> CsvRows<'a, R> {
+    fn new(reader: R, rounding: RoundingMode, options: &'a CsvOptions) -> Result<Self> {
+        // Trimming every field as it's read costs about as much as parsing them, so the fast path
+        // only trims those it reads
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(match options.fast_parse {
+                true => csv::Trim::None,
+                false => csv::Trim::All,
+            })
+            .has_headers(options.headerless.is_none())
+            .delimiter(options.delimiter_byte())
+            .quote(options.quote_byte())
+            .flexible(options.flexible)
+            .from_reader(reader);
+        let (headers, fields) = match options.headerless.as_ref() {
+            Some(fields) => {
+                let fields = csv::ByteRecord::from(fields.clone());
+                (fields.clone(), fields)
+            }
+            None => {
+                let headers = reader.byte_headers()?.clone();
+                let fields = column_fields(&headers, &options.columns)?;
+                (headers, fields)
+            }
+        };
+        let column = |name: &[u8]| fields.iter().position(|field| field == name);
+        Ok(Self {
+            type_column: column(b"type"),
+            client_column: column(b"client"),
+            tx_column: column(b"tx"),
+            amount_column: column(b"amount"),
+            timestamp_column: column(b"timestamp"),
+            currency_column: column(b"currency"),
+            reader,
+            headers,
+            fields,
+            record: csv::ByteRecord::new(),
+            // The header's line, the first row being on the next one
+            line: options.headerless.is_none() as u64,
+            byte: 0,
+            error: None,
+            merge_timestamp: 0,
+            rounding,
+            options,
+            stopped: false,
+        })
+    }
+
+    /// Reads the next row of the clients being read, or the one to stop at, returning whether
+    /// there was one
+    fn advance(&mut self) -> Result<bool> {
+        while self.read()? {
+            self.stopped = match self.options.until {
+                Some(Until::Tx(tx_id)) => self.field(self.tx_column) == Some(tx_id),
+                Some(Until::Timestamp(timestamp)) => self.merge_timestamp > timestamp,
+                None => false,
+            };
+            let Some(clients) = self.options.clients.as_ref() else {
+                return Ok(true);
+            };
+            let client_id = self.field(self.client_column);
+            if self.stopped || client_id.is_some_and(|client_id| clients.contains(&client_id)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The field of the current row in the given column, if it could be read and is valid
+    fn field<T: FromStr>(&self, column: Option<usize>) -> Option<T> {
+        match self.error {
+            None => parse_field(&self.record, column),
+            Some(_) => None,
+        }
+    }
+
+    /// Reads the next row, whichever client it's for, returning whether there was one
+    fn read(&mut self) -> Result<bool> {
+        // The reader itself rejects rows with the wrong number of fields, but only I/O errors
+        // stop processing
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(false) => return Ok(false),
+            Ok(true) => {
+                let position = self.record.position().cloned();
+                self.set_position(position);
+                self.error = None;
+                if let Some(timestamp) = parse_field(&self.record, self.timestamp_column) {
+                    self.merge_timestamp = timestamp;
+                }
+            }
+            Err(e) if e.is_io_error() => return Err(e.into()),
+            Err(e) => {
+                self.set_position(e.position().cloned());
+                self.error = Some(e);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Moves to where the row last read starts, or to the next line if the reader doesn't know
+    fn set_position(&mut self, position: Option<csv::Position>) {
+        match position {
+            Some(position) => {
+                self.line = position.line();
+                self.byte = position.byte();
+            }
+            None => self.line += 1,
+        }
+    }
+
+    /// The row last read
+    fn row(&mut self, input: usize) -> CsvRow<'_> {
+        match self.error.take() {
+            None => CsvRow {
+                line: self.line,
+                byte: self.byte,
+                input,
+                client_id: parse_field(&self.record, self.client_column),
+                timestamp: parse_field(&self.record, self.timestamp_column),
+                transaction: match self.options.fast_parse {
+                    true => self.parse_transaction(),
+                    false => self.deserialize_transaction(),
+                },
+                record: &self.record,
+                headers: &self.headers,
+                options: self.options,
+            },
+            Some(e) => CsvRow {
+                line: self.line,
+                byte: self.byte,
+                input,
+                client_id: None,
+                timestamp: None,
+                transaction: Err(e.into()),
+                record: &self.record,
+                headers: &self.headers,
+                options: self.options,
+            },
+        }
+    }
+
+    /// Reads the transaction of the current row by deserializing it with serde
+    fn deserialize_transaction(&self) -> Result<Transaction> {
+        let mut raw = self
+            .record
+            .deserialize::<RawTransaction>(Some(&self.fields))?;
+        if let Some(amount) = raw.amount.as_deref() {
+            if let Cow::Owned(amount) = self.options.decimal_separator.to_point(amount)? {
+                raw.amount = Some(amount);
+            }
+        }
+        raw.into_transaction(self.rounding)
+    }
+
+    /// Reads the transaction of the current row from its fields, without allocating, see
+    /// `CsvOptions::fast_parse`
+    fn parse_transaction(&self) -> Result<Transaction> {
+        let transaction_type = match self
+            .type_column
+            .and_then(|column| self.record.get(column))
+            .map(<[u8]>::trim_ascii)
+        {
+            Some(b"deposit") => RawTransactionType::Deposit,
+            Some(b"withdrawal") => RawTransactionType::Withdrawal,
+            Some(b"dispute") => RawTransactionType::Dispute,
+            Some(b"resolve") => RawTransactionType::Resolve,
+            Some(b"chargeback") => RawTransactionType::Chargeback,
+            Some(b"unlock") => RawTransactionType::Unlock,
+            Some(b"reversal") => RawTransactionType::Reversal,
+            Some(b"hold") => RawTransactionType::Hold,
+            Some(b"release") => RawTransactionType::Release,
+            Some(field) => bail!(
+                "Unknown transaction type `{}`",
+                String::from_utf8_lossy(field)
+            ),
+            None => bail!("Missing field `type`"),
+        };
+        if let Some(timestamp) = self.text(self.timestamp_column)? {
+            timestamp
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid field `timestamp`: {e}"))?;
+        }
+
+        let amount = self
+            .text(self.amount_column)?
+            .map(|amount| self.options.decimal_separator.to_point(amount))
+            .transpose()?;
+        RawFields {
+            transaction_type,
+            client: self.required(self.client_column, "client")?,
+            tx: self.required(self.tx_column, "tx")?,
+            amount: amount.as_deref(),
+            currency: self
+                .text(self.currency_column)?
+                .map(str::parse)
+                .transpose()?,
+        }
+        .into_transaction(self.rounding)
+    }
+
+    /// The field of the current row in the given column as text, or `None` if it's empty or
+    /// there's no such column
+    fn text(&self, column: Option<usize>) -> Result<Option<&str>> {
+        match column.and_then(|column| self.record.get(column).map(<[u8]>::trim_ascii)) {
+            None | Some(b"") => Ok(None),
+            Some(field) => Ok(Some(std::str::from_utf8(field)?)),
+        }
+    }
+
+    /// Parses the field of the current row in the given column, which can't be empty
+    fn required<T: FromStr<Err: std::fmt::Display>>(
+        &self,
+        column: Option<usize>,
+        name: &str,
+    ) -> Result<T> {
+        self.text(column)?
+            .ok_or_else(|| anyhow!("Missing field `{name}`"))?
+            .parse()
+            .map_err(|e| anyhow!("Invalid field `{name}`: {e}"))
+    }
+}
+
+/// Parses the field of a row in the given column, if there's such a column and the field is valid
+fn parse_field<T: FromStr>(record: &csv::ByteRecord, column: Option<usize>) -> Option<T> {
+    let field = record.get(column?)?;
+    std::str::from_utf8(field.trim_ascii()).ok()?.parse().ok()
+}
+
+/// Applies the transactions of one or more csvs to the engine, one row at a time, read as given in
+/// `options`. Transactions the engine rejects are reported on stderr, and so are invalid rows
+/// unless `invalid_rows` says to fail. Both are also written to `rejected`, if given.
+pub fn process_csv_readers<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+    mut rejected: Option<&mut RejectedRows>,
+) -> Result<ProcessedRows> {
+    let mut processed = ProcessedRows::default();
+    let inputs = readers.len();
+
+    read_csv_inputs(readers, options, engine.config().rounding, |row| {
+        match &row.transaction {
+            Ok(transaction) => {
+                let transaction_type = transaction.transaction_type();
+                match engine.process_transaction_at(transaction.clone(), row.timestamp) {
+                    Ok(()) => processed.count(transaction_type, true),
+                    Err(e) if options.strict => {
+                        bail!(
+                            "Rejected transaction at {}: {e}",
+                            row_location(&row, inputs)
+                        )
+                    }
+                    Err(e) => {
+                        processed.count(transaction_type, false);
+                        warn!(
+                            tx = transaction.tx_id(),
+                            client = transaction.client_id(),
+                            line = row.line,
+                            byte = row.byte,
+                            code = e.code(),
+                            "Engine failed to process transaction: {e}"
+                        );
+                        if let Some(rejected) = rejected.as_deref_mut() {
+                            rejected.write(&row, e.code())?;
+                        }
+                    }
+                }
+            }
+            Err(e) if invalid_rows == InvalidRows::Fail => {
+                bail!(invalid_row_message(&row, inputs, e))
+            }
+            Err(e) => {
+                processed.invalid += 1;
+                error!(
+                    line = row.line,
+                    byte = row.byte,
+                    "{}",
+                    invalid_row_message(&row, inputs, e)
+                );
+                if let Some(rejected) = rejected.as_deref_mut() {
+                    rejected.write(&row, rejected::INVALID_ROW)?;
+                }
+            }
+        }
+        processed.check_error_rate_so_far(options)
+    })?;
+
+    Ok(processed)
+}
+
+/// The field each column of a csv holds, by its headers: the one `columns` maps the header to, or
+/// else the one the header names if that field isn't mapped to another column. Columns of no
+/// field get an empty name.
+fn column_fields(
+    headers: &csv::ByteRecord,
+    columns: &HashMap<String, String>,
+) -> Result<csv::ByteRecord> {
+    let matches = |header: &[u8], name: &str| {
+        header
+            .trim_ascii()
+            .eq_ignore_ascii_case(name.trim().as_bytes())
+    };
+    for (field, name) in columns {
+        ensure!(
+            headers.iter().any(|header| matches(header, name)),
+            "No column {name:?} to read the {field} field from"
+        );
+    }
+
+    Ok(headers
+        .iter()
+        .map(|header| {
+            let mapped = columns
+                .iter()
+                .find(|(_, name)| matches(header, name))
+                .map(|(field, _)| field.as_str());
+            let named = FIELDS
+                .into_iter()
+                .find(|field| matches(header, field) && columns.contains_key(*field).not());
+            mapped.or(named).unwrap_or_default()
+        })
+        .collect())
+}
+
+/// Where a row is, for messages about it, naming the csv it's from when there are several
+pub fn row_location(row: &CsvRow, inputs: usize) -> String {
+    match inputs {
+        1 => format!("line {} (byte {}) of provided csv", row.line, row.byte),
+        _ => format!(
+            "line {} (byte {}) of provided csv #{}",
+            row.line,
+            row.byte,
+            row.input + 1
+        ),
+    }
+}
+
+/// What's wrong with an invalid row and where it is, along with the fields read from it
+pub fn invalid_row_message(row: &CsvRow, inputs: usize, error: &anyhow::Error) -> String {
+    format!(
+        "Invalid row at {}: {error}; row: {:?}",
+        row_location(row, inputs),
+        row.raw()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::{
+        decompress, process_csv_readers, read_csv_inputs, Compression, CsvOptions,
+        DecimalSeparator, InputOrder, InvalidRows, Until,
+    };
+    use crate::util::RoundingMode;
+    use flate2::write::GzEncoder;
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    fn read_order(options: CsvOptions) -> Vec<(usize, u64)> {
+        let first = "type,client,tx,amount,timestamp\n\
+            deposit,1,1,10,1000\n\
+            deposit,1,2,10,3000\n\
+            deposit,1,3,10,\n\
+            deposit,1,4,10,5000\n";
+        let second = "type,client,tx,amount,timestamp\n\
+            deposit,2,5,10,2000\n\
+            deposit,2,6,10,3000\n\
+            deposit,2,7,10,4000\n";
+        let mut rows = Vec::new();
+        let inputs = vec![first.as_bytes(), second.as_bytes()];
+        read_csv_inputs(inputs, &options, RoundingMode::default(), |row| {
+            rows.push((row.input, row.line));
+            Ok(())
+        })
+        .unwrap();
+        rows
+    }
+
+    #[test]
+    fn test_read_csv_inputs_in_order() {
+        assert_eq!(
+            read_order(CsvOptions::default()),
+            [(0, 2), (0, 3), (0, 4), (0, 5), (1, 2), (1, 3), (1, 4)]
+        );
+        // The row without a timestamp stays behind the one before it, and the tie at 3000 goes to
+        // the first csv
+        assert_eq!(
+            read_order(CsvOptions {
+                order: InputOrder::Timestamp,
+                ..CsvOptions::default()
+            }),
+            [(0, 2), (1, 2), (0, 3), (0, 4), (1, 3), (1, 4), (0, 5)]
+        );
+    }
+
+    #[test]
+    fn test_read_csv_inputs_until() {
+        assert_eq!(
+            read_order(CsvOptions {
+                until: Some(Until::Tx(6)),
+                ..CsvOptions::default()
+            }),
+            [(0, 2), (0, 3), (0, 4), (0, 5), (1, 2)]
+        );
+        // The row without a timestamp is merged as of 3000
+        assert_eq!(
+            read_order(CsvOptions {
+                order: InputOrder::Timestamp,
+                until: Some(Until::Timestamp(3000)),
+                ..CsvOptions::default()
+            }),
+            [(0, 2), (1, 2), (0, 3), (0, 4), (1, 3)]
+        );
+        // The csv with the row to stop at is read ahead of the merge, which still goes up to it
+        assert_eq!(
+            read_order(CsvOptions {
+                order: InputOrder::Timestamp,
+                until: Some(Until::Tx(4)),
+                ..CsvOptions::default()
+            }),
+            [(0, 2), (1, 2), (0, 3), (0, 4), (1, 3), (1, 4)]
+        );
+    }
+
+    #[test]
+    fn test_read_csv_inputs_of_clients() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,10\n\
+            deposit,x,3,10\n\
+            deposit,3,4\n\
+            withdrawal,3,5,abc\n\
+            dispute,1,1,\n";
+        let options = CsvOptions {
+            clients: Some([1, 3].into()),
+            ..CsvOptions::default()
+        };
+        let mut rows = Vec::new();
+        read_csv_inputs(
+            vec![csv.as_bytes()],
+            &options,
+            RoundingMode::default(),
+            |row| {
+                rows.push((row.line, row.transaction.is_ok()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        // The row with too few fields can't have its client read
+        assert_eq!(rows, [(2, true), (6, false), (7, true)]);
+    }
+
+    #[test]
+    fn test_fast_parse_reads_rows_the_same() {
+        let csv = "type,client,tx,amount,timestamp,currency\n\
+            deposit,1,1,10.5,1000,\n\
+            withdrawal, 2 ,2, 1.23456 ,,usd\n\
+            dispute,1,1,,,\n\
+            hold,3,3,2,,\n\
+            release,3,3,,,\n\
+            unlock,1,4,,,\n\
+            deposit,1,5,,,\n\
+            dispute,1,1,3,,\n\
+            Deposit,1,6,1,,\n\
+            deposit,70000,7,1,,\n\
+            deposit,1,,1,,\n\
+            deposit,1,8,1,soon,\n\
+            deposit,1,9,-1,,\n\
+            unlock,1,10,,,eur\n\
+            deposit,1,11,1,,toolongcode\n\
+            deposit,1,12\n";
+        let read = |fast_parse| {
+            let options = CsvOptions {
+                fast_parse,
+                ..CsvOptions::default()
+            };
+            let mut transactions = Vec::new();
+            read_csv_inputs(
+                vec![csv.as_bytes()],
+                &options,
+                RoundingMode::HalfEven,
+                |row| {
+                    transactions.push(row.transaction.ok());
+                    Ok(())
+                },
+            )
+            .unwrap();
+            transactions
+        };
+
+        let transactions = read(true);
+        assert_eq!(transactions, read(false));
+        assert_eq!(
+            transactions
+                .iter()
+                .filter(|transaction| transaction.is_some())
+                .count(),
+            6
+        );
+        assert_eq!(transactions[1].as_ref().unwrap().amount(), Some(1_2346));
+    }
+
+    #[test]
+    fn test_max_error_rate() {
+        // Every fourth deposit reuses a tx id, so a quarter of the rows are rejected
+        let mut csv = String::from("type,client,tx,amount\n");
+        for row in 0..2000 {
+            let tx_id = if row % 4 == 3 { row - 1 } else { row };
+            csv.push_str(&format!("deposit,1,{tx_id},1\n"));
+        }
+        let process = |max_error_rate| {
+            let options = CsvOptions {
+                max_error_rate: Some(max_error_rate),
+                ..CsvOptions::default()
+            };
+            let mut engine = Engine::new();
+            let processed = process_csv_readers(
+                &mut engine,
+                vec![csv.as_bytes()],
+                &options,
+                InvalidRows::Skip,
+                None,
+            );
+            (processed, engine)
+        };
+
+        let (processed, _) = process(0.25);
+        assert_eq!(processed.unwrap().failed(), 500);
+        // Processing stops as soon as enough rows were read to check the rate
+        let (processed, engine) = process(0.2);
+        assert!(processed.is_err());
+        assert_eq!(engine.accounts().next().unwrap().available_amount, 750_0000);
+    }
+
+    #[test]
+    fn test_mapped_columns() {
+        // Reordered, extra and differently cased columns, one holding a client id it isn't read
+        // from
+        let csv = "Note,Client ID,TX,Amount,Type,client\n\
+            first,1,1,10,deposit,7\n\
+            second,1,2,4,withdrawal,7\n";
+        for fast_parse in [false, true] {
+            let options = CsvOptions {
+                columns: [("client".to_string(), "client id".to_string())].into(),
+                fast_parse,
+                ..CsvOptions::default()
+            };
+            let mut rows = Vec::new();
+            read_csv_inputs(
+                vec![csv.as_bytes()],
+                &options,
+                RoundingMode::default(),
+                |row| {
+                    let transaction = row.transaction?;
+                    rows.push((transaction.client_id(), transaction.tx_id()));
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(rows, [(1, 1), (1, 2)]);
+        }
+
+        let options = CsvOptions {
+            columns: [("tx".to_string(), "Reference".to_string())].into(),
+            ..CsvOptions::default()
+        };
+        let error = read_csv_inputs(
+            vec![csv.as_bytes()],
+            &options,
+            RoundingMode::default(),
+            |_| Ok(()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No column \"Reference\" to read the tx field from"
+        );
+    }
+
+    #[test]
+    fn test_headerless() {
+        let csv = "1,note,deposit,1,10\n\
+            1,,withdrawal,2,4\n";
+        for fast_parse in [false, true] {
+            let options = CsvOptions {
+                headerless: Some(
+                    ["client", "", "type", "tx", "amount"]
+                        .map(String::from)
+                        .into(),
+                ),
+                fast_parse,
+                ..CsvOptions::default()
+            };
+            let mut rows = Vec::new();
+            read_csv_inputs(
+                vec![csv.as_bytes()],
+                &options,
+                RoundingMode::default(),
+                |row| {
+                    let transaction = row.transaction?;
+                    rows.push((row.line, transaction.tx_id(), transaction.amount()));
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(rows, [(1, 1, Some(10_0000)), (2, 2, Some(4_0000))]);
+        }
+    }
+
+    #[test]
+    fn test_delimiter_quote_and_flexible() {
+        let csv = "type;client;tx;amount\n\
+            deposit;1;1;'1.5'\n\
+            deposit;1;2;'2';extra\n\
+            dispute;1;1\n\
+            deposit;1;3;'x;y'\n";
+        for fast_parse in [false, true] {
+            let options = CsvOptions {
+                delimiter: Some(b';'),
+                quote: Some(b'\''),
+                flexible: true,
+                fast_parse,
+                ..CsvOptions::default()
+            };
+            let (mut rows, mut invalid) = (Vec::new(), Vec::new());
+            read_csv_inputs(
+                vec![csv.as_bytes()],
+                &options,
+                RoundingMode::default(),
+                |row| {
+                    match &row.transaction {
+                        Ok(transaction) => rows.push((transaction.tx_id(), transaction.amount())),
+                        Err(_) => invalid.push(row.raw()),
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(rows, [(1, Some(1_5000)), (2, Some(2_0000)), (1, None)]);
+            // Invalid rows are written back the way they were read
+            assert_eq!(invalid, ["deposit;1;3;'x;y'"]);
+        }
+    }
+
+    #[test]
+    fn test_decimal_comma() {
+        let csv = "type;client;tx;amount\n\
+            deposit;1;1;1,5\n\
+            deposit;1;2;\"2,25\"\n\
+            deposit;1;3;3\n\
+            deposit;1;4;1.5\n";
+        for fast_parse in [false, true] {
+            let options = CsvOptions {
+                delimiter: Some(b';'),
+                decimal_separator: DecimalSeparator::Comma,
+                fast_parse,
+                ..CsvOptions::default()
+            };
+            let mut amounts = Vec::new();
+            read_csv_inputs(
+                vec![csv.as_bytes()],
+                &options,
+                RoundingMode::default(),
+                |row| {
+                    amounts.push(row.transaction.ok().and_then(|t| t.amount()));
+                    Ok(())
+                },
+            )
+            .unwrap();
+            // Amounts with a decimal point are invalid once commas separate decimals
+            assert_eq!(amounts, [Some(1_5000), Some(2_2500), Some(3_0000), None]);
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,20\n\
+            deposit,1,3,5\n";
+        let options = CsvOptions {
+            strict: true,
+            ..CsvOptions::default()
+        };
+        let mut engine = Engine::new();
+        let error = process_csv_readers(
+            &mut engine,
+            vec![csv.as_bytes()],
+            &options,
+            InvalidRows::Skip,
+            None,
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("Rejected transaction at line 3 (byte 37) of provided csv"));
+        assert_eq!(engine.accounts().next().unwrap().available_amount, 10_0000);
+    }
+
+    #[test]
+    fn test_decompress() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10\n";
+
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(csv.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(csv.as_bytes(), 0).unwrap();
+
+        for (compressed, compression) in [
+            (gzip, Compression::Auto.of(Path::new("transactions.csv.gz"))),
+            (
+                zstd,
+                Compression::Auto.of(Path::new("transactions.csv.zst")),
+            ),
+            (
+                csv.into(),
+                Compression::Auto.of(Path::new("transactions.csv")),
+            ),
+        ] {
+            let mut decompressed = String::new();
+            decompress(std::io::Cursor::new(compressed), compression)
+                .unwrap()
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, csv);
+        }
+
+        assert_eq!(
+            Compression::Zstd.of(Path::new("transactions.csv")),
+            Compression::Zstd
+        );
+    }
+}
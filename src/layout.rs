@@ -0,0 +1,618 @@
+use crate::input::{self, CsvOptions, DecimalSeparator, FIELDS};
+use crate::transaction::RawTransactionType;
+use crate::util::RoundingMode;
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Not;
+use std::path::Path;
+
+/// Delimiters `infer` tries, in order of preference when several fit as well
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// Smallest integer taken for a timestamp in milliseconds since the Unix epoch (March 1973),
+/// rather than for an id or an amount
+const MIN_TIMESTAMP: u64 = 100_000_000_000;
+
+/// Headers, as normalized by `normalize_header`, taken for each field
+const HEADER_NAMES: [(&str, &[&str]); 6] = [
+    (
+        "type",
+        &[
+            "type",
+            "kind",
+            "transaction type",
+            "tx type",
+            "txn type",
+            "action",
+            "operation",
+        ],
+    ),
+    (
+        "client",
+        &[
+            "client",
+            "client id",
+            "clientid",
+            "customer",
+            "customer id",
+            "account",
+            "account id",
+            "user",
+            "user id",
+        ],
+    ),
+    (
+        "tx",
+        &[
+            "tx",
+            "tx id",
+            "txid",
+            "txn",
+            "txn id",
+            "transaction",
+            "transaction id",
+            "reference",
+            "ref",
+            "id",
+        ],
+    ),
+    ("amount", &["amount", "amt", "value", "sum"]),
+    (
+        "timestamp",
+        &[
+            "timestamp",
+            "time",
+            "ts",
+            "created at",
+            "time ms",
+            "epoch ms",
+        ],
+    ),
+    ("currency", &["currency", "ccy", "currency code", "cur"]),
+];
+
+/// How a transactions csv is laid out, as `infer` guesses it and `--csv-layout` reads it: the
+/// YAML counterpart of `--delimiter`, `--decimal-separator`, `--map-column` and, for csvs without
+/// a header row, `--no-header` with `--column-order`
+///
+/// ```yaml
+/// delimiter: ';'
+/// decimal_separator: comma
+/// columns:
+///   type: Kind
+///   client: Customer ID
+///   tx: Reference
+///   amount: Amount
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsvLayout {
+    /// Character separating fields, a comma if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<char>,
+    #[serde(default)]
+    pub decimal_separator: DecimalSeparator,
+    /// Header of the column each field is read from, by field, for csvs with a header row
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub columns: BTreeMap<String, String>,
+    /// Field of every column, in order, for csvs without a header row, an empty name leaving a
+    /// column out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column_order: Option<Vec<String>>,
+}
+
+impl CsvLayout {
+    /// Reads a layout from a YAML file, such as one `infer` wrote
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        let layout: Self = serde_yaml::from_str(yaml)?;
+        if let Some(delimiter) = layout.delimiter {
+            ensure!(
+                delimiter.is_ascii(),
+                "The delimiter must be an ASCII character"
+            );
+        }
+        ensure!(
+            layout.columns.is_empty() || layout.column_order.is_none(),
+            "A layout has either columns, for csvs with a header row, or a column order, for csvs \
+            without"
+        );
+        let fields = layout
+            .columns
+            .keys()
+            .chain(layout.column_order.iter().flatten());
+        for field in fields {
+            ensure!(
+                field.is_empty() || FIELDS.contains(&field.as_str()),
+                "Unknown field {field:?}, expected one of {}",
+                FIELDS.join(", ")
+            );
+        }
+        Ok(layout)
+    }
+
+    /// Sets how the csvs are laid out in the options they're read with
+    pub fn apply(&self, options: CsvOptions) -> CsvOptions {
+        CsvOptions {
+            columns: self.columns.clone().into_iter().collect(),
+            headerless: self.column_order.clone(),
+            delimiter: self.delimiter.map(|delimiter| delimiter as u8),
+            decimal_separator: self.decimal_separator,
+            ..options
+        }
+    }
+}
+
+/// What `infer` made of a csv
+#[derive(Debug)]
+pub struct Inference {
+    pub layout: CsvLayout,
+    /// The guess for every column, in order
+    pub columns: Vec<ColumnGuess>,
+    /// Number of rows looked at, not counting the header
+    pub sampled: usize,
+    /// How many of them read as valid transactions with the layout
+    pub valid: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ColumnGuess {
+    /// The column's header, if the csv has a header row
+    pub header: Option<String>,
+    /// The field it was taken for, if any
+    pub field: Option<&'static str>,
+    /// Why, e.g. `from its header`
+    pub reason: &'static str,
+}
+
+/// Guesses how a transactions csv of an unfamiliar format is laid out from its first
+/// `sample_rows` rows: the delimiter that splits them into the same number of fields, whether the
+/// first row is a header, which column holds which field, by header first (`Customer ID` for
+/// `client`, ...) then by values (transaction types, decimal amounts, currency codes, ids), and
+/// the decimal separator of amounts. The rows are then read with the layout guessed, to count
+/// those that are valid transactions. Csvs in which no column could be taken for the `type`,
+/// `client` or `tx` field are an error.
+pub fn infer(reader: impl Read, sample_rows: usize) -> Result<Inference> {
+    ensure!(sample_rows > 0, "At least one row must be sampled");
+    // One more line than rows sampled, in case the first is a header
+    let mut sample = String::new();
+    let mut line_ends = Vec::new();
+    let mut reader = BufReader::new(reader);
+    for _ in 0..=sample_rows {
+        if reader.read_line(&mut sample)? == 0 {
+            break;
+        }
+        line_ends.push(sample.len());
+    }
+
+    let (delimiter, mut rows) = split_rows(&sample)?;
+    let has_header = is_header(&rows);
+    if has_header.not() && line_ends.len() > sample_rows {
+        rows.truncate(sample_rows);
+        sample.truncate(line_ends[sample_rows - 1]);
+    }
+    let (headers, values) = match has_header {
+        true => (Some(&rows[0]), &rows[1..]),
+        false => (None, &rows[..]),
+    };
+    let width = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let columns: Vec<Column> = (0..width)
+        .map(|index| Column::new(values.iter().filter_map(|row| row.get(index))))
+        .collect();
+
+    let mut fields: Vec<Option<(&'static str, &'static str)>> = vec![None; width];
+    if let Some(headers) = headers {
+        for (index, header) in headers.iter().enumerate() {
+            let header = normalize_header(header);
+            let field = HEADER_NAMES
+                .iter()
+                .find(|(_, names)| names.contains(&header.as_str()))
+                .map(|(field, _)| *field);
+            if let Some(field) = field.filter(|field| mapped(&fields, field).not()) {
+                fields[index] = Some((field, "from its header"));
+            }
+        }
+    }
+    guess_from_values(&columns, &mut fields);
+
+    let missing: Vec<&str> = ["type", "client", "tx"]
+        .into_iter()
+        .filter(|field| mapped(&fields, field).not())
+        .collect();
+    if missing.is_empty().not() {
+        bail!(
+            "Couldn't find a column for the {} field{}, which --map-column or --column-order can \
+            point to",
+            missing.join(", "),
+            if missing.len() > 1 { "s" } else { "" }
+        );
+    }
+
+    let decimal_separator = match fields
+        .iter()
+        .position(|field| field.is_some_and(|(field, _)| field == "amount"))
+    {
+        Some(index) if columns[index].comma_decimals => DecimalSeparator::Comma,
+        _ => DecimalSeparator::Point,
+    };
+    let layout = CsvLayout {
+        delimiter: (delimiter != b',').then_some(delimiter as char),
+        decimal_separator,
+        columns: match headers {
+            Some(headers) => fields
+                .iter()
+                .zip(headers)
+                .filter_map(|(field, header)| Some((field.as_ref()?.0.to_string(), header.clone())))
+                .collect(),
+            None => BTreeMap::new(),
+        },
+        column_order: has_header.not().then(|| {
+            fields
+                .iter()
+                .map(|field| field.map_or(String::new(), |(field, _)| field.to_string()))
+                .collect()
+        }),
+    };
+
+    let (mut sampled, mut valid) = (0, 0);
+    let options = layout.apply(CsvOptions {
+        flexible: true,
+        ..CsvOptions::default()
+    });
+    input::read_csv_inputs(
+        vec![sample.as_bytes()],
+        &options,
+        RoundingMode::default(),
+        |row| {
+            sampled += 1;
+            valid += row.transaction.is_ok() as usize;
+            Ok(())
+        },
+    )
+    .context("Failed to read the csv with the layout guessed")?;
+
+    Ok(Inference {
+        layout,
+        columns: fields
+            .iter()
+            .enumerate()
+            .map(|(index, field)| ColumnGuess {
+                header: headers.and_then(|headers| headers.get(index).cloned()),
+                field: field.map(|(field, _)| field),
+                reason: field.map_or("no field fits it", |(_, reason)| reason),
+            })
+            .collect(),
+        sampled,
+        valid,
+    })
+}
+
+/// Splits the sample into rows of trimmed fields with the delimiter that gives the most rows of
+/// the same number of fields, more than one
+fn split_rows(sample: &str) -> Result<(u8, Vec<Vec<String>>)> {
+    let mut best: Option<(usize, u8, Vec<Vec<String>>)> = None;
+    for delimiter in DELIMITERS {
+        let rows: Vec<Vec<String>> = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(delimiter)
+            .from_reader(sample.as_bytes())
+            .records()
+            .filter_map(Result::ok)
+            .map(|record| record.iter().map(String::from).collect())
+            .collect();
+        let mut widths: BTreeMap<usize, usize> = BTreeMap::new();
+        for row in rows.iter() {
+            *widths.entry(row.len()).or_default() += 1;
+        }
+        let consistent = widths
+            .iter()
+            .filter(|(width, _)| **width > 1)
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or_default();
+        if consistent > best.as_ref().map_or(0, |(consistent, _, _)| *consistent) {
+            best = Some((consistent, delimiter, rows));
+        }
+    }
+    match best {
+        Some((_, delimiter, rows)) => Ok((delimiter, rows)),
+        None => bail!("Couldn't find a delimiter splitting the rows into several fields"),
+    }
+}
+
+/// Whether the first row is a header: one of its fields is a known header, or it has text where
+/// the other rows have numbers
+fn is_header(rows: &[Vec<String>]) -> bool {
+    let Some((first, rest)) = rows.split_first() else {
+        return false;
+    };
+    let known = first.iter().any(|field| {
+        let field = normalize_header(field);
+        HEADER_NAMES
+            .iter()
+            .any(|(_, names)| names.contains(&field.as_str()))
+    });
+    known
+        || (0..first.len()).any(|index| {
+            let numbers = rest
+                .iter()
+                .filter(|row| row.get(index).is_some_and(|field| is_number(field)))
+                .count();
+            is_number(&first[index]).not() && rest.is_empty().not() && numbers * 2 >= rest.len()
+        })
+}
+
+/// Assigns the fields no header was found for to the columns whose values fit them
+fn guess_from_values(columns: &[Column], fields: &mut [Option<(&'static str, &'static str)>]) {
+    let free = |fields: &[Option<_>], fit: &dyn Fn(&Column) -> bool| {
+        (0..columns.len()).find(|index| fields[*index].is_none() && fit(&columns[*index]))
+    };
+    let assign = |fields: &mut [Option<_>], field, reason, fit: &dyn Fn(&Column) -> bool| {
+        if mapped(fields, field).not() {
+            if let Some(index) = free(fields, fit) {
+                fields[index] = Some((field, reason));
+            }
+        }
+    };
+
+    assign(
+        fields,
+        "type",
+        "its values are transaction types",
+        &|column| column.types,
+    );
+    assign(
+        fields,
+        "amount",
+        "its values are decimal amounts",
+        &|column| column.decimals && column.integers.not(),
+    );
+    assign(
+        fields,
+        "timestamp",
+        "its values are timestamps in milliseconds",
+        &|column| column.integers && column.min >= MIN_TIMESTAMP,
+    );
+    assign(
+        fields,
+        "currency",
+        "its values are currency codes",
+        &|column| column.currencies,
+    );
+
+    // Of the id columns, the one whose values repeat the least is taken for the tx ids
+    let ids: Vec<usize> = (0..columns.len())
+        .filter(|index| {
+            let column = &columns[*index];
+            fields[*index].is_none() && column.integers && column.empty == 0
+        })
+        .collect();
+    let mut by_uniqueness = ids.clone();
+    by_uniqueness.sort_by(|a, b| {
+        let key = |column: &Column| (column.max > u64::from(u16::MAX), column.distinct_ratio());
+        key(&columns[*b])
+            .partial_cmp(&key(&columns[*a]))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    if mapped(fields, "tx").not() {
+        if let Some(index) = by_uniqueness.iter().find(|index| fields[**index].is_none()) {
+            fields[*index] = Some(("tx", "its values are unique ids"));
+        }
+    }
+    if mapped(fields, "client").not() {
+        if let Some(index) = ids
+            .iter()
+            .find(|index| fields[**index].is_none() && columns[**index].max <= u64::from(u16::MAX))
+        {
+            fields[*index] = Some(("client", "its values are repeated ids"));
+        }
+    }
+    // Amounts without decimals, such as `100`, are only told apart from ids by elimination
+    assign(fields, "amount", "its values are amounts", &|column| {
+        column.decimals
+    });
+}
+
+fn mapped(fields: &[Option<(&str, &str)>], field: &str) -> bool {
+    fields
+        .iter()
+        .any(|mapped| mapped.is_some_and(|(mapped, _)| mapped == field))
+}
+
+/// Lowercases a header and turns the separators of its words into single spaces
+fn normalize_header(header: &str) -> String {
+    header
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_' || c == '-' || c == '.')
+        .filter(|word| word.is_empty().not())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_number(field: &str) -> bool {
+    field.is_empty().not()
+        && field
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == ',' || c == '-')
+}
+
+/// What the values of a column look like, ignoring empty ones
+#[derive(Default)]
+struct Column {
+    /// Every value is the name of a transaction type
+    types: bool,
+    /// Every value is a non-negative integer
+    integers: bool,
+    /// Every value is a non-negative decimal number, with or without decimals
+    decimals: bool,
+    /// Some value has a decimal comma rather than a point
+    comma_decimals: bool,
+    /// Every value is a currency code, in capital letters so as not to be taken for a number or
+    /// for text
+    currencies: bool,
+    min: u64,
+    max: u64,
+    values: usize,
+    distinct: usize,
+    empty: usize,
+}
+
+impl Column {
+    fn new<'a>(values: impl Iterator<Item = &'a String>) -> Self {
+        let mut column = Self {
+            types: true,
+            integers: true,
+            decimals: true,
+            currencies: true,
+            min: u64::MAX,
+            ..Self::default()
+        };
+        let mut distinct = HashSet::new();
+        for value in values {
+            if value.is_empty() {
+                column.empty += 1;
+                continue;
+            }
+            column.values += 1;
+            distinct.insert(value.as_str());
+            column.types &= RawTransactionType::ALL.iter().any(|transaction_type| {
+                serde_json::to_value(transaction_type)
+                    .is_ok_and(|name| name.as_str() == Some(value.to_lowercase().as_str()))
+            });
+            match value.parse::<u64>() {
+                Ok(value) => {
+                    column.min = column.min.min(value);
+                    column.max = column.max.max(value);
+                }
+                Err(_) => column.integers = false,
+            }
+            let (whole, decimals) = match value.split_once(['.', ',']) {
+                Some((whole, decimals)) => (whole, Some(decimals)),
+                None => (value.as_str(), None),
+            };
+            let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+            column.decimals &= digits(whole) && decimals.is_none_or(digits);
+            column.comma_decimals |= value.contains(',');
+            column.currencies &=
+                (2..=8).contains(&value.len()) && value.chars().all(|c| c.is_ascii_uppercase());
+        }
+        column.distinct = distinct.len();
+        if column.values == 0 {
+            column.types = false;
+            column.integers = false;
+            column.decimals = false;
+            column.currencies = false;
+        }
+        column.currencies &= column.types.not();
+        column
+    }
+
+    fn distinct_ratio(&self) -> f64 {
+        self.distinct as f64 / self.values.max(1) as f64
+    }
+}
+
+impl fmt::Display for Inference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, column) in self.columns.iter().enumerate() {
+            let header = match column.header.as_deref() {
+                Some(header) => format!(" ({header:?})"),
+                None => String::new(),
+            };
+            let field = column.field.unwrap_or("ignored");
+            writeln!(
+                f,
+                "Column {}{header}: {field}, {}",
+                index + 1,
+                column.reason
+            )?;
+        }
+        write!(
+            f,
+            "{} of {} sample rows read as valid transactions",
+            self.valid, self.sampled
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{self, DecimalSeparator};
+    use crate::layout::{infer, CsvLayout};
+    use crate::util::RoundingMode;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_infer_with_header() {
+        let csv = "Kind;Customer ID;Reference;Amount;Notes\n\
+            deposit;1;1001;1,5;first\n\
+            deposit;2;1002;20;\n\
+            withdrawal;1;1003;0,75;\n\
+            dispute;1;1001;;late\n";
+        let inference = infer(csv.as_bytes(), 100).unwrap();
+        assert_eq!(
+            inference.layout,
+            CsvLayout {
+                delimiter: Some(';'),
+                decimal_separator: DecimalSeparator::Comma,
+                columns: BTreeMap::from([
+                    ("type".to_string(), "Kind".to_string()),
+                    ("client".to_string(), "Customer ID".to_string()),
+                    ("tx".to_string(), "Reference".to_string()),
+                    ("amount".to_string(), "Amount".to_string()),
+                ]),
+                column_order: None,
+            }
+        );
+        assert_eq!(inference.columns[4].field, None);
+        assert_eq!((inference.sampled, inference.valid), (4, 4));
+
+        // The layout written as YAML reads back the same and reads the csv
+        let yaml = serde_yaml::to_string(&inference.layout).unwrap();
+        let layout = CsvLayout::from_yaml(&yaml).unwrap();
+        assert_eq!(layout, inference.layout);
+        let mut amounts = Vec::new();
+        input::read_csv_inputs(
+            vec![csv.as_bytes()],
+            &layout.apply(Default::default()),
+            RoundingMode::default(),
+            |row| {
+                amounts.push(row.transaction?.amount());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(amounts, [Some(1_5000), Some(20_0000), Some(7500), None]);
+    }
+
+    #[test]
+    fn test_infer_without_header() {
+        let csv = "7,deposit,1700000000000,100001,12.5,EUR\n\
+            7,deposit,1700000000500,100002,3,EUR\n\
+            9,deposit,1700000001000,100003,1.25,USD\n\
+            7,withdrawal,1700000002000,100004,2.0,EUR\n";
+        let inference = infer(csv.as_bytes(), 100).unwrap();
+        assert_eq!(inference.layout.delimiter, None);
+        assert!(inference.layout.columns.is_empty());
+        assert_eq!(
+            inference.layout.column_order.as_deref().unwrap(),
+            ["client", "type", "timestamp", "tx", "amount", "currency"]
+        );
+        assert_eq!((inference.sampled, inference.valid), (4, 4));
+
+        // Only the first rows are looked at
+        assert_eq!(infer(csv.as_bytes(), 2).unwrap().sampled, 2);
+
+        assert!(infer("a,b\nc,d\n".as_bytes(), 100).is_err());
+        assert!(CsvLayout::from_yaml("columns:\n  kind: Kind\n").is_err());
+    }
+}
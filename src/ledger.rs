@@ -0,0 +1,455 @@
+use core::fmt::{self, Debug, Display, Formatter};
+use core::ops::Not;
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+
+/// Signed balances, such as an account's available funds. Amounts are fixed point numbers with 4
+/// decimal places, so an `i64` caps balances around 922 trillion, and the `wide-balances` feature
+/// switches to an `i128` for ledgers that could outgrow it.
+#[cfg(not(feature = "wide-balances"))]
+pub type Balance = i64;
+#[cfg(feature = "wide-balances")]
+pub type Balance = i128;
+
+/// Unsigned balances, such as an account's held funds, as wide as `Balance`
+#[cfg(not(feature = "wide-balances"))]
+pub type HeldBalance = u64;
+#[cfg(feature = "wide-balances")]
+pub type HeldBalance = u128;
+
+/// A currency code such as `USD`: up to 8 ASCII letters or digits, kept uppercase. It's stored
+/// inline so that transactions stay cheap to clone.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "std",
+    derive(Serialize, Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Currency(pub(crate) [u8; 8]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|byte| *byte == 0).unwrap_or(8);
+        core::str::from_utf8(&self.0[..len]).expect("Currency codes are ASCII")
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Debug for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// What happens when a dispute holds more of a deposit than the account has available, such as
+/// when the deposited funds were withdrawn since
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NegativeBalancePolicy {
+    /// Hold the whole amount, taking the available funds negative
+    #[default]
+    Allow,
+    /// Only hold what's available, recording the rest as the dispute's shortfall (see
+    /// `DisputeView::shortfall`), so that the available funds stop at zero
+    Clamp,
+    /// Reject the dispute
+    Reject,
+}
+
+/// Why a change to an account's balances was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalanceError {
+    /// A withdrawal or hold asked for more than the available funds
+    InsufficientFunds,
+    /// One of the balances, or their total, would go out of range
+    Overflow,
+}
+
+impl Display for BalanceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BalanceError::InsufficientFunds => write!(f, "Insufficient available funds"),
+            BalanceError::Overflow => write!(f, "The balance would overflow"),
+        }
+    }
+}
+
+/// Why a dispute, resolve, chargeback or reversal was refused by the transaction it references
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisputeError {
+    Balance(BalanceError),
+    /// The transaction is older than the dispute window
+    WindowExpired,
+    /// The funds of the disputed deposit were spent, which `NegativeBalancePolicy::Reject`
+    /// refuses
+    FundsSpent,
+    /// A reversal references a deposit
+    NotAWithdrawal,
+    /// A reversal references a withdrawal that was reversed already
+    AlreadyReversed,
+    /// The operation doesn't fit the transaction's state
+    InvalidState(DisputeState),
+}
+
+impl From<BalanceError> for DisputeError {
+    fn from(error: BalanceError) -> Self {
+        DisputeError::Balance(error)
+    }
+}
+
+impl Display for DisputeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DisputeError::Balance(error) => Display::fmt(error, f),
+            DisputeError::WindowExpired => write!(f, "The transaction is past the dispute window"),
+            DisputeError::FundsSpent => write!(f, "The deposited funds were spent"),
+            DisputeError::NotAWithdrawal => write!(f, "The transaction isn't a withdrawal"),
+            DisputeError::AlreadyReversed => write!(f, "The withdrawal was already reversed"),
+            DisputeError::InvalidState(state) => write!(f, "The transaction is {state:?}"),
+        }
+    }
+}
+
+/// An account's balances in one currency. Their total always fits a `Balance`, since every
+/// change is checked, so it can be computed without overflowing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct Balances {
+    pub available_amount: Balance,
+    pub held_amount: HeldBalance,
+}
+
+impl Balances {
+    /// Balances with these amounts, unless their total doesn't fit a `Balance`
+    pub fn new(available: Balance, held: HeldBalance) -> Result<Self, BalanceError> {
+        available
+            .checked_add_unsigned(held)
+            .map(|_| Self {
+                available_amount: available,
+                held_amount: held,
+            })
+            .ok_or(BalanceError::Overflow)
+    }
+
+    /// The total of the balances, within range even when the held balance alone isn't
+    pub fn total(&self) -> Balance {
+        self.available_amount
+            .wrapping_add_unsigned(self.held_amount)
+    }
+
+    /// Adds to the available funds
+    pub fn credit(self, amount: u64) -> Result<Self, BalanceError> {
+        Self::new(credit(self.available_amount, amount)?, self.held_amount)
+    }
+
+    /// Takes from the available funds, which may go negative, like a suspense account's
+    pub fn debit(self, amount: u64) -> Result<Self, BalanceError> {
+        Self::new(debit(self.available_amount, amount)?, self.held_amount)
+    }
+
+    /// Adds to the held funds, like a deposit into a locked account
+    pub fn credit_held(self, amount: u64) -> Result<Self, BalanceError> {
+        Self::new(
+            self.available_amount,
+            credit_held(self.held_amount, amount)?,
+        )
+    }
+
+    /// Takes from the available funds, which can't go negative unless `from_held` lets the held
+    /// funds make up for them, the total staying positive
+    pub fn withdraw(self, amount: u64, from_held: bool) -> Result<Self, BalanceError> {
+        let spendable = |available: Balance| match from_held {
+            true => available.checked_add_unsigned(self.held_amount),
+            false => Some(available),
+        };
+        match self
+            .available_amount
+            .checked_sub_unsigned(amount as HeldBalance)
+        {
+            Some(available) if spendable(available).is_some_and(|spendable| spendable >= 0) => {
+                Self::new(available, self.held_amount)
+            }
+            _ => Err(BalanceError::InsufficientFunds),
+        }
+    }
+
+    /// Moves available funds to the held ones, as long as enough of them are available
+    pub fn hold(self, amount: u64) -> Result<Self, BalanceError> {
+        match self
+            .available_amount
+            .checked_sub_unsigned(amount as HeldBalance)
+        {
+            Some(available) if available >= 0 => {
+                Self::new(available, credit_held(self.held_amount, amount)?)
+            }
+            _ => Err(BalanceError::InsufficientFunds),
+        }
+    }
+
+    /// Moves held funds back to the available ones
+    pub fn release(self, amount: u64) -> Result<Self, BalanceError> {
+        Self::new(
+            credit(self.available_amount, amount)?,
+            debit_held(self.held_amount, amount)?,
+        )
+    }
+}
+
+fn credit(available: Balance, amount: u64) -> Result<Balance, BalanceError> {
+    available
+        .checked_add_unsigned(amount as HeldBalance)
+        .ok_or(BalanceError::Overflow)
+}
+
+fn debit(available: Balance, amount: u64) -> Result<Balance, BalanceError> {
+    available
+        .checked_sub_unsigned(amount as HeldBalance)
+        .ok_or(BalanceError::Overflow)
+}
+
+fn credit_held(held: HeldBalance, amount: u64) -> Result<HeldBalance, BalanceError> {
+    held.checked_add(amount as HeldBalance)
+        .ok_or(BalanceError::Overflow)
+}
+
+fn debit_held(held: HeldBalance, amount: u64) -> Result<HeldBalance, BalanceError> {
+    held.checked_sub(amount as HeldBalance)
+        .ok_or(BalanceError::Overflow)
+}
+
+/// The kinds of transactions that can be disputed
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub enum DisputableKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Where a deposit or withdrawal stands with regard to disputes
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub enum DisputeState {
+    Valid,
+    InDispute,
+    ChargedBack,
+    /// A withdrawal undone by a reversal, which can no longer be disputed
+    Reversed,
+}
+
+/// An applied deposit or withdrawal, which disputes, resolves, chargebacks and reversals move
+/// through its `DisputeState`. Each of them takes the balances of the account in the
+/// transaction's currency, and returns the transaction and balances it results in, leaving both
+/// as they were when it's refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct DisputableTransaction {
+    pub kind: DisputableKind,
+    pub amount: u64,
+    pub currency: Option<Currency>,
+    /// When the deposit or withdrawal happened, if its row had a timestamp
+    pub timestamp: Option<u64>,
+    /// Whether it's a deposit into a locked account, whose funds are held until it's unlocked
+    pub held: bool,
+    pub state: DisputeState,
+    /// The part of the amount its dispute couldn't hold, see `DisputeView::shortfall`
+    pub shortfall: u64,
+}
+
+impl DisputableTransaction {
+    pub fn new(
+        kind: DisputableKind,
+        amount: u64,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+        held: bool,
+    ) -> Self {
+        Self {
+            kind,
+            amount,
+            currency,
+            timestamp,
+            held,
+            state: DisputeState::Valid,
+            shortfall: 0,
+        }
+    }
+
+    /// The part of the amount a dispute holds, all of it unless there's a shortfall
+    pub fn covered_amount(&self) -> u64 {
+        self.amount - self.shortfall
+    }
+
+    /// Whether it's neither in dispute nor held, and wasn't charged back
+    pub fn is_settled(&self) -> bool {
+        self.state == DisputeState::Valid && self.held.not()
+    }
+
+    /// Disputing a deposit holds the deposited funds, as far as the policy allows when they were
+    /// spent already. Disputing a withdrawal holds the withdrawn funds as a provisional credit,
+    /// which the client can't use until the dispute is settled. With `not_before`, transactions
+    /// with an earlier timestamp can no longer be disputed.
+    pub fn dispute(
+        mut self,
+        balances: Balances,
+        not_before: Option<u64>,
+        negative_balance_policy: NegativeBalancePolicy,
+    ) -> Result<(Self, Balances), DisputeError> {
+        if self.state != DisputeState::Valid {
+            return Err(DisputeError::InvalidState(self.state));
+        }
+        if let (Some(not_before), Some(timestamp)) = (not_before, self.timestamp) {
+            if timestamp < not_before {
+                return Err(DisputeError::WindowExpired);
+            }
+        }
+        let mut balances = balances;
+        // The funds of a deposit into a locked account are held already
+        if self.held.not() {
+            if self.kind == DisputableKind::Deposit {
+                let available = balances.available_amount.max(0) as HeldBalance;
+                let unavailable = match available < self.amount as HeldBalance {
+                    true => self.amount - available as u64,
+                    false => 0,
+                };
+                match negative_balance_policy {
+                    NegativeBalancePolicy::Allow => {}
+                    NegativeBalancePolicy::Clamp => self.shortfall = unavailable,
+                    NegativeBalancePolicy::Reject if unavailable > 0 => {
+                        return Err(DisputeError::FundsSpent)
+                    }
+                    NegativeBalancePolicy::Reject => {}
+                }
+            }
+            let covered = self.covered_amount();
+            let available = match self.kind {
+                DisputableKind::Deposit => debit(balances.available_amount, covered)?,
+                DisputableKind::Withdrawal => balances.available_amount,
+            };
+            balances = Balances::new(available, credit_held(balances.held_amount, covered)?)?;
+        }
+        self.state = DisputeState::InDispute;
+        Ok((self, balances))
+    }
+
+    /// Resolving a dispute releases the held funds: back to available for a deposit, and no
+    /// longer credited for a withdrawal
+    pub fn resolve(mut self, balances: Balances) -> Result<(Self, Balances), DisputeError> {
+        if self.state != DisputeState::InDispute {
+            return Err(DisputeError::InvalidState(self.state));
+        }
+        let mut balances = balances;
+        if self.held.not() {
+            let covered = self.covered_amount();
+            let available = match self.kind {
+                DisputableKind::Deposit => credit(balances.available_amount, covered)?,
+                DisputableKind::Withdrawal => balances.available_amount,
+            };
+            balances = Balances::new(available, debit_held(balances.held_amount, covered)?)?;
+        }
+        self.shortfall = 0;
+        self.state = DisputeState::Valid;
+        Ok((self, balances))
+    }
+
+    /// Charging back a deposit takes the held funds out of the account, while charging back a
+    /// withdrawal refunds them
+    pub fn chargeback(mut self, balances: Balances) -> Result<(Self, Balances), DisputeError> {
+        if self.state != DisputeState::InDispute {
+            return Err(DisputeError::InvalidState(self.state));
+        }
+        let available = match self.kind {
+            DisputableKind::Deposit => balances.available_amount,
+            DisputableKind::Withdrawal => credit(balances.available_amount, self.amount)?,
+        };
+        let held = debit_held(balances.held_amount, self.covered_amount())?;
+        self.state = DisputeState::ChargedBack;
+        Ok((self, Balances::new(available, held)?))
+    }
+
+    /// Reversing a withdrawal credits its amount back to the available funds, as long as it's
+    /// neither in dispute nor charged back, and it can't be disputed afterwards
+    pub fn reverse(mut self, balances: Balances) -> Result<(Self, Balances), DisputeError> {
+        if self.kind != DisputableKind::Withdrawal {
+            return Err(DisputeError::NotAWithdrawal);
+        }
+        match self.state {
+            DisputeState::Valid => {
+                self.state = DisputeState::Reversed;
+                Ok((self, balances.credit(self.amount)?))
+            }
+            DisputeState::Reversed => Err(DisputeError::AlreadyReversed),
+            DisputeState::InDispute | DisputeState::ChargedBack => {
+                Err(DisputeError::InvalidState(self.state))
+            }
+        }
+    }
+
+    /// What a chargeback of the transaction, which has to be in dispute, moves to a suspense
+    /// account: the disputed funds of a deposit, and the refund of a withdrawal
+    pub fn suspense_balances(&self, balances: Balances) -> Result<Balances, BalanceError> {
+        match self.kind {
+            DisputableKind::Deposit => balances.credit(self.covered_amount()),
+            DisputableKind::Withdrawal => balances.debit(self.amount),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{
+        Balance, BalanceError, Balances, DisputableKind, DisputableTransaction, DisputeError,
+        DisputeState, NegativeBalancePolicy,
+    };
+
+    #[test]
+    fn test_dispute_transitions() {
+        let deposit =
+            DisputableTransaction::new(DisputableKind::Deposit, 10_0000, None, None, false);
+        // Most of the deposit was spent
+        let balances = Balances::new(4_0000, 0).unwrap();
+
+        let (disputed, held) = deposit
+            .dispute(balances, None, NegativeBalancePolicy::Clamp)
+            .unwrap();
+        assert_eq!(
+            (disputed.state, disputed.shortfall),
+            (DisputeState::InDispute, 6_0000)
+        );
+        assert_eq!(held, Balances::new(0, 4_0000).unwrap());
+        assert_eq!(
+            deposit.dispute(balances, None, NegativeBalancePolicy::Reject),
+            Err(DisputeError::FundsSpent)
+        );
+        assert_eq!(
+            disputed.dispute(held, None, NegativeBalancePolicy::Allow),
+            Err(DisputeError::InvalidState(DisputeState::InDispute))
+        );
+
+        let (resolved, released) = disputed.resolve(held).unwrap();
+        assert_eq!(resolved, deposit);
+        assert_eq!(released, balances);
+
+        let (charged_back, remaining) = disputed.chargeback(held).unwrap();
+        assert_eq!(charged_back.state, DisputeState::ChargedBack);
+        assert_eq!(remaining, Balances::default());
+        assert_eq!(deposit.reverse(balances), Err(DisputeError::NotAWithdrawal));
+    }
+
+    #[test]
+    fn test_balances() {
+        let balances = Balances::new(1_0000, 0).unwrap();
+        assert_eq!(
+            balances.withdraw(2_0000, false),
+            Err(BalanceError::InsufficientFunds)
+        );
+        let held = balances.hold(1_0000).unwrap();
+        assert_eq!(held.withdraw(1_0000, true).unwrap().total(), 0);
+        assert_eq!(held.release(1_0000).unwrap(), balances);
+        assert_eq!(Balances::new(Balance::MAX, 1), Err(BalanceError::Overflow));
+    }
+}
@@ -0,0 +1,138 @@
+//! A payments engine processing deposits, withdrawals, disputes, resolves and chargebacks into
+//! client account balances.
+//!
+//! The [`Engine`] can be embedded directly: feed it [`Transaction`]s and read the resulting
+//! [`AccountView`]s back. Amounts are fixed point numbers with 4 decimal places, which the helpers
+//! in [`util`] convert from and to their decimal string representation.
+//!
+//! ```
+//! use payments_engine::{float_str_to_fixed_point_4_decimal, Engine, Transaction};
+//!
+//! let mut engine = Engine::new();
+//! engine
+//!     .process_transaction(Transaction::Deposit {
+//!         client_id: 1,
+//!         tx_id: 1,
+//!         amount: float_str_to_fixed_point_4_decimal("10.5").unwrap(),
+//!         currency: None,
+//!     })
+//!     .unwrap();
+//! assert!(engine
+//!     .process_transaction(Transaction::Withdrawal {
+//!         client_id: 1,
+//!         tx_id: 2,
+//!         amount: float_str_to_fixed_point_4_decimal("20").unwrap(),
+//!         currency: None,
+//!     })
+//!     .is_err());
+//! assert_eq!(engine.account(1).unwrap().available_amount, 105_000);
+//! ```
+//!
+//! Only the [`Engine`] and what it needs come with the `std` feature: reading and writing csv
+//! files, the command line, the servers and the files backing the engine are behind the `csv`,
+//! `cli`, `server` and `storage` features, all of them on by default.
+//!
+//! Without the default `std` feature, the crate is `no_std` and only has the [`ledger`] module:
+//! the balance arithmetic and dispute transitions the engine applies to accounts, for
+//! environments without files, threads or sockets, such as WASM workers.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "server")]
+pub mod alerts;
+#[cfg(feature = "csv")]
+pub mod anomalies;
+#[cfg(feature = "async-engine")]
+pub mod async_engine;
+#[cfg(feature = "csv")]
+pub mod audit;
+#[cfg(feature = "csv")]
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod engine;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod event_log;
+#[cfg(feature = "csv")]
+pub mod explain;
+#[cfg(feature = "flight")]
+pub mod flight;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "std")]
+pub mod handlers;
+#[cfg(feature = "server")]
+pub mod http;
+#[cfg(feature = "csv")]
+pub mod input;
+#[cfg(feature = "csv")]
+pub mod layout;
+pub mod ledger;
+#[cfg(feature = "server")]
+pub mod loadtest;
+#[cfg(feature = "cli")]
+pub mod logging;
+#[cfg(feature = "server")]
+pub mod openapi;
+#[cfg(feature = "csv")]
+pub mod output;
+#[cfg(feature = "csv")]
+pub mod parallel;
+#[cfg(feature = "arrow")]
+pub mod record_batch;
+#[cfg(feature = "csv")]
+pub mod rejected;
+#[cfg(feature = "server")]
+pub mod rpc;
+#[cfg(feature = "csv")]
+pub mod rules;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(feature = "csv")]
+pub mod settlement;
+#[cfg(feature = "std")]
+pub mod shadow;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "csv")]
+pub mod soak;
+#[cfg(feature = "csv")]
+pub mod statement;
+#[cfg(feature = "csv")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod transaction;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "std")]
+pub mod tx_index;
+#[cfg(feature = "std")]
+pub mod util;
+#[cfg(feature = "csv")]
+pub mod validate;
+#[cfg(feature = "storage")]
+pub mod wal;
+#[cfg(feature = "server")]
+pub mod webhook;
+
+#[cfg(feature = "std")]
+pub use engine::{
+    AccountLockedEvent, AccountView, Balance, BatchResult, ChargebackLockPolicy,
+    DepositEvictedEvent, Engine, EngineConfig, HeldBalance, LockedAccountPolicy,
+    NegativeBalancePolicy, SnapshotFormat, StatementEntry,
+};
+#[cfg(feature = "std")]
+pub use error::{EngineError, ReasonCode};
+#[cfg(feature = "std")]
+pub use transaction::{Currency, Transaction};
+#[cfg(feature = "std")]
+pub use tx_index::{TxIdBitmap, TxIdBloomFilter, TxIdIndex};
+#[cfg(feature = "std")]
+pub use util::{
+    fixed_point_4_decimal_to_float_str, float_str_to_fixed_point_4_decimal,
+    float_str_to_fixed_point_4_decimal_rounded, signed_fixed_point_4_decimal_to_float_str,
+    RoundingMode,
+};
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Settings of a load test against the TCP JSON-RPC server
+#[derive(Debug, Clone)]
+pub struct LoadTestConfig {
+    pub addr: String,
+    /// Requests per second across all connections
+    pub rps: u32,
+    pub duration: Duration,
+    pub connections: u16,
+    /// Number of distinct clients transactions are spread over
+    pub clients: u16,
+    /// Tx id of the first submitted transaction, so repeated runs against the same server don't
+    /// collide with earlier tx ids
+    pub first_tx_id: u32,
+}
+
+/// Outcome of a load test
+#[derive(Debug, Default)]
+pub struct LoadTestReport {
+    pub elapsed: Duration,
+    /// Transactions the engine applied
+    pub accepted: u64,
+    /// Transactions the engine rejected (e.g. withdrawals without enough funds)
+    pub rejected: u64,
+    /// Requests that failed for any other reason
+    pub errors: u64,
+    latencies: Vec<Duration>,
+}
+
+/// Submits generated transactions over `config.connections` connections, one request in flight
+/// per connection, pacing them to reach `config.rps` when the server keeps up
+pub fn run(config: &LoadTestConfig) -> Result<LoadTestReport> {
+    let start = Instant::now();
+    let workers: Vec<_> = (0..config.connections)
+        .map(|connection| {
+            let config = config.clone();
+            let stream = TcpStream::connect(&config.addr)?;
+            Ok(thread::spawn(move || {
+                run_connection(&config, connection, stream, start)
+            }))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut report = LoadTestReport::default();
+    for worker in workers {
+        let connection_report = worker
+            .join()
+            .map_err(|_| anyhow!("Load test connection panicked"))??;
+        report.accepted += connection_report.accepted;
+        report.rejected += connection_report.rejected;
+        report.errors += connection_report.errors;
+        report.latencies.extend(connection_report.latencies);
+    }
+    report.elapsed = start.elapsed();
+    report.latencies.sort_unstable();
+    Ok(report)
+}
+
+fn run_connection(
+    config: &LoadTestConfig,
+    connection: u16,
+    stream: TcpStream,
+    start: Instant,
+) -> Result<LoadTestReport> {
+    let interval = Duration::from_secs_f64(config.connections as f64 / config.rps as f64);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut report = LoadTestReport::default();
+    let mut line = String::new();
+
+    for request in 0u32.. {
+        let due = interval * request;
+        if due >= config.duration {
+            break;
+        }
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+
+        let sent = Instant::now();
+        writeln!(writer, "{}", submit_request(config, connection, request))?;
+        writer.flush()?;
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("The JSON-RPC server closed the connection"));
+        }
+        report.latencies.push(sent.elapsed());
+
+        match serde_json::from_str::<Value>(&line) {
+            Ok(response) if response.get("result").is_some() => report.accepted += 1,
+            Ok(response) if response["error"]["code"] == -32000 => report.rejected += 1,
+            Ok(_) | Err(_) => report.errors += 1,
+        }
+    }
+
+    Ok(report)
+}
+
+/// The `request`-th transaction of a connection. Every fifth one is a withdrawal, smaller than the
+/// four deposits to the same client before it.
+fn submit_request(config: &LoadTestConfig, connection: u16, request: u32) -> Value {
+    let tx_id = config
+        .first_tx_id
+        .wrapping_add(request.wrapping_mul(config.connections as u32))
+        .wrapping_add(connection as u32);
+    let client = ((connection as u32 + request / 5 * config.connections as u32)
+        % config.clients as u32) as u16;
+    let transaction_type = if request % 5 == 4 {
+        "withdrawal"
+    } else {
+        "deposit"
+    };
+
+    json!({
+        "jsonrpc": "2.0",
+        "method": "submitTransaction",
+        "id": request,
+        "params": {
+            "type": transaction_type,
+            "client": client,
+            "tx": tx_id,
+            "amount": format!("{}.{:04}", 1 + request % 100, request % 10_000),
+        },
+    })
+}
+
+impl LoadTestReport {
+    pub fn requests(&self) -> u64 {
+        self.accepted + self.rejected + self.errors
+    }
+
+    /// Latency under which `percentile` percent of the requests completed
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((self.latencies.len() - 1) as f64 * percentile / 100.0).round() as usize;
+        self.latencies[index]
+    }
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requests = self.requests();
+        let percent = |count: u64| count as f64 * 100.0 / requests.max(1) as f64;
+
+        writeln!(
+            f,
+            "Requests: {requests} in {:.1}s ({:.0} requests/s)",
+            self.elapsed.as_secs_f64(),
+            requests as f64 / self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "Accepted: {} ({:.2}%), rejected: {} ({:.2}%), errors: {} ({:.2}%)",
+            self.accepted,
+            percent(self.accepted),
+            self.rejected,
+            percent(self.rejected),
+            self.errors,
+            percent(self.errors)
+        )?;
+        write!(
+            f,
+            "Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.latency_percentile(50.0),
+            self.latency_percentile(90.0),
+            self.latency_percentile(99.0),
+            self.latency_percentile(100.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::loadtest::{run, LoadTestConfig};
+    use crate::rpc::serve_listener;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_load_test_against_tcp_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve_listener(Engine::new(), listener));
+
+        let report = run(&LoadTestConfig {
+            addr,
+            rps: 400,
+            duration: Duration::from_millis(500),
+            connections: 2,
+            clients: 10,
+            first_tx_id: 0,
+        })
+        .unwrap();
+
+        assert_eq!(report.requests(), 200);
+        assert_eq!(report.errors, 0);
+        // Withdrawals follow deposits of the same client, so none of them lack funds
+        assert_eq!(report.rejected, 0);
+        assert!(report.latency_percentile(50.0) <= report.latency_percentile(99.0));
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::io::{self, IsTerminal};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
+use tracing_subscriber::fmt::{MakeWriter, SubscriberBuilder};
+use tracing_subscriber::EnvFilter;
+
+/// How log events are written to stderr
+#[derive(Clone, Copy, Default, PartialEq, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// One line of text per event, its message followed by its fields
+    #[default]
+    Text,
+    /// One JSON object per line, with the event's level, target, message and fields, such as the
+    /// `tx`, `client` and `line` of the transaction it's about
+    Json,
+}
+
+/// Sends log events to stderr in the given format: engine rejections and other notices as
+/// warnings, invalid rows, shadow check mismatches and failures of the modes as errors, and
+/// progress as info. With `quiet`, only the errors are. `RUST_LOG` filter directives take
+/// precedence over both, e.g. `RUST_LOG=payments_engine=debug` also records the `transaction` span
+/// (with its `tx` and `client`) the engine processes every transaction in, which then prefixes the
+/// events logged while processing it. Records of the `log` crate, from dependencies, are logged
+/// as events too.
+pub fn init(format: LogFormat, quiet: bool) -> Result<()> {
+    let builder = builder(quiet, io::stderr).with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .with_level(true)
+            .with_target(true)
+            .json()
+            .flatten_event(true)
+            .try_init(),
+    }
+    .map_err(|e| anyhow!(e))
+}
+
+/// The subscriber settings shared by both formats: no timestamps (the output is usually short
+/// lived, or piped to something that adds them), no colors, and the level and target only in JSON
+fn builder<W>(
+    quiet: bool,
+    writer: W,
+) -> SubscriberBuilder<DefaultFields, Format<Full, ()>, EnvFilter, W>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::builder()
+        .with_default_directive(match quiet {
+            true => LevelFilter::ERROR.into(),
+            false => LevelFilter::INFO.into(),
+        })
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .without_time()
+        .with_ansi(false)
+        .with_level(false)
+        .with_target(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::builder;
+    use serde_json::{json, Value};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use tracing::{debug_span, info, warn};
+
+    /// Collects what a subscriber writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn log_rejection() {
+        info!("Processing transactions");
+        let _span = debug_span!("transaction", tx = 7u32, client = 2u16).entered();
+        warn!(
+            tx = 7u32,
+            client = 2u16,
+            "Engine failed to process transaction: {}",
+            "locked"
+        );
+    }
+
+    #[test]
+    fn test_text_format() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = builder(false, move || writer.clone()).finish();
+        tracing::subscriber::with_default(subscriber, log_rejection);
+
+        assert_eq!(
+            output.lines(),
+            [
+                "Processing transactions",
+                "Engine failed to process transaction: locked tx=7 client=2"
+            ]
+        );
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = builder(true, move || writer.clone()).finish();
+        tracing::subscriber::with_default(subscriber, log_rejection);
+        assert!(output.lines().is_empty());
+    }
+
+    #[test]
+    fn test_json_format() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = builder(false, move || writer.clone())
+            .with_level(true)
+            .with_target(true)
+            .json()
+            .flatten_event(true)
+            .finish();
+        tracing::subscriber::with_default(subscriber, log_rejection);
+
+        let lines = output.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(&lines[1]).unwrap(),
+            json!({
+                "level": "WARN",
+                "target": "payments_engine::logging::tests",
+                "message": "Engine failed to process transaction: locked",
+                "tx": 7,
+                "client": 2
+            })
+        );
+    }
+}
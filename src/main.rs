@@ -1,44 +1,463 @@
-use crate::engine::Engine;
-use crate::transaction::RawTransaction;
-use std::env;
+use crate::cli::{Cli, Command, Hooks, ProcessArgs};
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use payments_engine::input::{
+    Compression, CsvOptions, InputFormat, InputOrder, InvalidRows, ProcessedRows,
+};
+use payments_engine::rejected::RejectedRows;
+use payments_engine::stats::Stats;
+use payments_engine::store::FileStore;
+#[cfg(feature = "account-store")]
+use payments_engine::store::SledAccountStore;
+#[cfg(feature = "tui")]
+use payments_engine::tui;
+#[cfg(feature = "grpc")]
+use payments_engine::{async_engine, grpc};
+use payments_engine::{
+    audit, codec, explain, http, input, layout, loadtest, logging, output, parallel, rpc,
+    settlement, snapshot, soak, statement, validate, Engine, SnapshotFormat, TxIdBloomFilter,
+};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::ops::Not;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::{error, info};
 
-mod engine;
-mod transaction;
-mod util;
+mod cli;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Correct usage: `cargo run -- <transactions_csv_file>`");
-    }
+/// Exit code of runs that completed, but with transactions rejected by the engine or invalid rows
+const EXIT_REJECTIONS: u8 = 1;
+
+/// Exit code of runs that failed, like that of invalid arguments
+const EXIT_FATAL: u8 = 2;
 
-    let transactions_csv_path = &args[1];
+/// Transactions and queries of gRPC calls waiting for the engine, beyond which calls wait too
+#[cfg(feature = "grpc")]
+const GRPC_QUEUE_CAPACITY: usize = 1024;
 
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(transactions_csv_path)
-        .expect("Failed to create input csv reader");
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    logging::init(cli.log_format, cli.quiet).expect("Failed to set up logging");
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{e:#}");
+            ExitCode::from(EXIT_FATAL)
+        }
+    }
+}
 
+fn run(cli: Cli) -> Result<ExitCode> {
     let mut engine = Engine::new();
 
-    for result in csv_reader.deserialize::<RawTransaction>() {
-        let transaction = match result.map(TryInto::try_into) {
-            Ok(Ok(t)) => t,
-            Ok(Err(e)) => {
-                eprintln!("Invalid row in provided csv: {e}");
-                continue;
+    match cli.command {
+        Some(Command::Rpc {
+            listen: None,
+            hooks,
+            reports,
+        }) => {
+            let hooks = hooks
+                .register(&mut engine, Some(&reports))
+                .context("Failed to set up engine hooks")?;
+            rpc::serve_stdio(&mut engine).context("JSON-RPC server failed")?;
+            finish_hooks(engine, hooks)?;
+        }
+        Some(Command::Rpc {
+            listen: Some(addr),
+            hooks,
+            ..
+        }) => {
+            let _hooks = hooks
+                .register(&mut engine, None)
+                .context("Failed to set up engine hooks")?;
+            rpc::serve_tcp(engine, addr).context("JSON-RPC server failed")?;
+        }
+        Some(Command::Serve {
+            listen,
+            max_connections,
+            read_timeout_secs,
+            load_state: state_path,
+            hooks,
+        }) => {
+            if let Some(path) = state_path.as_deref() {
+                engine = load_state(path).context("Failed to load engine state")?;
             }
-            Err(e) => {
-                eprintln!("Invalid row in provided csv: {e}");
-                continue;
+            let _hooks = hooks
+                .register(&mut engine, None)
+                .context("Failed to set up engine hooks")?;
+            let config = http::ServerConfig {
+                max_connections: max_connections.into(),
+                read_timeout: Duration::from_secs(read_timeout_secs),
+            };
+            http::serve(engine, listen, &config).context("HTTP server failed")?;
+        }
+        #[cfg(feature = "grpc")]
+        Some(Command::Grpc {
+            listen,
+            load_state: state_path,
+            hooks,
+        }) => {
+            if let Some(path) = state_path.as_deref() {
+                engine = load_state(path).context("Failed to load engine state")?;
+            }
+            let _hooks = hooks
+                .register(&mut engine, None)
+                .context("Failed to set up engine hooks")?;
+            tokio::runtime::Runtime::new()
+                .context("Failed to start the async runtime")?
+                .block_on(async {
+                    let (handle, _task) = async_engine::spawn(engine, GRPC_QUEUE_CAPACITY);
+                    grpc::serve(handle, listen).await
+                })
+                .context("gRPC server failed")?;
+        }
+        Some(Command::Loadtest(args)) => {
+            let report = loadtest::run(&args.config()).context("Load test failed")?;
+            println!("{report}");
+        }
+        Some(Command::Validate {
+            transactions_csv_paths,
+            compression,
+            input_order,
+            csv_format,
+            engine,
+        }) => {
+            let readers = open_inputs(&transactions_csv_paths, compression)
+                .context("Failed to open transactions csv")?;
+            let options = csv_format.apply(CsvOptions {
+                order: input_order,
+                ..CsvOptions::default()
+            });
+            let report = validate::validate(
+                engine.engine_config(),
+                readers,
+                &options,
+                std::io::stdout().lock(),
+            )
+            .context("Failed to validate transactions csv")?;
+            println!("{report}");
+            if report.is_clean().not() {
+                return Ok(ExitCode::from(EXIT_REJECTIONS));
+            }
+        }
+        Some(Command::Encode {
+            transactions_csv_path,
+            output,
+        }) => {
+            let input = open_input(Some(&transactions_csv_path), Compression::Auto)
+                .context("Failed to open transactions csv")?;
+            let output = BufWriter::new(File::create(output).context("Failed to create output")?);
+            let written =
+                codec::encode_csv(input, output).context("Failed to encode transactions")?;
+            info!(transactions = written, "Encoded {written} transactions");
+        }
+        Some(Command::InferLayout {
+            transactions_csv_path,
+            sample_rows,
+            compression,
+        }) => {
+            let input = open_input(Some(&transactions_csv_path), compression)
+                .context("Failed to open transactions csv")?;
+            let inference =
+                layout::infer(input, sample_rows).context("Failed to infer the csv layout")?;
+            print!("{}", serde_yaml::to_string(&inference.layout)?);
+            eprintln!("{inference}");
+        }
+        Some(Command::VerifySnapshot { snapshot_path }) => {
+            let file = File::open(&snapshot_path).context("Failed to open snapshot")?;
+            let report =
+                snapshot::verify(BufReader::new(file)).context("Failed to verify snapshot")?;
+            println!("{report}");
+            if report.is_clean().not() {
+                return Ok(ExitCode::from(EXIT_REJECTIONS));
             }
-        };
-        if let Err(e) = engine.process_transaction(transaction) {
-            eprintln!("Engine failed to process transaction: {e}")
         }
+        Some(Command::Audit {
+            client,
+            transactions_csv_path,
+        }) => {
+            let input = open_input(Some(&transactions_csv_path), Compression::Auto)
+                .context("Failed to open transactions csv")?;
+            audit::audit(input, client, std::io::stdout().lock())
+                .context("Failed to audit client")?;
+        }
+        Some(Command::Statement {
+            client,
+            format,
+            transactions_csv_path,
+            engine,
+        }) => {
+            let input = open_input(Some(&transactions_csv_path), Compression::Auto)
+                .context("Failed to open transactions csv")?;
+            statement::statement(
+                engine.engine_config(),
+                input,
+                client,
+                format,
+                std::io::stdout().lock(),
+            )
+            .context("Failed to write statement")?;
+        }
+        Some(Command::Explain {
+            tx,
+            transactions_csv_path,
+        }) => {
+            let input = open_input(Some(&transactions_csv_path), Compression::Auto)
+                .context("Failed to open transactions csv")?;
+            explain::explain(input, tx, std::io::stdout().lock())
+                .context("Failed to explain transaction")?;
+        }
+        Some(Command::Settle(args)) => {
+            let input = open_input(Some(&args.transactions_csv_path), Compression::Auto)
+                .context("Failed to open transactions csv")?;
+            let report = settlement::settle(input, &args.config())
+                .context("Failed to write settlement files")?;
+            println!("{report}");
+        }
+        Some(Command::Soak(args)) => {
+            let report = soak::run(&args.config()).context("Soak test failed")?;
+            println!("{report}");
+        }
+        Some(Command::Process(args)) => return process(&args),
+        None => return process(&cli.process),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Processes transactions into account states, with or without the `process` subcommand, exiting
+/// with `EXIT_REJECTIONS` if any row was rejected or invalid
+fn process(args: &ProcessArgs) -> Result<ExitCode> {
+    let mut engine = match args.load_state.as_deref() {
+        Some(path) => load_state(path).context("Failed to load engine state")?,
+        None => Engine::new(),
+    };
+    engine.set_config(args.engine.engine_config());
+    engine.on_deposit_evicted(|event| {
+        info!(
+            tx = event.tx_id,
+            client = event.client_id,
+            "Evicted deposit {} of client {}, which can no longer be disputed",
+            event.tx_id,
+            event.client_id
+        )
+    });
+    if let Some(path) = args.transaction_store.as_deref() {
+        FileStore::create(path)
+            .and_then(|store| engine.set_transaction_store(store, args.max_transactions_in_memory))
+            .context("Failed to create transaction store")?;
+    }
+    #[cfg(feature = "account-store")]
+    if let Some(path) = args.account_store.as_deref() {
+        SledAccountStore::create(path)
+            .and_then(|store| engine.set_account_store(store, args.max_accounts_in_memory))
+            .context("Failed to create account store")?;
+    }
+    if let Some(clients) = args.expected_clients {
+        engine.reserve(clients, args.expected_transactions.unwrap_or(0));
+    }
+    if let Some(capacity) = args.probabilistic_dedup {
+        engine.set_tx_id_index(TxIdBloomFilter::new(
+            capacity,
+            args.dedup_false_positive_rate,
+        ));
+    }
+    let hooks = args
+        .hooks
+        .register(&mut engine, Some(&args.reports))
+        .context("Failed to set up engine hooks")?;
+    let readers = open_inputs(&args.transactions_csv_paths, args.compression)
+        .context("Failed to open transactions csv")?;
+    let processed = match args.format {
+        InputFormat::Csv => {
+            process_csv(&mut engine, readers, args).context("Failed to process transactions csv")?
+        }
+        InputFormat::Bin => process_bin(&mut engine, readers, args)
+            .context("Failed to process binary transactions")?,
+    };
+    if let Some(max_error_rate) = args.max_error_rate {
+        processed.check_error_rate(max_error_rate)?;
+    }
+    let failed = processed.failed();
+    write_accounts(&engine, args).context("Failed to write accounts")?;
+    if args.stats {
+        // A report asked for, like the account states, so it's written whatever the log level
+        eprintln!("{}", Stats::new(processed, &engine));
+    }
+    if let Some(path) = args.save_state.as_deref() {
+        save_state(&mut engine, path, args.state_format).context("Failed to save engine state")?;
     }
+    finish_hooks(engine, hooks)?;
+    Ok(match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_REJECTIONS),
+    })
+}
+
+fn process_csv(
+    engine: &mut Engine,
+    readers: Vec<Box<dyn Read>>,
+    args: &ProcessArgs,
+) -> Result<ProcessedRows> {
+    let options = &args.csv_options();
+    let invalid_rows = match args.strict {
+        true => InvalidRows::Fail,
+        false => args.invalid_rows,
+    };
+    if let Some(seed) = args.schedule_seed {
+        return parallel::process_csv_readers_deterministic(
+            engine,
+            readers,
+            options,
+            invalid_rows,
+            args.threads as usize,
+            seed,
+        );
+    }
+    if args.threads > 1 {
+        return parallel::process_csv_readers(
+            engine,
+            readers,
+            options,
+            invalid_rows,
+            args.threads as usize,
+        );
+    }
+    if args.pipeline {
+        return parallel::process_csv_readers_pipelined(engine, readers, options, invalid_rows);
+    }
+
+    let mut rejected = match args.rejected_output.as_deref() {
+        Some(path) => Some(RejectedRows::new(
+            BufWriter::new(File::create(path)?),
+            options,
+        )),
+        None => None,
+    };
+
+    #[cfg(feature = "tui")]
+    let processed = match args.tui {
+        true => tui::process_csv_readers(engine, readers, options, invalid_rows, rejected.as_mut()),
+        false => {
+            input::process_csv_readers(engine, readers, options, invalid_rows, rejected.as_mut())
+        }
+    };
+    #[cfg(not(feature = "tui"))]
+    let processed =
+        input::process_csv_readers(engine, readers, options, invalid_rows, rejected.as_mut());
+    let processed = processed?;
+
+    if let Some(rejected) = rejected.as_mut() {
+        rejected.finish()?;
+    }
+    Ok(processed)
+}
+
+fn process_bin(
+    engine: &mut Engine,
+    readers: Vec<Box<dyn Read>>,
+    args: &ProcessArgs,
+) -> Result<ProcessedRows> {
+    ensure!(
+        args.input_order == InputOrder::Sequential,
+        "Binary transactions have no timestamps to be ordered by"
+    );
+    ensure!(
+        args.until_tx.is_none() && args.until_timestamp.is_none(),
+        "Binary transactions can't be processed up to a point"
+    );
+    ensure!(
+        args.rejected_output.is_none(),
+        "Rejected binary transactions can't be written to a csv"
+    );
+    ensure!(
+        args.strict.not(),
+        "Binary transactions can't be processed strictly"
+    );
+    ensure!(
+        args.threads == 1 && args.pipeline.not(),
+        "Binary transactions can't be processed in parallel"
+    );
+    #[cfg(feature = "tui")]
+    ensure!(
+        args.tui.not(),
+        "Binary transactions can't be processed with the dashboard"
+    );
+    codec::process_readers(
+        engine,
+        readers,
+        args.invalid_rows,
+        args.csv_options().clients.as_ref(),
+    )
+}
+
+fn write_accounts(engine: &Engine, args: &ProcessArgs) -> Result<()> {
+    let (format, delimiter) = (args.output_format, args.output_delimiter);
+    match args.output.as_deref() {
+        Some(path) => output::write_accounts_delimited(
+            engine,
+            format,
+            delimiter,
+            BufWriter::new(File::create(path)?),
+        ),
+        None => {
+            output::write_accounts_delimited(engine, format, delimiter, std::io::stdout().lock())
+        }
+    }
+}
+
+/// Opens the transactions csvs at `paths`, or stdin if there are none
+fn open_inputs(paths: &[PathBuf], compression: Compression) -> Result<Vec<Box<dyn Read>>> {
+    match paths {
+        [] => Ok(vec![open_input(None, compression)?]),
+        paths => paths
+            .iter()
+            .map(|path| open_input(Some(path), compression))
+            .collect(),
+    }
+}
+
+/// Opens the transactions csv at `path`, or stdin when the path is `-` or not given, so the engine
+/// can sit at the end of a pipeline, decompressing it if needed
+fn open_input(path: Option<&Path>, compression: Compression) -> Result<Box<dyn Read>> {
+    match path {
+        Some(path) if path != Path::new("-") => {
+            input::decompress(File::open(path)?, compression.of(path))
+        }
+        _ => input::decompress(std::io::stdin().lock(), compression),
+    }
+}
+
+/// Maps snapshots in the mapped format instead of reading them, see `Engine::restore_mapped`
+fn load_state(path: &Path) -> Result<Engine> {
+    let mut magic = [0; 4];
+    File::open(path)?.read_exact(&mut magic)?;
+    match SnapshotFormat::detect(&magic) {
+        SnapshotFormat::Mapped => Engine::restore_mapped(path),
+        _ => Engine::restore(BufReader::new(File::open(path)?)),
+    }
+}
+
+/// Writes the snapshot next to `path` first, so that an interrupted write doesn't replace the
+/// previous snapshot with a truncated one. The write-ahead log, if any, is emptied once the
+/// snapshot is on disk, since it holds everything the log recorded.
+fn save_state(engine: &mut Engine, path: &Path, format: SnapshotFormat) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    engine.snapshot_as(format, &mut writer)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(partial, path)?;
+    if let Some(wal) = engine.wal_mut() {
+        wal.truncate()?;
+    }
+    Ok(())
+}
 
-    engine
-        .print_state_csv()
-        .expect("Failed to print output csv");
+/// Drops the engine (and with it the hooks' listeners) before wrapping up the hooks
+fn finish_hooks(engine: Engine, hooks: Hooks) -> Result<()> {
+    drop(engine);
+    hooks.finish().context("Failed to finish engine hooks")
 }
@@ -0,0 +1,334 @@
+use crate::error::ReasonCode;
+use crate::transaction::RawTransactionType;
+use serde_json::{json, Value};
+
+/// Decimal amounts with 4 decimal places, as accounts are written
+const AMOUNT_PATTERN: &str = r"^-?[0-9]+\.[0-9]{4}$";
+
+/// The OpenAPI 3.0 document describing the REST interface of `http::serve`, which serves it at
+/// `GET /openapi.json`: its endpoints, the schemas of the transactions and accounts they take and
+/// return, and every status they respond with, including the `code` of rejected transactions.
+/// Enumerations are built from the engine's own, so that the document can't fall behind it.
+pub fn document() -> Value {
+    let transaction_types: Vec<Value> = RawTransactionType::ALL
+        .iter()
+        .map(|transaction_type| json!(transaction_type))
+        .collect();
+    // Rows that can't be parsed are reported as `400`s over HTTP, without a code
+    let rejection_codes: Vec<&str> = ReasonCode::ALL
+        .into_iter()
+        .filter(|code| *code != ReasonCode::InvalidRow)
+        .map(ReasonCode::as_str)
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "payments-engine",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Applies deposits, withdrawals, disputes and the other transactions of a \
+                transactions csv to client accounts, one at a time, and reports their state. Every \
+                connection is closed after its response.",
+        },
+        "paths": {
+            "/transactions": {
+                "post": {
+                    "operationId": "submitTransaction",
+                    "summary": "Apply a transaction",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/Transaction" },
+                                "example": {
+                                    "type": "deposit",
+                                    "client": 1,
+                                    "tx": 1,
+                                    "amount": "1.5"
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The transaction was applied. The state of the account \
+                                it applied to, in its currency, or `null` for transactions that \
+                                don't leave the client with an account.",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "allOf": [{ "$ref": "#/components/schemas/Account" }],
+                                        "nullable": true,
+                                    },
+                                },
+                            },
+                        },
+                        "400": {
+                            "description": "The body isn't a valid transaction",
+                            "content": error_content(),
+                        },
+                        "405": { "$ref": "#/components/responses/MethodNotAllowed" },
+                        "408": { "$ref": "#/components/responses/RequestTimeout" },
+                        "422": {
+                            "description": "The engine rejected the transaction",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Rejection" },
+                                },
+                            },
+                        },
+                        "503": { "$ref": "#/components/responses/ServiceUnavailable" },
+                    },
+                },
+            },
+            "/accounts/{id}": {
+                "get": {
+                    "operationId": "getAccount",
+                    "summary": "Get a client's account in every currency it has balances in",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "description": "The client id",
+                        "schema": { "type": "integer", "minimum": 0, "maximum": u16::MAX },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The client's account, once per currency, the \
+                                default currency first",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "minItems": 1,
+                                        "items": { "$ref": "#/components/schemas/Account" },
+                                    },
+                                },
+                            },
+                        },
+                        "400": {
+                            "description": "The id isn't a client id",
+                            "content": error_content(),
+                        },
+                        "404": {
+                            "description": "The client has no account",
+                            "content": error_content(),
+                        },
+                        "405": { "$ref": "#/components/responses/MethodNotAllowed" },
+                        "408": { "$ref": "#/components/responses/RequestTimeout" },
+                        "503": { "$ref": "#/components/responses/ServiceUnavailable" },
+                    },
+                },
+            },
+            "/report": {
+                "get": {
+                    "operationId": "exportReport",
+                    "summary": "Get every account's state as csv, streamed with chunked transfer \
+                        encoding",
+                    "responses": {
+                        "200": {
+                            "description": "A `client,available,held,total,locked` header and a \
+                                row per account, with a `currency` column after the client's when \
+                                any account has a currency, left empty for the default one",
+                            "content": {
+                                "text/csv": {
+                                    "schema": { "type": "string" },
+                                    "example": "client,available,held,total,locked\n\
+                                        1,1.5000,0.0000,1.5000,false\n",
+                                },
+                            },
+                        },
+                        "405": { "$ref": "#/components/responses/MethodNotAllowed" },
+                        "408": { "$ref": "#/components/responses/RequestTimeout" },
+                        "503": { "$ref": "#/components/responses/ServiceUnavailable" },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "operationId": "getOpenApi",
+                    "summary": "Get this document",
+                    "responses": {
+                        "200": {
+                            "description": "The OpenAPI document of the server",
+                            "content": { "application/json": { "schema": { "type": "object" } } },
+                        },
+                        "405": { "$ref": "#/components/responses/MethodNotAllowed" },
+                        "408": { "$ref": "#/components/responses/RequestTimeout" },
+                        "503": { "$ref": "#/components/responses/ServiceUnavailable" },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "Transaction": {
+                    "type": "object",
+                    "required": ["type", "client", "tx"],
+                    "properties": {
+                        "type": { "type": "string", "enum": transaction_types },
+                        "client": { "type": "integer", "minimum": 0, "maximum": u16::MAX },
+                        "tx": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "maximum": u32::MAX,
+                            "description": "The transaction's id, or for disputes, resolves, \
+                                chargebacks, reversals and releases, that of the transaction they \
+                                reference",
+                        },
+                        "amount": {
+                            "type": "string",
+                            "pattern": r"^[0-9]*\.?[0-9]*$",
+                            "description": "Decimal amount, for deposits, withdrawals and holds. \
+                                Digits past the 4th decimal place are handled by the server's \
+                                rounding mode.",
+                        },
+                        "timestamp": {
+                            "type": "integer",
+                            "format": "int64",
+                            "minimum": 0,
+                            "description": "Milliseconds since the Unix epoch",
+                        },
+                        "currency": {
+                            "type": "string",
+                            "pattern": "^[A-Za-z0-9]{1,8}$",
+                            "description": "Currency code, the default currency if left out",
+                        },
+                    },
+                },
+                "Account": {
+                    "type": "object",
+                    "required": ["client", "available", "held", "total", "locked"],
+                    "properties": {
+                        "client": { "type": "integer", "minimum": 0, "maximum": u16::MAX },
+                        "currency": {
+                            "type": "string",
+                            "description": "Currency code, only present for accounts in a \
+                                currency other than the default one",
+                        },
+                        "available": {
+                            "type": "string",
+                            "pattern": AMOUNT_PATTERN,
+                            "description": "Funds available, negative when a dispute held \
+                                more than was available",
+                        },
+                        "held": {
+                            "type": "string",
+                            "pattern": AMOUNT_PATTERN,
+                            "description": "Funds held by disputes and holds",
+                        },
+                        "total": { "type": "string", "pattern": AMOUNT_PATTERN },
+                        "locked": { "type": "boolean" },
+                    },
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": { "error": { "type": "string" } },
+                },
+                "Rejection": {
+                    "type": "object",
+                    "required": ["error", "code"],
+                    "properties": {
+                        "error": { "type": "string" },
+                        "code": {
+                            "type": "string",
+                            "enum": rejection_codes,
+                            "description": "Why the transaction was rejected, the same code as \
+                                in the `reason_code` column of `--rejected-output`",
+                        },
+                    },
+                },
+            },
+            "responses": {
+                "MethodNotAllowed": {
+                    "description": "The endpoint doesn't support the method",
+                    "content": error_content(),
+                },
+                "RequestTimeout": {
+                    "description": "The rest of the request didn't arrive within the server's \
+                        read timeout",
+                    "content": error_content(),
+                },
+                "ServiceUnavailable": {
+                    "description": "The server is serving as many connections as it allows",
+                    "content": error_content(),
+                },
+            },
+        },
+    })
+}
+
+fn error_content() -> Value {
+    json!({
+        "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::http::{handle_request, Body};
+    use crate::openapi::document;
+    use serde_json::Value;
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let paths = document["paths"].as_object().unwrap();
+        assert_eq!(
+            paths.keys().collect::<Vec<_>>(),
+            [
+                "/accounts/{id}",
+                "/openapi.json",
+                "/report",
+                "/transactions"
+            ]
+        );
+        let codes = &document["components"]["schemas"]["Rejection"]["properties"]["code"]["enum"];
+        assert_eq!(codes.as_array().unwrap().len(), 24);
+        assert!(codes
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("duplicate_tx_id")));
+
+        // The example transaction is accepted, and the account it returns has the documented
+        // properties
+        let post = &paths["/transactions"]["post"];
+        let example = &post["requestBody"]["content"]["application/json"]["example"];
+        let mut engine = Engine::new();
+        let response = handle_request(
+            &mut engine,
+            "POST",
+            "/transactions",
+            example.to_string().as_bytes(),
+        );
+        assert_eq!(response.status, 200);
+        let Body::Bytes(body) = response.body else {
+            panic!("Expected a JSON body");
+        };
+        let account: Value = serde_json::from_slice(&body).unwrap();
+        let schema = &document["components"]["schemas"]["Account"];
+        for property in schema["required"].as_array().unwrap() {
+            assert!(account.get(property.as_str().unwrap()).is_some());
+        }
+        for property in account.as_object().unwrap().keys() {
+            assert!(schema["properties"].get(property).is_some());
+        }
+
+        // Every status the server responds with is documented
+        let statuses = |path: &str, method: &str| {
+            let responses = paths[path][method]["responses"].as_object().unwrap();
+            responses.keys().cloned().collect::<Vec<_>>()
+        };
+        assert_eq!(
+            statuses("/transactions", "post"),
+            ["200", "400", "405", "408", "422", "503"]
+        );
+        assert_eq!(
+            statuses("/accounts/{id}", "get"),
+            ["200", "400", "404", "405", "408", "503"]
+        );
+        assert_eq!(statuses("/report", "get"), ["200", "405", "408", "503"]);
+    }
+}
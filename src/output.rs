@@ -0,0 +1,383 @@
+use crate::engine::{AccountView, DisputableKind, DisputeState, DisputeView, Engine};
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::io::Write;
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OutputFormat {
+    /// One row per account, or per account and currency for feeds with currencies
+    Csv,
+    /// An array of accounts, each with the disputes its transactions went through
+    Json,
+    /// One JSON account per line, each with the disputes its transactions went through
+    Jsonl,
+    /// One row per account like `csv`, followed by the number and total of its deposits and
+    /// withdrawals in dispute, and the total of those charged back
+    Extended,
+}
+
+/// Writes the state of every account of the engine in the given format
+pub fn write_accounts(engine: &Engine, format: OutputFormat, writer: impl Write) -> Result<()> {
+    write_accounts_delimited(engine, format, b',', writer)
+}
+
+/// Writes the state of every account of the engine in the given format, separating the fields of
+/// csv formats with `delimiter`, such as `\t` for TSV
+pub fn write_accounts_delimited(
+    engine: &Engine,
+    format: OutputFormat,
+    delimiter: u8,
+    mut writer: impl Write,
+) -> Result<()> {
+    match format {
+        OutputFormat::Csv => write_accounts_csv(engine.accounts(), delimiter, writer),
+        OutputFormat::Extended => write_accounts_extended_csv(engine, delimiter, writer),
+        OutputFormat::Json => {
+            writer.write_all(b"[")?;
+            for (index, account) in engine.accounts().enumerate() {
+                if index > 0 {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(
+                    &mut writer,
+                    &account_with_disputes_to_json(engine, account),
+                )?;
+            }
+            writer.write_all(b"]\n")?;
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Jsonl => {
+            for account in engine.accounts() {
+                serde_json::to_writer(
+                    &mut writer,
+                    &account_with_disputes_to_json(engine, account),
+                )?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// Writes accounts as csv rows, with fields separated by `delimiter`. A `currency` column follows
+/// the client's when any account has one, with the default currency's balances left without.
+pub fn write_accounts_csv(
+    accounts: impl Iterator<Item = AccountView>,
+    delimiter: u8,
+    writer: impl Write,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    let accounts: Vec<AccountView> = accounts.collect();
+    let currencies = accounts.iter().any(|account| account.currency.is_some());
+
+    if currencies {
+        wtr.write_record(["client", "currency", "available", "held", "total", "locked"])?;
+    } else {
+        wtr.write_record(["client", "available", "held", "total", "locked"])?;
+    }
+
+    for account in accounts {
+        let balances = (
+            signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+            fixed_point_4_decimal_to_float_str(account.held_amount),
+            signed_fixed_point_4_decimal_to_float_str(account.total_amount),
+            account.locked,
+        );
+        if currencies {
+            wtr.serialize((
+                account.client_id,
+                account.currency.map(String::from).unwrap_or_default(),
+                balances,
+            ))?;
+        } else {
+            wtr.serialize((account.client_id, balances))?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes accounts as csv rows like `write_accounts_csv`, followed by `disputed_count`,
+/// `disputed_total`, `chargeback_total` and `chargeback_count` columns: the number and total of the
+/// account's deposits and withdrawals currently in dispute, and the total of those charged back, in
+/// the account's currency, then the number of chargebacks of the client in any currency
+pub fn write_accounts_extended_csv(
+    engine: &Engine,
+    delimiter: u8,
+    writer: impl Write,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    let accounts: Vec<AccountView> = engine.accounts().collect();
+    let currencies = accounts.iter().any(|account| account.currency.is_some());
+
+    let mut headers = vec!["client"];
+    if currencies {
+        headers.push("currency");
+    }
+    headers.extend([
+        "available",
+        "held",
+        "total",
+        "locked",
+        "disputed_count",
+        "disputed_total",
+        "chargeback_total",
+        "chargeback_count",
+    ]);
+    wtr.write_record(headers)?;
+
+    for account in accounts {
+        let (mut disputed_count, mut disputed_total, mut chargeback_total) = (0u64, 0u128, 0u128);
+        for dispute in engine.disputes(account.client_id) {
+            if dispute.currency != account.currency {
+                continue;
+            }
+            match dispute.state {
+                DisputeState::InDispute => {
+                    disputed_count += 1;
+                    disputed_total += u128::from(dispute.amount);
+                }
+                DisputeState::ChargedBack => chargeback_total += u128::from(dispute.amount),
+                DisputeState::Valid | DisputeState::Reversed => {}
+            }
+        }
+        let balances = (
+            signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+            fixed_point_4_decimal_to_float_str(account.held_amount),
+            signed_fixed_point_4_decimal_to_float_str(account.total_amount),
+            account.locked,
+        );
+        let disputes = (
+            disputed_count,
+            fixed_point_4_decimal_to_float_str(disputed_total),
+            fixed_point_4_decimal_to_float_str(chargeback_total),
+            engine.chargeback_count(account.client_id),
+        );
+        if currencies {
+            wtr.serialize((
+                account.client_id,
+                account.currency.map(String::from).unwrap_or_default(),
+                balances,
+                disputes,
+            ))?;
+        } else {
+            wtr.serialize((account.client_id, balances, disputes))?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// JSON representation of an account, using the same field names and amount formatting as the
+/// csv report
+pub fn account_to_json(account: AccountView) -> Value {
+    let mut json = json!({
+        "client": account.client_id,
+        "available": signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+        "held": fixed_point_4_decimal_to_float_str(account.held_amount),
+        "total": signed_fixed_point_4_decimal_to_float_str(account.total_amount),
+        "locked": account.locked,
+    });
+    if let Some(currency) = account.currency {
+        json["currency"] = currency.as_str().into();
+    }
+    json
+}
+
+/// JSON representation of an account like `account_to_json`, along with a `disputes` array of its
+/// disputed deposits and withdrawals in the account's currency
+fn account_with_disputes_to_json(engine: &Engine, account: AccountView) -> Value {
+    let mut json = account_to_json(account);
+    json["disputes"] = engine
+        .disputes(account.client_id)
+        .into_iter()
+        .filter(|dispute| dispute.currency == account.currency)
+        .map(dispute_to_json)
+        .collect();
+    json
+}
+
+/// A dispute as JSON, with a `shortfall` when part of the disputed amount couldn't be held
+fn dispute_to_json(dispute: DisputeView) -> Value {
+    let mut json = json!({
+        "tx": dispute.tx_id,
+        "type": match dispute.kind {
+            DisputableKind::Deposit => "deposit",
+            DisputableKind::Withdrawal => "withdrawal",
+        },
+        "amount": fixed_point_4_decimal_to_float_str(dispute.amount),
+        "state": match dispute.state {
+            DisputeState::Valid => "valid",
+            DisputeState::InDispute => "in_dispute",
+            DisputeState::ChargedBack => "charged_back",
+            DisputeState::Reversed => "reversed",
+        },
+    });
+    if dispute.shortfall > 0 {
+        json["shortfall"] = fixed_point_4_decimal_to_float_str(dispute.shortfall).into();
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{AccountView, Engine};
+    use crate::output::{
+        write_accounts, write_accounts_csv, write_accounts_delimited, OutputFormat,
+    };
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_write_accounts_csv() {
+        let accounts = [
+            AccountView {
+                client_id: 1,
+                currency: None,
+                available_amount: -5_000,
+                held_amount: 20_000,
+                total_amount: 15_000,
+                locked: false,
+            },
+            AccountView {
+                client_id: 2,
+                currency: None,
+                available_amount: 0,
+                held_amount: 0,
+                total_amount: 0,
+                locked: true,
+            },
+        ];
+
+        let mut output = Vec::new();
+        write_accounts_csv(accounts.into_iter(), b',', &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+            1,-0.5000,2.0000,1.5000,false\n\
+            2,0.0000,0.0000,0.0000,true\n"
+        );
+
+        // A currency column is added as soon as an account has a currency
+        let accounts = [
+            AccountView {
+                currency: Some("USD".parse().unwrap()),
+                ..accounts[0]
+            },
+            accounts[1],
+        ];
+        let mut output = Vec::new();
+        write_accounts_csv(accounts.into_iter(), b',', &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n\
+            1,USD,-0.5000,2.0000,1.5000,false\n\
+            2,,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    fn engine_with_disputes() -> Engine {
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 20_000,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 2,
+                amount: 10_000,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 3,
+                amount: 5_000,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 3,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_write_accounts_json_with_disputes() {
+        let engine = engine_with_disputes();
+
+        let mut output = Vec::new();
+        write_accounts(&engine, OutputFormat::Jsonl, &mut output).unwrap();
+        let expected = r#"{"available":"0.5000","client":1,"disputes":[{"amount":"2.0000","state":"charged_back","tx":1,"type":"deposit"},{"amount":"0.5000","state":"in_dispute","tx":3,"type":"withdrawal"}],"held":"0.5000","locked":true,"total":"1.0000"}"#;
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{expected}\n"));
+
+        let mut output = Vec::new();
+        write_accounts(&engine, OutputFormat::Json, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("[{expected}]\n")
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_extended() {
+        let engine = engine_with_disputes();
+
+        let mut output = Vec::new();
+        write_accounts(&engine, OutputFormat::Extended, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,disputed_count,disputed_total,chargeback_total,\
+            chargeback_count\n\
+            1,0.5000,0.5000,1.0000,true,1,0.5000,2.0000,1\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_delimited() {
+        let engine = engine_with_disputes();
+
+        let mut output = Vec::new();
+        write_accounts_delimited(&engine, OutputFormat::Csv, b'\t', &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client\tavailable\theld\ttotal\tlocked\n\
+            1\t0.5000\t0.5000\t1.0000\ttrue\n"
+        );
+
+        let mut output = Vec::new();
+        write_accounts_delimited(&engine, OutputFormat::Extended, b'|', &mut output).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("1|0.5000|0.5000|1.0000|true|1|0.5000|2.0000|1\n"));
+    }
+}
@@ -0,0 +1,527 @@
+use crate::engine::Engine;
+use crate::error::EngineError;
+use crate::input::{self, CsvOptions, CsvRow, InvalidRows, ProcessedRows};
+use crate::transaction::Transaction;
+use crate::tx_index::{TxIdBitmap, TxIdIndex};
+use crate::util::RoundingMode;
+use anyhow::{anyhow, bail, Result};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::ops::Not;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use tracing::{debug, error, warn};
+
+/// Number of transactions sent to a shard at once
+const BATCH_SIZE: usize = 1024;
+
+/// Transactions sent to a shard together, along with their timestamps
+type Batch = Vec<(Transaction, Option<u64>)>;
+
+/// Number of batches a shard can fall behind the reader by before the reader waits for it
+const QUEUED_BATCHES: usize = 16;
+
+/// Most transactions a shard applies in a step of a deterministic schedule
+const MAX_STEP: usize = 64;
+
+/// Applies the transactions of one or more csvs like `input::process_csv_readers`, with the
+/// accounts spread over `threads` engines, each on its own thread and fed the transactions of the
+/// clients whose `client_id % threads` is its index. The shards' accounts are merged into `engine`
+/// once every row has been processed.
+///
+/// Every client's transactions are still applied in order, and tx ids are checked for uniqueness
+/// across shards as they're read, so the resulting accounts are the same as with a single engine.
+/// Listeners, validators and the suspense account of `engine` aren't used, its other settings are.
+pub fn process_csv_readers<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+    threads: usize,
+) -> Result<ProcessedRows> {
+    let threads = threads.max(1);
+
+    thread::scope(|scope| {
+        let (senders, shards): (Vec<_>, Vec<_>) = (0..threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Batch>(QUEUED_BATCHES);
+                let mut shard = engine.shard();
+                let shard = scope.spawn(move || {
+                    let processed = apply(&mut shard, receiver);
+                    (shard, processed)
+                });
+                (sender, shard)
+            })
+            .unzip();
+
+        // The senders are dropped once every row is dispatched, letting the shards finish
+        let rounding = engine.config().rounding;
+        let dispatched = dispatch(
+            readers,
+            options,
+            invalid_rows,
+            rounding,
+            threads,
+            true,
+            move |shard, batch| send(&senders[shard], batch),
+        );
+
+        let mut processed = ProcessedRows::default();
+        for shard in shards {
+            let (shard, shard_processed) = shard
+                .join()
+                .map_err(|_| anyhow!("A shard thread panicked"))?;
+            engine.merge(shard);
+            processed.add(shard_processed);
+        }
+        processed.add(dispatched?);
+        Ok(processed)
+    })
+}
+
+/// Applies the transactions of one or more csvs like `input::process_csv_readers`, with the rows
+/// read on the calling thread and their transactions applied to `engine` on another one, so that
+/// parsing rows and applying transactions overlap. The reader gets up to `QUEUED_BATCHES` batches
+/// ahead of the engine before waiting for it.
+///
+/// Every transaction still goes through `engine` in order, so unlike with `process_csv_readers`
+/// its listeners, validators and suspense account are all used.
+pub fn process_csv_readers_pipelined<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+) -> Result<ProcessedRows> {
+    let rounding = engine.config().rounding;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<Batch>(QUEUED_BATCHES);
+        let applier = scope.spawn(move || apply(engine, receiver));
+
+        // The sender is dropped once every row is dispatched, letting the engine finish
+        let dispatched = dispatch(
+            readers,
+            options,
+            invalid_rows,
+            rounding,
+            1,
+            false,
+            move |_, batch| send(&sender, batch),
+        );
+
+        let mut processed = applier
+            .join()
+            .map_err(|_| anyhow!("The engine thread panicked"))?;
+        processed.add(dispatched?);
+        Ok(processed)
+    })
+}
+
+/// Applies the transactions of one or more csvs like `process_csv_readers`, with the same shards
+/// and the same merge, but all on the calling thread, the shards taking turns in an order drawn
+/// from `seed`: every step, a shard with transactions waiting applies between 1 and `MAX_STEP` of
+/// them. Runs over the same input with the same number of shards and seed apply, and log, every
+/// transaction in exactly the same order, so that an interleaving that went wrong can be replayed,
+/// and a range of seeds bisected to find one that does. Every step is logged at debug level.
+pub fn process_csv_readers_deterministic<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+    threads: usize,
+    seed: u64,
+) -> Result<ProcessedRows> {
+    let mut scheduler = Scheduler::new((0..threads.max(1)).map(|_| engine.shard()), seed);
+    let rounding = engine.config().rounding;
+    let dispatched = dispatch(
+        readers,
+        options,
+        invalid_rows,
+        rounding,
+        scheduler.shards.len(),
+        true,
+        |shard, batch| {
+            scheduler.queue(shard, batch);
+            // Steps are taken as rows are read, like threads would, rather than all at the end
+            while scheduler.queued > QUEUED_BATCHES * BATCH_SIZE {
+                scheduler.step();
+            }
+            Ok(())
+        },
+    );
+    while scheduler.step().is_some() {}
+
+    let mut processed = scheduler.processed;
+    for shard in scheduler.shards {
+        engine.merge(shard);
+    }
+    processed.add(dispatched?);
+    Ok(processed)
+}
+
+/// Steps the shards of a deterministic schedule, see `process_csv_readers_deterministic`
+struct Scheduler {
+    shards: Vec<Engine>,
+    /// The transactions waiting for each shard, in order
+    queues: Vec<VecDeque<(Transaction, Option<u64>)>>,
+    queued: usize,
+    /// State of the splitmix64 generator the schedule is drawn from
+    random: u64,
+    steps: u64,
+    processed: ProcessedRows,
+}
+
+impl Scheduler {
+    fn new(shards: impl Iterator<Item = Engine>, seed: u64) -> Self {
+        let shards: Vec<Engine> = shards.collect();
+        Self {
+            queues: shards.iter().map(|_| VecDeque::new()).collect(),
+            shards,
+            queued: 0,
+            random: seed,
+            steps: 0,
+            processed: ProcessedRows::default(),
+        }
+    }
+
+    fn queue(&mut self, shard: usize, batch: Batch) {
+        self.queued += batch.len();
+        self.queues[shard].extend(batch);
+    }
+
+    /// Lets a shard drawn among those with transactions waiting apply some of them, returning
+    /// which shard and how many, or `None` once none are waiting
+    fn step(&mut self) -> Option<(usize, usize)> {
+        let waiting: Vec<usize> = (0..self.queues.len())
+            .filter(|shard| self.queues[*shard].is_empty().not())
+            .collect();
+        if waiting.is_empty() {
+            return None;
+        }
+        let shard = waiting[(self.next_random() % waiting.len() as u64) as usize];
+        let count = 1 + (self.next_random() % MAX_STEP as u64) as usize;
+        let count = count.min(self.queues[shard].len());
+        debug!(
+            step = self.steps,
+            shard,
+            transactions = count,
+            "Scheduled shard"
+        );
+        for (transaction, timestamp) in self.queues[shard].drain(..count) {
+            apply_transaction(
+                &mut self.shards[shard],
+                &mut self.processed,
+                transaction,
+                timestamp,
+            );
+        }
+        self.queued -= count;
+        self.steps += 1;
+        Some((shard, count))
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.random = self.random.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Applies the transactions of the batches received to the engine, until the sender hangs up
+fn apply(engine: &mut Engine, receiver: Receiver<Batch>) -> ProcessedRows {
+    let mut processed = ProcessedRows::default();
+    for (transaction, timestamp) in receiver.into_iter().flatten() {
+        apply_transaction(engine, &mut processed, transaction, timestamp);
+    }
+    processed
+}
+
+fn apply_transaction(
+    engine: &mut Engine,
+    processed: &mut ProcessedRows,
+    transaction: Transaction,
+    timestamp: Option<u64>,
+) {
+    let (tx_id, client_id) = (transaction.tx_id(), transaction.client_id());
+    let transaction_type = transaction.transaction_type();
+    match engine.process_transaction_at(transaction, timestamp) {
+        Ok(()) => processed.count(transaction_type, true),
+        Err(e) => {
+            processed.count(transaction_type, false);
+            warn!(
+                tx = tx_id,
+                client = client_id,
+                code = e.code(),
+                "Engine failed to process transaction: {e}"
+            );
+        }
+    }
+}
+
+/// Reads the rows and hands their transactions to `send` in batches, along with the shard of their
+/// client, returning the rows that never made it to an engine. Tx ids are checked for uniqueness
+/// here if `check_tx_ids`, for engines that only see some of the clients.
+fn dispatch<R: Read>(
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+    rounding: RoundingMode,
+    shards: usize,
+    check_tx_ids: bool,
+    mut send: impl FnMut(usize, Batch) -> Result<()>,
+) -> Result<ProcessedRows> {
+    let mut processed = ProcessedRows::default();
+    let mut batches = vec![Vec::with_capacity(BATCH_SIZE); shards];
+    let mut tx_ids = TxIdBitmap::new();
+    let inputs = readers.len();
+
+    input::read_csv_inputs(readers, options, rounding, |row: CsvRow| {
+        let transaction = match &row.transaction {
+            Ok(transaction) => transaction.clone(),
+            Err(e) if invalid_rows == InvalidRows::Fail => {
+                bail!(input::invalid_row_message(&row, inputs, e))
+            }
+            Err(e) => {
+                processed.invalid += 1;
+                error!(
+                    line = row.line,
+                    byte = row.byte,
+                    "{}",
+                    input::invalid_row_message(&row, inputs, e)
+                );
+                return Ok(());
+            }
+        };
+
+        // Shards only see their own clients' tx ids, so uniqueness is checked here for them
+        match transaction {
+            Transaction::Deposit { tx_id, .. }
+            | Transaction::Withdrawal { tx_id, .. }
+            | Transaction::Hold { tx_id, .. }
+                if check_tx_ids =>
+            {
+                if tx_ids.insert(tx_id).not() {
+                    processed.count(transaction.transaction_type(), false);
+                    let e = EngineError::DuplicateTxId { tx_id };
+                    warn!(
+                        tx = tx_id,
+                        client = transaction.client_id(),
+                        line = row.line,
+                        byte = row.byte,
+                        code = e.code(),
+                        "Engine failed to process transaction: {e}"
+                    );
+                    return Ok(());
+                }
+            }
+            Transaction::Deposit { .. }
+            | Transaction::Withdrawal { .. }
+            | Transaction::Hold { .. }
+            | Transaction::Dispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::Release { .. }
+            | Transaction::Unlock { .. } => {}
+        }
+
+        let shard = transaction.client_id() as usize % shards;
+        batches[shard].push((transaction, row.timestamp));
+        if batches[shard].len() == BATCH_SIZE {
+            let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+            send(shard, batch)?;
+        }
+        Ok(())
+    })?;
+
+    for (shard, batch) in batches.into_iter().enumerate() {
+        send(shard, batch)?;
+    }
+    Ok(processed)
+}
+
+fn send(sender: &SyncSender<Batch>, batch: Batch) -> Result<()> {
+    sender
+        .send(batch)
+        .map_err(|_| anyhow!("A shard thread stopped before every row was processed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{AccountView, Engine};
+    use crate::input::{self, CsvOptions, InvalidRows};
+    use crate::parallel::{self, Scheduler};
+    use crate::transaction::Transaction;
+    use std::sync::mpsc;
+
+    const CSV: &str = "type,client,tx,amount\n\
+        deposit,1,1,100\n\
+        deposit,2,2,50\n\
+        deposit,3,3,20\n\
+        deposit,4,1,10\n\
+        withdrawal,2,4,60\n\
+        withdrawal,1,5,30\n\
+        dispute,1,1,\n\
+        deposit,3,6,5\n\
+        chargeback,1,1,\n\
+        deposit,1,7,1\n\
+        dispute,3,6,\n\
+        resolve,3,6,\n\
+        withdrawal,3,8,25\n\
+        deposit,5,abc,1\n\
+        deposit,6,9,1\n";
+
+    fn sorted_accounts(engine: &Engine) -> Vec<AccountView> {
+        let mut accounts: Vec<AccountView> = engine.accounts().collect();
+        accounts.sort_by_key(|account| account.client_id);
+        accounts
+    }
+
+    #[test]
+    fn test_parallel_processing_matches_single_engine() {
+        let mut engine = Engine::new();
+        let expected = input::process_csv_readers(
+            &mut engine,
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+            None,
+        )
+        .unwrap();
+
+        for threads in [1, 2, 3, 8] {
+            let mut sharded = Engine::new();
+            let processed = parallel::process_csv_readers(
+                &mut sharded,
+                vec![CSV.as_bytes()],
+                &CsvOptions::default(),
+                InvalidRows::Skip,
+                threads,
+            )
+            .unwrap();
+
+            assert_eq!(processed.applied, expected.applied);
+            assert_eq!(processed.rejected, expected.rejected);
+            assert_eq!(processed.invalid, expected.invalid);
+            assert_eq!(processed.by_type, expected.by_type);
+            assert_eq!(sorted_accounts(&sharded), sorted_accounts(&engine));
+        }
+    }
+
+    #[test]
+    fn test_pipelined_processing_matches_single_engine() {
+        let mut engine = Engine::new();
+        let expected = input::process_csv_readers(
+            &mut engine,
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+            None,
+        )
+        .unwrap();
+
+        // Every transaction goes through the engine, listeners included
+        let mut pipelined = Engine::new();
+        let (sender, receiver) = mpsc::channel();
+        pipelined.on_account_locked(move |event| sender.send(event.account.client_id).unwrap());
+        let processed = parallel::process_csv_readers_pipelined(
+            &mut pipelined,
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+        )
+        .unwrap();
+
+        assert_eq!(processed.applied, expected.applied);
+        assert_eq!(processed.rejected, expected.rejected);
+        assert_eq!(processed.invalid, expected.invalid);
+        assert_eq!(processed.by_type, expected.by_type);
+        assert_eq!(sorted_accounts(&pipelined), sorted_accounts(&engine));
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1]);
+
+        assert!(parallel::process_csv_readers_pipelined(
+            &mut Engine::new(),
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Fail,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deterministic_processing_matches_single_engine() {
+        let mut engine = Engine::new();
+        let expected = input::process_csv_readers(
+            &mut engine,
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+            None,
+        )
+        .unwrap();
+
+        for (threads, seed) in [(1, 0), (3, 0), (3, 42), (8, u64::MAX)] {
+            let mut sharded = Engine::new();
+            let processed = parallel::process_csv_readers_deterministic(
+                &mut sharded,
+                vec![CSV.as_bytes()],
+                &CsvOptions::default(),
+                InvalidRows::Skip,
+                threads,
+                seed,
+            )
+            .unwrap();
+
+            assert_eq!(processed.applied, expected.applied);
+            assert_eq!(processed.rejected, expected.rejected);
+            assert_eq!(processed.invalid, expected.invalid);
+            assert_eq!(sorted_accounts(&sharded), sorted_accounts(&engine));
+        }
+    }
+
+    #[test]
+    fn test_deterministic_schedule() {
+        let schedule = |seed| {
+            let mut scheduler = Scheduler::new((0..4).map(|_| Engine::new()), seed);
+            for client_id in 0..4u16 {
+                let batch = (0..200)
+                    .map(|tx_id| {
+                        let deposit = Transaction::Deposit {
+                            client_id,
+                            tx_id: u32::from(client_id) * 1000 + tx_id,
+                            amount: 1,
+                            currency: None,
+                        };
+                        (deposit, None)
+                    })
+                    .collect();
+                scheduler.queue(client_id as usize, batch);
+            }
+            let steps: Vec<_> = std::iter::from_fn(|| scheduler.step()).collect();
+            assert_eq!(scheduler.processed.applied, 800);
+            steps
+        };
+
+        // The same seed always gives the same interleaving, and another seed another one
+        assert_eq!(schedule(7), schedule(7));
+        assert_ne!(schedule(7), schedule(8));
+        assert!(schedule(7)
+            .iter()
+            .all(|(_, count)| (1..=parallel::MAX_STEP).contains(count)));
+    }
+
+    #[test]
+    fn test_parallel_processing_stops_at_invalid_row() {
+        let mut engine = Engine::new();
+        assert!(parallel::process_csv_readers(
+            &mut engine,
+            vec![CSV.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Fail,
+            4,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::engine::{BatchResult, Engine};
+use crate::transaction::{Currency, RawFields, Transaction};
+use crate::util::RoundingMode;
+use anyhow::{anyhow, Context, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{UInt16Type, UInt32Type, UInt64Type};
+use arrow_array::{Array, ArrayRef, RecordBatch, StringArray};
+use arrow_cast::{cast_with_options, CastOptions};
+use arrow_schema::{DataType, TimeUnit};
+
+/// Casts fail on values out of the target type's range rather than turning them into nulls
+const CAST_OPTIONS: CastOptions = CastOptions {
+    safe: false,
+    format_options: arrow_cast::display::FormatOptions::new(),
+};
+
+impl Engine {
+    /// Processes the transactions of an Apache Arrow record batch, one per row, like
+    /// `process_batch`. See `read_record_batch` for its columns. The whole batch is read before
+    /// any of its transactions is processed, so that batches with an invalid row are rejected as
+    /// a whole, leaving the engine as it was.
+    pub fn process_record_batch(&mut self, batch: &RecordBatch) -> Result<BatchResult> {
+        let transactions = read_record_batch(batch, self.config().rounding)?;
+        Ok(BatchResult {
+            outcomes: transactions
+                .into_iter()
+                .map(|(transaction, timestamp)| self.process_transaction_at(transaction, timestamp))
+                .collect(),
+        })
+    }
+}
+
+/// Reads the transactions of an Apache Arrow record batch, along with their timestamps, from the
+/// columns named like the headers of a transactions csv:
+///
+/// * `type`, a string column, e.g. `deposit`
+/// * `client` and `tx`, integer columns
+/// * `amount`, a decimal, float, integer or string column, optional
+/// * `timestamp`, integers of milliseconds since the Unix epoch or a timestamp column, optional
+/// * `currency`, a string column, optional
+///
+/// Each column is converted as a whole to the type the engine reads, with the casts of
+/// `arrow-cast`, so that rows only have to be picked from arrays of the right type. Amounts are
+/// converted to strings and read like those of a csv, with the given rounding mode, so that
+/// floats and decimals with more than 4 decimal places round the same way. Nulls stand for the
+/// values missing from a csv row, and are only valid in the optional columns.
+pub fn read_record_batch(
+    batch: &RecordBatch,
+    rounding: RoundingMode,
+) -> Result<Vec<(Transaction, Option<u64>)>> {
+    let types = required_column(batch, "type", &DataType::Utf8)?;
+    let types = types.as_string::<i32>();
+    let clients = required_column(batch, "client", &DataType::UInt16)?;
+    let clients = clients.as_primitive::<UInt16Type>();
+    let tx_ids = required_column(batch, "tx", &DataType::UInt32)?;
+    let tx_ids = tx_ids.as_primitive::<UInt32Type>();
+    let amounts = column(batch, "amount", &DataType::Utf8)?;
+    let amounts = amounts.as_ref().map(|amounts| amounts.as_string::<i32>());
+    let timestamps = timestamp_column(batch)?;
+    let timestamps = timestamps
+        .as_ref()
+        .map(|timestamps| timestamps.as_primitive::<UInt64Type>());
+    let currencies = column(batch, "currency", &DataType::Utf8)?;
+    let currencies = currencies
+        .as_ref()
+        .map(|currencies| currencies.as_string::<i32>());
+
+    let read_row = |row| -> Result<_> {
+        let required = |array: &dyn Array, name: &str| match array.is_valid(row) {
+            true => Ok(()),
+            false => Err(anyhow!("The {name} is missing")),
+        };
+        required(types, "type")?;
+        required(clients, "client")?;
+        required(tx_ids, "tx")?;
+        let transaction = RawFields {
+            transaction_type: types.value(row).parse()?,
+            client: clients.value(row),
+            tx: tx_ids.value(row),
+            amount: string_at(amounts, row),
+            currency: string_at(currencies, row)
+                .map(str::parse::<Currency>)
+                .transpose()?,
+        }
+        .into_transaction(rounding)?;
+        let timestamp = timestamps
+            .filter(|timestamps| timestamps.is_valid(row))
+            .map(|timestamps| timestamps.value(row));
+        Ok((transaction, timestamp))
+    };
+    (0..batch.num_rows())
+        .map(|row| read_row(row).with_context(|| format!("Invalid row {row}")))
+        .collect()
+}
+
+/// The value of an optional string column in a row, if the batch has the column and the value
+/// isn't null
+fn string_at(array: Option<&StringArray>, row: usize) -> Option<&str> {
+    array
+        .filter(|array| array.is_valid(row))
+        .map(|array| array.value(row))
+}
+
+/// A column cast to the given type, if the batch has it
+fn column(batch: &RecordBatch, name: &str, data_type: &DataType) -> Result<Option<ArrayRef>> {
+    batch
+        .column_by_name(name)
+        .map(|column| {
+            cast_with_options(column, data_type, &CAST_OPTIONS)
+                .with_context(|| format!("Invalid {name} column"))
+        })
+        .transpose()
+}
+
+fn required_column(batch: &RecordBatch, name: &str, data_type: &DataType) -> Result<ArrayRef> {
+    column(batch, name, data_type)?.ok_or_else(|| anyhow!("The record batch has no {name} column"))
+}
+
+/// The timestamp column in milliseconds, whatever the unit of a timestamp column
+fn timestamp_column(batch: &RecordBatch) -> Result<Option<ArrayRef>> {
+    let Some(column) = batch.column_by_name("timestamp") else {
+        return Ok(None);
+    };
+    let millis = match column.data_type() {
+        DataType::Timestamp(..) => {
+            let millis = DataType::Timestamp(TimeUnit::Millisecond, None);
+            cast_with_options(column, &millis, &CAST_OPTIONS)
+                .and_then(|column| cast_with_options(&column, &DataType::Int64, &CAST_OPTIONS))
+        }
+        _ => Ok(column.clone()),
+    };
+    millis
+        .and_then(|column| cast_with_options(&column, &DataType::UInt64, &CAST_OPTIONS))
+        .map(Some)
+        .context("Invalid timestamp column")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::error::EngineError;
+    use arrow_array::{
+        ArrayRef, Decimal128Array, Float64Array, Int64Array, RecordBatch, StringArray,
+        TimestampSecondArray, UInt16Array,
+    };
+    use std::sync::Arc;
+
+    fn batch(columns: Vec<(&str, ArrayRef)>) -> RecordBatch {
+        RecordBatch::try_from_iter(columns).unwrap()
+    }
+
+    #[test]
+    fn test_process_record_batch() {
+        let mut engine = Engine::new();
+        let deposits = batch(vec![
+            (
+                "type",
+                Arc::new(StringArray::from(vec!["deposit", "deposit", "withdrawal"])),
+            ),
+            ("client", Arc::new(Int64Array::from(vec![1, 2, 1]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 3]))),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![10_500, 1_000, 20_000])
+                        .with_precision_and_scale(10, 3)
+                        .unwrap(),
+                ),
+            ),
+            (
+                "timestamp",
+                Arc::new(TimestampSecondArray::from(vec![Some(1), None, Some(2)])),
+            ),
+        ]);
+        let result = engine.process_record_batch(&deposits).unwrap();
+        assert_eq!(result.applied(), 2);
+        assert!(matches!(
+            result.rejections().collect::<Vec<_>>().as_slice(),
+            [(2, EngineError::InsufficientFunds)]
+        ));
+        assert_eq!(engine.account(1).unwrap().available_amount, 10_5000);
+        assert_eq!(engine.account(2).unwrap().available_amount, 1_0000);
+
+        // Nulls stand for missing amounts, and floats are read like csv amounts
+        let disputes = batch(vec![
+            (
+                "type",
+                Arc::new(StringArray::from(vec!["dispute", "withdrawal"])),
+            ),
+            ("client", Arc::new(UInt16Array::from(vec![1, 2]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 4]))),
+            (
+                "amount",
+                Arc::new(Float64Array::from(vec![None, Some(0.25)])),
+            ),
+        ]);
+        assert!(engine.process_record_batch(&disputes).unwrap().is_clean());
+        assert_eq!(engine.account(1).unwrap().held_amount, 10_5000);
+        assert_eq!(engine.account(2).unwrap().available_amount, 7500);
+
+        // Batches with an invalid row are rejected before any of their transactions apply
+        for invalid in [
+            batch(vec![
+                (
+                    "type",
+                    Arc::new(StringArray::from(vec!["deposit", "bonus"])),
+                ),
+                ("client", Arc::new(Int64Array::from(vec![3, 3]))),
+                ("tx", Arc::new(Int64Array::from(vec![5, 6]))),
+                ("amount", Arc::new(StringArray::from(vec!["1", "1"]))),
+            ]),
+            batch(vec![
+                ("type", Arc::new(StringArray::from(vec!["deposit"]))),
+                ("client", Arc::new(Int64Array::from(vec![70_000]))),
+                ("tx", Arc::new(Int64Array::from(vec![5]))),
+                ("amount", Arc::new(StringArray::from(vec!["1"]))),
+            ]),
+            batch(vec![
+                ("type", Arc::new(StringArray::from(vec!["deposit"]))),
+                ("client", Arc::new(Int64Array::from(vec![3]))),
+                ("tx", Arc::new(Int64Array::from(vec![5]))),
+            ]),
+            batch(vec![
+                ("type", Arc::new(StringArray::from(vec!["deposit"]))),
+                ("tx", Arc::new(Int64Array::from(vec![5]))),
+            ]),
+        ] {
+            engine.process_record_batch(&invalid).unwrap_err();
+        }
+        assert!(engine.account(3).is_none());
+    }
+}
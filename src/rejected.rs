@@ -0,0 +1,136 @@
+use crate::error::ReasonCode;
+use crate::input::{CsvOptions, CsvRow};
+use anyhow::Result;
+use std::io::Write;
+use std::ops::Not;
+
+/// Reason code of rows that couldn't be parsed into a transaction
+pub const INVALID_ROW: &str = ReasonCode::InvalidRow.as_str();
+
+/// Columns added after a rejected row's own fields
+const REPORT_COLUMNS: [&str; 3] = ["reason_code", "line", "byte"];
+
+/// Writes the rows of a transactions csv that were rejected or couldn't be parsed, as csv, with
+/// their original fields followed by `reason_code`, `line` and `byte` columns, the last two
+/// locating the row in its csv. Rows are written with the delimiter and quote of the csvs they're
+/// from.
+pub struct RejectedRows {
+    writer: csv::Writer<Box<dyn Write>>,
+    header_written: bool,
+}
+
+impl RejectedRows {
+    pub fn new(writer: impl Write + 'static, options: &CsvOptions) -> Self {
+        Self {
+            writer: csv::WriterBuilder::new()
+                .delimiter(options.delimiter_byte())
+                .quote(options.quote_byte())
+                .flexible(true)
+                .from_writer(Box::new(writer)),
+            header_written: false,
+        }
+    }
+
+    /// Writes a row along with the reason it was rejected and where it is. The fields of rows that
+    /// don't have as many as the header are padded or cut to match it.
+    pub fn write(&mut self, row: &CsvRow, reason_code: &str) -> Result<()> {
+        if self.header_written.not() {
+            self.writer
+                .write_record(row.headers.iter().chain(REPORT_COLUMNS.map(str::as_bytes)))?;
+            self.header_written = true;
+        }
+        let fields = row.headers.len();
+        let (line, byte) = (row.line.to_string(), row.byte.to_string());
+        self.writer.write_record(
+            row.record
+                .iter()
+                .chain(std::iter::repeat(b"".as_slice()))
+                .take(fields)
+                .chain([reason_code, &line, &byte].map(str::as_bytes)),
+        )?;
+        Ok(())
+    }
+
+    /// Flushes the rows written, writing the header of the engine's input format if no row was
+    /// rejected
+    pub fn finish(&mut self) -> Result<()> {
+        if self.header_written.not() {
+            self.writer.write_record(
+                ["type", "client", "tx", "amount"]
+                    .iter()
+                    .chain(&REPORT_COLUMNS),
+            )?;
+            self.header_written = true;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::{process_csv_readers, CsvOptions, InvalidRows};
+    use crate::rejected::RejectedRows;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose output can still be read once it's been handed over
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rejected_rows_have_reason_codes() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,50\n\
+            withdrawal,2,3,1\n\
+            dispute,1,9,\n\
+            resolve,1,1,\n\
+            deposit,1,4,abc\n\
+            deposit,1\n\
+            withdrawal,1,5,1000\n";
+        let mut engine = Engine::new();
+        engine.add_validator(|transaction, _| match transaction.amount() {
+            Some(amount) if amount > 5_000_000 => anyhow::bail!("Above the limit"),
+            _ => Ok(()),
+        });
+        let output = SharedBuffer::default();
+        let mut rejected = RejectedRows::new(output.clone(), &CsvOptions::default());
+
+        let processed = process_csv_readers(
+            &mut engine,
+            vec![csv.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+            Some(&mut rejected),
+        )
+        .unwrap();
+        rejected.finish().unwrap();
+
+        assert_eq!(processed.applied, 1);
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "type,client,tx,amount,reason_code,line,byte\n\
+            deposit,1,1,10,duplicate_tx_id,3,37\n\
+            withdrawal,1,2,50,insufficient_funds,4,52\n\
+            withdrawal,2,3,1,account_not_found,5,70\n\
+            dispute,1,9,,unknown_tx,6,87\n\
+            resolve,1,1,,invalid_dispute_state,7,100\n\
+            deposit,1,4,abc,invalid_row,8,113\n\
+            deposit,1,,,invalid_row,9,129\n\
+            withdrawal,1,5,1000,rejected_by_validator,10,139\n"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use crate::engine::Engine;
+use crate::output::account_to_json;
+use crate::transaction::RawTransaction;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::ops::Not;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{error, info, warn};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const TRANSACTION_REJECTED: i64 = -32000;
+const ACCOUNT_NOT_FOUND: i64 = -32001;
+
+/// Serves newline-delimited JSON-RPC 2.0 requests from stdin, writing responses to stdout
+pub fn serve_stdio(engine: &mut Engine) -> Result<()> {
+    serve_lines(std::io::stdin().lock(), std::io::stdout().lock(), |line| {
+        Ok(handle_line(engine, line))
+    })
+}
+
+/// Serves newline-delimited JSON-RPC 2.0 requests over TCP. Every connection operates on the
+/// same engine, and requests are applied one at a time.
+pub fn serve_tcp(engine: Engine, addr: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("JSON-RPC server listening on {}", listener.local_addr()?);
+    serve_listener(engine, listener)
+}
+
+/// Serves JSON-RPC connections accepted by an already bound listener, see `serve_tcp`
+pub fn serve_listener(engine: Engine, listener: TcpListener) -> Result<()> {
+    let engine = Arc::new(Mutex::new(engine));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept JSON-RPC connection: {e}");
+                continue;
+            }
+        };
+        let engine = Arc::clone(&engine);
+
+        thread::spawn(move || {
+            let result = stream.try_clone().map_err(Into::into).and_then(|reader| {
+                // Buffered so each response goes out in a single segment instead of being held
+                // back by Nagle's algorithm
+                serve_lines(BufReader::new(reader), BufWriter::new(stream), |line| {
+                    let mut engine = engine
+                        .lock()
+                        .map_err(|_| anyhow!("Engine lock was poisoned"))?;
+                    Ok(handle_line(&mut engine, line))
+                })
+            });
+            if let Err(e) = result {
+                warn!("JSON-RPC connection closed with an error: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn serve_lines<R, W, F>(reader: R, mut writer: W, mut handle: F) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&str) -> Result<Option<String>>,
+{
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle(&line)? {
+            writeln!(writer, "{response}")?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles a single JSON-RPC message (a request, a notification or a batch of those) and
+/// returns the serialized response, if one is due
+pub fn handle_line(engine: &mut Engine, line: &str) -> Option<String> {
+    let response = match serde_json::from_str::<Value>(line) {
+        Ok(Value::Array(batch)) if batch.is_empty() => Some(error_response(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "Invalid request: empty batch"),
+        )),
+        Ok(Value::Array(batch)) => {
+            let responses: Vec<Value> = batch
+                .into_iter()
+                .filter_map(|request| handle_request(engine, request))
+                .collect();
+            responses
+                .is_empty()
+                .not()
+                .then_some(Value::Array(responses))
+        }
+        Ok(request) => handle_request(engine, request),
+        Err(e) => Some(error_response(
+            Value::Null,
+            RpcError::new(PARSE_ERROR, format!("Parse error: {e}")),
+        )),
+    };
+    response.map(|response| response.to_string())
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+fn handle_request(engine: &mut Engine, request: Value) -> Option<Value> {
+    let request = match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(_) | Err(_) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "Invalid request"),
+            ))
+        }
+    };
+
+    let result = call_method(engine, &request.method, request.params);
+
+    // Requests without an id are notifications and must not be answered
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn call_method(engine: &mut Engine, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "submitTransaction" => {
+            let transaction = parse_params::<RawTransaction>(params)?
+                .into_transaction(engine.config().rounding)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {e}")))?;
+            let client_id = transaction.client_id();
+
+            engine
+                .process_transaction(transaction)
+                .map_err(|e| RpcError::new(TRANSACTION_REJECTED, e.to_string()))?;
+
+            Ok(engine
+                .account(client_id)
+                .map(account_to_json)
+                .unwrap_or(Value::Null))
+        }
+        "getAccount" => {
+            #[derive(Deserialize)]
+            struct GetAccountParams {
+                client: u16,
+            }
+
+            let params = parse_params::<GetAccountParams>(params)?;
+            engine
+                .account(params.client)
+                .map(account_to_json)
+                .ok_or(RpcError::new(
+                    ACCOUNT_NOT_FOUND,
+                    format!("Account not found - client: {}", params.client),
+                ))
+        }
+        "dumpState" => Ok(Value::Array(
+            engine.accounts().map(account_to_json).collect(),
+        )),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method not found: {method}"),
+        )),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {e}")))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::rpc::handle_line;
+    use serde_json::{json, Value};
+
+    fn call(engine: &mut Engine, request: Value) -> Value {
+        serde_json::from_str(&handle_line(engine, &request.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_submit_transaction_and_get_account() {
+        let mut engine = Engine::new();
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "submitTransaction", "id": 1,
+                "params": {"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}}),
+        );
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": 1, "result":
+                {"client": 1, "available": "10.5000", "held": "0.0000", "total": "10.5000", "locked": false}})
+        );
+
+        call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "submitTransaction", "id": 2,
+                "params": {"type": "dispute", "client": 1, "tx": 1}}),
+        );
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "getAccount", "id": "a", "params": {"client": 1}}),
+        );
+        assert_eq!(
+            response["result"],
+            json!({"client": 1, "available": "0.0000", "held": "10.5000", "total": "10.5000", "locked": false})
+        );
+        assert_eq!(response["id"], "a");
+    }
+
+    #[test]
+    fn test_rejected_transaction() {
+        let mut engine = Engine::new();
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "submitTransaction", "id": 1,
+                "params": {"type": "withdrawal", "client": 1, "tx": 1, "amount": "1"}}),
+        );
+        assert_eq!(response["error"]["code"], -32000);
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "submitTransaction", "id": 2,
+                "params": {"type": "deposit", "client": 1, "tx": 1}}),
+        );
+        assert_eq!(response["error"]["code"], -32602);
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let mut engine = Engine::new();
+        for (client, tx) in [(1, 1), (2, 2)] {
+            call(
+                &mut engine,
+                json!({"jsonrpc": "2.0", "method": "submitTransaction", "id": tx,
+                    "params": {"type": "deposit", "client": client, "tx": tx, "amount": "1"}}),
+            );
+        }
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "dumpState", "id": 3}),
+        );
+        assert_eq!(response["result"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let mut engine = Engine::new();
+
+        let response: Value =
+            serde_json::from_str(&handle_line(&mut engine, "{not json").unwrap()).unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+
+        let response = call(&mut engine, json!({"method": "dumpState", "id": 1}));
+        assert_eq!(response["error"]["code"], -32600);
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "unknown", "id": 1}),
+        );
+        assert_eq!(response["error"]["code"], -32601);
+
+        let response = call(
+            &mut engine,
+            json!({"jsonrpc": "2.0", "method": "getAccount", "id": 1, "params": {"client": 7}}),
+        );
+        assert_eq!(response["error"]["code"], -32001);
+    }
+
+    #[test]
+    fn test_notifications_and_batches() {
+        let mut engine = Engine::new();
+
+        let notification = json!({"jsonrpc": "2.0", "method": "submitTransaction",
+            "params": {"type": "deposit", "client": 1, "tx": 1, "amount": "1"}});
+        assert!(handle_line(&mut engine, &notification.to_string()).is_none());
+        assert!(engine.account(1).is_some());
+
+        let response = call(
+            &mut engine,
+            json!([
+                {"jsonrpc": "2.0", "method": "getAccount", "id": 1, "params": {"client": 1}},
+                notification,
+                {"jsonrpc": "2.0", "method": "dumpState", "id": 2},
+            ]),
+        );
+        assert_eq!(response.as_array().unwrap().len(), 2);
+    }
+}
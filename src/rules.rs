@@ -0,0 +1,394 @@
+use crate::engine::{AccountView, Balance, HeldBalance};
+use crate::transaction::{RawTransactionType, Transaction};
+use crate::util::{fixed_point_4_decimal_to_float_str, float_str_to_fixed_point_4_decimal};
+use anyhow::{anyhow, bail, ensure, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::ops::Not;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Declarative rules evaluated in order before every transaction is applied. Flagging rules are
+/// reported and evaluation continues; the first matching reject or route rule decides the outcome.
+///
+/// ```yaml
+/// rules:
+///   - name: large-withdrawal
+///     when:
+///       types: [withdrawal]
+///       amount_above: 10000
+///     action: reject
+///   - name: locked-account-deposit
+///     when:
+///       types: [deposit]
+///       account_locked: true
+///     action: route
+///     route_to: review.csv
+/// ```
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    hits: Vec<u64>,
+    routes: HashMap<PathBuf, csv::Writer<File>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub when: Conditions,
+    pub action: Action,
+    /// Destination csv file of `route` actions
+    pub route_to: Option<PathBuf>,
+}
+
+/// Conditions a transaction must all meet for a rule to match. Amount conditions never match
+/// disputes, resolves and chargebacks, and clients without an account are treated as having an
+/// empty, unlocked one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(default)]
+    pub types: Vec<RawTransactionType>,
+    #[serde(default)]
+    pub clients: Vec<u16>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount_above: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount_below: Option<u64>,
+    pub account_locked: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub available_below: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub held_above: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Reject,
+    Flag,
+    /// Diverts the transaction to the `route_to` csv file (e.g. for manual review) instead of
+    /// applying it
+    Route,
+}
+
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Text(String),
+        Number(serde_yaml::Number),
+    }
+
+    let amount = match Amount::deserialize(deserializer)? {
+        Amount::Text(text) => text,
+        Amount::Number(number) => number.to_string(),
+    };
+    float_str_to_fixed_point_4_decimal(&amount)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("Invalid amount {amount:?}: {e}")))
+}
+
+impl Conditions {
+    fn matches(&self, transaction: &Transaction, account: &AccountView) -> bool {
+        let amount = transaction.amount();
+        let amount_matches = |threshold: Option<u64>, check: fn(u64, u64) -> bool| {
+            threshold.is_none_or(|threshold| amount.is_some_and(|amount| check(amount, threshold)))
+        };
+
+        (self.types.is_empty() || self.types.contains(&transaction.transaction_type()))
+            && (self.clients.is_empty() || self.clients.contains(&transaction.client_id()))
+            && amount_matches(self.amount_above, |amount, threshold| amount > threshold)
+            && amount_matches(self.amount_below, |amount, threshold| amount < threshold)
+            && self
+                .account_locked
+                .is_none_or(|locked| account.locked == locked)
+            && self
+                .available_below
+                .is_none_or(|threshold| account.available_amount < threshold as Balance)
+            && self
+                .held_above
+                .is_none_or(|threshold| account.held_amount > threshold as HeldBalance)
+    }
+}
+
+impl RuleEngine {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        let file: RulesFile =
+            serde_yaml::from_str(yaml).map_err(|e| anyhow!("Invalid rules file: {e}"))?;
+
+        for rule in file.rules.iter() {
+            ensure!(
+                (rule.action == Action::Route) == rule.route_to.is_some(),
+                anyhow!(
+                    "Invalid rules file: rule '{}' must set `route_to` if and only if its action \
+                    is `route`",
+                    rule.name
+                )
+            );
+        }
+
+        Ok(Self {
+            hits: vec![0; file.rules.len()],
+            rules: file.rules,
+            routes: HashMap::new(),
+        })
+    }
+
+    /// Validator for `Engine::add_validator`
+    pub fn validate(
+        &mut self,
+        transaction: &Transaction,
+        account: Option<&AccountView>,
+    ) -> Result<()> {
+        let account = account.copied().unwrap_or(AccountView {
+            client_id: transaction.client_id(),
+            currency: transaction.currency(),
+            available_amount: 0,
+            held_amount: 0,
+            total_amount: 0,
+            locked: false,
+        });
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.when.matches(transaction, &account).not() {
+                continue;
+            }
+            self.hits[index] += 1;
+
+            match rule.action {
+                Action::Flag => warn!(
+                    tx = transaction.tx_id(),
+                    client = transaction.client_id(),
+                    "Transaction flagged by rule '{}' - tx_id: {}",
+                    rule.name,
+                    transaction.tx_id()
+                ),
+                Action::Reject => bail!(
+                    "A transaction was rejected by rule '{}' - tx_id: {}",
+                    rule.name,
+                    transaction.tx_id()
+                ),
+                Action::Route => {
+                    let path = rule
+                        .route_to
+                        .as_ref()
+                        .expect("Route rules are validated to have a destination");
+                    let writer = match self.routes.get_mut(path) {
+                        Some(writer) => writer,
+                        None => {
+                            let mut writer = csv::Writer::from_path(path)?;
+                            writer.write_record(["type", "client", "tx", "amount"])?;
+                            self.routes.entry(path.clone()).or_insert(writer)
+                        }
+                    };
+                    write_transaction(writer, transaction)?;
+
+                    bail!(
+                        "A transaction was routed to {} by rule '{}' - tx_id: {}",
+                        path.display(),
+                        rule.name,
+                        transaction.tx_id()
+                    )
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of transactions each rule matched, in the order the rules were defined
+    pub fn hits(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.rules
+            .iter()
+            .zip(self.hits.iter())
+            .map(|(rule, hits)| (rule.name.as_str(), *hits))
+    }
+
+    /// Flushes the files transactions were routed to
+    pub fn flush(&mut self) -> Result<()> {
+        for writer in self.routes.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_transaction(writer: &mut csv::Writer<File>, transaction: &Transaction) -> Result<()> {
+    let transaction_type = match transaction.transaction_type() {
+        RawTransactionType::Deposit => "deposit",
+        RawTransactionType::Withdrawal => "withdrawal",
+        RawTransactionType::Dispute => "dispute",
+        RawTransactionType::Resolve => "resolve",
+        RawTransactionType::Chargeback => "chargeback",
+        RawTransactionType::Unlock => "unlock",
+        RawTransactionType::Reversal => "reversal",
+        RawTransactionType::Hold => "hold",
+        RawTransactionType::Release => "release",
+    };
+    writer.write_record([
+        transaction_type.to_string(),
+        transaction.client_id().to_string(),
+        transaction.tx_id().to_string(),
+        transaction
+            .amount()
+            .map(fixed_point_4_decimal_to_float_str)
+            .unwrap_or_default(),
+    ])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::rules::RuleEngine;
+    use crate::transaction::Transaction;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    fn deposit(client_id: u16, tx_id: u32, amount: u64) -> Transaction {
+        Transaction::Deposit {
+            client_id,
+            tx_id,
+            amount,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_rules_reject_and_flag() {
+        let yaml = r#"
+rules:
+  - name: watched-client
+    when:
+      clients: [2]
+    action: flag
+  - name: large-deposit
+    when:
+      types: [deposit]
+      amount_above: 100.5
+    action: reject
+  - name: tiny-deposit
+    when:
+      types: [deposit]
+      amount_below: "0.01"
+    action: reject
+"#;
+        let rules = Arc::new(Mutex::new(RuleEngine::from_yaml(yaml).unwrap()));
+        let mut engine = Engine::new();
+        let validator_rules = Arc::clone(&rules);
+        engine.add_validator(move |transaction, account| {
+            validator_rules
+                .lock()
+                .unwrap()
+                .validate(transaction, account)
+        });
+
+        assert!(engine.process_transaction(deposit(1, 1, 1_005_000)).is_ok());
+        assert!(engine
+            .process_transaction(deposit(1, 2, 1_005_001))
+            .is_err());
+        assert!(engine.process_transaction(deposit(2, 3, 99)).is_err());
+        assert!(engine.process_transaction(deposit(2, 4, 100)).is_ok());
+        assert!(engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 2,
+                tx_id: 4,
+                currency: None
+            })
+            .is_ok());
+
+        assert_eq!(engine.account(1).unwrap().available_amount, 1_005_000);
+        assert_eq!(engine.account(2).unwrap().held_amount, 100);
+
+        let rules = rules.lock().unwrap();
+        let hits: Vec<(&str, u64)> = rules.hits().collect();
+        assert_eq!(
+            hits,
+            vec![
+                ("watched-client", 3),
+                ("large-deposit", 1),
+                ("tiny-deposit", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rules_on_account_state_and_routing() {
+        let route_path = std::env::temp_dir().join(format!(
+            "payments-engine-rules-test-{}.csv",
+            std::process::id()
+        ));
+        let yaml = format!(
+            r#"
+rules:
+  - name: held-funds
+    when:
+      types: [withdrawal]
+      held_above: 0
+    action: route
+    route_to: {}
+  - name: overdrawn
+    when:
+      available_below: 0
+    action: reject
+"#,
+            route_path.display()
+        );
+        let mut rules = RuleEngine::from_yaml(&yaml).unwrap();
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, 100)).unwrap();
+        engine.process_transaction(deposit(1, 2, 100)).unwrap();
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+
+        let withdrawal = Transaction::Withdrawal {
+            client_id: 1,
+            tx_id: 3,
+            amount: 50,
+            currency: None,
+        };
+        let result = rules.validate(&withdrawal, engine.account(1).as_ref());
+        assert!(result.unwrap_err().to_string().contains("routed"));
+        assert!(rules.validate(&deposit(2, 4, 1), None).is_ok());
+        rules.flush().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&route_path).unwrap(),
+            "type,client,tx,amount\nwithdrawal,1,3,0.0050\n"
+        );
+        fs::remove_file(route_path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(RuleEngine::from_yaml("rules: [{name: a, action: explode}]").is_err());
+        assert!(RuleEngine::from_yaml(
+            "rules: [{name: a, when: {amount_above: abc}, action: reject}]"
+        )
+        .is_err());
+        assert!(
+            RuleEngine::from_yaml("rules: [{name: a, when: {colour: red}, action: flag}]").is_err()
+        );
+    }
+}
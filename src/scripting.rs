@@ -0,0 +1,267 @@
+use crate::engine::AccountView;
+use crate::transaction::Transaction;
+use anyhow::{anyhow, bail, ensure, Result};
+use rhai::{Dynamic, Map, Scope, AST};
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// Upper bound on the operations a single script call may run, so a runaway script can't stall
+/// processing
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Outcome of running a validation script against a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Accept,
+    Reject(String),
+    Flag(String),
+}
+
+/// A rhai script defining a `validate(tx, account)` function, called before every transaction is
+/// applied.
+///
+/// `tx` is a map with `type`, `client`, `tx` and, for deposits and withdrawals, `amount` (as a
+/// float). `account` is a map with `available`, `held`, `total` and `locked`, or `()` when the
+/// client has no account yet. The function returns `accept()`, `reject(reason)` or
+/// `flag(reason)`; returning nothing accepts the transaction.
+pub struct ValidationScript {
+    engine: rhai::Engine,
+    ast: AST,
+}
+
+impl ValidationScript {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_source(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_source(source: &str) -> Result<Self> {
+        let engine = Self::new_engine();
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow!("Failed to compile validation script: {e}"))?;
+
+        ensure!(
+            ast.iter_functions()
+                .any(|f| f.name == "validate" && f.params.len() == 2),
+            anyhow!("Validation script must define a `validate(tx, account)` function")
+        );
+        Ok(Self { engine, ast })
+    }
+
+    fn new_engine() -> rhai::Engine {
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.register_type_with_name::<Decision>("Decision");
+        engine.register_fn("accept", || Decision::Accept);
+        engine.register_fn("reject", |reason: &str| Decision::Reject(reason.into()));
+        engine.register_fn("flag", |reason: &str| Decision::Flag(reason.into()));
+        engine
+    }
+
+    pub fn evaluate(
+        &self,
+        transaction: &Transaction,
+        account: Option<&AccountView>,
+    ) -> Result<Decision> {
+        let account = account.map_or(Dynamic::UNIT, |account| account_to_map(account).into());
+
+        let result: Dynamic = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "validate",
+                (transaction_to_map(transaction), account),
+            )
+            .map_err(|e| anyhow!("Validation script failed: {e}"))?;
+
+        if result.is_unit() {
+            Ok(Decision::Accept)
+        } else if let Some(decision) = result.clone().try_cast::<Decision>() {
+            Ok(decision)
+        } else {
+            bail!(
+                "Validation script returned an unexpected value: {result} - expected accept(), \
+                reject(reason) or flag(reason)"
+            )
+        }
+    }
+
+    /// Validator for `Engine::add_validator`. Flagged transactions are reported on stderr and
+    /// still applied, while script failures reject the transaction.
+    pub fn validate(&self, transaction: &Transaction, account: Option<&AccountView>) -> Result<()> {
+        match self.evaluate(transaction, account)? {
+            Decision::Accept => Ok(()),
+            Decision::Flag(reason) => {
+                warn!(
+                    tx = transaction.tx_id(),
+                    client = transaction.client_id(),
+                    "Transaction flagged by validation script - tx_id: {} - reason: {reason}",
+                    transaction.tx_id()
+                );
+                Ok(())
+            }
+            Decision::Reject(reason) => bail!(
+                "A transaction was rejected by the validation script - tx_id: {} - reason: \
+                {reason}",
+                transaction.tx_id()
+            ),
+        }
+    }
+}
+
+fn transaction_to_map(transaction: &Transaction) -> Map {
+    let (transaction_type, amount) = match transaction {
+        Transaction::Deposit { amount, .. } => ("deposit", Some(*amount)),
+        Transaction::Withdrawal { amount, .. } => ("withdrawal", Some(*amount)),
+        Transaction::Dispute { .. } => ("dispute", None),
+        Transaction::Resolve { .. } => ("resolve", None),
+        Transaction::Chargeback { .. } => ("chargeback", None),
+        Transaction::Unlock { .. } => ("unlock", None),
+        Transaction::Reversal { .. } => ("reversal", None),
+        Transaction::Hold { amount, .. } => ("hold", Some(*amount)),
+        Transaction::Release { .. } => ("release", None),
+    };
+
+    let mut map = Map::new();
+    map.insert("type".into(), transaction_type.into());
+    map.insert("client".into(), (transaction.client_id() as i64).into());
+    map.insert("tx".into(), (transaction.tx_id() as i64).into());
+    if let Some(amount) = amount {
+        map.insert("amount".into(), (amount as f64 / 10_000.0).into());
+    }
+    map
+}
+
+fn account_to_map(account: &AccountView) -> Map {
+    let mut map = Map::new();
+    map.insert(
+        "available".into(),
+        (account.available_amount as f64 / 10_000.0).into(),
+    );
+    map.insert(
+        "held".into(),
+        (account.held_amount as f64 / 10_000.0).into(),
+    );
+    map.insert(
+        "total".into(),
+        (account.total_amount as f64 / 10_000.0).into(),
+    );
+    map.insert("locked".into(), account.locked.into());
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::scripting::{Decision, ValidationScript};
+    use crate::transaction::Transaction;
+
+    const SCRIPT: &str = r#"
+        fn validate(tx, account) {
+            if tx.type == "withdrawal" && tx.amount > 1000.0 {
+                return reject("withdrawal above 1000");
+            }
+            if tx.type == "deposit" && account == () {
+                return flag("first deposit");
+            }
+        }
+    "#;
+
+    #[test]
+    fn test_script_decisions() {
+        let script = ValidationScript::from_source(SCRIPT).unwrap();
+
+        let deposit = Transaction::Deposit {
+            client_id: 1,
+            tx_id: 1,
+            amount: 20_000_000,
+            currency: None,
+        };
+        assert_eq!(
+            script.evaluate(&deposit, None).unwrap(),
+            Decision::Flag("first deposit".to_string())
+        );
+
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit).unwrap();
+        let account = engine.account(1).unwrap();
+
+        let small_withdrawal = Transaction::Withdrawal {
+            client_id: 1,
+            tx_id: 2,
+            amount: 10_000,
+            currency: None,
+        };
+        assert_eq!(
+            script.evaluate(&small_withdrawal, Some(&account)).unwrap(),
+            Decision::Accept
+        );
+
+        let large_withdrawal = Transaction::Withdrawal {
+            client_id: 1,
+            tx_id: 3,
+            amount: 10_000_001,
+            currency: None,
+        };
+        assert_eq!(
+            script.evaluate(&large_withdrawal, Some(&account)).unwrap(),
+            Decision::Reject("withdrawal above 1000".to_string())
+        );
+    }
+
+    #[test]
+    fn test_script_rejections_are_not_applied() {
+        let script = ValidationScript::from_source(SCRIPT).unwrap();
+        let mut engine = Engine::new();
+        engine.add_validator(move |transaction, account| script.validate(transaction, account));
+
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 20_000_000,
+                currency: None,
+            })
+            .unwrap();
+        assert!(engine
+            .process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 15_000_000,
+                currency: None,
+            })
+            .is_err());
+        assert_eq!(engine.account(1).unwrap().available_amount, 20_000_000);
+
+        // The rejected tx_id was never registered, so it can still be used
+        engine
+            .process_transaction(Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 5_000_000,
+                currency: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        assert!(ValidationScript::from_source("fn check(tx) { }").is_err());
+        assert!(ValidationScript::from_source("fn validate(tx, account) {").is_err());
+
+        let script = ValidationScript::from_source("fn validate(tx, account) { 42 }").unwrap();
+        let deposit = Transaction::Deposit {
+            client_id: 1,
+            tx_id: 1,
+            amount: 1,
+            currency: None,
+        };
+        assert!(script.evaluate(&deposit, None).is_err());
+
+        let script =
+            ValidationScript::from_source("fn validate(tx, account) { loop { } }").unwrap();
+        assert!(script.validate(&deposit, None).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::engine::Engine;
+use crate::input::{self, CsvRow};
+use crate::transaction::Transaction;
+use crate::util::fixed_point_4_decimal_to_float_str;
+use anyhow::{anyhow, bail, Result};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read};
+use std::path::PathBuf;
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Batching {
+    /// One batch per range of `size` client ids
+    Clients,
+    /// One batch per window of `size` rows of the csv
+    Rows,
+    /// One batch per UTC day, going by the csv's `timestamp` column
+    Day,
+}
+
+#[derive(Debug, Clone)]
+pub struct SettlementConfig {
+    pub batching: Batching,
+    /// Client ids per batch or rows per batch, depending on the batching
+    pub size: u64,
+    pub output_dir: PathBuf,
+}
+
+/// Totals across every settlement batch written
+#[derive(Debug)]
+pub struct SettlementReport {
+    pub batches: usize,
+    pub withdrawals: u64,
+    pub total: u64,
+    pub output_dir: PathBuf,
+}
+
+impl Display for SettlementReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} withdrawals totalling {} settled in {} batches, written to {}",
+            self.withdrawals,
+            fixed_point_4_decimal_to_float_str(self.total),
+            self.batches,
+            self.output_dir.display()
+        )
+    }
+}
+
+struct Batch {
+    name: String,
+    /// Open until no more withdrawals can fall into the batch
+    writer: Option<csv::Writer<BufWriter<File>>>,
+    withdrawals: u64,
+    total: u64,
+}
+
+/// Replays a transactions csv and writes the withdrawals the engine accepted to one settlement
+/// file per batch in `config.output_dir`, along with a `batches.csv` index of every batch's file,
+/// number of withdrawals and total amount.
+pub fn settle(reader: impl Read, config: &SettlementConfig) -> Result<SettlementReport> {
+    fs::create_dir_all(&config.output_dir)?;
+    let mut engine = Engine::new();
+    let mut batches: BTreeMap<u64, Batch> = BTreeMap::new();
+    let mut rows = 0;
+
+    input::read_csv_rows(reader, |row: CsvRow| {
+        let row_index = rows;
+        rows += 1;
+        let Ok(transaction) = row.transaction else {
+            return Ok(());
+        };
+        let withdrawal = match transaction {
+            Transaction::Withdrawal {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                // Batch totals add up amounts, which only makes sense in a single currency
+                if currency.is_some() {
+                    bail!(
+                        "Withdrawal at line {} has a currency, which settlement doesn't support",
+                        row.line
+                    );
+                }
+                Some((client_id, tx_id, amount))
+            }
+            _ => None,
+        };
+        if engine.process_transaction(transaction).is_err() {
+            return Ok(());
+        }
+        let Some((client_id, tx_id, amount)) = withdrawal else {
+            return Ok(());
+        };
+
+        let key = match config.batching {
+            Batching::Clients => client_id as u64 / config.size,
+            Batching::Rows => row_index / config.size,
+            Batching::Day => {
+                row.timestamp.ok_or_else(|| {
+                    anyhow!(
+                        "Withdrawal at line {} has no timestamp to batch by",
+                        row.line
+                    )
+                })? / MILLIS_PER_DAY
+            }
+        };
+        // Row windows follow each other, so a window is complete as soon as the next one starts
+        if config.batching == Batching::Rows {
+            if let Some((_, previous)) = batches.range_mut(..key).next_back() {
+                close(previous)?;
+            }
+        }
+        let batch = match batches.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let name = batch_name(config, key);
+                let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(
+                    config.output_dir.join(format!("{name}.csv")),
+                )?));
+                writer.write_record(["client", "tx", "amount"])?;
+                entry.insert(Batch {
+                    name,
+                    writer: Some(writer),
+                    withdrawals: 0,
+                    total: 0,
+                })
+            }
+        };
+        batch
+            .writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Settlement batch {} was already closed", batch.name))?
+            .serialize((client_id, tx_id, fixed_point_4_decimal_to_float_str(amount)))?;
+        batch.withdrawals += 1;
+        batch.total += amount;
+        Ok(())
+    })?;
+
+    let mut index = csv::Writer::from_path(config.output_dir.join("batches.csv"))?;
+    index.write_record(["batch", "file", "withdrawals", "total"])?;
+    let mut report = SettlementReport {
+        batches: batches.len(),
+        withdrawals: 0,
+        total: 0,
+        output_dir: config.output_dir.clone(),
+    };
+    for batch in batches.values_mut() {
+        close(batch)?;
+        index.serialize((
+            &batch.name,
+            format!("{}.csv", batch.name),
+            batch.withdrawals,
+            fixed_point_4_decimal_to_float_str(batch.total),
+        ))?;
+        report.withdrawals += batch.withdrawals;
+        report.total += batch.total;
+    }
+    index.flush()?;
+
+    Ok(report)
+}
+
+fn close(batch: &mut Batch) -> Result<()> {
+    if let Some(mut writer) = batch.writer.take() {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn batch_name(config: &SettlementConfig, key: u64) -> String {
+    match config.batching {
+        Batching::Clients => {
+            let first = key * config.size;
+            let last = first.saturating_add(config.size - 1).min(u16::MAX as u64);
+            format!("clients-{first:05}-{last:05}")
+        }
+        Batching::Rows => {
+            let first = key * config.size + 1;
+            format!(
+                "rows-{first:010}-{:010}",
+                first.saturating_add(config.size - 1)
+            )
+        }
+        Batching::Day => {
+            let (year, month, day) = civil_date(key);
+            format!("day-{year:04}-{month:02}-{day:02}")
+        }
+    }
+}
+
+/// The (year, month, day) of the given number of days since the Unix epoch, in the proleptic
+/// Gregorian calendar (from Howard Hinnant's `civil_from_days`)
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settlement::{civil_date, settle, Batching, SettlementConfig};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const CSV: &str = "type,client,tx,amount,timestamp\n\
+        deposit,1,1,100,1700000000000\n\
+        deposit,2,2,100,1700000001000\n\
+        withdrawal,1,3,10,1700000002000\n\
+        withdrawal,2,4,500,1700000003000\n\
+        withdrawal,2,5,20,1700100000000\n\
+        withdrawal,1,6,1.5,1700100001000\n";
+
+    fn settle_into(name: &str, batching: Batching, size: u64) -> PathBuf {
+        let output_dir = env::temp_dir().join(format!(
+            "payments-engine-settlement-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&output_dir);
+        let report = settle(
+            CSV.as_bytes(),
+            &SettlementConfig {
+                batching,
+                size,
+                output_dir: output_dir.clone(),
+            },
+        )
+        .unwrap();
+        assert_eq!(report.withdrawals, 3);
+        assert_eq!(report.total, 315_000);
+        output_dir
+    }
+
+    fn read_file(dir: &Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_settle_batches_accepted_withdrawals() {
+        let dir = settle_into("rows", Batching::Rows, 4);
+        assert_eq!(
+            read_file(&dir, "batches.csv"),
+            "batch,file,withdrawals,total\n\
+            rows-0000000001-0000000004,rows-0000000001-0000000004.csv,1,10.0000\n\
+            rows-0000000005-0000000008,rows-0000000005-0000000008.csv,2,21.5000\n"
+        );
+        assert_eq!(
+            read_file(&dir, "rows-0000000005-0000000008.csv"),
+            "client,tx,amount\n2,5,20.0000\n1,6,1.5000\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        let dir = settle_into("clients", Batching::Clients, 2);
+        assert_eq!(
+            read_file(&dir, "batches.csv"),
+            "batch,file,withdrawals,total\n\
+            clients-00000-00001,clients-00000-00001.csv,2,11.5000\n\
+            clients-00002-00003,clients-00002-00003.csv,1,20.0000\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        let dir = settle_into("day", Batching::Day, 1);
+        assert_eq!(
+            read_file(&dir, "batches.csv"),
+            "batch,file,withdrawals,total\n\
+            day-2023-11-14,day-2023-11-14.csv,1,10.0000\n\
+            day-2023-11-16,day-2023-11-16.csv,2,21.5000\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(19_722), (2023, 12, 31));
+        assert_eq!(civil_date(19_723), (2024, 1, 1));
+    }
+}
@@ -0,0 +1,550 @@
+use crate::engine::{AccountView, Balance, HeldBalance};
+use crate::transaction::{Currency, Transaction};
+use anyhow::{ensure, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Not;
+use tracing::{error, info};
+
+/// Runs a reference ledger alongside the engine and reports whenever the two disagree, either on
+/// whether a transaction is applied or on the resulting state of the account
+#[derive(Default)]
+pub struct ShadowCheck {
+    reference: ReferenceLedger,
+    /// The latest transaction, along with whether the reference applied it, until the engine's
+    /// outcome is known
+    pending: Option<(Transaction, bool)>,
+    checked: u64,
+    mismatches: u64,
+}
+
+impl ShadowCheck {
+    /// To be called right before the engine processes `transaction`
+    pub fn before(&mut self, transaction: &Transaction) {
+        // Had the engine applied the previous transaction, `applied` would have settled it
+        self.settle(false);
+        let applied = self.reference.apply(transaction);
+        self.pending = Some((transaction.clone(), applied));
+    }
+
+    /// To be called once the engine applied `transaction`, with the resulting account state
+    pub fn applied(&mut self, transaction: &Transaction, account: &AccountView) {
+        self.settle(true);
+
+        let reference = self
+            .reference
+            .account(transaction.client_id(), account.currency);
+        if reference.as_ref() != Some(account) {
+            self.mismatches += 1;
+            error!(
+                tx = transaction.tx_id(),
+                client = transaction.client_id(),
+                "Shadow check mismatch after {transaction:?}: the engine has {account:?}, the \
+                reference ledger has {reference:?}"
+            );
+        }
+    }
+
+    /// Reports the number of transactions checked, failing if any mismatch was found
+    pub fn finish(&mut self) -> Result<()> {
+        self.settle(false);
+        info!(
+            checked = self.checked,
+            mismatches = self.mismatches,
+            "Shadow check: {} transactions checked, {} mismatches",
+            self.checked,
+            self.mismatches
+        );
+        ensure!(
+            self.mismatches == 0,
+            "The engine disagreed with the reference ledger {} times",
+            self.mismatches
+        );
+        Ok(())
+    }
+
+    fn settle(&mut self, engine_applied: bool) {
+        let Some((transaction, reference_applied)) = self.pending.take() else {
+            return;
+        };
+        self.checked += 1;
+        if engine_applied != reference_applied {
+            self.mismatches += 1;
+            error!(
+                tx = transaction.tx_id(),
+                client = transaction.client_id(),
+                "Shadow check mismatch on {transaction:?}: applied by the engine: \
+                {engine_applied}, by the reference ledger: {reference_applied}"
+            );
+        }
+    }
+}
+
+/// A straightforward implementation of the engine's rules, favouring obviousness over speed and
+/// memory usage. It shares no code with the engine, so it can catch regressions in it.
+#[derive(Default)]
+struct ReferenceLedger {
+    accounts: BTreeMap<u16, ReferenceAccount>,
+    /// Tx ids of every deposit, withdrawal and hold seen, applied or not
+    tx_ids: HashSet<u32>,
+    /// Applied deposits and withdrawals, which can be disputed
+    disputables: HashMap<u32, ReferenceDisputable>,
+    /// Manual holds not released yet
+    holds: HashMap<u32, ReferenceHold>,
+}
+
+#[derive(Default)]
+struct ReferenceAccount {
+    balances: BTreeMap<Option<Currency>, ReferenceBalances>,
+    locked: bool,
+}
+
+#[derive(Default)]
+struct ReferenceBalances {
+    available: Balance,
+    held: Balance,
+}
+
+struct ReferenceDisputable {
+    client_id: u16,
+    currency: Option<Currency>,
+    withdrawal: bool,
+    amount: Balance,
+    status: DisputeStatus,
+}
+
+struct ReferenceHold {
+    client_id: u16,
+    currency: Option<Currency>,
+    amount: Balance,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DisputeStatus {
+    Settled,
+    Disputed,
+    ChargedBack,
+    Reversed,
+}
+
+impl ReferenceLedger {
+    /// Applies a transaction, returning whether it was accepted
+    fn apply(&mut self, transaction: &Transaction) -> bool {
+        match *transaction {
+            Transaction::Deposit {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                if self.tx_ids.insert(tx_id).not() {
+                    return false;
+                }
+                // The account is opened even when the deposit is then rejected
+                let account = self.accounts.entry(client_id).or_default();
+                if account.locked {
+                    return false;
+                }
+                account.balances.entry(currency).or_default().available += amount as Balance;
+                self.disputables.insert(
+                    tx_id,
+                    ReferenceDisputable {
+                        client_id,
+                        currency,
+                        withdrawal: false,
+                        amount: amount as Balance,
+                        status: DisputeStatus::Settled,
+                    },
+                );
+                true
+            }
+            Transaction::Withdrawal {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                if self.tx_ids.insert(tx_id).not() {
+                    return false;
+                }
+                let Some(account) = self.accounts.get_mut(&client_id) else {
+                    return false;
+                };
+                let available = account
+                    .balances
+                    .get(&currency)
+                    .map_or(0, |balances| balances.available);
+                if account.locked || available < amount as Balance {
+                    return false;
+                }
+                account.balances.entry(currency).or_default().available -= amount as Balance;
+                self.disputables.insert(
+                    tx_id,
+                    ReferenceDisputable {
+                        client_id,
+                        currency,
+                        withdrawal: true,
+                        amount: amount as Balance,
+                        status: DisputeStatus::Settled,
+                    },
+                );
+                true
+            }
+            Transaction::Dispute {
+                client_id,
+                tx_id,
+                currency,
+            } => self.move_deposit(
+                client_id,
+                tx_id,
+                currency,
+                DisputeStatus::Settled,
+                DisputeStatus::Disputed,
+            ),
+            Transaction::Resolve {
+                client_id,
+                tx_id,
+                currency,
+            } => self.move_deposit(
+                client_id,
+                tx_id,
+                currency,
+                DisputeStatus::Disputed,
+                DisputeStatus::Settled,
+            ),
+            Transaction::Chargeback {
+                client_id,
+                tx_id,
+                currency,
+            } => self.move_deposit(
+                client_id,
+                tx_id,
+                currency,
+                DisputeStatus::Disputed,
+                DisputeStatus::ChargedBack,
+            ),
+            Transaction::Reversal {
+                client_id,
+                tx_id,
+                currency,
+            } => {
+                let locked = self
+                    .accounts
+                    .get(&client_id)
+                    .is_some_and(|account| account.locked);
+                let withdrawal = self
+                    .disputables
+                    .get(&tx_id)
+                    .is_some_and(|disputable| disputable.withdrawal);
+                if locked || withdrawal.not() {
+                    return false;
+                }
+                self.move_deposit(
+                    client_id,
+                    tx_id,
+                    currency,
+                    DisputeStatus::Settled,
+                    DisputeStatus::Reversed,
+                )
+            }
+            Transaction::Hold {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                if self.tx_ids.insert(tx_id).not() {
+                    return false;
+                }
+                let Some(account) = self.accounts.get_mut(&client_id) else {
+                    return false;
+                };
+                let balances = account.balances.entry(currency).or_default();
+                if balances.available < amount as Balance {
+                    return false;
+                }
+                balances.available -= amount as Balance;
+                balances.held += amount as Balance;
+                self.holds.insert(
+                    tx_id,
+                    ReferenceHold {
+                        client_id,
+                        currency,
+                        amount: amount as Balance,
+                    },
+                );
+                true
+            }
+            Transaction::Release {
+                client_id,
+                tx_id,
+                currency,
+            } => {
+                let released = self.holds.get(&tx_id).is_some_and(|hold| {
+                    hold.client_id == client_id
+                        && currency.is_none_or(|currency| hold.currency == Some(currency))
+                });
+                if released.not() {
+                    return false;
+                }
+                let hold = self.holds.remove(&tx_id).expect("Checked above");
+                let balances = self
+                    .accounts
+                    .get_mut(&client_id)
+                    .and_then(|account| account.balances.get_mut(&hold.currency))
+                    .expect("Holds open a balance in their currency");
+                balances.held -= hold.amount;
+                balances.available += hold.amount;
+                true
+            }
+            Transaction::Unlock { client_id, .. } => {
+                let disputed = self.disputables.values().any(|disputable| {
+                    disputable.client_id == client_id
+                        && disputable.status == DisputeStatus::Disputed
+                });
+                match self.accounts.get_mut(&client_id) {
+                    Some(account) if account.locked && disputed.not() => {
+                        account.locked = false;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Moves the client's deposit or withdrawal `tx_id` from the `from` status to the `to` status,
+    /// moving its amount between the account's balances accordingly. A disputed withdrawal's amount
+    /// is held on top of the available funds, and refunded if charged back or reversed. With a
+    /// currency, it has to be that of the deposit or withdrawal.
+    fn move_deposit(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        currency: Option<Currency>,
+        from: DisputeStatus,
+        to: DisputeStatus,
+    ) -> bool {
+        let Some(disputable) = self.disputables.get_mut(&tx_id) else {
+            return false;
+        };
+        if disputable.client_id != client_id
+            || disputable.status != from
+            || currency.is_some_and(|currency| disputable.currency != Some(currency))
+        {
+            return false;
+        }
+        let account = self
+            .accounts
+            .get_mut(&client_id)
+            .expect("Clients with deposits or withdrawals have an account");
+        let balances = account
+            .balances
+            .get_mut(&disputable.currency)
+            .expect("Deposits and withdrawals open a balance in their currency");
+
+        disputable.status = to;
+        let amount = disputable.amount;
+        match (to, disputable.withdrawal) {
+            (DisputeStatus::Disputed, false) => {
+                balances.available -= amount;
+                balances.held += amount;
+            }
+            (DisputeStatus::Disputed, true) => balances.held += amount,
+            (DisputeStatus::Settled, false) => {
+                balances.held -= amount;
+                balances.available += amount;
+            }
+            (DisputeStatus::Settled, true) => balances.held -= amount,
+            (DisputeStatus::ChargedBack, withdrawal) => {
+                balances.held -= amount;
+                if withdrawal {
+                    balances.available += amount;
+                }
+                account.locked = true;
+            }
+            // Only withdrawals get reversed, crediting their amount back
+            (DisputeStatus::Reversed, _) => balances.available += amount,
+        }
+        true
+    }
+
+    fn account(&self, client_id: u16, currency: Option<Currency>) -> Option<AccountView> {
+        self.accounts.get(&client_id).map(|account| {
+            let (available, held) = account
+                .balances
+                .get(&currency)
+                .map_or((0, 0), |balances| (balances.available, balances.held));
+            AccountView {
+                client_id,
+                currency,
+                available_amount: available,
+                held_amount: held as HeldBalance,
+                total_amount: available + held,
+                locked: account.locked,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::shadow::ShadowCheck;
+    use crate::transaction::Transaction;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_shadow_check_agrees_with_engine() {
+        let shadow = Arc::new(Mutex::new(ShadowCheck::default()));
+        let mut engine = Engine::new();
+        let before = Arc::clone(&shadow);
+        engine.add_validator(move |transaction, _| {
+            before.lock().unwrap().before(transaction);
+            Ok(())
+        });
+        let applied = Arc::clone(&shadow);
+        engine.on_transaction_applied(move |transaction, account| {
+            applied.lock().unwrap().applied(transaction, account);
+        });
+
+        let transactions = [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 500,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 2,
+                tx_id: 3,
+                amount: 1,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 2,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 4,
+                amount: 10,
+                currency: None,
+            },
+            Transaction::Resolve {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 4,
+                tx_id: 6,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 4,
+                tx_id: 7,
+                amount: 40,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 4,
+                tx_id: 7,
+                currency: None,
+            },
+            Transaction::Chargeback {
+                client_id: 4,
+                tx_id: 7,
+                currency: None,
+            },
+            Transaction::Deposit {
+                client_id: 5,
+                tx_id: 8,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Hold {
+                client_id: 5,
+                tx_id: 9,
+                amount: 60,
+                currency: None,
+            },
+            Transaction::Hold {
+                client_id: 5,
+                tx_id: 10,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 5,
+                tx_id: 11,
+                amount: 50,
+                currency: None,
+            },
+            Transaction::Release {
+                client_id: 4,
+                tx_id: 9,
+                currency: None,
+            },
+            Transaction::Release {
+                client_id: 5,
+                tx_id: 9,
+                currency: None,
+            },
+            Transaction::Release {
+                client_id: 5,
+                tx_id: 9,
+                currency: None,
+            },
+        ];
+        for transaction in transactions {
+            let _ = engine.process_transaction(transaction);
+        }
+
+        let mut shadow = shadow.lock().unwrap();
+        assert!(shadow.finish().is_ok());
+        assert_eq!(shadow.checked, 22);
+
+        // A transaction the engine never got to apply is reported
+        shadow.before(&Transaction::Deposit {
+            client_id: 3,
+            tx_id: 5,
+            amount: 1,
+            currency: None,
+        });
+        assert!(shadow.finish().is_err());
+    }
+}
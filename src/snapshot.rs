@@ -0,0 +1,141 @@
+use crate::engine::{Engine, SnapshotFormat};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write as _};
+use std::io::Read;
+
+/// Outcome of checking an engine snapshot
+#[derive(Debug)]
+pub struct SnapshotReport {
+    pub format: SnapshotFormat,
+    /// Number of accounts in the snapshot, once per currency they have balances in
+    pub accounts: usize,
+    /// SHA-256 of the snapshot, in hex
+    pub digest: String,
+    /// SHA-256 of the snapshot written again by the engine restored from it, which only differs
+    /// from `digest` when restoring it lost or changed part of it
+    pub reserialized_digest: String,
+    /// What the restored engine's state is inconsistent about, see `Engine::check_invariants`
+    pub violations: Vec<String>,
+}
+
+impl SnapshotReport {
+    /// Whether the snapshot restored to the same state, and that state is consistent
+    pub fn is_clean(&self) -> bool {
+        self.digest == self.reserialized_digest && self.violations.is_empty()
+    }
+}
+
+/// Checks a snapshot written by `Engine::snapshot` or `Engine::snapshot_as`: restores an engine
+/// from it, writes that engine's snapshot again, in the same format, to compare their digests,
+/// since snapshots of the same state are byte for byte the same, and checks the restored state's
+/// invariants. Snapshots that can't be restored at all are an error.
+pub fn verify(mut reader: impl Read) -> Result<SnapshotReport> {
+    let mut snapshot = Vec::new();
+    reader.read_to_end(&mut snapshot)?;
+    let engine = Engine::restore(snapshot.as_slice()).context("Failed to restore the snapshot")?;
+    let format = SnapshotFormat::detect(&snapshot);
+    let mut reserialized = Vec::new();
+    engine
+        .snapshot_as(format, &mut reserialized)
+        .context("Failed to write the restored engine's snapshot")?;
+
+    Ok(SnapshotReport {
+        format,
+        accounts: engine.accounts().count(),
+        digest: sha256_hex(&snapshot),
+        reserialized_digest: sha256_hex(&reserialized),
+        violations: engine.check_invariants(),
+    })
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+impl fmt::Display for SnapshotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.format {
+            SnapshotFormat::Bincode => "bincode",
+            SnapshotFormat::MessagePack => "MessagePack",
+            SnapshotFormat::Mapped => "mapped",
+        };
+        writeln!(f, "Format: {format}")?;
+        writeln!(f, "Accounts: {}", self.accounts)?;
+        writeln!(f, "Digest: {}", self.digest)?;
+        writeln!(
+            f,
+            "Re-serialized digest: {} ({})",
+            self.reserialized_digest,
+            match self.digest == self.reserialized_digest {
+                true => "matches",
+                false => "differs",
+            }
+        )?;
+        write!(f, "Invariant violations: {}", self.violations.len())?;
+        for violation in self.violations.iter() {
+            write!(f, "\n  {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, SnapshotFormat};
+    use crate::snapshot::verify;
+    use crate::transaction::Transaction;
+    use std::ops::Not;
+
+    #[test]
+    fn test_verify() {
+        let mut engine = Engine::new();
+        for client_id in [3, 1, 2] {
+            for tx_id in (0..20).map(|tx| u32::from(client_id) * 100 + tx) {
+                engine
+                    .process_transaction(Transaction::Deposit {
+                        client_id,
+                        tx_id,
+                        amount: 100,
+                        currency: None,
+                    })
+                    .unwrap();
+            }
+            engine
+                .process_transaction(Transaction::Dispute {
+                    client_id,
+                    tx_id: u32::from(client_id) * 100,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let mut snapshot = Vec::new();
+        engine
+            .snapshot_as(SnapshotFormat::MessagePack, &mut snapshot)
+            .unwrap();
+        let report = verify(snapshot.as_slice()).unwrap();
+        assert_eq!(report.format, SnapshotFormat::MessagePack);
+        assert!(report.is_clean());
+
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let report = verify(snapshot.as_slice()).unwrap();
+        assert_eq!(report.accounts, 3);
+        assert_eq!(report.digest, report.reserialized_digest);
+        assert!(report.violations.is_empty());
+        assert!(report.is_clean());
+
+        // Trailing bytes aren't read by restoring, so they don't survive writing it again
+        snapshot.push(0);
+        let report = verify(snapshot.as_slice()).unwrap();
+        assert_ne!(report.digest, report.reserialized_digest);
+        assert!(report.is_clean().not());
+
+        assert!(verify(&b"PESN"[..]).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::engine::Engine;
+use crate::input::{self, CsvOptions, InvalidRows, ProcessedRows};
+use anyhow::Result;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Settings of a soak test
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+    /// Number of csv rows to stream through the engine
+    pub rows: u64,
+    /// Number of distinct clients rows are spread over
+    pub clients: u16,
+    /// How often throughput and memory usage are sampled and reported
+    pub report_interval: Duration,
+}
+
+/// Outcome of a soak test
+#[derive(Debug, Default)]
+pub struct SoakReport {
+    pub elapsed: Duration,
+    pub processed: ProcessedRows,
+    pub samples: Vec<SoakSample>,
+}
+
+/// Throughput and memory usage over one report interval
+#[derive(Debug)]
+pub struct SoakSample {
+    /// Rows generated since the start of the test
+    pub rows: u64,
+    pub rows_per_sec: f64,
+    /// Resident memory of the process, when it can be measured
+    pub rss_bytes: Option<u64>,
+}
+
+/// Streams `config.rows` generated csv rows through a new engine, without touching the disk,
+/// printing a sample on stderr every `config.report_interval`
+pub fn run(config: &SoakConfig) -> Result<SoakReport> {
+    let mut engine = Engine::new();
+    let mut rows = GeneratedRows::new(config);
+
+    // The generated rows are all valid, so any invalid one is a bug worth stopping for
+    let processed = input::process_csv_readers(
+        &mut engine,
+        vec![&mut rows],
+        &CsvOptions::default(),
+        InvalidRows::Fail,
+        None,
+    )?;
+    rows.sample();
+
+    Ok(SoakReport {
+        elapsed: rows.start.elapsed(),
+        processed,
+        samples: rows.samples,
+    })
+}
+
+/// A csv of `rows` rows, generated as it's read. Clients take turns making blocks of ten rows:
+/// seven deposits, a withdrawal smaller than any of them, then a dispute of the block's first
+/// deposit and its resolve, so the engine never rejects any of them.
+struct GeneratedRows {
+    rows: u64,
+    clients: u16,
+    generated: u64,
+    buffer: Vec<u8>,
+    position: usize,
+    start: Instant,
+    report_interval: Duration,
+    last_sample: (Instant, u64),
+    samples: Vec<SoakSample>,
+}
+
+impl GeneratedRows {
+    fn new(config: &SoakConfig) -> Self {
+        let start = Instant::now();
+        Self {
+            rows: config.rows,
+            clients: config.clients,
+            generated: 0,
+            buffer: b"type,client,tx,amount\n".to_vec(),
+            position: 0,
+            start,
+            report_interval: config.report_interval,
+            last_sample: (start, 0),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Appends the `row`-th row to the buffer. Rows are numbered from 0 and used as tx ids, so tx
+    /// ids wrap around (and get rejected as duplicates) past `u32::MAX` rows.
+    fn push_row(&mut self, row: u64) -> io::Result<()> {
+        let tx_id = row as u32;
+        let block_start = tx_id.wrapping_sub((row % 10) as u32);
+        let client = (row / 10 % self.clients as u64) as u16;
+        let amount = format!("{}.{:04}", 10 + row % 90, row % 10_000);
+
+        match row % 10 {
+            0..=6 => writeln!(self.buffer, "deposit,{client},{tx_id},{amount}"),
+            7 => writeln!(self.buffer, "withdrawal,{client},{tx_id},1.5"),
+            8 => writeln!(self.buffer, "dispute,{client},{block_start},"),
+            _ => writeln!(self.buffer, "resolve,{client},{block_start},"),
+        }
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        let (last_time, last_rows) = self.last_sample;
+        let sample = SoakSample {
+            rows: self.generated,
+            rows_per_sec: (self.generated - last_rows) as f64
+                / now.duration_since(last_time).as_secs_f64(),
+            rss_bytes: rss_bytes(),
+        };
+        info!(
+            "{:.0?} elapsed, {} rows, {:.0} rows/s, {} resident",
+            now.duration_since(self.start),
+            sample.rows,
+            sample.rows_per_sec,
+            format_rss(sample.rss_bytes)
+        );
+        self.samples.push(sample);
+        self.last_sample = (now, self.generated);
+    }
+}
+
+impl Read for GeneratedRows {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            self.buffer.clear();
+            self.position = 0;
+            let end = self.rows.min(self.generated + 1_024);
+            for row in self.generated..end {
+                self.push_row(row)?;
+            }
+            self.generated = end;
+
+            if self.last_sample.0.elapsed() >= self.report_interval {
+                self.sample();
+            }
+        }
+
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Resident memory of the process, only available on Linux
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn format_rss(rss_bytes: Option<u64>) -> String {
+    match rss_bytes {
+        Some(bytes) => format!("{:.1}MB", bytes as f64 / 1_000_000.0),
+        None => "unknown memory".to_string(),
+    }
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.processed.applied + self.processed.rejected + self.processed.invalid;
+        writeln!(
+            f,
+            "Rows: {rows} in {:.1}s ({:.0} rows/s), applied: {}, rejected: {}, invalid: {}",
+            self.elapsed.as_secs_f64(),
+            rows as f64 / self.elapsed.as_secs_f64(),
+            self.processed.applied,
+            self.processed.rejected,
+            self.processed.invalid
+        )?;
+
+        // The last sample usually covers a partial interval, so drift is measured on the ones
+        // before it when there are enough of them
+        let full_samples = match self.samples.len() {
+            0..=2 => &self.samples[..],
+            len => &self.samples[..len - 1],
+        };
+        if let (Some(first), Some(last)) = (full_samples.first(), full_samples.last()) {
+            writeln!(
+                f,
+                "Throughput: {:.0} rows/s in the first interval, {:.0} rows/s in the last ({:+.1}%)",
+                first.rows_per_sec,
+                last.rows_per_sec,
+                (last.rows_per_sec / first.rows_per_sec - 1.0) * 100.0
+            )?;
+        }
+        if let (Some(first), Some(last)) = (self.samples.first(), self.samples.last()) {
+            let peak = self
+                .samples
+                .iter()
+                .filter_map(|sample| sample.rss_bytes)
+                .max();
+            write!(
+                f,
+                "Memory: {} resident after the first interval, {} at the end, {} at the peak",
+                format_rss(first.rss_bytes),
+                format_rss(last.rss_bytes),
+                format_rss(peak)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::soak::{run, SoakConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_soak_applies_every_generated_row() {
+        let report = run(&SoakConfig {
+            rows: 25_005,
+            clients: 7,
+            report_interval: Duration::from_secs(60),
+        })
+        .unwrap();
+
+        assert_eq!(report.processed.applied, 25_005);
+        assert_eq!(report.processed.rejected, 0);
+        assert_eq!(report.processed.invalid, 0);
+        assert_eq!(report.samples.last().unwrap().rows, 25_005);
+    }
+}
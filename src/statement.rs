@@ -0,0 +1,588 @@
+use crate::engine::{Engine, EngineConfig, StatementEntry};
+use crate::input::{self, CsvOptions};
+use crate::ledger::{Balance, Currency};
+use crate::transaction::RawTransactionType;
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum StatementFormat {
+    /// One row per transaction, with the balances it resulted in
+    Csv,
+    /// An ISO 20022 bank-to-customer statement (camt.053.001.08), with one statement per currency
+    Camt053,
+    /// A beancount journal, with postings to the account's available, held and chargeback loss
+    /// accounts
+    Beancount,
+}
+
+/// ISO 4217's code for "no currency", for the amounts of camt.053 statements and beancount
+/// journals in the default currency
+const NO_CURRENCY: &str = "XXX";
+
+/// Replays a transactions csv into an engine with the given config, keeping its history, and
+/// writes the statement of one client's account in the given format. Every row goes through the
+/// engine, since other clients' rows can affect the client's (e.g. by using a tx id first), and
+/// rejected or invalid ones are left out of the statement.
+pub fn statement(
+    config: EngineConfig,
+    reader: impl Read,
+    client_id: u16,
+    format: StatementFormat,
+    writer: impl Write,
+) -> Result<()> {
+    let mut engine = Engine::with_config(EngineConfig {
+        keep_history: true,
+        ..config
+    });
+    let rounding = engine.config().rounding;
+    input::read_csv_inputs(vec![reader], &CsvOptions::default(), rounding, |row| {
+        if let Ok(transaction) = row.transaction {
+            let _ = engine.process_transaction_at(transaction, row.timestamp);
+        }
+        Ok(())
+    })?;
+
+    let entries = engine.client_statement(client_id);
+    match format {
+        StatementFormat::Csv => write_statement_csv(&entries, writer),
+        StatementFormat::Camt053 => {
+            let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+            write_statement_camt053(client_id, &entries, created_at, writer)
+        }
+        StatementFormat::Beancount => write_statement_beancount(client_id, &entries, writer),
+    }
+}
+
+/// Writes statement entries as csv rows, one per applied transaction with the account's balances
+/// right after it. A `currency` column follows the type when any entry has one, like in account
+/// reports.
+pub fn write_statement_csv(entries: &[StatementEntry], writer: impl Write) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    let currencies = entries.iter().any(|entry| entry.currency.is_some());
+
+    let mut headers = vec!["tx", "type"];
+    if currencies {
+        headers.push("currency");
+    }
+    headers.extend(["amount", "timestamp", "available", "held", "total"]);
+    wtr.write_record(headers)?;
+
+    for entry in entries {
+        let mut record = vec![
+            entry.tx_id.to_string(),
+            type_name(entry.transaction_type).to_string(),
+        ];
+        if currencies {
+            record.push(entry.currency.map(String::from).unwrap_or_default());
+        }
+        record.extend([
+            entry
+                .amount
+                .map(fixed_point_4_decimal_to_float_str)
+                .unwrap_or_default(),
+            entry
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            signed_fixed_point_4_decimal_to_float_str(entry.available_amount),
+            fixed_point_4_decimal_to_float_str(entry.held_amount),
+            signed_fixed_point_4_decimal_to_float_str(entry.total_amount),
+        ]);
+        wtr.write_record(record)?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes statement entries as a camt.053 bank-to-customer statement created at `created_at`
+/// (milliseconds since the Unix epoch), with a `Stmt` per currency of the account. Each has the
+/// opening (zero, as entries start with the account), closing booked and closing available
+/// balances, and an `Ntry` per transaction that moved the account's total: deposits, withdrawals,
+/// chargebacks and reversals. Disputes and holds only move funds between available and held, so
+/// they show in the closing available balance alone. Entries without a timestamp have no booking
+/// date.
+pub fn write_statement_camt053(
+    client_id: u16,
+    entries: &[StatementEntry],
+    created_at: u64,
+    mut writer: impl Write,
+) -> Result<()> {
+    let mut currencies: BTreeMap<Option<Currency>, Vec<&StatementEntry>> = BTreeMap::new();
+    for entry in entries {
+        currencies.entry(entry.currency).or_default().push(entry);
+    }
+    let created_at = date_time(created_at);
+
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.08">"#
+    )?;
+    writeln!(writer, "  <BkToCstmrStmt>")?;
+    writeln!(writer, "    <GrpHdr>")?;
+    writeln!(writer, "      <MsgId>STMT-{client_id}-{created_at}</MsgId>")?;
+    writeln!(writer, "      <CreDtTm>{created_at}</CreDtTm>")?;
+    writeln!(writer, "    </GrpHdr>")?;
+    for (currency, entries) in currencies {
+        let code = currency.as_ref().map_or(NO_CURRENCY, Currency::as_str);
+        let opened_at = entries
+            .iter()
+            .find_map(|entry| entry.timestamp)
+            .map_or_else(|| created_at.clone(), date_time);
+        let last = entries.last().expect("Currencies have entries");
+
+        writeln!(writer, "    <Stmt>")?;
+        writeln!(writer, "      <Id>{client_id}-{code}</Id>")?;
+        writeln!(writer, "      <CreDtTm>{created_at}</CreDtTm>")?;
+        writeln!(writer, "      <Acct>")?;
+        writeln!(writer, "        <Id>")?;
+        writeln!(writer, "          <Othr>")?;
+        writeln!(writer, "            <Id>{client_id}</Id>")?;
+        writeln!(writer, "          </Othr>")?;
+        writeln!(writer, "        </Id>")?;
+        writeln!(writer, "        <Ccy>{code}</Ccy>")?;
+        writeln!(writer, "      </Acct>")?;
+        write_camt053_balance(&mut writer, "OPBD", 0, code, &opened_at)?;
+        write_camt053_balance(&mut writer, "CLBD", last.total_amount, code, &created_at)?;
+        write_camt053_balance(
+            &mut writer,
+            "CLAV",
+            last.available_amount,
+            code,
+            &created_at,
+        )?;
+
+        let mut total: Balance = 0;
+        for entry in entries {
+            let moved = entry.total_amount - total;
+            total = entry.total_amount;
+            if moved == 0 {
+                continue;
+            }
+            let reversal = matches!(
+                entry.transaction_type,
+                RawTransactionType::Chargeback | RawTransactionType::Reversal
+            );
+            writeln!(writer, "      <Ntry>")?;
+            writeln!(writer, "        <NtryRef>{}</NtryRef>", entry.tx_id)?;
+            writeln!(
+                writer,
+                r#"        <Amt Ccy="{code}">{}</Amt>"#,
+                fixed_point_4_decimal_to_float_str(moved.unsigned_abs())
+            )?;
+            writeln!(
+                writer,
+                "        <CdtDbtInd>{}</CdtDbtInd>",
+                credit_or_debit(moved)
+            )?;
+            if reversal {
+                writeln!(writer, "        <RvslInd>true</RvslInd>")?;
+            }
+            writeln!(writer, "        <Sts>")?;
+            writeln!(writer, "          <Cd>BOOK</Cd>")?;
+            writeln!(writer, "        </Sts>")?;
+            if let Some(timestamp) = entry.timestamp {
+                writeln!(writer, "        <BookgDt>")?;
+                writeln!(writer, "          <DtTm>{}</DtTm>", date_time(timestamp))?;
+                writeln!(writer, "        </BookgDt>")?;
+            }
+            writeln!(writer, "        <AcctSvcrRef>{}</AcctSvcrRef>", entry.tx_id)?;
+            writeln!(writer, "        <BkTxCd>")?;
+            writeln!(writer, "          <Prtry>")?;
+            writeln!(
+                writer,
+                "            <Cd>{}</Cd>",
+                type_name(entry.transaction_type)
+            )?;
+            writeln!(writer, "          </Prtry>")?;
+            writeln!(writer, "        </BkTxCd>")?;
+            writeln!(writer, "      </Ntry>")?;
+        }
+        writeln!(writer, "    </Stmt>")?;
+    }
+    writeln!(writer, "  </BkToCstmrStmt>")?;
+    writeln!(writer, "</Document>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes statement entries as a beancount journal, a transaction per entry that moved funds,
+/// with postings of the change to the client's `Available` and `Held` accounts balanced by one
+/// to `Equity:ClientN:Transfers` for deposits, withdrawals and reversals, or
+/// `Expenses:ClientN:ChargebackLoss` for chargebacks. Disputes, resolves, holds and releases only
+/// post between `Available` and `Held`. Entries without a timestamp are dated like the entry
+/// before them, and the journal ends with balance assertions of the account's final balances, for
+/// `bean-check` to verify the postings add up to them.
+pub fn write_statement_beancount(
+    client_id: u16,
+    entries: &[StatementEntry],
+    mut writer: impl Write,
+) -> Result<()> {
+    let available = format!("Assets:Client{client_id}:Available");
+    let held = format!("Assets:Client{client_id}:Held");
+    let transfers = format!("Equity:Client{client_id}:Transfers");
+    let chargeback_loss = format!("Expenses:Client{client_id}:ChargebackLoss");
+
+    let mut timestamp = 0;
+    let dated: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            timestamp = entry.timestamp.unwrap_or(timestamp);
+            (entry, timestamp)
+        })
+        .collect();
+    let opened_at = dated.iter().map(|(_, timestamp)| *timestamp).min();
+    let closed_at = dated.iter().map(|(_, timestamp)| *timestamp).max();
+    let (Some(opened_at), Some(closed_at)) = (opened_at, closed_at) else {
+        return Ok(());
+    };
+
+    let opened_at = date(opened_at);
+    for account in [&available, &held, &transfers, &chargeback_loss] {
+        writeln!(writer, "{opened_at} open {account}")?;
+    }
+
+    // Balances by currency, as of the last entry
+    let mut balances: BTreeMap<Option<Currency>, (Balance, Balance)> = BTreeMap::new();
+    for (entry, timestamp) in dated {
+        let (last_available, last_held) = balances.entry(entry.currency).or_default();
+        let held_amount = entry.held_amount as Balance;
+        let moved_available = entry.available_amount - *last_available;
+        let moved_held = held_amount - *last_held;
+        (*last_available, *last_held) = (entry.available_amount, held_amount);
+        if moved_available == 0 && moved_held == 0 {
+            continue;
+        }
+
+        let code = entry
+            .currency
+            .as_ref()
+            .map_or(NO_CURRENCY, Currency::as_str);
+        let transaction_type = type_name(entry.transaction_type);
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            r#"{} * "{transaction_type} of tx {}""#,
+            date(timestamp),
+            entry.tx_id
+        )?;
+        for (account, amount) in [(&available, moved_available), (&held, moved_held)] {
+            if amount != 0 {
+                write_beancount_posting(&mut writer, account, amount, code)?;
+            }
+        }
+        let moved = moved_available + moved_held;
+        if moved != 0 {
+            let account = match entry.transaction_type {
+                RawTransactionType::Chargeback => &chargeback_loss,
+                _ => &transfers,
+            };
+            write_beancount_posting(&mut writer, account, -moved, code)?;
+        }
+    }
+
+    // Balance assertions apply at the start of their day
+    let asserted_at = date(closed_at + 86_400_000);
+    writeln!(writer)?;
+    for (currency, (last_available, last_held)) in balances {
+        let code = currency.as_ref().map_or(NO_CURRENCY, Currency::as_str);
+        for (account, amount) in [(&available, last_available), (&held, last_held)] {
+            writeln!(
+                writer,
+                "{asserted_at} balance {account} {} {code}",
+                signed_fixed_point_4_decimal_to_float_str(amount)
+            )?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_beancount_posting(
+    writer: &mut impl Write,
+    account: &str,
+    amount: Balance,
+    currency: &str,
+) -> Result<()> {
+    writeln!(
+        writer,
+        "  {account}  {} {currency}",
+        signed_fixed_point_4_decimal_to_float_str(amount)
+    )?;
+    Ok(())
+}
+
+fn write_camt053_balance(
+    writer: &mut impl Write,
+    code: &str,
+    amount: Balance,
+    currency: &str,
+    at: &str,
+) -> Result<()> {
+    writeln!(writer, "      <Bal>")?;
+    writeln!(writer, "        <Tp>")?;
+    writeln!(writer, "          <CdOrPrtry>")?;
+    writeln!(writer, "            <Cd>{code}</Cd>")?;
+    writeln!(writer, "          </CdOrPrtry>")?;
+    writeln!(writer, "        </Tp>")?;
+    writeln!(
+        writer,
+        r#"        <Amt Ccy="{currency}">{}</Amt>"#,
+        fixed_point_4_decimal_to_float_str(amount.unsigned_abs())
+    )?;
+    writeln!(
+        writer,
+        "        <CdtDbtInd>{}</CdtDbtInd>",
+        credit_or_debit(amount)
+    )?;
+    writeln!(writer, "        <Dt>")?;
+    writeln!(writer, "          <DtTm>{at}</DtTm>")?;
+    writeln!(writer, "        </Dt>")?;
+    writeln!(writer, "      </Bal>")?;
+    Ok(())
+}
+
+/// camt.053 amounts are unsigned, with negative ones marked as debits
+fn credit_or_debit(amount: Balance) -> &'static str {
+    if amount < 0 {
+        "DBIT"
+    } else {
+        "CRDT"
+    }
+}
+
+/// A timestamp in milliseconds since the Unix epoch as an ISO 8601 UTC date and time, e.g.
+/// `1970-01-01T00:00:01Z`
+fn date_time(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    let (days, seconds) = ((seconds / 86_400) as i64, seconds % 86_400);
+    // Days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// A timestamp in milliseconds since the Unix epoch as an ISO 8601 UTC date, e.g. `1970-01-01`
+fn date(timestamp: u64) -> String {
+    let mut date = date_time(timestamp);
+    date.truncate(10);
+    date
+}
+
+/// A type as written in the `type` column of a csv
+fn type_name(transaction_type: RawTransactionType) -> &'static str {
+    match transaction_type {
+        RawTransactionType::Deposit => "deposit",
+        RawTransactionType::Withdrawal => "withdrawal",
+        RawTransactionType::Dispute => "dispute",
+        RawTransactionType::Resolve => "resolve",
+        RawTransactionType::Chargeback => "chargeback",
+        RawTransactionType::Unlock => "unlock",
+        RawTransactionType::Reversal => "reversal",
+        RawTransactionType::Hold => "hold",
+        RawTransactionType::Release => "release",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, EngineConfig};
+    use crate::statement::{
+        statement, write_statement_beancount, write_statement_camt053, StatementFormat,
+    };
+    use crate::transaction::Transaction;
+    use std::ops::Not;
+
+    #[test]
+    fn test_statement() {
+        let csv = "type,client,tx,amount,timestamp\n\
+            deposit,1,1,10,1000\n\
+            deposit,2,2,5,1500\n\
+            deposit,1,2,3,2000\n\
+            withdrawal,1,3,4,\n\
+            dispute,1,1,,3000\n\
+            withdrawal,1,4,1,4000\n\
+            resolve,1,1,,5000\n";
+        let mut output = Vec::new();
+        statement(
+            EngineConfig::default(),
+            csv.as_bytes(),
+            1,
+            StatementFormat::Csv,
+            &mut output,
+        )
+        .unwrap();
+
+        // The duplicate tx id and the withdrawal of funds held by the dispute were rejected
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,type,amount,timestamp,available,held,total\n\
+            1,deposit,10.0000,1000,10.0000,0.0000,10.0000\n\
+            3,withdrawal,4.0000,,6.0000,0.0000,6.0000\n\
+            1,dispute,,3000,-4.0000,10.0000,6.0000\n\
+            1,resolve,,5000,6.0000,0.0000,6.0000\n"
+        );
+    }
+
+    #[test]
+    fn test_statement_camt053() {
+        let mut engine = Engine::with_config(EngineConfig {
+            keep_history: true,
+            ..EngineConfig::default()
+        });
+        for (transaction, timestamp) in [
+            (
+                Transaction::Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: 100_000,
+                    currency: None,
+                },
+                Some(1_700_000_000_000),
+            ),
+            (
+                Transaction::Withdrawal {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: 25_000,
+                    currency: None,
+                },
+                None,
+            ),
+            (
+                Transaction::Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Some(1_700_000_060_000),
+            ),
+            (
+                Transaction::Chargeback {
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                },
+                Some(1_700_086_400_000),
+            ),
+        ] {
+            engine
+                .process_transaction_at(transaction, timestamp)
+                .unwrap();
+        }
+        let mut output = Vec::new();
+        write_statement_camt053(
+            1,
+            &engine.client_statement(1),
+            1_700_100_000_000,
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("<CreDtTm>2023-11-16T02:00:00Z</CreDtTm>"));
+        // The chargeback of the 10 deposited after 2.5 were withdrawn leaves a debit balance
+        let balances: Vec<_> = output.split("<Bal>").skip(1).collect();
+        assert_eq!(balances.len(), 3);
+        for (balance, (code, amount, indicator, at)) in balances.iter().zip([
+            ("OPBD", "0.0000", "CRDT", "2023-11-14T22:13:20Z"),
+            ("CLBD", "2.5000", "DBIT", "2023-11-16T02:00:00Z"),
+            ("CLAV", "2.5000", "DBIT", "2023-11-16T02:00:00Z"),
+        ]) {
+            assert!(balance.contains(&format!("<Cd>{code}</Cd>")));
+            assert!(balance.contains(&format!(r#"<Amt Ccy="XXX">{amount}</Amt>"#)));
+            assert!(balance.contains(&format!("<CdtDbtInd>{indicator}</CdtDbtInd>")));
+            assert!(balance.contains(&format!("<DtTm>{at}</DtTm>")));
+        }
+
+        // The dispute didn't move the booked balance, so it has no entry
+        let entries: Vec<_> = output.split("<Ntry>").skip(1).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].contains(r#"<Amt Ccy="XXX">10.0000</Amt>"#));
+        assert!(entries[0].contains("<DtTm>2023-11-14T22:13:20Z</DtTm>"));
+        assert!(entries[1].contains("<CdtDbtInd>DBIT</CdtDbtInd>"));
+        assert!(entries[1].contains("<BookgDt>").not());
+        assert!(entries[2].contains("<RvslInd>true</RvslInd>"));
+        assert!(entries[2].contains("<Cd>chargeback</Cd>"));
+    }
+
+    #[test]
+    fn test_statement_beancount() {
+        let csv = "type,client,tx,amount,timestamp,currency\n\
+            deposit,1,1,10,1700000000000,\n\
+            deposit,1,2,3,,eur\n\
+            withdrawal,1,3,4,1700086400000,\n\
+            dispute,1,1,,1700090000000,\n\
+            chargeback,1,1,,1700172800000,\n";
+        let mut output = Vec::new();
+        statement(
+            EngineConfig::default(),
+            csv.as_bytes(),
+            1,
+            StatementFormat::Beancount,
+            &mut output,
+        )
+        .unwrap();
+
+        // The euro deposit has no timestamp, so it's dated like the deposit before it
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "2023-11-14 open Assets:Client1:Available\n\
+            2023-11-14 open Assets:Client1:Held\n\
+            2023-11-14 open Equity:Client1:Transfers\n\
+            2023-11-14 open Expenses:Client1:ChargebackLoss\n\
+            \n\
+            2023-11-14 * \"deposit of tx 1\"\n  \
+            Assets:Client1:Available  10.0000 XXX\n  \
+            Equity:Client1:Transfers  -10.0000 XXX\n\
+            \n\
+            2023-11-14 * \"deposit of tx 2\"\n  \
+            Assets:Client1:Available  3.0000 EUR\n  \
+            Equity:Client1:Transfers  -3.0000 EUR\n\
+            \n\
+            2023-11-15 * \"withdrawal of tx 3\"\n  \
+            Assets:Client1:Available  -4.0000 XXX\n  \
+            Equity:Client1:Transfers  4.0000 XXX\n\
+            \n\
+            2023-11-15 * \"dispute of tx 1\"\n  \
+            Assets:Client1:Available  -10.0000 XXX\n  \
+            Assets:Client1:Held  10.0000 XXX\n\
+            \n\
+            2023-11-16 * \"chargeback of tx 1\"\n  \
+            Assets:Client1:Held  -10.0000 XXX\n  \
+            Expenses:Client1:ChargebackLoss  10.0000 XXX\n\
+            \n\
+            2023-11-17 balance Assets:Client1:Available -4.0000 XXX\n\
+            2023-11-17 balance Assets:Client1:Held 0.0000 XXX\n\
+            2023-11-17 balance Assets:Client1:Available 3.0000 EUR\n\
+            2023-11-17 balance Assets:Client1:Held 0.0000 EUR\n"
+        );
+
+        let mut output = Vec::new();
+        write_statement_beancount(2, &[], &mut output).unwrap();
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::engine::{Balance, Engine, HeldBalance};
+use crate::input::ProcessedRows;
+use crate::transaction::{Currency, RawTransactionType};
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A summary of a run, to sanity check the accounts written against the rows read
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub processed: ProcessedRows,
+    /// Number of clients whose account is locked
+    pub locked_accounts: u64,
+    /// Sums of every account's balances, by currency
+    #[serde(with = "balances_by_currency")]
+    pub balances: BTreeMap<Option<Currency>, BalanceSums>,
+}
+
+/// Sums of the balances of several accounts in a currency, saturating at the bounds of `Balance`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BalanceSums {
+    pub available: Balance,
+    pub held: HeldBalance,
+    pub total: Balance,
+}
+
+impl Stats {
+    /// Sums up the accounts of the engine the rows were processed into
+    pub fn new(processed: ProcessedRows, engine: &Engine) -> Self {
+        let mut locked = BTreeSet::new();
+        let mut balances = BTreeMap::<_, BalanceSums>::new();
+        for account in engine.accounts() {
+            if account.locked {
+                locked.insert(account.client_id);
+            }
+            let sums = balances.entry(account.currency).or_default();
+            sums.available = sums.available.saturating_add(account.available_amount);
+            sums.held = sums.held.saturating_add(account.held_amount);
+            sums.total = sums.total.saturating_add(account.total_amount);
+        }
+
+        Self {
+            processed,
+            locked_accounts: locked.len() as u64,
+            balances,
+        }
+    }
+}
+
+/// Balances by currency serialize as a list of `[currency, sums]` pairs, since formats like JSON
+/// only have string keys, which the balances without a currency don't have
+mod balances_by_currency {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        balances: &BTreeMap<Option<Currency>, BalanceSums>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(balances.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Option<Currency>, BalanceSums>, D::Error> {
+        Vec::<(Option<Currency>, BalanceSums)>::deserialize(deserializer)
+            .map(|balances| balances.into_iter().collect())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let processed = &self.processed;
+        write!(
+            f,
+            "Rows: {}, applied: {}, rejected: {}, invalid: {}",
+            processed.applied + processed.rejected + processed.invalid,
+            processed.applied,
+            processed.rejected,
+            processed.invalid
+        )?;
+        for (transaction_type, counts) in processed.by_type.iter() {
+            let transaction_type = match transaction_type {
+                RawTransactionType::Deposit => "deposit",
+                RawTransactionType::Withdrawal => "withdrawal",
+                RawTransactionType::Dispute => "dispute",
+                RawTransactionType::Resolve => "resolve",
+                RawTransactionType::Chargeback => "chargeback",
+                RawTransactionType::Unlock => "unlock",
+                RawTransactionType::Reversal => "reversal",
+                RawTransactionType::Hold => "hold",
+                RawTransactionType::Release => "release",
+            };
+            write!(
+                f,
+                "\n  {transaction_type}: applied: {}, rejected: {}",
+                counts.applied, counts.rejected
+            )?;
+        }
+        write!(f, "\nLocked accounts: {}", self.locked_accounts)?;
+        for (currency, sums) in self.balances.iter() {
+            let currency = currency.map(|currency| format!(" in {}", currency.as_str()));
+            write!(
+                f,
+                "\nBalances{}: available: {}, held: {}, total: {}",
+                currency.unwrap_or_default(),
+                signed_fixed_point_4_decimal_to_float_str(sums.available),
+                fixed_point_4_decimal_to_float_str(sums.held),
+                signed_fixed_point_4_decimal_to_float_str(sums.total)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::{self, CsvOptions, InvalidRows};
+    use crate::stats::Stats;
+
+    #[test]
+    fn test_stats() {
+        let csv = "type,client,tx,amount,currency\n\
+            deposit,1,1,10,\n\
+            deposit,1,2,5,\n\
+            withdrawal,1,3,20,\n\
+            dispute,1,2,,\n\
+            deposit,2,4,3,\n\
+            dispute,2,4,,\n\
+            chargeback,2,4,,\n\
+            deposit,3,5,7.5,EUR\n\
+            deposit,3,six,1,\n";
+        let mut engine = Engine::new();
+        let processed = input::process_csv_readers(
+            &mut engine,
+            vec![csv.as_bytes()],
+            &CsvOptions::default(),
+            InvalidRows::Skip,
+            None,
+        )
+        .unwrap();
+
+        let stats = Stats::new(processed, &engine);
+        assert_eq!(stats.locked_accounts, 1);
+        assert_eq!(
+            stats.to_string(),
+            "Rows: 9, applied: 7, rejected: 1, invalid: 1\n  \
+                deposit: applied: 4, rejected: 0\n  \
+                withdrawal: applied: 0, rejected: 1\n  \
+                dispute: applied: 2, rejected: 0\n  \
+                chargeback: applied: 1, rejected: 0\n\
+            Locked accounts: 1\n\
+            Balances: available: 10.0000, held: 5.0000, total: 15.0000\n\
+            Balances in EUR: available: 7.5000, held: 0.0000, total: 7.5000"
+        );
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains(r#"[null,{"available":100000,"held":50000,"total":150000}]"#));
+        assert_eq!(serde_json::from_str::<Stats>(&json).unwrap(), stats);
+    }
+}
@@ -0,0 +1,607 @@
+use crate::engine::DisputableKind;
+use crate::transaction::Currency;
+#[cfg(feature = "storage")]
+use memmap2::Mmap;
+#[cfg(feature = "storage")]
+use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "storage")]
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(feature = "storage")]
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::iter::Peekable;
+#[cfg(feature = "storage")]
+use std::ops::Not;
+#[cfg(feature = "storage")]
+use std::path::Path;
+
+/// A settled deposit or withdrawal, neither in dispute nor held, moved out of the engine's memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoredTransaction {
+    pub client_id: u16,
+    pub kind: DisputableKind,
+    pub amount: u64,
+    pub currency: Option<Currency>,
+    pub timestamp: Option<u64>,
+}
+
+/// Where an engine moves the deposits and withdrawals it no longer keeps in memory, by tx id, so
+/// that they can still be disputed. See `Engine::set_transaction_store`.
+pub trait TransactionStore: Send {
+    fn insert(&mut self, tx_id: u32, transaction: StoredTransaction) -> io::Result<()>;
+
+    fn get(&self, tx_id: u32) -> io::Result<Option<StoredTransaction>>;
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<Option<StoredTransaction>>;
+
+    /// Every transaction in the store, in tx id order, for snapshots
+    fn transactions(&self) -> StoredTransactions<'_>;
+}
+
+/// The transactions of a store, see `TransactionStore::transactions`
+pub type StoredTransactions<'a> =
+    Box<dyn Iterator<Item = io::Result<(u32, StoredTransaction)>> + 'a>;
+
+/// Keeps the transactions in a map, which saves no memory but is handy for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    transactions: HashMap<u32, StoredTransaction>,
+}
+
+impl TransactionStore for MemoryStore {
+    fn insert(&mut self, tx_id: u32, transaction: StoredTransaction) -> io::Result<()> {
+        self.transactions.insert(tx_id, transaction);
+        Ok(())
+    }
+
+    fn get(&self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        Ok(self.transactions.get(&tx_id).copied())
+    }
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        Ok(self.transactions.remove(&tx_id))
+    }
+
+    fn transactions(&self) -> StoredTransactions<'_> {
+        let mut tx_ids: Vec<_> = self.transactions.keys().copied().collect();
+        tx_ids.sort_unstable();
+        Box::new(
+            tx_ids
+                .into_iter()
+                .map(|tx_id| Ok((tx_id, self.transactions[&tx_id]))),
+        )
+    }
+}
+
+/// Size of a transaction's record in a `FileStore`
+#[cfg(feature = "storage")]
+const RECORD_SIZE: u64 = 32;
+
+/// Keeps the transactions in a file of fixed size records, the one of a tx id being at offset
+/// `tx_id * 32`, so that no index has to be kept in memory. The file is sparse on filesystems that
+/// support it, and only takes up disk space for the records written to it.
+///
+/// A record is laid out as follows, all integers being little endian:
+///
+/// | Offset | Size | Field                                               |
+/// |--------|------|-----------------------------------------------------|
+/// | 0      | 1    | 1 if the record holds a transaction, 0 otherwise    |
+/// | 1      | 1    | kind: 0 for a deposit, 1 for a withdrawal           |
+/// | 2      | 2    | client id                                           |
+/// | 4      | 8    | amount                                              |
+/// | 12     | 8    | currency code, padded with zeros, or all zeros      |
+/// | 20     | 1    | 1 if the transaction has a timestamp, 0 otherwise   |
+/// | 21     | 8    | timestamp                                           |
+#[cfg(feature = "storage")]
+pub struct FileStore {
+    file: File,
+}
+
+#[cfg(feature = "storage")]
+impl FileStore {
+    /// Creates the store's file at `path`, truncating it if it exists: its records are only
+    /// meaningful to the engine that wrote them
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+
+    fn read_record(&self, tx_id: u32) -> io::Result<[u8; RECORD_SIZE as usize]> {
+        let mut record = [0; RECORD_SIZE as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(u64::from(tx_id) * RECORD_SIZE))?;
+        // Records past the end of the file were never written
+        let mut read = 0;
+        while read < record.len() {
+            match file.read(&mut record[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(record)
+    }
+
+    fn write_record(&mut self, tx_id: u32, record: &[u8; RECORD_SIZE as usize]) -> io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start(u64::from(tx_id) * RECORD_SIZE))?;
+        self.file.write_all(record)
+    }
+}
+
+#[cfg(feature = "storage")]
+impl TransactionStore for FileStore {
+    fn insert(&mut self, tx_id: u32, transaction: StoredTransaction) -> io::Result<()> {
+        let mut record = [0; RECORD_SIZE as usize];
+        record[0] = 1;
+        record[1] = match transaction.kind {
+            DisputableKind::Deposit => 0,
+            DisputableKind::Withdrawal => 1,
+        };
+        record[2..4].copy_from_slice(&transaction.client_id.to_le_bytes());
+        record[4..12].copy_from_slice(&transaction.amount.to_le_bytes());
+        if let Some(currency) = transaction.currency {
+            record[12..20].copy_from_slice(&currency.to_bytes());
+        }
+        if let Some(timestamp) = transaction.timestamp {
+            record[20] = 1;
+            record[21..29].copy_from_slice(&timestamp.to_le_bytes());
+        }
+        self.write_record(tx_id, &record)
+    }
+
+    fn get(&self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        decode_file_record(tx_id, &self.read_record(tx_id)?)
+    }
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        let transaction = self.get(tx_id)?;
+        if transaction.is_some() {
+            self.write_record(tx_id, &[0; RECORD_SIZE as usize])?;
+        }
+        Ok(transaction)
+    }
+
+    fn transactions(&self) -> StoredTransactions<'_> {
+        let file = match self.file.try_clone().and_then(|mut file| {
+            file.seek(SeekFrom::Start(0))?;
+            Ok(file)
+        }) {
+            Ok(file) => file,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let mut reader = BufReader::new(file);
+        let mut tx_id = 0u32;
+        Box::new(
+            std::iter::from_fn(move || {
+                let mut record = [0; RECORD_SIZE as usize];
+                match reader.read_exact(&mut record) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+                let transaction = decode_file_record(tx_id, &record)
+                    .map(|transaction| transaction.map(|transaction| (tx_id, transaction)));
+                tx_id = tx_id.wrapping_add(1);
+                Some(transaction)
+            })
+            .filter_map(Result::transpose),
+        )
+    }
+}
+
+#[cfg(feature = "storage")]
+fn decode_file_record(
+    tx_id: u32,
+    record: &[u8; RECORD_SIZE as usize],
+) -> io::Result<Option<StoredTransaction>> {
+    if record[0] == 0 {
+        return Ok(None);
+    }
+    let u64_at =
+        |offset: usize| u64::from_le_bytes(record[offset..offset + 8].try_into().expect("8 bytes"));
+    Ok(Some(StoredTransaction {
+        client_id: u16::from_le_bytes([record[2], record[3]]),
+        kind: decode_kind(tx_id, record[1])?,
+        amount: u64_at(4),
+        currency: Currency::from_bytes(record[12..20].try_into().expect("8 bytes")),
+        timestamp: (record[20] == 1).then(|| u64_at(21)),
+    }))
+}
+
+fn decode_kind(tx_id: u32, kind: u8) -> io::Result<DisputableKind> {
+    match kind {
+        0 => Ok(DisputableKind::Deposit),
+        1 => Ok(DisputableKind::Withdrawal),
+        kind => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid transaction kind {kind} in the record of tx {tx_id}"),
+        )),
+    }
+}
+
+/// Size of a transaction's record in a `MappedStore`
+pub const MAPPED_RECORD_SIZE: usize = 32;
+
+/// Reads the transactions of a memory-mapped file in place, such as those of a snapshot in the
+/// `SnapshotFormat::Mapped` format, rather than loading them, so that a store of any size is
+/// ready as soon as it's mapped. The file is never written to: the transactions inserted and
+/// removed since it was mapped are kept in memory.
+///
+/// The mapped transactions are an array of fixed size records sorted by tx id, found by binary
+/// search, each laid out as follows, all integers being little endian:
+///
+/// | Offset | Size | Field                                               |
+/// |--------|------|-----------------------------------------------------|
+/// | 0      | 4    | tx id                                               |
+/// | 4      | 1    | kind: 0 for a deposit, 1 for a withdrawal           |
+/// | 5      | 1    | 1 if the transaction has a timestamp, 0 otherwise   |
+/// | 6      | 2    | client id                                           |
+/// | 8      | 8    | amount                                              |
+/// | 16     | 8    | currency code, padded with zeros, or all zeros      |
+/// | 24     | 8    | timestamp                                           |
+#[cfg(feature = "storage")]
+pub struct MappedStore {
+    map: Mmap,
+    /// Offset of the first record in the map
+    offset: usize,
+    len: usize,
+    /// Transactions inserted since the file was mapped, which take precedence over its own
+    inserted: HashMap<u32, StoredTransaction>,
+    /// Tx ids of the mapped transactions removed since
+    removed: HashSet<u32>,
+}
+
+#[cfg(feature = "storage")]
+impl MappedStore {
+    /// Reads the records from `offset` to the end of `map`, which the caller has to have checked
+    /// are sorted by tx id
+    pub fn new(map: Mmap, offset: usize) -> io::Result<Self> {
+        let Some(records) = map
+            .len()
+            .checked_sub(offset)
+            .filter(|records| records % MAPPED_RECORD_SIZE == 0)
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Mapped transactions aren't a whole number of records",
+            ));
+        };
+        Ok(Self {
+            map,
+            offset,
+            len: records / MAPPED_RECORD_SIZE,
+            inserted: HashMap::new(),
+            removed: HashSet::new(),
+        })
+    }
+
+    fn record(&self, index: usize) -> &[u8; MAPPED_RECORD_SIZE] {
+        let start = self.offset + index * MAPPED_RECORD_SIZE;
+        self.map[start..start + MAPPED_RECORD_SIZE]
+            .try_into()
+            .expect("Record size")
+    }
+
+    fn record_tx_id(&self, index: usize) -> u32 {
+        u32::from_le_bytes(self.record(index)[0..4].try_into().expect("4 bytes"))
+    }
+
+    /// The mapped transaction of a tx id, whether it was removed since or not
+    fn mapped(&self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.record_tx_id(middle).cmp(&tx_id) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => {
+                    return read_mapped_record(self.record(middle))
+                        .map(|(_, transaction)| Some(transaction))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "storage")]
+impl TransactionStore for MappedStore {
+    fn insert(&mut self, tx_id: u32, transaction: StoredTransaction) -> io::Result<()> {
+        self.inserted.insert(tx_id, transaction);
+        Ok(())
+    }
+
+    fn get(&self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        if let Some(transaction) = self.inserted.get(&tx_id) {
+            return Ok(Some(*transaction));
+        }
+        match self.removed.contains(&tx_id) {
+            true => Ok(None),
+            false => self.mapped(tx_id),
+        }
+    }
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        if let Some(transaction) = self.inserted.remove(&tx_id) {
+            return Ok(Some(transaction));
+        }
+        let transaction = self.get(tx_id)?;
+        if transaction.is_some() {
+            self.removed.insert(tx_id);
+        }
+        Ok(transaction)
+    }
+
+    fn transactions(&self) -> StoredTransactions<'_> {
+        let mapped = (0..self.len)
+            .map(|index| read_mapped_record(self.record(index)))
+            .filter(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |(tx_id, _)| self.removed.contains(tx_id).not())
+            });
+        let mut inserted: Vec<_> = self
+            .inserted
+            .iter()
+            .map(|(tx_id, transaction)| (*tx_id, *transaction))
+            .collect();
+        inserted.sort_unstable_by_key(|(tx_id, _)| *tx_id);
+        merge_by_tx_id(Box::new(mapped), inserted)
+    }
+}
+
+/// Writes a transaction's record in the layout of a `MappedStore`, for files to be mapped
+pub fn write_mapped_record(
+    mut writer: impl Write,
+    tx_id: u32,
+    transaction: &StoredTransaction,
+) -> io::Result<()> {
+    let mut record = [0; MAPPED_RECORD_SIZE];
+    record[0..4].copy_from_slice(&tx_id.to_le_bytes());
+    record[4] = match transaction.kind {
+        DisputableKind::Deposit => 0,
+        DisputableKind::Withdrawal => 1,
+    };
+    record[6..8].copy_from_slice(&transaction.client_id.to_le_bytes());
+    record[8..16].copy_from_slice(&transaction.amount.to_le_bytes());
+    if let Some(currency) = transaction.currency {
+        record[16..24].copy_from_slice(&currency.to_bytes());
+    }
+    if let Some(timestamp) = transaction.timestamp {
+        record[5] = 1;
+        record[24..32].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    writer.write_all(&record)
+}
+
+/// Reads a record in the layout of a `MappedStore`, from a mapped file or any other copy of one
+pub fn read_mapped_record(
+    record: &[u8; MAPPED_RECORD_SIZE],
+) -> io::Result<(u32, StoredTransaction)> {
+    let tx_id = u32::from_le_bytes(record[0..4].try_into().expect("4 bytes"));
+    let u64_at =
+        |offset: usize| u64::from_le_bytes(record[offset..offset + 8].try_into().expect("8 bytes"));
+    let transaction = StoredTransaction {
+        client_id: u16::from_le_bytes([record[6], record[7]]),
+        kind: decode_kind(tx_id, record[4])?,
+        amount: u64_at(8),
+        currency: Currency::from_bytes(record[16..24].try_into().expect("8 bytes")),
+        timestamp: (record[5] == 1).then(|| u64_at(24)),
+    };
+    Ok((tx_id, transaction))
+}
+
+/// Merges transactions in tx id order with others sorted by tx id, none having the same tx id
+pub(crate) fn merge_by_tx_id<'a>(
+    stored: StoredTransactions<'a>,
+    others: Vec<(u32, StoredTransaction)>,
+) -> StoredTransactions<'a> {
+    Box::new(MergeByTxId {
+        stored: stored.peekable(),
+        others: others.into_iter().peekable(),
+    })
+}
+
+struct MergeByTxId<'a> {
+    stored: Peekable<StoredTransactions<'a>>,
+    others: Peekable<std::vec::IntoIter<(u32, StoredTransaction)>>,
+}
+
+impl Iterator for MergeByTxId<'_> {
+    type Item = io::Result<(u32, StoredTransaction)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let others_first = match (self.stored.peek(), self.others.peek()) {
+            (Some(Ok((stored, _))), Some((other, _))) => other < stored,
+            (Some(_), _) => false,
+            (None, _) => true,
+        };
+        match others_first {
+            true => self.others.next().map(Ok),
+            false => self.stored.next(),
+        }
+    }
+}
+
+/// Where an engine moves the accounts it no longer keeps in memory, by client id, see
+/// `Engine::set_account_store`. Accounts are stored encoded, as the engine reads them back.
+pub trait AccountStore: Send {
+    fn insert(&mut self, client_id: u16, account: Vec<u8>) -> io::Result<()>;
+
+    fn get(&self, client_id: u16) -> io::Result<Option<Vec<u8>>>;
+
+    fn remove(&mut self, client_id: u16) -> io::Result<Option<Vec<u8>>>;
+
+    /// Every account in the store, in client id order, for snapshots and reports
+    fn accounts(&self) -> StoredAccounts<'_>;
+}
+
+/// The accounts of a store, see `AccountStore::accounts`
+pub type StoredAccounts<'a> = Box<dyn Iterator<Item = io::Result<(u16, Vec<u8>)>> + 'a>;
+
+/// Keeps the accounts in a map, which saves no memory but is handy for tests
+#[derive(Default)]
+pub struct MemoryAccountStore {
+    accounts: BTreeMap<u16, Vec<u8>>,
+}
+
+impl AccountStore for MemoryAccountStore {
+    fn insert(&mut self, client_id: u16, account: Vec<u8>) -> io::Result<()> {
+        self.accounts.insert(client_id, account);
+        Ok(())
+    }
+
+    fn get(&self, client_id: u16) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.accounts.get(&client_id).cloned())
+    }
+
+    fn remove(&mut self, client_id: u16) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.accounts.remove(&client_id))
+    }
+
+    fn accounts(&self) -> StoredAccounts<'_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(client_id, account)| Ok((*client_id, account.clone()))),
+        )
+    }
+}
+
+/// Keeps the accounts in a sled database, keyed by big endian client id so that they're listed in
+/// client id order
+#[cfg(feature = "account-store")]
+pub struct SledAccountStore {
+    db: sled::Db,
+}
+
+#[cfg(feature = "account-store")]
+impl SledAccountStore {
+    /// Opens the database at `path`, creating it if needed, and clears it: its accounts are only
+    /// meaningful to the engine that wrote them
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let db = sled::open(path).map_err(io::Error::other)?;
+        db.clear().map_err(io::Error::other)?;
+        Ok(Self { db })
+    }
+}
+
+#[cfg(feature = "account-store")]
+impl AccountStore for SledAccountStore {
+    fn insert(&mut self, client_id: u16, account: Vec<u8>) -> io::Result<()> {
+        self.db
+            .insert(client_id.to_be_bytes(), account)
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn get(&self, client_id: u16) -> io::Result<Option<Vec<u8>>> {
+        let account = self
+            .db
+            .get(client_id.to_be_bytes())
+            .map_err(io::Error::other)?;
+        Ok(account.map(|account| account.to_vec()))
+    }
+
+    fn remove(&mut self, client_id: u16) -> io::Result<Option<Vec<u8>>> {
+        let account = self
+            .db
+            .remove(client_id.to_be_bytes())
+            .map_err(io::Error::other)?;
+        Ok(account.map(|account| account.to_vec()))
+    }
+
+    fn accounts(&self) -> StoredAccounts<'_> {
+        Box::new(self.db.iter().map(|entry| {
+            let (key, account) = entry.map_err(io::Error::other)?;
+            let client_id = <[u8; 2]>::try_from(key.as_ref())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid client id key"))?;
+            Ok((u16::from_be_bytes(client_id), account.to_vec()))
+        }))
+    }
+}
+
+#[cfg(all(test, feature = "storage"))]
+mod tests {
+    use crate::engine::DisputableKind;
+    use crate::store::{FileStore, StoredTransaction, TransactionStore};
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-store-test-{}.bin",
+            std::process::id()
+        ));
+        let mut store = FileStore::create(&path).unwrap();
+
+        let deposit = StoredTransaction {
+            client_id: 7,
+            kind: DisputableKind::Deposit,
+            amount: 12_3456,
+            currency: Some("EUR".parse().unwrap()),
+            timestamp: Some(1_700_000_000_000),
+        };
+        let withdrawal = StoredTransaction {
+            client_id: 65535,
+            kind: DisputableKind::Withdrawal,
+            amount: u64::MAX,
+            currency: None,
+            timestamp: None,
+        };
+        store.insert(3, deposit).unwrap();
+        store.insert(100_000, withdrawal).unwrap();
+
+        assert_eq!(store.get(3).unwrap(), Some(deposit));
+        assert_eq!(store.get(100_000).unwrap(), Some(withdrawal));
+        assert_eq!(store.get(2).unwrap(), None);
+        assert_eq!(store.get(1_000_000).unwrap(), None);
+        assert_eq!(
+            store
+                .transactions()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap(),
+            [(3, deposit), (100_000, withdrawal)]
+        );
+
+        assert_eq!(store.remove(3).unwrap(), Some(deposit));
+        assert_eq!(store.get(3).unwrap(), None);
+        assert_eq!(store.remove(3).unwrap(), None);
+
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "account-store")]
+    #[test]
+    fn test_sled_account_store() {
+        use crate::store::{AccountStore, SledAccountStore};
+
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-account-store-test-{}",
+            std::process::id()
+        ));
+        let mut store = SledAccountStore::create(&path).unwrap();
+        store.insert(300, vec![3]).unwrap();
+        store.insert(2, vec![2, 2]).unwrap();
+        assert_eq!(store.get(2).unwrap(), Some(vec![2, 2]));
+        assert_eq!(store.get(7).unwrap(), None);
+        let accounts: Vec<_> = store.accounts().collect::<io::Result<_>>().unwrap();
+        assert_eq!(accounts, [(2, vec![2, 2]), (300, vec![3])]);
+        assert_eq!(store.remove(2).unwrap(), Some(vec![2, 2]));
+        drop(store);
+
+        // Accounts left by an earlier run are cleared
+        let store = SledAccountStore::create(&path).unwrap();
+        assert_eq!(store.accounts().count(), 0);
+        drop(store);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
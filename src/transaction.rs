@@ -1,8 +1,11 @@
-use crate::util::float_str_to_fixed_point_4_decimal;
-use anyhow::{anyhow, ensure, Result};
-use serde::{Deserialize, Deserializer};
+pub use crate::ledger::Currency;
+use crate::util::{float_str_to_fixed_point_4_decimal_rounded, RoundingMode};
+use anyhow::{anyhow, bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::str::FromStr;
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum RawTransactionType {
     Deposit,
@@ -10,6 +13,45 @@ pub enum RawTransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Reversal,
+    Hold,
+    Release,
+}
+
+impl RawTransactionType {
+    /// Every transaction type, in declaration order
+    pub const ALL: [RawTransactionType; 9] = [
+        RawTransactionType::Deposit,
+        RawTransactionType::Withdrawal,
+        RawTransactionType::Dispute,
+        RawTransactionType::Resolve,
+        RawTransactionType::Chargeback,
+        RawTransactionType::Unlock,
+        RawTransactionType::Reversal,
+        RawTransactionType::Hold,
+        RawTransactionType::Release,
+    ];
+}
+
+impl FromStr for RawTransactionType {
+    type Err = anyhow::Error;
+
+    /// Reads a type as written in the `type` column of a csv, e.g. `deposit`
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value.trim() {
+            "deposit" => RawTransactionType::Deposit,
+            "withdrawal" => RawTransactionType::Withdrawal,
+            "dispute" => RawTransactionType::Dispute,
+            "resolve" => RawTransactionType::Resolve,
+            "chargeback" => RawTransactionType::Chargeback,
+            "unlock" => RawTransactionType::Unlock,
+            "reversal" => RawTransactionType::Reversal,
+            "hold" => RawTransactionType::Hold,
+            "release" => RawTransactionType::Release,
+            _ => bail!("Unknown transaction type: {value}"),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,105 +60,327 @@ pub struct RawTransaction {
     pub transaction_type: RawTransactionType,
     pub client: u16,
     pub tx: u32,
-    #[serde(deserialize_with = "deserialize_fixed_point")]
-    pub amount: Option<u64>,
+    /// The amount as written, parsed once the rounding mode is known
+    #[serde(default)]
+    pub amount: Option<String>,
+    /// Milliseconds since the Unix epoch, for csvs with a `timestamp` column
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// For csvs with a `currency` column
+    #[serde(default)]
+    pub currency: Option<Currency>,
 }
 
-fn deserialize_fixed_point<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let f_string: Option<String> = Deserialize::deserialize(deserializer)?;
-    f_string
-        .map(|f| {
-            float_str_to_fixed_point_4_decimal(&f).map_err(|e| {
-                serde::de::Error::custom(format!(
-                    "Failed to parse float into fixed point representation: {e}"
-                ))
-            })
-        })
-        .transpose()
+impl Currency {
+    /// The code's bytes, padded with zeros
+    pub(crate) fn to_bytes(self) -> [u8; 8] {
+        self.0
+    }
+
+    /// The currency whose code `Currency::to_bytes` returned, or `None` for all zeros
+    pub(crate) fn from_bytes(bytes: [u8; 8]) -> Option<Self> {
+        (bytes != [0; 8]).then_some(Self(bytes))
+    }
 }
 
-impl TryFrom<RawTransaction> for Transaction {
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        ensure!(
+            (1..=8).contains(&value.len())
+                && value.bytes().all(|byte| byte.is_ascii_alphanumeric()),
+            "Invalid currency code: {value}"
+        );
+        let mut code = [0; 8];
+        code[..value.len()].copy_from_slice(value.to_ascii_uppercase().as_bytes());
+        Ok(Self(code))
+    }
+}
+
+impl TryFrom<String> for Currency {
     type Error = anyhow::Error;
 
-    fn try_from(value: RawTransaction) -> Result<Self> {
-        match value.transaction_type {
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(value: Currency) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl RawTransaction {
+    /// Validates the transaction, parsing its amount with the given rounding mode
+    pub fn into_transaction(self, rounding: RoundingMode) -> Result<Transaction> {
+        RawFields {
+            transaction_type: self.transaction_type,
+            client: self.client,
+            tx: self.tx,
+            amount: self.amount.as_deref(),
+            currency: self.currency,
+        }
+        .into_transaction(rounding)
+    }
+}
+
+/// The fields of a transaction as read, borrowing its amount, so that csvs can be read without
+/// going through `RawTransaction` (see `CsvOptions::fast_parse`)
+pub(crate) struct RawFields<'a> {
+    pub transaction_type: RawTransactionType,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Option<&'a str>,
+    pub currency: Option<Currency>,
+}
+
+impl RawFields<'_> {
+    /// Validates the transaction like `RawTransaction::into_transaction`
+    pub fn into_transaction(self, rounding: RoundingMode) -> Result<Transaction> {
+        let amount = |transaction_type: &str| -> Result<u64> {
+            let amount = self
+                .amount
+                .ok_or_else(|| anyhow!("{transaction_type} found without amount"))?;
+            float_str_to_fixed_point_4_decimal_rounded(amount, rounding)
+                .map_err(|e| anyhow!("Failed to parse float into fixed point representation: {e}"))
+        };
+
+        match self.transaction_type {
             RawTransactionType::Deposit => Ok(Transaction::Deposit {
-                client_id: value.client,
-                tx_id: value.tx,
-                amount: value
-                    .amount
-                    .ok_or(anyhow!("Deposit found without amount"))?,
+                client_id: self.client,
+                tx_id: self.tx,
+                amount: amount("Deposit")?,
+                currency: self.currency,
             }),
             RawTransactionType::Withdrawal => Ok(Transaction::Withdrawal {
-                client_id: value.client,
-                tx_id: value.tx,
-                amount: value
-                    .amount
-                    .ok_or(anyhow!("Withdrawal found without amount"))?,
+                client_id: self.client,
+                tx_id: self.tx,
+                amount: amount("Withdrawal")?,
+                currency: self.currency,
             }),
             RawTransactionType::Dispute => {
-                ensure!(value.amount.is_none(), anyhow!("Dispute found with amount"));
+                ensure!(self.amount.is_none(), anyhow!("Dispute found with amount"));
                 Ok(Transaction::Dispute {
-                    client_id: value.client,
-                    tx_id: value.tx,
+                    client_id: self.client,
+                    tx_id: self.tx,
+                    currency: self.currency,
                 })
             }
             RawTransactionType::Resolve => {
-                ensure!(value.amount.is_none(), anyhow!("Resolve found with amount"));
+                ensure!(self.amount.is_none(), anyhow!("Resolve found with amount"));
                 Ok(Transaction::Resolve {
-                    client_id: value.client,
-                    tx_id: value.tx,
+                    client_id: self.client,
+                    tx_id: self.tx,
+                    currency: self.currency,
                 })
             }
             RawTransactionType::Chargeback => {
                 ensure!(
-                    value.amount.is_none(),
+                    self.amount.is_none(),
                     anyhow!("Chargeback found with amount")
                 );
                 Ok(Transaction::Chargeback {
-                    client_id: value.client,
-                    tx_id: value.tx,
+                    client_id: self.client,
+                    tx_id: self.tx,
+                    currency: self.currency,
+                })
+            }
+            RawTransactionType::Reversal => {
+                ensure!(self.amount.is_none(), anyhow!("Reversal found with amount"));
+                Ok(Transaction::Reversal {
+                    client_id: self.client,
+                    tx_id: self.tx,
+                    currency: self.currency,
+                })
+            }
+            RawTransactionType::Hold => Ok(Transaction::Hold {
+                client_id: self.client,
+                tx_id: self.tx,
+                amount: amount("Hold")?,
+                currency: self.currency,
+            }),
+            RawTransactionType::Release => {
+                ensure!(self.amount.is_none(), anyhow!("Release found with amount"));
+                Ok(Transaction::Release {
+                    client_id: self.client,
+                    tx_id: self.tx,
+                    currency: self.currency,
+                })
+            }
+            RawTransactionType::Unlock => {
+                ensure!(self.amount.is_none(), anyhow!("Unlock found with amount"));
+                ensure!(
+                    self.currency.is_none(),
+                    anyhow!("Unlock found with currency")
+                );
+                Ok(Transaction::Unlock {
+                    client_id: self.client,
+                    tx_id: self.tx,
                 })
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub(crate) enum Transaction {
+impl TryFrom<RawTransaction> for Transaction {
+    type Error = anyhow::Error;
+
+    /// Validates the transaction, truncating amounts with more than 4 decimal places
+    fn try_from(value: RawTransaction) -> Result<Self> {
+        value.into_transaction(RoundingMode::default())
+    }
+}
+
+/// A validated transaction. Amounts are in the engine's fixed point representation (see `util`).
+/// Transactions without a currency are in the default currency of feeds that don't have any, and
+/// disputes, resolves and chargebacks without one apply to the transaction they reference
+/// whatever its currency.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transaction {
     Deposit {
         client_id: u16,
         tx_id: u32,
         amount: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
     },
     Withdrawal {
         client_id: u16,
-        #[allow(dead_code)]
         tx_id: u32,
         amount: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
     },
     Dispute {
         client_id: u16,
         tx_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
     },
     Resolve {
         client_id: u16,
         tx_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
     },
     Chargeback {
         client_id: u16,
         tx_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// Clears the lock a chargeback put on the account, once an operations team reviewed it
+    Unlock { client_id: u16, tx_id: u32 },
+    /// Undoes the withdrawal with the tx id, crediting its amount back
+    Reversal {
+        client_id: u16,
+        tx_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// Moves an amount from the available to the held funds, as a manual risk hold placed by an
+    /// operations team
+    Hold {
+        client_id: u16,
+        tx_id: u32,
+        amount: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// Lifts the hold with the tx id, moving its amount back to the available funds
+    Release {
+        client_id: u16,
+        tx_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
     },
 }
 
+impl Transaction {
+    pub fn client_id(&self) -> u16 {
+        match self {
+            Transaction::Deposit { client_id, .. }
+            | Transaction::Withdrawal { client_id, .. }
+            | Transaction::Dispute { client_id, .. }
+            | Transaction::Resolve { client_id, .. }
+            | Transaction::Chargeback { client_id, .. }
+            | Transaction::Unlock { client_id, .. }
+            | Transaction::Reversal { client_id, .. }
+            | Transaction::Hold { client_id, .. }
+            | Transaction::Release { client_id, .. } => *client_id,
+        }
+    }
+
+    pub fn transaction_type(&self) -> RawTransactionType {
+        match self {
+            Transaction::Deposit { .. } => RawTransactionType::Deposit,
+            Transaction::Withdrawal { .. } => RawTransactionType::Withdrawal,
+            Transaction::Dispute { .. } => RawTransactionType::Dispute,
+            Transaction::Resolve { .. } => RawTransactionType::Resolve,
+            Transaction::Chargeback { .. } => RawTransactionType::Chargeback,
+            Transaction::Unlock { .. } => RawTransactionType::Unlock,
+            Transaction::Reversal { .. } => RawTransactionType::Reversal,
+            Transaction::Hold { .. } => RawTransactionType::Hold,
+            Transaction::Release { .. } => RawTransactionType::Release,
+        }
+    }
+
+    pub fn amount(&self) -> Option<u64> {
+        match self {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Hold { amount, .. } => Some(*amount),
+            Transaction::Dispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. }
+            | Transaction::Unlock { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::Release { .. } => None,
+        }
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Transaction::Deposit { currency, .. }
+            | Transaction::Withdrawal { currency, .. }
+            | Transaction::Dispute { currency, .. }
+            | Transaction::Resolve { currency, .. }
+            | Transaction::Chargeback { currency, .. }
+            | Transaction::Reversal { currency, .. }
+            | Transaction::Hold { currency, .. }
+            | Transaction::Release { currency, .. } => *currency,
+            Transaction::Unlock { .. } => None,
+        }
+    }
+
+    pub fn tx_id(&self) -> u32 {
+        match self {
+            Transaction::Deposit { tx_id, .. }
+            | Transaction::Withdrawal { tx_id, .. }
+            | Transaction::Dispute { tx_id, .. }
+            | Transaction::Resolve { tx_id, .. }
+            | Transaction::Chargeback { tx_id, .. }
+            | Transaction::Unlock { tx_id, .. }
+            | Transaction::Reversal { tx_id, .. }
+            | Transaction::Hold { tx_id, .. }
+            | Transaction::Release { tx_id, .. } => *tx_id,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "csv")]
+    use crate::transaction::Currency;
     use crate::transaction::{RawTransaction, RawTransactionType, Transaction};
+    use crate::util::RoundingMode;
+    #[cfg(feature = "csv")]
     use std::io::BufReader;
 
+    #[cfg(feature = "csv")]
     #[test]
     fn test_transaction_deserialization() {
         let csv = "type, client, tx, amount
@@ -125,7 +389,8 @@ mod tests {
                         dispute, 1, 1,
                         resolve, 1, 1,
                         dispute, 2, 2,
-                        chargeback, 2, 2,";
+                        chargeback, 2, 2,
+                        unlock, 2, 3,";
 
         let mut csv_reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All)
@@ -137,6 +402,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "csv")]
     #[test]
     fn test_transaction_deserialization_white_spaces_are_ignored() {
         let csv = "type,client, tx,amount
@@ -153,6 +419,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_transaction_serde_round_trip() {
+        let transaction = Transaction::Deposit {
+            client_id: 1,
+            tx_id: 2,
+            amount: 15_000,
+            currency: None,
+        };
+
+        let json = serde_json::to_string(&transaction).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"deposit","client_id":1,"tx_id":2,"amount":15000}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Transaction>(&json).unwrap(),
+            transaction
+        );
+
+        let json = r#"{"type":"chargeback","client_id":3,"tx_id":4}"#;
+        assert_eq!(
+            serde_json::from_str::<Transaction>(json).unwrap(),
+            Transaction::Chargeback {
+                client_id: 3,
+                tx_id: 4,
+                currency: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_transaction_type_from_str() {
+        // Types parse from the names they're serialized with
+        for transaction_type in RawTransactionType::ALL {
+            let json = serde_json::to_value(transaction_type).unwrap();
+            assert_eq!(
+                json.as_str()
+                    .unwrap()
+                    .parse::<RawTransactionType>()
+                    .unwrap(),
+                transaction_type
+            );
+        }
+        assert_eq!(
+            " hold ".parse::<RawTransactionType>().unwrap(),
+            RawTransactionType::Hold
+        );
+        assert!("bonus".parse::<RawTransactionType>().is_err());
+    }
+
     #[test]
     fn test_deposit_without_amount() {
         let raw = RawTransaction {
@@ -160,6 +476,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: None,
+            timestamp: None,
+            currency: None,
         };
         let result = Transaction::try_from(raw);
         assert!(result.is_err());
@@ -176,6 +494,8 @@ mod tests {
             client: 1,
             tx: 1,
             amount: None,
+            timestamp: None,
+            currency: None,
         };
         let result = Transaction::try_from(raw);
         assert!(result.is_err());
@@ -184,4 +504,54 @@ mod tests {
             "Withdrawal found without amount"
         );
     }
+
+    #[test]
+    fn test_amount_rounding() {
+        let raw = || RawTransaction {
+            transaction_type: RawTransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some("1.00015".to_string()),
+            timestamp: None,
+            currency: None,
+        };
+
+        assert_eq!(Transaction::try_from(raw()).unwrap().amount(), Some(10_001));
+        assert_eq!(
+            raw()
+                .into_transaction(RoundingMode::HalfEven)
+                .unwrap()
+                .amount(),
+            Some(10_002)
+        );
+        assert_eq!(
+            raw()
+                .into_transaction(RoundingMode::Reject)
+                .unwrap_err()
+                .to_string(),
+            "Failed to parse float into fixed point representation: The amount has more than 4 \
+            decimal places"
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_transaction_deserialization_with_currency() {
+        let csv = "type,client,tx,amount,currency
+                        deposit,1,1,1.0,usd
+                        dispute,1,1,,
+                        deposit,1,2,1.0,US-D";
+
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(BufReader::new(csv.as_bytes()));
+        let transactions: Vec<Result<RawTransaction, _>> = csv_reader.deserialize().collect();
+
+        let usd = "USD".parse::<Currency>().unwrap();
+        assert_eq!(usd.to_string(), "USD");
+        let raw = transactions[0].as_ref().unwrap();
+        assert_eq!(raw.currency, Some(usd));
+        assert_eq!(transactions[1].as_ref().unwrap().currency, None);
+        assert!(transactions[2].is_err());
+    }
 }
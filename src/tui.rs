@@ -0,0 +1,321 @@
+use crate::engine::{AccountView, Engine};
+use crate::error::EngineError;
+use crate::input::{self, CsvOptions, CsvRow, InvalidRows, ProcessedRows};
+use crate::rejected::{self, RejectedRows};
+use crate::util::{fixed_point_4_decimal_to_float_str, signed_fixed_point_4_decimal_to_float_str};
+use anyhow::{bail, Result};
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::cursor::{Hide, Show};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+/// How often the dashboard is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of seconds of throughput history shown
+const THROUGHPUT_HISTORY_SECS: usize = 120;
+
+/// Number of accounts listed by held funds
+const TOP_ACCOUNTS: usize = 10;
+
+/// Applies the transactions of one or more csvs to the engine like `input::process_csv_readers`,
+/// showing a live dashboard on stderr instead of reporting every rejected transaction and invalid
+/// row
+pub fn process_csv_readers<R: Read>(
+    engine: &mut Engine,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    invalid_rows: InvalidRows,
+    rejected: Option<&mut RejectedRows>,
+) -> Result<ProcessedRows> {
+    let mut stderr = io::stderr();
+    execute!(stderr, EnterAlternateScreen, Hide)?;
+    let result = Terminal::new(CrosstermBackend::new(io::stderr()))
+        .map_err(Into::into)
+        .and_then(|terminal| {
+            let mut dashboard = Dashboard::new(terminal);
+            dashboard.process(engine, readers, options, invalid_rows, rejected)?;
+            Ok(dashboard.processed)
+        });
+    execute!(stderr, LeaveAlternateScreen, Show)?;
+    result
+}
+
+struct Dashboard<B: Backend> {
+    terminal: Terminal<B>,
+    start: Instant,
+    last_draw: Instant,
+    processed: ProcessedRows,
+    /// Number of rejected transactions and invalid rows, by reason
+    rejections: BTreeMap<String, u64>,
+    /// Rows processed in each of the last seconds, the current one last
+    throughput: VecDeque<u64>,
+    second_start: Instant,
+}
+
+impl<B: Backend> Dashboard<B> {
+    fn new(terminal: Terminal<B>) -> Self {
+        let now = Instant::now();
+        Self {
+            terminal,
+            start: now,
+            last_draw: now,
+            processed: ProcessedRows::default(),
+            rejections: BTreeMap::new(),
+            throughput: VecDeque::from([0]),
+            second_start: now,
+        }
+    }
+
+    fn process<R: Read>(
+        &mut self,
+        engine: &mut Engine,
+        readers: Vec<R>,
+        options: &CsvOptions,
+        invalid_rows: InvalidRows,
+        mut rejected: Option<&mut RejectedRows>,
+    ) -> Result<()> {
+        let inputs = readers.len();
+        input::read_csv_inputs(readers, options, engine.config().rounding, |row: CsvRow| {
+            match &row.transaction {
+                Ok(transaction) => {
+                    let transaction_type = transaction.transaction_type();
+                    match engine.process_transaction_at(transaction.clone(), row.timestamp) {
+                        Ok(()) => self.processed.count(transaction_type, true),
+                        Err(e) if options.strict => {
+                            bail!(
+                                "Rejected transaction at {}: {e}",
+                                input::row_location(&row, inputs)
+                            )
+                        }
+                        Err(e) => {
+                            self.processed.count(transaction_type, false);
+                            *self.rejections.entry(rejection_reason(&e)).or_default() += 1;
+                            if let Some(rejected) = rejected.as_deref_mut() {
+                                rejected.write(&row, e.code())?;
+                            }
+                        }
+                    }
+                }
+                Err(e) if invalid_rows == InvalidRows::Fail => {
+                    bail!(input::invalid_row_message(&row, inputs, e))
+                }
+                Err(_) => {
+                    self.processed.invalid += 1;
+                    *self
+                        .rejections
+                        .entry("Invalid row".to_string())
+                        .or_default() += 1;
+                    if let Some(rejected) = rejected.as_deref_mut() {
+                        rejected.write(&row, rejected::INVALID_ROW)?;
+                    }
+                }
+            }
+            self.processed.check_error_rate_so_far(options)?;
+            self.tick(engine)
+        })?;
+
+        self.draw(engine)?;
+        Ok(())
+    }
+
+    /// Counts a processed row, redrawing the dashboard when it's due
+    fn tick(&mut self, engine: &Engine) -> Result<()> {
+        let now = Instant::now();
+        if now.duration_since(self.second_start) >= Duration::from_secs(1) {
+            if self.throughput.len() == THROUGHPUT_HISTORY_SECS {
+                self.throughput.pop_front();
+            }
+            self.throughput.push_back(0);
+            self.second_start = now;
+        }
+        *self
+            .throughput
+            .back_mut()
+            .expect("There's a current second") += 1;
+
+        if now.duration_since(self.last_draw) >= REDRAW_INTERVAL {
+            self.draw(engine)?;
+            self.last_draw = now;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, engine: &Engine) -> Result<()> {
+        let mut locked = 0;
+        let mut top_accounts: Vec<AccountView> = Vec::with_capacity(TOP_ACCOUNTS + 1);
+        for account in engine.accounts() {
+            locked += account.locked as u64;
+            if account.held_amount > 0 {
+                top_accounts.push(account);
+                top_accounts.sort_by_key(|account| Reverse(account.held_amount));
+                top_accounts.truncate(TOP_ACCOUNTS);
+            }
+        }
+
+        let elapsed = self.start.elapsed();
+        let processed = &self.processed;
+        let rejections = &self.rejections;
+        let throughput = &self.throughput;
+        self.terminal
+            .draw(|frame| {
+                render(
+                    frame,
+                    elapsed,
+                    processed,
+                    locked,
+                    throughput,
+                    rejections,
+                    &top_accounts,
+                )
+            })
+            .map_err(|e| anyhow::anyhow!("Failed to draw the dashboard: {e}"))?;
+        Ok(())
+    }
+}
+
+fn render(
+    frame: &mut Frame,
+    elapsed: Duration,
+    processed: &ProcessedRows,
+    locked: u64,
+    throughput: &VecDeque<u64>,
+    rejections: &BTreeMap<String, u64>,
+    top_accounts: &[AccountView],
+) {
+    let [summary_area, throughput_area, tables_area] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(8),
+        Constraint::Min(5),
+    ])
+    .areas(frame.area());
+    let [rejections_area, accounts_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .areas(tables_area);
+
+    let rows = processed.applied + processed.rejected + processed.invalid;
+    let summary = Paragraph::new(vec![
+        format!(
+            "Rows processed: {rows} in {:.0?} ({:.0} rows/s)",
+            elapsed,
+            rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        )
+        .into(),
+        format!(
+            "Applied: {}, rejected: {}, invalid: {}, locked accounts: {locked}",
+            processed.applied, processed.rejected, processed.invalid
+        )
+        .into(),
+    ])
+    .block(Block::bordered().title("Payments engine"));
+    frame.render_widget(summary, summary_area);
+
+    // The current second is still being counted, so it's left out
+    let completed = throughput.len() - 1;
+    let history: Vec<u64> = throughput.iter().take(completed).copied().collect();
+    let sparkline = Sparkline::default()
+        .data(
+            &history[history
+                .len()
+                .saturating_sub(throughput_area.width as usize - 2)..],
+        )
+        .block(Block::bordered().title(format!(
+            "Throughput (rows/s over the last {}s)",
+            history.len()
+        )));
+    frame.render_widget(sparkline, throughput_area);
+
+    let mut rejections: Vec<(&String, &u64)> = rejections.iter().collect();
+    rejections.sort_by_key(|(_, count)| Reverse(**count));
+    let rejections_table = Table::new(
+        rejections
+            .into_iter()
+            .map(|(reason, count)| Row::new([count.to_string(), reason.clone()])),
+        [Constraint::Length(10), Constraint::Fill(1)],
+    )
+    .header(Row::new(["Count", "Reason"]))
+    .block(Block::bordered().title("Rejections by reason"));
+    frame.render_widget(rejections_table, rejections_area);
+
+    let accounts_table = Table::new(
+        top_accounts.iter().map(|account| {
+            Row::new([
+                account.client_id.to_string(),
+                fixed_point_4_decimal_to_float_str(account.held_amount),
+                signed_fixed_point_4_decimal_to_float_str(account.available_amount),
+                account.locked.to_string(),
+            ])
+        }),
+        [
+            Constraint::Length(8),
+            Constraint::Length(16),
+            Constraint::Length(16),
+            Constraint::Length(6),
+        ],
+    )
+    .header(Row::new(["Client", "Held", "Available", "Locked"]))
+    .block(Block::bordered().title("Top accounts by held funds"));
+    frame.render_widget(accounts_table, accounts_area);
+}
+
+/// The reason an engine error gives for rejecting a transaction, without the details specific to
+/// the transaction (tx id, deposit state...) so rejections can be grouped by it
+fn rejection_reason(e: &EngineError) -> String {
+    let message = e.to_string();
+    let reason = message.split(" - ").next().unwrap_or_default();
+    let reason = reason.split(':').next().unwrap_or_default();
+    reason.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::{CsvOptions, InvalidRows};
+    use crate::tui::Dashboard;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_dashboard_shows_rejections_and_accounts() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,1,10\n\
+            deposit,2,2,20\n\
+            dispute,2,2,\n\
+            withdrawal,3,3,5\n\
+            withdrawal,1,4,abc\n";
+        let mut engine = Engine::new();
+        let mut dashboard = Dashboard::new(Terminal::new(TestBackend::new(160, 24)).unwrap());
+        dashboard
+            .process(
+                &mut engine,
+                vec![csv.as_bytes()],
+                &CsvOptions::default(),
+                InvalidRows::Skip,
+                None,
+            )
+            .unwrap();
+
+        let buffer = dashboard.terminal.backend().buffer();
+        let screen: String = buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect();
+        assert!(screen.contains("Rows processed: 6"));
+        assert!(screen.contains("Applied: 3, rejected: 2, invalid: 1, locked accounts: 0"));
+        assert!(screen.contains("A transaction failed because it had a duplicate tx_id"));
+        assert!(screen.contains("An withdrawal failed because the target account couldn't be"));
+        assert!(screen.contains("Invalid row"));
+        assert!(screen
+            .lines()
+            .any(|line| line.contains("││2 ") && line.contains("20.0000")));
+    }
+}
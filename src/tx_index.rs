@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The tx ids an engine has seen, so that deposits, withdrawals and holds reusing one are
+/// rejected. See `Engine::set_tx_id_index`.
+pub trait TxIdIndex: Send {
+    /// Adds a tx id, returning whether it wasn't in the index yet
+    fn insert(&mut self, tx_id: u32) -> bool;
+
+    /// Whether the tx id was added, which probabilistic indexes can wrongly answer yes to
+    fn contains(&self, tx_id: u32) -> bool;
+
+    /// Every tx id added, in increasing order, for snapshots and for merging engines, or `None`
+    /// for indexes that can't list them
+    fn tx_ids(&self) -> Option<Box<dyn Iterator<Item = u32> + '_>>;
+}
+
+/// Number of tx ids over which a chunk of a `TxIdBitmap` switches to a bitmap, past which the
+/// bitmap is the smaller of the two
+const ARRAY_MAX: usize = 4096;
+
+/// Number of words in the bitmap of a chunk, one bit per tx id of the chunk
+const BITMAP_WORDS: usize = 1 << 16 >> 6;
+
+/// A roaring bitmap of tx ids, the engine's default index: tx ids are split by their upper 16 bits
+/// into chunks, each a sorted array of the lower 16 bits while it has up to 4096 tx ids, and an
+/// 8KiB bitmap past that. Tx ids are usually dense, so most chunks end up as bitmaps taking about
+/// a bit per tx id, against some 40 bytes in a `HashSet<u32>`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TxIdBitmap {
+    chunks: BTreeMap<u16, Chunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Chunk {
+    /// The sorted lower bits of the chunk's tx ids
+    Array(Vec<u16>),
+    /// One bit per lower bits, `BITMAP_WORDS` words long
+    Bitmap(Vec<u64>),
+}
+
+impl TxIdBitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl FromIterator<u32> for TxIdBitmap {
+    fn from_iter<I: IntoIterator<Item = u32>>(tx_ids: I) -> Self {
+        let mut bitmap = Self::new();
+        for tx_id in tx_ids {
+            bitmap.insert(tx_id);
+        }
+        bitmap
+    }
+}
+
+impl TxIdIndex for TxIdBitmap {
+    fn insert(&mut self, tx_id: u32) -> bool {
+        let (high, low) = ((tx_id >> 16) as u16, tx_id as u16);
+        let chunk = self
+            .chunks
+            .entry(high)
+            .or_insert_with(|| Chunk::Array(Vec::new()));
+        let inserted = match chunk {
+            Chunk::Array(lows) => match lows.binary_search(&low) {
+                Ok(_) => false,
+                Err(position) => {
+                    lows.insert(position, low);
+                    true
+                }
+            },
+            Chunk::Bitmap(words) => {
+                let (word, bit) = (low as usize >> 6, 1 << (low & 63));
+                let inserted = words[word] & bit == 0;
+                words[word] |= bit;
+                inserted
+            }
+        };
+
+        if let Chunk::Array(lows) = chunk {
+            if lows.len() > ARRAY_MAX {
+                let mut words = vec![0; BITMAP_WORDS];
+                for low in lows.iter() {
+                    words[*low as usize >> 6] |= 1 << (low & 63);
+                }
+                *chunk = Chunk::Bitmap(words);
+            }
+        }
+        inserted
+    }
+
+    fn contains(&self, tx_id: u32) -> bool {
+        let (high, low) = ((tx_id >> 16) as u16, tx_id as u16);
+        match self.chunks.get(&high) {
+            Some(Chunk::Array(lows)) => lows.binary_search(&low).is_ok(),
+            Some(Chunk::Bitmap(words)) => words[low as usize >> 6] & (1 << (low & 63)) != 0,
+            None => false,
+        }
+    }
+
+    fn tx_ids(&self) -> Option<Box<dyn Iterator<Item = u32> + '_>> {
+        Some(Box::new(self.chunks.iter().flat_map(|(high, chunk)| {
+            let high = (*high as u32) << 16;
+            let lows: Box<dyn Iterator<Item = u32> + '_> = match chunk {
+                Chunk::Array(lows) => Box::new(lows.iter().map(|low| *low as u32)),
+                Chunk::Bitmap(words) => {
+                    Box::new(words.iter().enumerate().flat_map(|(index, word)| {
+                        (0..64)
+                            .filter(move |bit| word & (1 << bit) != 0)
+                            .map(move |bit| (index as u32) << 6 | bit)
+                    }))
+                }
+            };
+            lows.map(move |low| high | low)
+        })))
+    }
+}
+
+/// A Bloom filter of tx ids, taking the same memory whatever the number of tx ids, at the cost of
+/// rejecting some new tx ids as duplicates: up to `capacity` tx ids, about `false_positive_rate`
+/// of new ones are. Tx ids can't be listed back, so engines using one can't be snapshotted.
+pub struct TxIdBloomFilter {
+    bits: Vec<u64>,
+    /// Number of bits set per tx id
+    hashes: u32,
+}
+
+impl TxIdBloomFilter {
+    pub fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let words = ((bits / 64.0).ceil() as usize).max(1);
+        let hashes = ((words * 64) as f64 / capacity * ln2)
+            .round()
+            .clamp(1.0, 32.0) as u32;
+        Self {
+            bits: vec![0; words],
+            hashes,
+        }
+    }
+
+    /// The bits of a tx id, by double hashing
+    fn bit_indexes(&self, tx_id: u32) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        let first = mix(tx_id as u64);
+        let second = mix(first) | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+impl TxIdIndex for TxIdBloomFilter {
+    fn insert(&mut self, tx_id: u32) -> bool {
+        let mut inserted = false;
+        for index in self.bit_indexes(tx_id).collect::<Vec<_>>() {
+            let bit = 1 << (index & 63);
+            inserted |= self.bits[index >> 6] & bit == 0;
+            self.bits[index >> 6] |= bit;
+        }
+        inserted
+    }
+
+    fn contains(&self, tx_id: u32) -> bool {
+        self.bit_indexes(tx_id)
+            .all(|index| self.bits[index >> 6] & (1 << (index & 63)) != 0)
+    }
+
+    fn tx_ids(&self) -> Option<Box<dyn Iterator<Item = u32> + '_>> {
+        None
+    }
+}
+
+/// The splitmix64 finalizer, spreading the bits of tx ids over the filter
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tx_index::{TxIdBitmap, TxIdBloomFilter, TxIdIndex};
+    use std::ops::Not;
+
+    #[test]
+    fn test_tx_id_bitmap() {
+        let mut index = TxIdBitmap::new();
+        assert!(index.insert(7));
+        assert!(index.insert(u32::MAX));
+        assert!(index.insert(7).not());
+        // Enough tx ids in the first chunk to turn it into a bitmap
+        for tx_id in (0..20_000).step_by(3) {
+            index.insert(tx_id);
+        }
+        assert!(index.insert(9).not());
+        assert!(index.insert(8));
+        assert!(index.contains(19_998));
+        assert!(index.contains(19_997).not());
+        assert!(index.contains(u32::MAX));
+        assert!(index.contains(u32::MAX - 1).not());
+
+        let tx_ids: Vec<u32> = index.tx_ids().unwrap().collect();
+        assert_eq!(tx_ids.len(), 6_667 + 3);
+        assert_eq!(&tx_ids[..5], &[0, 3, 6, 7, 8]);
+        assert_eq!(tx_ids.last(), Some(&u32::MAX));
+        assert!(tx_ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_tx_id_bloom_filter() {
+        let mut index = TxIdBloomFilter::new(10_000, 0.01);
+        for tx_id in 0..10_000 {
+            index.insert(tx_id * 2);
+        }
+        // No tx id added is ever missed, while few of the others are wrongly found
+        assert!((0..10_000).all(|tx_id| index.contains(tx_id * 2)));
+        assert!(index.insert(0).not());
+        let false_positives = (0..10_000)
+            .filter(|tx_id| index.contains(tx_id * 2 + 1))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+        assert!(index.tx_ids().is_none());
+    }
+}
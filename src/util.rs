@@ -1,10 +1,31 @@
-use anyhow::Result;
+use crate::error::AmountError;
+use std::cmp::Ordering;
+use std::ops::Not;
 
-pub fn fixed_point_4_decimal_to_float_str(value: u64) -> String {
+/// What happens to amounts with more than 4 decimal places, which the fixed point representation
+/// can't hold
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum RoundingMode {
+    /// Drop the extra decimals, e.g. `1.00019` becomes `1.0001`
+    #[default]
+    Truncate,
+    /// Round to the nearest, ties away from zero, e.g. `1.00015` becomes `1.0002`
+    HalfUp,
+    /// Round to the nearest, ties to the even neighbour (banker's rounding), e.g. `1.00015`
+    /// becomes `1.0002` and `1.00025` becomes `1.0002`
+    HalfEven,
+    /// Reject the amount
+    Reject,
+}
+
+pub fn fixed_point_4_decimal_to_float_str(value: impl Into<u128>) -> String {
+    let value = value.into();
     format!("{}.{:04}", value / 10_000, value % 10_000)
 }
 
-pub fn signed_fixed_point_4_decimal_to_float_str(value: i64) -> String {
+pub fn signed_fixed_point_4_decimal_to_float_str(value: impl Into<i128>) -> String {
+    let value = value.into();
     format!(
         "{}{}",
         get_sign_prefix(value),
@@ -12,7 +33,7 @@ pub fn signed_fixed_point_4_decimal_to_float_str(value: i64) -> String {
     )
 }
 
-fn get_sign_prefix(value: i64) -> &'static str {
+fn get_sign_prefix(value: i128) -> &'static str {
     if value < 0 {
         "-"
     } else {
@@ -20,43 +41,103 @@ fn get_sign_prefix(value: i64) -> &'static str {
     }
 }
 
-pub fn float_str_to_fixed_point_4_decimal(value: &str) -> Result<u64> {
+/// Parses a non-negative decimal amount, such as `10.5`, into its fixed point representation.
+/// Surrounding whitespace is ignored, and digits past the 4th decimal place are dropped, but signs,
+/// exponents and whitespace within the amount are rejected.
+pub fn float_str_to_fixed_point_4_decimal(value: &str) -> Result<u64, AmountError> {
+    float_str_to_fixed_point_4_decimal_rounded(value, RoundingMode::Truncate)
+}
+
+/// Parses a non-negative decimal amount like `float_str_to_fixed_point_4_decimal`, handling the
+/// digits past the 4th decimal place according to `rounding`
+pub fn float_str_to_fixed_point_4_decimal_rounded(
+    value: &str,
+    rounding: RoundingMode,
+) -> Result<u64, AmountError> {
     let value = value.trim();
+    if value.is_empty() {
+        return Err(AmountError::EmptyAmount);
+    }
+    if value.starts_with('-') {
+        return Err(AmountError::NegativeAmount);
+    }
+    if let Some(c) = value
+        .chars()
+        .find(|c| c.is_ascii_digit().not() && *c != '.')
+    {
+        return Err(AmountError::InvalidCharacter(c));
+    }
     let (integer, fractional) = match value.split_once('.') {
         None => (value, ""),
+        Some((_, s)) if s.contains('.') => return Err(AmountError::TooManyDecimalPoints),
         Some((p, s)) => (p, s),
     };
+    if integer.is_empty() {
+        return Err(AmountError::MissingIntegerPart);
+    }
 
-    let integer = integer.parse::<u64>()? * 10_000;
-    let fractional = first_four_chars_or_pad(fractional).parse::<u64>()?;
+    // The fractional part is made of ASCII digits only by now
+    let (fractional, extra) = fractional.split_at(fractional.len().min(4));
 
-    Ok(integer + fractional)
-}
+    let integer = integer
+        .parse::<u64>()
+        .ok()
+        .and_then(|integer| integer.checked_mul(10_000))
+        .ok_or(AmountError::AmountTooLarge)?;
+    let fractional = fractional
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(4)
+        .fold(0, |fractional, digit| {
+            fractional * 10 + (digit - b'0') as u64
+        });
+    let amount = integer
+        .checked_add(fractional)
+        .ok_or(AmountError::AmountTooLarge)?;
 
-fn first_four_chars_or_pad(s: &str) -> String {
-    let mut result = s.to_string();
-    while result.len() < 4 {
-        result.push('0');
+    if round_up(amount, extra, rounding)? {
+        amount.checked_add(1).ok_or(AmountError::AmountTooLarge)
+    } else {
+        Ok(amount)
     }
-    result[..4].to_string()
+}
+
+/// Whether an amount truncated to 4 decimal places should be rounded up to make up for the
+/// `extra` digits that followed
+fn round_up(truncated: u64, extra: &str, rounding: RoundingMode) -> Result<bool, AmountError> {
+    let Some((first, rest)) = extra.trim_end_matches('0').as_bytes().split_first() else {
+        return Ok(false);
+    };
+    Ok(match rounding {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => *first >= b'5',
+        RoundingMode::HalfEven => match first.cmp(&b'5') {
+            Ordering::Less => false,
+            Ordering::Greater => true,
+            Ordering::Equal => rest.is_empty().not() || truncated % 2 == 1,
+        },
+        RoundingMode::Reject => return Err(AmountError::TooManyDecimalPlaces),
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::AmountError;
     use crate::util::{
         fixed_point_4_decimal_to_float_str, float_str_to_fixed_point_4_decimal,
-        signed_fixed_point_4_decimal_to_float_str,
+        float_str_to_fixed_point_4_decimal_rounded, signed_fixed_point_4_decimal_to_float_str,
+        RoundingMode,
     };
 
     #[test]
     fn test_fixed_4_decimal_points_to_float() {
-        assert_eq!(fixed_point_4_decimal_to_float_str(0), "0.0000");
-        assert_eq!(fixed_point_4_decimal_to_float_str(1), "0.0001");
-        assert_eq!(fixed_point_4_decimal_to_float_str(9_999), "0.9999");
-        assert_eq!(fixed_point_4_decimal_to_float_str(10_000), "1.0000");
-        assert_eq!(fixed_point_4_decimal_to_float_str(10_001), "1.0001");
+        assert_eq!(fixed_point_4_decimal_to_float_str(0u64), "0.0000");
+        assert_eq!(fixed_point_4_decimal_to_float_str(1u64), "0.0001");
+        assert_eq!(fixed_point_4_decimal_to_float_str(9_999u64), "0.9999");
+        assert_eq!(fixed_point_4_decimal_to_float_str(10_000u64), "1.0000");
+        assert_eq!(fixed_point_4_decimal_to_float_str(10_001u64), "1.0001");
         assert_eq!(
-            fixed_point_4_decimal_to_float_str(10_000_000_001),
+            fixed_point_4_decimal_to_float_str(10_000_000_001u64),
             "1000000.0001"
         );
     }
@@ -69,7 +150,7 @@ mod tests {
         assert_eq!(signed_fixed_point_4_decimal_to_float_str(10_000), "1.0000");
         assert_eq!(signed_fixed_point_4_decimal_to_float_str(10_001), "1.0001");
         assert_eq!(
-            signed_fixed_point_4_decimal_to_float_str(10_000_000_001),
+            signed_fixed_point_4_decimal_to_float_str(10_000_000_001i64),
             "1000000.0001"
         );
         assert_eq!(signed_fixed_point_4_decimal_to_float_str(-0), "0.0000");
@@ -84,7 +165,7 @@ mod tests {
             "-1.0001"
         );
         assert_eq!(
-            signed_fixed_point_4_decimal_to_float_str(-10_000_000_001),
+            signed_fixed_point_4_decimal_to_float_str(-10_000_000_001i64),
             "-1000000.0001"
         );
     }
@@ -130,5 +211,62 @@ mod tests {
         // Test error cases
         assert!(float_str_to_fixed_point_4_decimal("").is_err());
         assert!(float_str_to_fixed_point_4_decimal("abc").is_err());
+        for (value, error) in [
+            (" ", AmountError::EmptyAmount),
+            ("-1.5", AmountError::NegativeAmount),
+            ("-0", AmountError::NegativeAmount),
+            ("1.2.3", AmountError::TooManyDecimalPoints),
+            ("1.23456.7", AmountError::TooManyDecimalPoints),
+            ("+1", AmountError::InvalidCharacter('+')),
+            ("1 000", AmountError::InvalidCharacter(' ')),
+            ("1. 5", AmountError::InvalidCharacter(' ')),
+            ("1e5", AmountError::InvalidCharacter('e')),
+            ("1.\u{e9}1", AmountError::InvalidCharacter('\u{e9}')),
+            (".5", AmountError::MissingIntegerPart),
+            ("1844674407370956", AmountError::AmountTooLarge),
+            ("1844674407370955.1616", AmountError::AmountTooLarge),
+            ("99999999999999999999", AmountError::AmountTooLarge),
+        ] {
+            assert_eq!(float_str_to_fixed_point_4_decimal(value), Err(error));
+        }
+        assert_eq!(
+            float_str_to_fixed_point_4_decimal("1844674407370955.1615").unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn test_float_str_to_fixed_point_4_decimal_rounded() {
+        use RoundingMode::*;
+
+        // Expected results in the truncate, half-up, half-even and reject modes
+        for (value, expected) in [
+            ("1.0001", [Ok(10_001), Ok(10_001), Ok(10_001), Ok(10_001)]),
+            ("1.000100", [Ok(10_001), Ok(10_001), Ok(10_001), Ok(10_001)]),
+            ("1.00014", [Ok(10_001), Ok(10_001), Ok(10_001), Err(())]),
+            ("1.00015", [Ok(10_001), Ok(10_002), Ok(10_002), Err(())]),
+            ("1.00025", [Ok(10_002), Ok(10_003), Ok(10_002), Err(())]),
+            ("1.000250", [Ok(10_002), Ok(10_003), Ok(10_002), Err(())]),
+            ("1.000251", [Ok(10_002), Ok(10_003), Ok(10_003), Err(())]),
+            ("1.00019999", [Ok(10_001), Ok(10_002), Ok(10_002), Err(())]),
+            ("0.99995", [Ok(9_999), Ok(10_000), Ok(10_000), Err(())]),
+        ] {
+            for (rounding, expected) in [Truncate, HalfUp, HalfEven, Reject]
+                .into_iter()
+                .zip(expected)
+            {
+                let expected = expected.map_err(|()| AmountError::TooManyDecimalPlaces);
+                assert_eq!(
+                    float_str_to_fixed_point_4_decimal_rounded(value, rounding),
+                    expected,
+                    "{value} with {rounding:?}"
+                );
+            }
+        }
+
+        assert_eq!(
+            float_str_to_fixed_point_4_decimal_rounded("1844674407370955.16155", HalfUp),
+            Err(AmountError::AmountTooLarge)
+        );
     }
 }
@@ -0,0 +1,123 @@
+use crate::engine::{Engine, EngineConfig};
+use crate::input::{self, CsvOptions};
+use crate::rejected;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// The rows of transactions csvs that processing them would reject
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Number of rows read
+    pub rows: u64,
+    /// Number of rows the engine would reject, by reason code (see `EngineError::code`), with
+    /// invalid rows under `invalid_row`
+    pub rejections: BTreeMap<&'static str, u64>,
+}
+
+impl ValidationReport {
+    /// Whether every row would be applied
+    pub fn is_clean(&self) -> bool {
+        self.rejections.is_empty()
+    }
+}
+
+/// Checks transactions csvs without producing account states: every row is run through an engine
+/// with the given config, which sees the same duplicate tx ids, disputes of unknown transactions
+/// or transactions on locked accounts as processing them would. Every row that would be rejected
+/// is written to `writer`, along with its reason code and why.
+pub fn validate<R: Read>(
+    config: EngineConfig,
+    readers: Vec<R>,
+    options: &CsvOptions,
+    mut writer: impl Write,
+) -> Result<ValidationReport> {
+    let mut engine = Engine::with_config(config);
+    let mut report = ValidationReport::default();
+    let inputs = readers.len();
+
+    input::read_csv_inputs(readers, options, engine.config().rounding, |row| {
+        report.rows += 1;
+        let rejection = match &row.transaction {
+            Ok(transaction) => engine
+                .process_transaction_at(transaction.clone(), row.timestamp)
+                .err()
+                .map(|e| (e.code(), e.to_string())),
+            Err(e) => Some((rejected::INVALID_ROW, e.to_string())),
+        };
+        if let Some((code, reason)) = rejection {
+            *report.rejections.entry(code).or_default() += 1;
+            writeln!(
+                writer,
+                "{}: {code}: {reason}",
+                input::row_location(&row, inputs)
+            )?;
+        }
+        Ok(())
+    })?;
+
+    writer.flush()?;
+    Ok(report)
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rejected: u64 = self.rejections.values().sum();
+        write!(
+            f,
+            "Rows: {}, would be applied: {}, would be rejected: {rejected}",
+            self.rows,
+            self.rows - rejected
+        )?;
+        for (code, count) in self.rejections.iter() {
+            write!(f, "\n  {code}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::EngineConfig;
+    use crate::input::CsvOptions;
+    use crate::validate::validate;
+    use std::ops::Not;
+
+    #[test]
+    fn test_validate_reports_would_be_rejections() {
+        let csv = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,1,5\n\
+            dispute,1,9,\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,1,2,1\n\
+            deposit,2,three,1\n";
+        let mut output = Vec::new();
+        let report = validate(
+            EngineConfig::default(),
+            vec![csv.as_bytes()],
+            &CsvOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(report.rows, 7);
+        assert!(report.is_clean().not());
+        assert_eq!(
+            report.rejections.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("account_locked", 1),
+                ("duplicate_tx_id", 1),
+                ("invalid_row", 1),
+                ("unknown_tx", 1)
+            ]
+        );
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("line 3 (byte 37) of provided csv: duplicate_tx_id: "));
+        assert!(lines[2].starts_with("line 7 (byte 93) of provided csv: account_locked: "));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::engine::Engine;
+use crate::transaction::Transaction;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Not;
+use std::path::Path;
+use tracing::warn;
+
+/// A write-ahead log: every transaction that got past the validators is appended to it, and
+/// synced to disk, before the engine applies it. Replaying the log into the engine it was written
+/// from, as restored from its last snapshot, brings it back to the state it was in when it
+/// stopped, even if it crashed.
+///
+/// Entries are JSON objects, one per line. Transactions the engine then rejected are logged too,
+/// and rejected again when replayed.
+pub struct Wal {
+    file: File,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    transaction: Transaction,
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, creating it if needed. An incomplete last entry is
+    /// dropped, so that the next one starts on its own line.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let complete = complete_entries_len(&mut file)?;
+        if complete < file.metadata()?.len() {
+            file.set_len(complete)?;
+        }
+        Ok(Self { file })
+    }
+
+    /// Appends a transaction, along with its timestamp, returning once it's on disk
+    pub fn append(&mut self, transaction: &Transaction, timestamp: Option<u64>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&WalEntry {
+            timestamp,
+            transaction: transaction.clone(),
+        })?;
+        line.push(b'\n');
+        // A single write, so that a crash can at worst leave the last entry incomplete
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    /// Empties the log, once a snapshot holds everything it recorded
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+/// Length of the log up to the end of its last complete entry
+fn complete_entries_len(file: &mut File) -> io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut chunk = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|byte| *byte == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Replays the log at `path`, if there's one, into the engine, returning the number of
+/// transactions replayed. An incomplete last entry, left by a crash in the middle of appending it,
+/// is skipped, since the engine never applied it.
+pub fn replay(engine: &mut Engine, path: &Path) -> Result<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut replayed = 0;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let entry = match serde_json::from_str::<WalEntry>(&line) {
+            Ok(entry) => entry,
+            Err(_) if line.ends_with('\n').not() => {
+                warn!("Skipped the incomplete last entry of the write-ahead log");
+                break;
+            }
+            Err(e) => bail!("Invalid write-ahead log entry {}: {e}", replayed + 1),
+        };
+        // Rejections are part of the history being replayed
+        let _ = engine.process_transaction_at(entry.transaction, entry.timestamp);
+        replayed += 1;
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+    use crate::wal::{self, Wal};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    #[test]
+    fn test_wal_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-wal-test-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let mut engine = Engine::new();
+        engine.set_wal(Wal::open(&path).unwrap());
+        let transactions = [
+            Transaction::Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: 100,
+                currency: None,
+            },
+            Transaction::Withdrawal {
+                client_id: 1,
+                tx_id: 2,
+                amount: 500,
+                currency: None,
+            },
+            Transaction::Dispute {
+                client_id: 1,
+                tx_id: 1,
+                currency: None,
+            },
+        ];
+        for (timestamp, transaction) in transactions.into_iter().enumerate() {
+            let _ = engine.process_transaction_at(transaction, Some(timestamp as u64));
+        }
+
+        // A crash in the middle of appending the next entry
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"transaction":{"type":"resolve""#)
+            .unwrap();
+
+        let mut recovered = Engine::new();
+        assert_eq!(wal::replay(&mut recovered, &path).unwrap(), 3);
+        assert_eq!(recovered.account(1), engine.account(1));
+        assert_eq!(recovered.account(1).unwrap().held_amount, 100);
+
+        // Logging resumes after the last complete entry
+        recovered.set_wal(Wal::open(&path).unwrap());
+        let resolve = Transaction::Resolve {
+            client_id: 1,
+            tx_id: 1,
+            currency: None,
+        };
+        recovered.process_transaction(resolve).unwrap();
+        let mut replayed = Engine::new();
+        assert_eq!(wal::replay(&mut replayed, &path).unwrap(), 4);
+        assert_eq!(replayed.account(1).unwrap().available_amount, 100);
+
+        engine.wal_mut().unwrap().truncate().unwrap();
+        assert_eq!(wal::replay(&mut Engine::new(), &path).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(wal::replay(&mut Engine::new(), &path).unwrap(), 0);
+    }
+}
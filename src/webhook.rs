@@ -0,0 +1,223 @@
+use crate::engine::AccountLockedEvent;
+use crate::output::account_to_json;
+use crate::util::fixed_point_4_decimal_to_float_str;
+use anyhow::Result;
+use serde_json::Value;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{error, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    /// How many times a failed delivery is retried before giving up
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every subsequent attempt
+    pub initial_backoff: Duration,
+}
+
+/// Delivers JSON payloads to the configured webhooks from a background thread, so that slow or
+/// unavailable endpoints never stall transaction processing
+pub struct WebhookNotifier {
+    sender: Sender<Value>,
+    worker: JoinHandle<()>,
+}
+
+/// Cheaply cloneable handle used to queue payloads for a `WebhookNotifier`
+#[derive(Clone)]
+pub struct WebhookHandle {
+    sender: Sender<Value>,
+}
+
+impl WebhookNotifier {
+    pub fn spawn(config: WebhookConfig) -> Self {
+        let (sender, receiver) = mpsc::channel::<Value>();
+
+        let worker = thread::spawn(move || {
+            let agent: ureq::Agent = ureq::Agent::config_builder()
+                .timeout_global(Some(REQUEST_TIMEOUT))
+                .build()
+                .into();
+
+            for payload in receiver {
+                let payload = payload.to_string();
+                for url in config.urls.iter() {
+                    if let Err(e) = post_with_retries(&agent, url, &payload, &config) {
+                        error!("Failed to deliver webhook to {url}: {e}");
+                    }
+                }
+            }
+        });
+
+        Self { sender, worker }
+    }
+
+    pub fn handle(&self) -> WebhookHandle {
+        WebhookHandle {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Returns a listener to register through `Engine::on_account_locked`
+    pub fn account_locked_listener(&self) -> impl FnMut(&AccountLockedEvent) + Send + 'static {
+        let handle = self.handle();
+        move |event| handle.send(lock_event_payload(event))
+    }
+
+    /// Waits until all queued payloads have been delivered. Every handle (including the ones held
+    /// by listeners registered with an engine) must be dropped beforehand, otherwise this never
+    /// returns.
+    pub fn finish(self) {
+        drop(self.sender);
+        if self.worker.join().is_err() {
+            error!("Webhook delivery thread panicked");
+        }
+    }
+}
+
+impl WebhookHandle {
+    pub fn send(&self, payload: Value) {
+        if self.sender.send(payload).is_err() {
+            error!("Dropped webhook because the delivery thread stopped");
+        }
+    }
+}
+
+fn post_with_retries(
+    agent: &ureq::Agent,
+    url: &str,
+    payload: &str,
+    config: &WebhookConfig,
+) -> Result<()> {
+    let mut backoff = config.initial_backoff;
+    let mut retries = 0;
+
+    loop {
+        match agent
+            .post(url)
+            .header("Content-Type", "application/json")
+            .send(payload)
+        {
+            Ok(_) => return Ok(()),
+            Err(e) if retries < config.max_retries => {
+                warn!("Webhook to {url} failed, retrying in {backoff:?}: {e}");
+                thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn lock_event_payload(event: &AccountLockedEvent) -> Value {
+    let mut payload = account_to_json(event.account);
+    payload["event"] = "account_locked".into();
+    payload["tx"] = event.tx_id.into();
+    payload["amount"] = fixed_point_4_decimal_to_float_str(event.amount).into();
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+    use crate::webhook::{WebhookConfig, WebhookNotifier};
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Answers each incoming request with the next status code and returns the received bodies
+    fn spawn_http_server(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let body = loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                        let content_length = headers
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= content_length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                bodies.push(body);
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+            bodies
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn test_lock_webhook_is_retried_until_delivered() {
+        let (url, server) = spawn_http_server(vec![500, 200]);
+        let notifier = WebhookNotifier::spawn(WebhookConfig {
+            urls: vec![url],
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+        });
+
+        let mut engine = Engine::new();
+        engine.on_account_locked(notifier.account_locked_listener());
+        engine
+            .process_transaction(Transaction::Deposit {
+                client_id: 3,
+                tx_id: 1,
+                amount: 20_000,
+                currency: None,
+            })
+            .unwrap();
+        engine
+            .process_transaction(Transaction::Dispute {
+                client_id: 3,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        engine
+            .process_transaction(Transaction::Chargeback {
+                client_id: 3,
+                tx_id: 1,
+                currency: None,
+            })
+            .unwrap();
+
+        drop(engine);
+        notifier.finish();
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(
+            serde_json::from_str::<Value>(&bodies[1]).unwrap(),
+            json!({"event": "account_locked", "client": 3, "tx": 1, "amount": "2.0000",
+                "available": "0.0000", "held": "0.0000", "total": "0.0000", "locked": true})
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use csv::ReaderBuilder;
-use std::process::Command;
+use std::io::Write;
+use std::process::{Command, Stdio};
 
 #[test]
 fn test_sample_transactions() {
@@ -13,7 +14,8 @@ fn test_sample_transactions() {
         .output()
         .unwrap();
 
-    assert!(output.status.success(), "Cargo run failed");
+    // Some of the sample transactions are rejected by the engine
+    assert_eq!(output.status.code(), Some(1), "Cargo run failed");
 
     assert_eq!(
         find_client_row(&output.stdout, "1"),
@@ -49,6 +51,28 @@ fn test_sample_transactions() {
     );
 }
 
+#[test]
+fn test_transactions_from_stdin() {
+    let input = std::fs::read("tests/test_sample_data/sample_transactions.csv").unwrap();
+    for args in [&["run", "--release", "--", "-"][..], &["run", "--release"]] {
+        let mut child = Command::new("cargo")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(output.status.code(), Some(1), "Cargo run failed");
+        assert_eq!(
+            find_client_row(&output.stdout, "6"),
+            "6,-50.0000,100.0000,50.0000,false"
+        );
+    }
+}
+
 fn find_client_row(csv_data: &[u8], client_id: &str) -> String {
     let mut reader = ReaderBuilder::new().from_reader(csv_data);
     for result in reader.records() {
@@ -59,3 +83,58 @@ fn find_client_row(csv_data: &[u8], client_id: &str) -> String {
     }
     String::new()
 }
+
+#[test]
+fn test_corrupted_transactions() {
+    let run = |invalid_rows: &str| {
+        Command::new("cargo")
+            .args([
+                "run",
+                "--release",
+                "--",
+                "tests/test_sample_data/corrupted_transactions.csv",
+                "--invalid-rows",
+                invalid_rows,
+            ])
+            .output()
+            .unwrap()
+    };
+
+    // The rows with a flipped byte and a missing field are skipped, the duplicated row is
+    // rejected by the engine, and the last row, cut after the decimal point, still gets applied
+    let output = run("skip");
+    assert_eq!(output.status.code(), Some(1), "Cargo run failed");
+    assert_eq!(
+        find_client_row(&output.stdout, "1"),
+        "1,7.5000,5.0000,12.5000,false"
+    );
+    assert_eq!(
+        find_client_row(&output.stdout, "2"),
+        "2,1.0000,0.0000,1.0000,false"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid row at line 4"));
+    assert!(stderr.contains("Invalid row at line 5 (byte 84) of provided csv"));
+    assert!(stderr.contains(r#"row: "deposit, 2, 4""#));
+
+    let output = run("fail");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid row at line 4"));
+
+    // A third of the rows fail, more than the error rate allows
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--release",
+            "--",
+            "tests/test_sample_data/corrupted_transactions.csv",
+            "--max-error-rate",
+            "0.25",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("3 of 9 rows failed"));
+}